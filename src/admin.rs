@@ -0,0 +1,197 @@
+//! `GET /admin/queue`: why submitted jobs haven't started yet.
+//!
+//! A job is queued while it waits for an execution window (`queued_until`)
+//! or the end of a blackout, out its random start delay (`splay_secs`), for
+//! the host to recover from pressure (`on_host_pressure=defer`), or, once
+//! due, for one of the `AGENT_MAX_CONCURRENT` slots. Its `lock_free` guards
+//! are checked once it is due, so locks currently held are shown as
+//! conflicts. Requests waiting for a slot are not jobs yet and are only
+//! counted.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::blackout;
+use crate::clock;
+use crate::config::AppConfig;
+use crate::jobs::{Job, JobRegistry, JobStatus};
+use crate::pressure::HostCheck;
+use crate::DEFER_RETRY;
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum QueueReason {
+    /// Outside the request's execution windows.
+    Window,
+    /// In a blackout period, which closes every window.
+    Blackout,
+    /// Deferred while the host is over a load threshold.
+    HostPressure,
+    /// Waiting out its random start delay (`splay_secs`).
+    Splay,
+    /// Due, and waiting for a slot (`AGENT_MAX_CONCURRENT`).
+    Concurrency,
+}
+
+#[derive(Serialize)]
+struct LockConflict {
+    lock: String,
+    held_by: String,
+}
+
+#[derive(Serialize)]
+struct QueuedJob {
+    id: String,
+    command: String,
+    queued_at: String,
+    reason: QueueReason,
+    /// Earliest time the job can start, when known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimated_start: Option<String>,
+    /// Last host check of a deferred job.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_check: Option<HostCheck>,
+    /// Deferred jobs are skipped if the host hasn't recovered by then.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gives_up_at: Option<String>,
+    /// Locks its `lock_free` guards need that are held right now; the job is
+    /// skipped if they still are when it is due.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    lock_conflicts: Vec<LockConflict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RunningJob {
+    id: String,
+    command: String,
+    started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Workers {
+    /// Request handler threads.
+    http_workers: usize,
+    running_jobs: usize,
+    queued_jobs: usize,
+    /// `AGENT_MAX_CONCURRENT`, when set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_concurrent: Option<usize>,
+    /// Commands and playbooks holding a slot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    concurrent: Option<usize>,
+    /// Requests waiting for a slot, at most `AGENT_MAX_QUEUE_DEPTH`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    waiting_requests: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct QueueResponse {
+    success: bool,
+    queued: Vec<QueuedJob>,
+    running: Vec<RunningJob>,
+    /// Held locks and the job holding each.
+    locks: BTreeMap<String, String>,
+    workers: Workers,
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(value).ok().map(|time| time.with_timezone(&Local))
+}
+
+fn queued_job(job: Job, locks: &BTreeMap<String, String>, config: &AppConfig) -> QueuedJob {
+    let lock_conflicts = job
+        .lock_guards
+        .iter()
+        .filter_map(|lock| {
+            locks.get(lock).map(|holder| LockConflict {
+                lock: lock.clone(),
+                held_by: holder.clone(),
+            })
+        })
+        .collect();
+    // Once its window opens, a queued job may go on to wait for the host.
+    let window_opens = job.queued_until.as_deref().and_then(parse_time);
+    let splay = chrono::Duration::seconds(job.splay_secs.unwrap_or(0) as i64);
+    let (reason, estimated_start, gives_up_at) = match window_opens {
+        _ if config.concurrency.is_queued(&job.id) => (QueueReason::Concurrency, None, None),
+        Some(opens) if opens - splay > Local::now() => {
+            let reason = if blackout::covering(Local::now()).is_some() { QueueReason::Blackout } else { QueueReason::Window };
+            (reason, job.queued_until.clone(), None)
+        }
+        Some(opens) if opens > Local::now() => (QueueReason::Splay, job.queued_until.clone(), None),
+        _ => {
+            let checked_at = job.host_check.as_ref().and_then(|check| parse_time(&check.checked_at));
+            let retry = chrono::Duration::from_std(DEFER_RETRY).unwrap_or_default();
+            let max_defer = chrono::Duration::from_std(config.max_defer).unwrap_or_default();
+            let waiting_since = window_opens.or_else(|| parse_time(&job.started_at));
+            (
+                QueueReason::HostPressure,
+                checked_at.map(|checked_at| clock::format(&(checked_at + retry))),
+                waiting_since.map(|since| clock::format(&(since + max_defer))),
+            )
+        }
+    };
+    QueuedJob {
+        id: job.id,
+        command: job.command,
+        queued_at: job.started_at,
+        reason,
+        estimated_start,
+        host_check: job.host_check,
+        gives_up_at,
+        lock_conflicts,
+        api_key: job.api_key,
+    }
+}
+
+/// GET /admin/queue - queued and running jobs, held locks and worker state.
+pub async fn get_queue(jobs: web::Data<JobRegistry>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let mut all = jobs.list();
+    all.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+
+    let locks: BTreeMap<String, String> = all
+        .iter()
+        .filter(|job| job.status == JobStatus::Running)
+        .filter_map(|job| Some((job.lock.clone()?, job.id.clone())))
+        .collect();
+    let running: Vec<RunningJob> = all
+        .iter()
+        .filter(|job| job.status == JobStatus::Running)
+        .map(|job| RunningJob {
+            id: job.id.clone(),
+            command: job.command.clone(),
+            started_at: job.started_at.clone(),
+            pid: job.pid,
+            lock: job.lock.clone(),
+        })
+        .collect();
+    let queued: Vec<QueuedJob> = all
+        .into_iter()
+        .filter(|job| job.status == JobStatus::Queued)
+        .map(|job| queued_job(job, &locks, &config))
+        .collect();
+
+    let limiter = &config.concurrency;
+    Ok(HttpResponse::Ok().json(QueueResponse {
+        success: true,
+        workers: Workers {
+            http_workers: config.workers,
+            running_jobs: running.len(),
+            queued_jobs: queued.len(),
+            max_concurrent: limiter.enabled().then_some(limiter.max_concurrent),
+            concurrent: limiter.enabled().then(|| limiter.running()),
+            waiting_requests: limiter.enabled().then(|| limiter.waiting()),
+        },
+        queued,
+        running,
+        locks,
+    }))
+}
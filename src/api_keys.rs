@@ -0,0 +1,234 @@
+//! Per-integration API keys, each with request defaults and server-side
+//! overrides, so security can constrain what an integration can run
+//! regardless of what it sends.
+//!
+//! Keys are defined in the JSON file named by `AGENT_API_KEYS_FILE`:
+//!
+//! ```json
+//! {"ci": {"key": "...",
+//!         "defaults": {"timeout": 120, "capture_on_failure": true},
+//!         "overrides": {"hooks": "snapshot"},
+//!         "max_timeout": 600,
+//!         "run_as": "deploy",
+//!         "banned_shells": ["bash", "powershell", "pwsh"]}}
+//! ```
+//!
+//! Once keys are configured, every route except `/` and `/health`
+//! ([`crate::auth::OPEN`]) requires one of them in the `X-API-Key` header (or
+//! `Authorization: Bearer`); a job's stream, output and log routes
+//! ([`crate::job_stream::TOKEN_ROUTES`]) also accept a stream token. The
+//! `key` backend of [`crate::auth`] checks them; entries without a `key` only
+//! lend their defaults, overrides and limits to callers another backend
+//! names.
+
+use actix_web::HttpRequest;
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::auth;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ApiKey {
+    /// Left out for callers other auth backends identify.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Request fields filled in when the request leaves them out.
+    #[serde(default)]
+    pub defaults: Map<String, Value>,
+    /// Request fields replaced whatever the request says.
+    #[serde(default)]
+    pub overrides: Map<String, Value>,
+    /// Upper bound for the request's `timeout`.
+    pub max_timeout: Option<u64>,
+    /// Run commands as this local user (Unix; the agent must run as root).
+    pub run_as: Option<String>,
+    /// Shells that may not appear anywhere in a command, or run it as its
+    /// `shell` or `interpreter`, e.g. `powershell`.
+    #[serde(default)]
+    pub banned_shells: Vec<String>,
+    /// `observer` for callers that may only look (see [`crate::read_only`]).
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Every endpoint.
+    #[default]
+    Operator,
+    /// No endpoint that runs commands or changes the host.
+    Observer,
+}
+
+pub fn load(path: &Path) -> Result<HashMap<String, ApiKey>, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let keys: HashMap<String, ApiKey> =
+        serde_json::from_slice(&contents).map_err(|e| format!("Invalid API keys file {}: {}", path.display(), e))?;
+    if let Some(name) = keys.keys().find(|name| keys[*name].key.as_deref().is_some_and(|key| key.trim().is_empty())) {
+        return Err(format!("API key {:?} in {} is empty", name, path.display()));
+    }
+    Ok(keys)
+}
+
+/// The `key` auth backend: names and keys of the entries that have one.
+pub struct Keys(Vec<(String, String)>);
+
+impl Keys {
+    pub fn new(keys: &HashMap<String, ApiKey>) -> Self {
+        Keys(keys.iter().filter_map(|(name, key)| Some((name.clone(), key.key.clone()?))).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The name of the key a request was made with.
+    fn find(&self, req: &HttpRequest) -> Result<Option<String>, String> {
+        let headers = req.headers();
+        let presented = headers
+            .get("X-API-Key")
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| {
+                headers
+                    .get("Authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+            })
+            .map(str::trim);
+        let Some(presented) = presented else {
+            return Ok(None);
+        };
+        self.0
+            .iter()
+            .find(|(_, key)| constant_time_eq(key.as_bytes(), presented.as_bytes()))
+            .map(|(name, _)| Some(name.clone()))
+            .ok_or_else(|| "Invalid API key".to_string())
+    }
+}
+
+impl auth::Backend for Keys {
+    fn name(&self) -> &'static str {
+        "key"
+    }
+
+    fn credential(&self) -> &'static str {
+        "API key"
+    }
+
+    fn identify<'a>(&'a self, req: &'a HttpRequest) -> LocalBoxFuture<'a, Result<Option<String>, String>> {
+        Box::pin(async move { self.find(req) })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl ApiKey {
+    /// Fill in this key's defaults and force its overrides on a request body.
+    /// Returns the names of the fields filled in and of those replaced.
+    pub fn shape(&self, body: &mut Value) -> (Vec<String>, Vec<String>) {
+        let Some(fields) = body.as_object_mut() else {
+            return (Vec::new(), Vec::new());
+        };
+        let mut filled = Vec::new();
+        for (name, value) in &self.defaults {
+            if !fields.contains_key(name) {
+                fields.insert(name.clone(), value.clone());
+                filled.push(name.clone());
+            }
+        }
+        for (name, value) in &self.overrides {
+            fields.insert(name.clone(), value.clone());
+        }
+        (filled, self.overrides.keys().cloned().collect())
+    }
+
+    /// The first banned shell the command invokes, or is run by (`runs_in`),
+    /// if any.
+    pub fn banned_shell(&self, command: &str, runs_in: &[&str]) -> Option<&str> {
+        let mut programs: Vec<String> = command
+            .split(|c: char| c.is_whitespace() || ";|&()<>`$".contains(c))
+            .map(|word| {
+                let word = word.trim_matches(|c| c == '"' || c == '\'');
+                let name = word.rsplit(['/', '\\']).next().unwrap_or(word).to_lowercase();
+                name.strip_suffix(".exe").map(str::to_string).unwrap_or(name)
+            })
+            .collect();
+        programs.extend(runs_in.iter().map(|program| program.to_string()));
+        self.banned_shells
+            .iter()
+            .find(|shell| programs.contains(&shell.to_lowercase()))
+            .map(String::as_str)
+    }
+}
+
+/// A local user commands are switched to before they start.
+#[derive(Clone)]
+pub struct RunAs {
+    pub user: String,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    uid: u32,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    gid: u32,
+}
+
+impl RunAs {
+    pub fn resolve(user: &str) -> io::Result<Self> {
+        if cfg!(target_os = "windows") {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "run_as is not supported on Windows"));
+        }
+        let id = |flag: &str| -> io::Result<u32> {
+            let output = std::process::Command::new("id").args([flag, user]).output()?;
+            if !output.status.success() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no such user {:?}", user)));
+            }
+            String::from_utf8_lossy(&output.stdout)
+                .trim()
+                .parse()
+                .map_err(|_| io::Error::other(format!("unexpected output from id {}", flag)))
+        };
+        Ok(RunAs {
+            user: user.to_string(),
+            uid: id("-u")?,
+            gid: id("-g")?,
+        })
+    }
+
+    pub fn apply(&self, cmd: &mut tokio::process::Command) {
+        // Like std, tokio drops the agent's supplementary groups along with
+        // root.
+        #[cfg(unix)]
+        cmd.uid(self.uid).gid(self.gid).env("USER", &self.user).env("LOGNAME", &self.user);
+        #[cfg(not(unix))]
+        let _ = cmd;
+    }
+
+    /// Make the user the owner of a file the agent wrote for them.
+    pub fn chown(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid));
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            Ok(())
+        }
+    }
+
+    /// Like `chown`, for a symlink itself rather than its target.
+    #[cfg(feature = "sync")]
+    pub fn chown_link(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid));
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            Ok(())
+        }
+    }
+}
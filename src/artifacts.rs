@@ -0,0 +1,142 @@
+//! Files attached to a job (diagnostic bundles and the like), stored under
+//! `artifacts/<job_id>/` next to the executable. Artifacts compressed on disk
+//! are listed and served under their original name.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use serde::Serialize;
+use std::io;
+use std::path::PathBuf;
+
+use crate::compress;
+use crate::config::AppConfig;
+use crate::listing::{self, ListQuery, ListSpec};
+use crate::get_exe_dir;
+
+pub fn artifacts_dir() -> PathBuf {
+    get_exe_dir().join("artifacts")
+}
+
+/// Directory holding a job's artifacts, created on demand.
+pub fn job_dir(job_id: &str) -> io::Result<PathBuf> {
+    if !valid_name(job_id) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid job id"));
+    }
+    let dir = artifacts_dir().join(job_id);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Artifact and job names are single path components without traversal.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', ':'])
+        && !name.contains("..")
+}
+
+#[derive(Serialize)]
+struct ArtifactInfo {
+    name: String,
+    /// Size on disk.
+    size: u64,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    compressed: bool,
+}
+
+const ARTIFACT_LIST: ListSpec = ListSpec {
+    key: "name",
+    sort_keys: &["name", "size"],
+    default_sort: "name",
+};
+
+#[derive(Serialize)]
+struct ArtifactListResponse {
+    success: bool,
+    job_id: String,
+    artifacts: Vec<serde_json::Value>,
+    total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl ArtifactListResponse {
+    fn error(job_id: String, error: String) -> Self {
+        ArtifactListResponse {
+            success: false,
+            job_id,
+            artifacts: Vec::new(),
+            total: 0,
+            next_cursor: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// GET /jobs/{id}/artifacts - list a job's artifacts, paginated.
+pub async fn list_artifacts(path: web::Path<String>, query: web::Query<ListQuery>) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    let entries = if valid_name(&job_id) {
+        std::fs::read_dir(artifacts_dir().join(&job_id)).ok()
+    } else {
+        None
+    };
+    let Some(entries) = entries else {
+        return Ok(HttpResponse::NotFound().json(ArtifactListResponse::error(
+            job_id,
+            "No artifacts for this job".to_string(),
+        )));
+    };
+
+    let artifacts: Vec<ArtifactInfo> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let metadata = e.metadata().ok()?;
+            let name = e.file_name().to_string_lossy().to_string();
+            let original = name.strip_suffix(compress::SUFFIX);
+            metadata.is_file().then(|| ArtifactInfo {
+                name: original.unwrap_or(&name).to_string(),
+                size: metadata.len(),
+                compressed: original.is_some(),
+            })
+        })
+        .collect();
+
+    match listing::paginate(&artifacts, &query, &ARTIFACT_LIST) {
+        Ok(page) => Ok(HttpResponse::Ok().json(ArtifactListResponse {
+            success: true,
+            job_id,
+            artifacts: page.items,
+            total: page.total,
+            next_cursor: page.next_cursor,
+            error: None,
+        })),
+        Err(e) => Ok(HttpResponse::BadRequest().json(ArtifactListResponse::error(job_id, e))),
+    }
+}
+
+/// GET /jobs/{id}/artifacts/{name} - download a single artifact.
+pub async fn get_artifact(path: web::Path<(String, String)>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let (job_id, name) = path.into_inner();
+    if !valid_name(&job_id) || !valid_name(&name) {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+    let file = artifacts_dir().join(&job_id).join(&name);
+    let content_type = match file.extension().and_then(|e| e.to_str()) {
+        Some("png") => "image/png",
+        Some("json") => "application/json",
+        Some("txt") | Some("log") => "text/plain; charset=utf-8",
+        Some("cast") => "application/x-asciicast",
+        _ => "application/octet-stream",
+    };
+    match compress::read(&file).await {
+        Ok(bytes) => {
+            let mut response = HttpResponse::Ok();
+            response.content_type(content_type);
+            Ok(config.bandwidth.respond(response, bytes))
+        }
+        Err(_) => Ok(HttpResponse::NotFound().finish()),
+    }
+}
@@ -0,0 +1,75 @@
+//! Recording job output as an asciicast v2 file, replayable with
+//! `asciinema play` or the asciinema web player.
+//!
+//! See <https://docs.asciinema.org/manual/asciicast/v2/>.
+
+use serde_json::json;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+
+/// Artifact name of a job's recording.
+pub const FILE_NAME: &str = "session.cast";
+
+const WIDTH: u16 = 120;
+const HEIGHT: u16 = 40;
+
+pub struct Recorder {
+    file: tokio::fs::File,
+    started: Instant,
+}
+
+impl Recorder {
+    pub async fn create(path: &Path, command: &str) -> io::Result<Self> {
+        Self::create_sized(path, command, WIDTH, HEIGHT).await
+    }
+
+    /// A recording of a terminal `width` columns by `height` rows.
+    pub async fn create_sized(path: &Path, command: &str, width: u16, height: u16) -> io::Result<Self> {
+        let mut file = tokio::fs::File::create(path).await?;
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": chrono::Utc::now().timestamp(),
+            "command": command,
+            "title": command,
+        });
+        file.write_all(format!("{}\n", header).as_bytes()).await?;
+        Ok(Recorder {
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    /// Append an output event. Pipes carry bare `\n`, which a terminal
+    /// player would render as a staircase, so line feeds get a carriage return.
+    pub async fn output(&mut self, text: &str) -> io::Result<()> {
+        let mut data = String::with_capacity(text.len() + 16);
+        let mut previous = '\0';
+        for c in text.chars() {
+            if c == '\n' && previous != '\r' {
+                data.push('\r');
+            }
+            data.push(c);
+            previous = c;
+        }
+        self.event("o", &data).await
+    }
+
+    /// Append a resize of the terminal.
+    #[cfg(feature = "shell")]
+    pub async fn resize(&mut self, width: u16, height: u16) -> io::Result<()> {
+        self.event("r", &format!("{}x{}", width, height)).await
+    }
+
+    async fn event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        let event = json!([self.started.elapsed().as_secs_f64(), code, data]);
+        self.file.write_all(format!("{}\n", event).as_bytes()).await
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+}
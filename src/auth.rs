@@ -0,0 +1,243 @@
+//! Who a request comes from, established by one or more authentication
+//! backends, so each site can meet its own security mandate without a fork.
+//!
+//! Four backends implement [`Backend`]:
+//!
+//! - `key`: the static keys of `AGENT_API_KEYS_FILE`, in `X-API-Key` or
+//!   `Authorization: Bearer` (see [`crate::api_keys`])
+//! - `mtls`: the client certificate's common name (needs the `tls` feature
+//!   and `AGENT_TLS_CLIENT_CA`)
+//! - `oidc`: an OpenID Connect access or ID token in `Authorization: Bearer`,
+//!   checked against the issuer's keys (`oidc` feature, see [`crate::oidc`])
+//! - `hook`: an external HTTP service that is shown the request's headers
+//!   and answers whether, and as whom, to let it in (`auth-hook` feature, see
+//!   [`crate::auth_hook`])
+//!
+//! `AGENT_AUTH_BACKENDS` lists the ones to use, in order, e.g. `mtls,oidc`;
+//! it defaults to `key` when the keys file has keys and to none (every
+//! endpoint open) otherwise. With `AGENT_AUTH_MODE=any` (the default) the
+//! first backend to vouch for a request lets it in; with `all`, every one of
+//! them must, and the caller is who the first says. [`guard`] answers every
+//! request they don't let in with `401`, except on the few [`OPEN`] routes
+//! and those a job's stream token opens (see [`crate::job_stream`]).
+//!
+//! Whichever backend vouches, the caller's name picks its entry in the API
+//! keys file, so an OIDC subject or certificate gets the defaults,
+//! overrides and limits of the entry with that name; entries without a
+//! `key` only serve this way. Callers without an entry get none.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::api_keys::{ApiKey, Keys};
+use crate::config::AppConfig;
+use crate::job_stream;
+use crate::log_error;
+use crate::outbound::Outbound;
+use crate::tls;
+
+/// A way of establishing who a request comes from.
+pub trait Backend: Send + Sync {
+    /// As listed in `AGENT_AUTH_BACKENDS`.
+    fn name(&self) -> &'static str;
+
+    /// What a request must carry, for the error when it carries nothing,
+    /// e.g. `API key`.
+    fn credential(&self) -> &'static str;
+
+    /// The caller's name: `Ok(None)` when `req` has no credentials for this
+    /// backend, `Err` when they don't check out.
+    fn identify<'a>(&'a self, req: &'a HttpRequest) -> LocalBoxFuture<'a, Result<Option<String>, String>>;
+}
+
+/// The client certificate's common name (or whole subject, lacking one);
+/// the TLS handshake has verified it against `AGENT_TLS_CLIENT_CA`.
+struct ClientCertificate;
+
+impl Backend for ClientCertificate {
+    fn name(&self) -> &'static str {
+        "mtls"
+    }
+
+    fn credential(&self) -> &'static str {
+        "client certificate"
+    }
+
+    fn identify<'a>(&'a self, req: &'a HttpRequest) -> LocalBoxFuture<'a, Result<Option<String>, String>> {
+        Box::pin(async move { Ok(tls::client_common_name(req).or_else(|| tls::client_subject(req))) })
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Auth {
+    /// In the order they are asked.
+    pub backends: Vec<Arc<dyn Backend>>,
+    /// Every backend must vouch for a request, not just one.
+    pub require_all: bool,
+    /// The limits of callers without an entry in the API keys file: none.
+    unlisted: ApiKey,
+}
+
+impl Auth {
+    pub fn names(&self) -> Vec<&'static str> {
+        self.backends.iter().map(|backend| backend.name()).collect()
+    }
+}
+
+const BACKENDS: [&str; 4] = ["key", "mtls", "oidc", "hook"];
+
+/// `AGENT_AUTH_BACKENDS` and `AGENT_AUTH_MODE`.
+pub fn from_env(keys: &HashMap<String, ApiKey>, tls: &tls::Settings, outbound: &Outbound) -> Result<Auth, String> {
+    let require_all = match std::env::var("AGENT_AUTH_MODE").as_deref().map(str::trim) {
+        Err(_) | Ok("" | "any") => false,
+        Ok("all") => true,
+        Ok(other) => return Err(format!("Unknown AGENT_AUTH_MODE {:?}: expected any or all", other)),
+    };
+    let names: Vec<String> = match std::env::var("AGENT_AUTH_BACKENDS") {
+        Ok(names) => names.split(',').map(|name| name.trim().to_lowercase()).filter(|name| !name.is_empty()).collect(),
+        Err(_) if keys.values().any(|key| key.key.is_some()) => vec!["key".to_string()],
+        Err(_) => Vec::new(),
+    };
+    let mut backends: Vec<Arc<dyn Backend>> = Vec::new();
+    for name in &names {
+        if backends.iter().any(|backend| backend.name() == name) {
+            return Err(format!("{} is listed twice in AGENT_AUTH_BACKENDS", name));
+        }
+        let backend: Arc<dyn Backend> = match name.as_str() {
+            "key" => {
+                let keys = Keys::new(keys);
+                if keys.is_empty() {
+                    return Err("The key auth backend needs keys in AGENT_API_KEYS_FILE".to_string());
+                }
+                Arc::new(keys)
+            }
+            "mtls" => {
+                if !cfg!(feature = "tls") {
+                    return Err("The mtls auth backend needs the agent built with the tls feature".to_string());
+                }
+                if tls.client_ca.is_none() {
+                    return Err("The mtls auth backend needs AGENT_TLS_CLIENT_CA".to_string());
+                }
+                Arc::new(ClientCertificate)
+            }
+            #[cfg(feature = "oidc")]
+            "oidc" => Arc::new(crate::oidc::Oidc::from_env(outbound)?),
+            #[cfg(feature = "auth-hook")]
+            "hook" => Arc::new(crate::auth_hook::AuthHook::from_env(outbound)?),
+            #[cfg(not(feature = "oidc"))]
+            "oidc" => return Err("The oidc auth backend needs the agent built with the oidc feature".to_string()),
+            #[cfg(not(feature = "auth-hook"))]
+            "hook" => return Err("The hook auth backend needs the agent built with the auth-hook feature".to_string()),
+            other => {
+                return Err(format!("Unknown auth backend {:?} in AGENT_AUTH_BACKENDS; expected one of {}", other, BACKENDS.join(", ")))
+            }
+        };
+        backends.push(backend);
+    }
+    #[cfg(not(any(feature = "oidc", feature = "auth-hook")))]
+    let _ = outbound;
+    Ok(Auth {
+        backends,
+        require_all,
+        unlisted: ApiKey::default(),
+    })
+}
+
+/// Who a request comes from.
+pub struct Caller<'a> {
+    /// The API key's name, or the identity another backend vouched for.
+    pub name: String,
+    /// The backend that vouched for it.
+    pub backend: &'static str,
+    /// The caller's entry in the API keys file, with its defaults, overrides
+    /// and limits.
+    pub key: &'a ApiKey,
+}
+
+/// The caller's name and backend, or why there is none; kept with the
+/// request so the backends are asked once per request.
+#[derive(Clone)]
+struct Identified(Result<(String, &'static str), String>);
+
+/// Find who `req` comes from. `Ok(None)` when no backend is configured;
+/// `Err` when one is and the request doesn't get past them.
+pub async fn authenticate<'a>(config: &'a AppConfig, req: &HttpRequest) -> Result<Option<Caller<'a>>, String> {
+    let auth = &config.auth;
+    if auth.backends.is_empty() {
+        return Ok(None);
+    }
+    let cached = req.extensions().get::<Identified>().cloned();
+    let Identified(identified) = match cached {
+        Some(identified) => identified,
+        None => {
+            let identified = Identified(identify(auth, req).await);
+            req.extensions_mut().insert(identified.clone());
+            identified
+        }
+    };
+    let (name, backend) = identified?;
+    let key = config.api_keys.get(&name).unwrap_or(&auth.unlisted);
+    Ok(Some(Caller { name, backend, key }))
+}
+
+/// Ask the backends who `req` comes from.
+async fn identify(auth: &Auth, req: &HttpRequest) -> Result<(String, &'static str), String> {
+    let mut caller = None;
+    let mut errors = Vec::new();
+    for backend in &auth.backends {
+        match backend.identify(req).await {
+            Ok(Some(name)) => {
+                caller.get_or_insert((name, backend.name()));
+                if !auth.require_all {
+                    break;
+                }
+            }
+            Ok(None) if auth.require_all => return Err(format!("Missing {}", backend.credential())),
+            Ok(None) => {}
+            Err(error_msg) if auth.require_all => return Err(error_msg),
+            Err(error_msg) => errors.push(error_msg),
+        }
+    }
+    let Some((name, backend)) = caller else {
+        if errors.is_empty() {
+            let credentials: Vec<&str> = auth.backends.iter().map(|backend| backend.credential()).collect();
+            return Err(format!("Missing {}", credentials.join(" or ")));
+        }
+        return Err(errors.join("; "));
+    };
+    Ok((name, backend))
+}
+
+/// Routes anyone may request: the home page and the health check.
+pub const OPEN: &[&str] = &["/", "/health"];
+
+/// Middleware answering requests that no backend vouches for, or that carry
+/// a stream token that doesn't check out, with `401`, before any endpoint
+/// sees them.
+pub async fn guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let route = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+    let error_msg = match req.app_data::<web::Data<AppConfig>>() {
+        Some(config) if !OPEN.contains(&route.as_str()) => match job_stream::check_token(&req, &route) {
+            Some(allowed) => allowed.err(),
+            None => authenticate(config, req.request()).await.err(),
+        },
+        _ => None,
+    };
+    let Some(error_msg) = error_msg else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    log_error(&route, &error_msg, None);
+    let response = HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "error": error_msg,
+    }));
+    Ok(req.into_response(response).map_into_right_body())
+}
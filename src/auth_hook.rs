@@ -0,0 +1,102 @@
+//! The `hook` backend of [`crate::auth`] (`auth-hook` feature): an HTTP
+//! service of the site's own decides who a request comes from, for
+//! credentials the agent has no backend for (session cookies, signed
+//! headers, a corporate SSO gateway).
+//!
+//! The agent POSTs the request's method, path, headers (credentials
+//! included), client address and client certificate to `AGENT_AUTH_HOOK_URL`:
+//!
+//! ```json
+//! {"method": "POST", "path": "/execute", "headers": {"authorization": "..."},
+//!  "client": "10.0.0.12", "client_certificate": null}
+//! ```
+//!
+//! and the hook answers `{"allow": true, "name": "alice"}` or
+//! `{"allow": false, "reason": "..."}`. No answer within 5 seconds, or any
+//! other status than 200, denies.
+
+use actix_web::HttpRequest;
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::time::Duration;
+
+use crate::auth::Backend;
+use crate::offload::env_var;
+use crate::outbound::Outbound;
+use crate::tls;
+
+/// How long the hook may take to answer.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct Answer {
+    #[serde(default)]
+    allow: bool,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+pub struct AuthHook {
+    url: String,
+    outbound: Outbound,
+}
+
+impl AuthHook {
+    pub fn from_env(outbound: &Outbound) -> Result<Self, String> {
+        let url = env_var(&["AGENT_AUTH_HOOK_URL"]).ok_or("The hook auth backend needs AGENT_AUTH_HOOK_URL")?;
+        outbound.check(&url).map_err(|e| format!("AGENT_AUTH_HOOK_URL: {}", e))?;
+        Ok(AuthHook {
+            url,
+            outbound: outbound.clone(),
+        })
+    }
+
+    async fn ask(&self, req: &HttpRequest) -> Result<String, String> {
+        let headers: Map<String, Value> = req
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), Value::from(value.to_str().ok()?))))
+            .collect();
+        let question = json!({
+            "method": req.method().as_str(),
+            "path": req.path(),
+            "headers": headers,
+            "client": req.peer_addr().map(|addr| addr.ip().to_string()),
+            "client_certificate": tls::client_subject(req),
+        });
+        let client = self.outbound.client()?.build().map_err(|e| e.to_string())?;
+        let response = client
+            .post(&self.url)
+            .timeout(HOOK_TIMEOUT)
+            .json(&question)
+            .send()
+            .await
+            .map_err(|e| format!("Auth hook unavailable: {}", crate::outbound::describe(&e)))?;
+        if !response.status().is_success() {
+            return Err(format!("Auth hook unavailable: it returned {}", response.status()));
+        }
+        let answer: Answer = response.json().await.map_err(|e| format!("Unexpected auth hook answer: {}", e))?;
+        match answer {
+            Answer { allow: true, name: Some(name), .. } if !name.trim().is_empty() => Ok(name.trim().to_string()),
+            Answer { allow: true, .. } => Err("The auth hook let the request in without naming the caller".to_string()),
+            Answer { reason, .. } => Err(format!("Denied by the auth hook: {}", reason.unwrap_or_else(|| "no reason given".to_string()))),
+        }
+    }
+}
+
+impl Backend for AuthHook {
+    fn name(&self) -> &'static str {
+        "hook"
+    }
+
+    fn credential(&self) -> &'static str {
+        "credentials the auth hook accepts"
+    }
+
+    fn identify<'a>(&'a self, req: &'a HttpRequest) -> LocalBoxFuture<'a, Result<Option<String>, String>> {
+        Box::pin(async move { self.ask(req).await.map(Some) })
+    }
+}
@@ -0,0 +1,155 @@
+//! Gating executions on an external authorization service (`authz`
+//! feature), so a central governance system can allow or deny what agents
+//! run as it happens.
+//!
+//! With `AGENT_AUTHZ_URL` set, each execution (`/execute`,
+//! `/execute-script`, `/execute-async` and its follow-ups, `/playbooks/run`,
+//! `/documents/run`) is `POST`ed to the service, summarized, once the agent's
+//! own rules and OPA have let it through:
+//!
+//! ```json
+//! {"endpoint": "/execute", "caller": "ci", "client": "10.0.0.12",
+//!  "host": "build-01", "os": "linux", "commands": ["bash deploy.sh"],
+//!  "run_as": "deploy", "time": "2026-10-15T07:30:00+00:00"}
+//! ```
+//!
+//! The service answers `{"allow": true}` or `{"allow": false, "reason":
+//! "..."}`. `AGENT_AUTHZ_TOKEN` is sent as a bearer token. Decisions are
+//! cached for `AGENT_AUTHZ_CACHE_SECS` (default 10, `0` for none), so a burst
+//! of the same request asks once. No answer within 5 seconds, or any other
+//! status than 200, denies, and isn't cached.
+
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::env_parse;
+use crate::outbound::Outbound;
+use crate::{clock, events};
+
+/// How long the service may take to answer.
+const DECISION_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DEFAULT_CACHE_SECS: u64 = 10;
+
+/// Decisions kept at most; the cache is emptied when it grows past this.
+const MAX_CACHED: usize = 10_000;
+
+/// Commands are cut to this many bytes in the summary.
+const MAX_COMMAND_BYTES: usize = 4096;
+
+/// What the service is shown of a request.
+#[derive(Serialize)]
+pub struct Summary<'a> {
+    pub endpoint: &'a str,
+    /// The authenticated caller's name.
+    pub caller: Option<&'a str>,
+    pub client: Option<String>,
+    pub host: String,
+    pub os: &'static str,
+    /// One per playbook step; a single one otherwise.
+    pub commands: Vec<&'a str>,
+    pub run_as: Option<&'a str>,
+}
+
+impl<'a> Summary<'a> {
+    pub fn new(endpoint: &'a str, http_req: &HttpRequest, caller: Option<&'a str>, commands: Vec<&'a str>, run_as: Option<&'a str>) -> Self {
+        Summary {
+            endpoint,
+            caller,
+            client: http_req.peer_addr().map(|addr| addr.ip().to_string()),
+            host: events::hostname(),
+            os: std::env::consts::OS,
+            commands: commands.into_iter().map(|command| truncate(command, MAX_COMMAND_BYTES)).collect(),
+            run_as,
+        }
+    }
+}
+
+fn truncate(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Decision {
+    #[serde(default)]
+    pub allow: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Answered from the cache.
+    #[serde(skip)]
+    pub cached: bool,
+}
+
+pub struct Authz {
+    pub url: String,
+    token: Option<String>,
+    cache_for: Duration,
+    outbound: Outbound,
+    /// Decisions by summary, with when they were made.
+    cache: Mutex<HashMap<String, (Instant, Decision)>>,
+}
+
+/// `AGENT_AUTHZ_URL`, `AGENT_AUTHZ_TOKEN` and `AGENT_AUTHZ_CACHE_SECS`;
+/// `None` when the URL is unset.
+pub fn from_env(outbound: &Outbound) -> Result<Option<Authz>, String> {
+    let Some(url) = std::env::var("AGENT_AUTHZ_URL").ok().filter(|url| !url.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let url = url.trim().to_string();
+    reqwest::Url::parse(&url).map_err(|e| format!("Invalid AGENT_AUTHZ_URL {}: {}", url, e))?;
+    outbound.check(&url).map_err(|e| format!("AGENT_AUTHZ_URL: {}", e))?;
+    Ok(Some(Authz {
+        url,
+        token: std::env::var("AGENT_AUTHZ_TOKEN").ok().filter(|token| !token.is_empty()),
+        cache_for: Duration::from_secs(env_parse::<u64>("AGENT_AUTHZ_CACHE_SECS").unwrap_or(DEFAULT_CACHE_SECS)),
+        outbound: outbound.clone(),
+        cache: Mutex::new(HashMap::new()),
+    }))
+}
+
+impl Authz {
+    /// The service's decision on `summary`, from the cache when it is fresh.
+    pub async fn decide(&self, summary: &Summary<'_>) -> Result<Decision, String> {
+        let key = serde_json::to_string(summary).unwrap_or_default();
+        if let Some((decided, decision)) = self.cache.lock().unwrap().get(&key) {
+            if decided.elapsed() < self.cache_for {
+                return Ok(Decision { cached: true, ..decision.clone() });
+            }
+        }
+        let decision = self.ask(summary).await?;
+        if !self.cache_for.is_zero() {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, (decided, _)| decided.elapsed() < self.cache_for);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+            cache.insert(key, (Instant::now(), decision.clone()));
+        }
+        Ok(decision)
+    }
+
+    async fn ask(&self, summary: &Summary<'_>) -> Result<Decision, String> {
+        let mut question = serde_json::to_value(summary).map_err(|e| e.to_string())?;
+        question["time"] = serde_json::Value::String(clock::now());
+        let client = self.outbound.client()?.build().map_err(|e| e.to_string())?;
+        let mut request = client.post(&self.url).timeout(DECISION_TIMEOUT).json(&question);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| crate::outbound::describe(&e))?;
+        if !response.status().is_success() {
+            return Err(format!("it returned {}", response.status()));
+        }
+        response.json().await.map_err(|e| format!("unexpected answer: {}", e))
+    }
+}
@@ -0,0 +1,150 @@
+//! Azure Blob Storage for [`crate::offload`]. Files are uploaded as block
+//! blobs, authorized with the storage account key (Shared Key), a SAS token
+//! or, when neither is set, the VM's managed identity.
+
+use base64::Engine;
+use futures_util::future::BoxFuture;
+use std::path::Path;
+
+use crate::bandwidth::Bandwidth;
+use crate::offload::{self, env_var, hmac, uri_encode, Store, TokenCache};
+use crate::outbound::Outbound;
+
+/// Blob service version the requests are written against.
+const API_VERSION: &str = "2021-08-06";
+
+/// The managed identity endpoint of the Azure Instance Metadata Service.
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+enum Credential {
+    /// The decoded storage account key.
+    SharedKey(Vec<u8>),
+    /// Query string of a SAS token, without the leading `?`.
+    Sas(String),
+    /// Tokens from IMDS; `client_id` picks a user-assigned identity.
+    ManagedIdentity { client_id: Option<String>, token: TokenCache },
+}
+
+pub struct AzureBlob {
+    /// `https://<account>.blob.core.windows.net`, or the account URL of an
+    /// emulator such as Azurite; without a trailing slash.
+    endpoint: String,
+    account: String,
+    container: String,
+    credential: Credential,
+}
+
+impl AzureBlob {
+    pub fn from_env() -> Result<Self, String> {
+        let missing = |name: &str| format!("The azure offload backend needs {}", name);
+        let container = env_var(&["AGENT_AZURE_CONTAINER"]).ok_or_else(|| missing("AGENT_AZURE_CONTAINER"))?;
+        let account = env_var(&["AGENT_AZURE_ACCOUNT", "AZURE_STORAGE_ACCOUNT"]).ok_or_else(|| missing("AGENT_AZURE_ACCOUNT"))?;
+        let endpoint = env_var(&["AGENT_AZURE_ENDPOINT"])
+            .unwrap_or_else(|| format!("https://{}.blob.core.windows.net", account))
+            .trim_end_matches('/')
+            .to_string();
+        let credential = if let Some(key) = env_var(&["AGENT_AZURE_ACCOUNT_KEY", "AZURE_STORAGE_KEY"]) {
+            let key = base64::engine::general_purpose::STANDARD
+                .decode(key)
+                .map_err(|_| "AGENT_AZURE_ACCOUNT_KEY is not valid base64".to_string())?;
+            Credential::SharedKey(key)
+        } else if let Some(sas) = env_var(&["AGENT_AZURE_SAS_TOKEN", "AZURE_STORAGE_SAS_TOKEN"]) {
+            Credential::Sas(sas.trim_start_matches('?').to_string())
+        } else {
+            Credential::ManagedIdentity {
+                client_id: env_var(&["AGENT_AZURE_CLIENT_ID", "AZURE_CLIENT_ID"]),
+                token: TokenCache::default(),
+            }
+        };
+        Ok(AzureBlob {
+            endpoint,
+            account,
+            container,
+            credential,
+        })
+    }
+
+    /// The Shared Key `Authorization` header for a block blob PUT of `url`
+    /// with the given `x-ms-*` headers.
+    fn shared_key(&self, key: &[u8], url: &reqwest::Url, length: u64, ms_headers: &[(&str, String)]) -> String {
+        let mut ms_headers = ms_headers.to_vec();
+        ms_headers.sort();
+        let canonical_headers: String = ms_headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        // Content-Length is signed as empty when zero.
+        let length = if length == 0 { String::new() } else { length.to_string() };
+        let string_to_sign = format!(
+            "PUT\n\n\n{}\n\n\n\n\n\n\n\n\n{}/{}{}",
+            length,
+            canonical_headers,
+            self.account,
+            url.path()
+        );
+        let signature = base64::engine::general_purpose::STANDARD.encode(hmac(key, &string_to_sign));
+        format!("SharedKey {}:{}", self.account, signature)
+    }
+
+    async fn managed_identity_token(&self, outbound: &Outbound, client_id: Option<&str>, token: &TokenCache) -> Result<String, String> {
+        token
+            .get(|| async {
+                let mut url = format!("{}?api-version=2018-02-01&resource=https%3A%2F%2Fstorage.azure.com%2F", IMDS_TOKEN_URL);
+                if let Some(client_id) = client_id {
+                    url.push_str(&format!("&client_id={}", uri_encode(client_id, false)));
+                }
+                outbound.check(&url)?;
+                // IMDS refuses requests that went through a proxy.
+                let client = outbound.client()?.no_proxy().build().map_err(|e| e.to_string())?;
+                let response = client
+                    .get(&url)
+                    .header("Metadata", "true")
+                    .send()
+                    .await
+                    .map_err(|e| format!("Managed identity token request failed: {}", crate::outbound::describe(&e)))?;
+                offload::parse_token(response).await
+            })
+            .await
+    }
+}
+
+impl Store for AzureBlob {
+    fn put<'a>(&'a self, outbound: &'a Outbound, bandwidth: &'a Bandwidth, key: &'a str, path: &'a Path, length: u64) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let blob_url = format!("{}/{}/{}", self.endpoint, uri_encode(&self.container, false), uri_encode(key, true));
+            let url = match &self.credential {
+                Credential::Sas(sas) => format!("{}?{}", blob_url, sas),
+                _ => blob_url.clone(),
+            };
+            let url = reqwest::Url::parse(&url).map_err(|e| format!("Invalid blob URL {}: {}", blob_url, e))?;
+            outbound.check(url.as_str())?;
+
+            let ms_headers = [
+                ("x-ms-blob-type", "BlockBlob".to_string()),
+                ("x-ms-date", chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+                ("x-ms-version", API_VERSION.to_string()),
+            ];
+            let authorization = match &self.credential {
+                Credential::SharedKey(account_key) => Some(self.shared_key(account_key, &url, length, &ms_headers)),
+                Credential::Sas(_) => None,
+                Credential::ManagedIdentity { client_id, token } => {
+                    let token = self.managed_identity_token(outbound, client_id.as_deref(), token).await?;
+                    Some(format!("Bearer {}", token))
+                }
+            };
+
+            let client = outbound.client()?.build().map_err(|e| e.to_string())?;
+            let mut request = client.put(url);
+            for (name, value) in ms_headers {
+                request = request.header(name, value);
+            }
+            if let Some(authorization) = authorization {
+                request = request.header(reqwest::header::AUTHORIZATION, authorization);
+            }
+            offload::send_file(request, bandwidth, path, length).await?;
+            // Without the SAS token, which would grant access to anyone reading the job.
+            Ok(blob_url)
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+}
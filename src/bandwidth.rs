@@ -0,0 +1,148 @@
+//! Bandwidth limits for file transfers, so downloads from the agent and
+//! uploads to object storage don't saturate thin links.
+//!
+//! `AGENT_BANDWIDTH_LIMIT` caps all transfers together and
+//! `AGENT_TRANSFER_LIMIT` each transfer on its own, in bytes per second with
+//! an optional `K`, `M` or `G` suffix (powers of 1000), e.g. `2M`. Both apply
+//! to job log, artifact, file and screen recording frame downloads, to directory
+//! sync uploads, to artifact offload uploads and to `/fetch` downloads.
+
+use actix_web::{web::Bytes, HttpResponse, HttpResponseBuilder};
+use futures_util::{Stream, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Size of the pieces in-memory bodies are sent in.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Hands out send times so the bytes reserved never exceed the rate.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// When the bytes reserved so far have all been sent at the rate.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserve `bytes`; returns when they may be sent.
+    fn reserve(&self, bytes: usize) -> Instant {
+        let mut next = self.next.lock().unwrap();
+        // Idle time doesn't build up into a burst.
+        let start = (*next).max(Instant::now());
+        *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        start
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Bandwidth {
+    /// Shared by every transfer.
+    pub global: Option<Arc<RateLimiter>>,
+    /// Bytes per second for each transfer.
+    pub per_transfer: Option<u64>,
+}
+
+/// `2M`, `512K`, `1.5G` or plain bytes per second.
+fn parse_rate(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last()? {
+        (i, 'k' | 'K') => (&value[..i], 1e3),
+        (i, 'm' | 'M') => (&value[..i], 1e6),
+        (i, 'g' | 'G') => (&value[..i], 1e9),
+        _ => (value, 1.0),
+    };
+    let rate = number.trim().parse::<f64>().ok()? * multiplier;
+    (rate.is_finite() && rate >= 1.0).then_some(rate as u64)
+}
+
+fn rate_from_env(name: &str) -> Result<Option<u64>, String> {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => parse_rate(&value)
+            .map(Some)
+            .ok_or_else(|| format!("Invalid {} {:?}: expected bytes per second, e.g. 500K or 2M", name, value)),
+        _ => Ok(None),
+    }
+}
+
+pub fn from_env() -> Result<Bandwidth, String> {
+    Ok(Bandwidth {
+        global: rate_from_env("AGENT_BANDWIDTH_LIMIT")?.map(|rate| Arc::new(RateLimiter::new(rate))),
+        per_transfer: rate_from_env("AGENT_TRANSFER_LIMIT")?,
+    })
+}
+
+/// The limiters one transfer is held to, shared by all its streams.
+#[derive(Clone)]
+pub struct Transfer {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl Transfer {
+    /// `stream` slowed down to the transfer's limits.
+    pub fn throttle<S, E>(&self, stream: S) -> impl Stream<Item = Result<Bytes, E>>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        let limiters = self.limiters.clone();
+        stream.then(move |chunk| {
+            let send_at = match &chunk {
+                Ok(bytes) => limiters.iter().map(|limiter| limiter.reserve(bytes.len())).max(),
+                Err(_) => None,
+            };
+            async move {
+                if let Some(send_at) = send_at {
+                    tokio::time::sleep_until(send_at).await;
+                }
+                chunk
+            }
+        })
+    }
+}
+
+impl Bandwidth {
+    /// A new transfer, e.g. a download split over several connections.
+    pub fn transfer(&self) -> Transfer {
+        Transfer {
+            limiters: self
+                .global
+                .iter()
+                .cloned()
+                .chain(self.per_transfer.map(|rate| Arc::new(RateLimiter::new(rate))))
+                .collect(),
+        }
+    }
+
+    /// `stream` slowed down to the limits, as one transfer.
+    pub fn throttle<S, E>(&self, stream: S) -> impl Stream<Item = Result<Bytes, E>>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        self.transfer().throttle(stream)
+    }
+
+    fn unlimited(&self) -> bool {
+        self.global.is_none() && self.per_transfer.is_none()
+    }
+
+    /// A response with `bytes` as its body, sent in chunks at the limits.
+    pub fn respond(&self, mut response: HttpResponseBuilder, bytes: Vec<u8>) -> HttpResponse {
+        if self.unlimited() {
+            return response.body(bytes);
+        }
+        let bytes = Bytes::from(bytes);
+        let chunks: Vec<Result<Bytes, actix_web::Error>> = (0..bytes.len())
+            .step_by(CHUNK_SIZE)
+            .map(|start| Ok(bytes.slice(start..bytes.len().min(start + CHUNK_SIZE))))
+            .collect();
+        response
+            .no_chunking(bytes.len() as u64)
+            .streaming(self.throttle(futures_util::stream::iter(chunks)))
+    }
+}
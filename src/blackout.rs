@@ -0,0 +1,302 @@
+//! Blackout calendars: holidays and change freezes during which no execution
+//! window opens, so a month-end freeze is one setting per agent instead of
+//! edits to every scheduled request.
+//!
+//! Periods come from `AGENT_BLACKOUT_DATES` (comma-separated host-local dates,
+//! `2026-12-24`, or inclusive ranges, `2026-12-24..2026-12-26`) and from the
+//! iCalendar files or URLs in `AGENT_BLACKOUT_ICAL`, whose events are read
+//! again every `AGENT_BLACKOUT_REFRESH_SECS` (default 3600). A calendar that
+//! can't be read keeps the periods it last had.
+//!
+//! Only requests with `allowed_windows` are held to blackouts: a window
+//! overlapping a period is closed for that part of it, so those requests are
+//! rejected or queued until the next window outside every period.
+
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::config::{env_parse, AppConfig};
+use crate::log_error;
+
+const DEFAULT_REFRESH_SECS: u64 = 3600;
+#[cfg(feature = "http")]
+const FETCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone, Default)]
+pub struct Blackout {
+    /// From `AGENT_BLACKOUT_DATES`.
+    pub dates: Vec<Period>,
+    /// Files and URLs from `AGENT_BLACKOUT_ICAL`.
+    pub calendars: Vec<String>,
+    pub refresh: std::time::Duration,
+}
+
+#[derive(Clone)]
+pub struct Period {
+    /// The calendar event's summary.
+    pub name: Option<String>,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "blackout period {:?} until {}", name, crate::clock::format(&self.end)),
+            None => write!(f, "a blackout period until {}", crate::clock::format(&self.end)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Periods {
+    dates: Vec<Period>,
+    /// Per calendar, as last read.
+    calendars: BTreeMap<String, Vec<Period>>,
+}
+
+static PERIODS: Mutex<Periods> = Mutex::new(Periods {
+    dates: Vec::new(),
+    calendars: BTreeMap::new(),
+});
+
+fn midnight(date: NaiveDate) -> Option<DateTime<Local>> {
+    date.and_hms_opt(0, 0, 0)?.and_local_timezone(Local).earliest()
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d").map_err(|_| format!("Invalid blackout date {:?}; expected YYYY-MM-DD", value.trim()))
+}
+
+/// The blackout settings, checked at startup.
+pub fn from_env() -> Result<Blackout, String> {
+    let mut dates = Vec::new();
+    for entry in std::env::var("AGENT_BLACKOUT_DATES").unwrap_or_default().split(',') {
+        if entry.trim().is_empty() {
+            continue;
+        }
+        let (first, last) = match entry.split_once("..") {
+            Some((first, last)) => (parse_date(first)?, parse_date(last)?),
+            None => (parse_date(entry)?, parse_date(entry)?),
+        };
+        if last < first {
+            return Err(format!("Invalid blackout range {:?}: it ends before it starts", entry.trim()));
+        }
+        let (Some(start), Some(end)) = (midnight(first), last.succ_opt().and_then(midnight)) else {
+            return Err(format!("Invalid blackout date {:?}", entry.trim()));
+        };
+        dates.push(Period { name: None, start, end });
+    }
+    let calendars: Vec<String> = std::env::var("AGENT_BLACKOUT_ICAL")
+        .unwrap_or_default()
+        .split(',')
+        .map(|source| source.trim().to_string())
+        .filter(|source| !source.is_empty())
+        .collect();
+    for source in &calendars {
+        if is_url(source) && !cfg!(feature = "http") {
+            return Err(format!("AGENT_BLACKOUT_ICAL URL {} needs the agent built with the http feature", source));
+        }
+    }
+    Ok(Blackout {
+        dates,
+        calendars,
+        refresh: std::time::Duration::from_secs(env_parse::<u64>("AGENT_BLACKOUT_REFRESH_SECS").unwrap_or(DEFAULT_REFRESH_SECS).max(60)),
+    })
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// The period in force at `at`, if any; the one ending last when several
+/// overlap.
+pub fn covering(at: DateTime<Local>) -> Option<Period> {
+    let periods = PERIODS.lock().unwrap();
+    periods
+        .dates
+        .iter()
+        .chain(periods.calendars.values().flatten())
+        .filter(|period| period.start <= at && at < period.end)
+        .max_by_key(|period| period.end)
+        .cloned()
+}
+
+/// An iCalendar date or date-time: `20261224`, `20261224T220000Z` (UTC) or
+/// `20261224T220000` (host-local, as are values with a `TZID`).
+fn parse_ical_time(value: &str) -> Option<(DateTime<Local>, bool)> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Some((midnight(date)?, true));
+    }
+    if let Some(utc) = value.strip_suffix('Z') {
+        let time = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+        return Some((Utc.from_utc_datetime(&time).with_timezone(&Local), false));
+    }
+    let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+    Some((time.and_local_timezone(Local).earliest()?, false))
+}
+
+/// An iCalendar duration such as `P1D` or `PT2H30M`.
+fn parse_ical_duration(value: &str) -> Option<Duration> {
+    let value = value.strip_prefix('+').unwrap_or(value).strip_prefix('P')?;
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in value.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => in_time = true,
+            'W' | 'D' | 'H' | 'M' | 'S' => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                total += match (c, in_time) {
+                    ('W', false) => Duration::weeks(n),
+                    ('D', false) => Duration::days(n),
+                    ('H', true) => Duration::hours(n),
+                    ('M', true) => Duration::minutes(n),
+                    ('S', true) => Duration::seconds(n),
+                    _ => return None,
+                };
+            }
+            _ => return None,
+        }
+    }
+    Some(total)
+}
+
+/// The events of an iCalendar file as periods. Recurring events count only
+/// their first occurrence; cancelled events are left out.
+fn parse_ical(text: &str) -> Result<Vec<Period>, String> {
+    // Unfold continuation lines first.
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    if !lines.iter().any(|line| line.trim() == "BEGIN:VCALENDAR") {
+        return Err("Not an iCalendar file".to_string());
+    }
+    let mut periods = Vec::new();
+    let mut event: Option<BTreeMap<String, String>> = None;
+    // Components inside the event (alarms) have fields of their own.
+    let mut nested = 0;
+    for line in &lines {
+        match line.trim() {
+            "BEGIN:VEVENT" => event = Some(BTreeMap::new()),
+            line if event.is_some() && line.starts_with("BEGIN:") => nested += 1,
+            line if nested > 0 && line.starts_with("END:") => nested -= 1,
+            _ if nested > 0 => {}
+            "END:VEVENT" => {
+                let Some(fields) = event.take() else {
+                    continue;
+                };
+                if fields.get("STATUS").is_some_and(|status| status.eq_ignore_ascii_case("CANCELLED")) {
+                    continue;
+                }
+                let Some((start, all_day)) = fields.get("DTSTART").and_then(|value| parse_ical_time(value)) else {
+                    continue;
+                };
+                let end = match (fields.get("DTEND"), fields.get("DURATION")) {
+                    (Some(end), _) => parse_ical_time(end).map(|(end, _)| end),
+                    (None, Some(duration)) => parse_ical_duration(duration).map(|duration| start + duration),
+                    (None, None) if all_day => Some(start + Duration::days(1)),
+                    (None, None) => None,
+                };
+                let Some(end) = end.filter(|end| *end > start) else {
+                    continue;
+                };
+                let name = fields
+                    .get("SUMMARY")
+                    .map(|summary| summary.replace("\\,", ",").replace("\\;", ";").replace("\\n", " ").replace("\\\\", "\\"))
+                    .filter(|summary| !summary.trim().is_empty());
+                periods.push(Period { name, start, end });
+            }
+            _ => {
+                let (Some(fields), Some((name, value))) = (event.as_mut(), line.split_once(':')) else {
+                    continue;
+                };
+                let name = name.split(';').next().unwrap_or_default().to_ascii_uppercase();
+                fields.entry(name).or_insert_with(|| value.trim().to_string());
+            }
+        }
+    }
+    Ok(periods)
+}
+
+async fn read_calendar(source: &str, config: &AppConfig) -> Result<Vec<Period>, String> {
+    let text = if is_url(source) {
+        fetch(source, config).await?
+    } else {
+        tokio::fs::read_to_string(source).await.map_err(|e| format!("Failed to read {}: {}", source, e))?
+    };
+    parse_ical(&text).map_err(|e| format!("Invalid calendar {}: {}", source, e))
+}
+
+#[cfg(feature = "http")]
+async fn fetch(url: &str, config: &AppConfig) -> Result<String, String> {
+    config.outbound.check(url)?;
+    let client = config
+        .outbound
+        .client()?
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch {}: {}", url, crate::outbound::describe(&e)))?;
+    if !response.status().is_success() {
+        return Err(format!("Fetching {} returned {}", url, response.status()));
+    }
+    response.text().await.map_err(|e| format!("Failed to fetch {}: {}", url, e))
+}
+
+#[cfg(not(feature = "http"))]
+async fn fetch(url: &str, _config: &AppConfig) -> Result<String, String> {
+    Err(format!("Fetching {} needs the agent built with the http feature", url))
+}
+
+/// Read every calendar again; `failing` holds the ones that failed last
+/// time, so a failure is logged when a calendar starts failing, not on
+/// every attempt.
+async fn refresh(config: &AppConfig, failing: &mut Vec<String>) {
+    for source in &config.blackout.calendars {
+        match read_calendar(source, config).await {
+            Ok(periods) => {
+                if failing.contains(source) {
+                    failing.retain(|failed| failed != source);
+                    tracing::info!("Blackout calendar {} can be read again", source);
+                }
+                PERIODS.lock().unwrap().calendars.insert(source.clone(), periods);
+            }
+            Err(e) if !failing.contains(source) => {
+                failing.push(source.clone());
+                log_error("blackout", &e, None);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Keep the calendars up to date after [`init`], which found `failing`
+/// unreadable; runs forever.
+pub async fn run(config: actix_web::web::Data<AppConfig>, mut failing: Vec<String>) {
+    loop {
+        tokio::time::sleep(config.blackout.refresh).await;
+        refresh(&config, &mut failing).await;
+    }
+}
+
+/// Set the dates and read the calendars once, before jobs are accepted;
+/// returns the calendars that couldn't be read.
+pub async fn init(config: &AppConfig) -> Vec<String> {
+    PERIODS.lock().unwrap().dates = config.blackout.dates.clone();
+    let mut failing = Vec::new();
+    refresh(config, &mut failing).await;
+    failing
+}
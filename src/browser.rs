@@ -0,0 +1,315 @@
+//! Scripted browser steps driven over the W3C WebDriver protocol.
+//!
+//! The agent talks to a WebDriver server (chromedriver, geckodriver, Selenium)
+//! reachable from the target machine, so web-app smoke tests run from that
+//! machine's network vantage point. Compiled in with the `browser` feature.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+use crate::config::AppConfig;
+use crate::log_error;
+use crate::outbound;
+
+/// W3C identifier of the key holding an element reference.
+const ELEMENT_KEY: &str = "element-6066-11e4-a52e-4f735466cecf";
+
+#[derive(Deserialize)]
+pub struct BrowserRunRequest {
+    /// WebDriver server; defaults to `AGENT_WEBDRIVER_URL` or http://127.0.0.1:4444.
+    webdriver_url: Option<String>,
+    #[serde(default = "default_browser")]
+    browser: String,
+    #[serde(default = "default_headless")]
+    headless: bool,
+    /// Overall time budget in seconds for all steps.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    steps: Vec<BrowserStep>,
+}
+
+fn default_browser() -> String {
+    "chrome".to_string()
+}
+
+fn default_headless() -> bool {
+    true
+}
+
+fn default_timeout() -> u64 {
+    120
+}
+
+fn default_wait_timeout() -> u64 {
+    10
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum BrowserStep {
+    Navigate { url: String },
+    Fill { selector: String, text: String },
+    Click { selector: String },
+    WaitFor {
+        selector: String,
+        #[serde(default = "default_wait_timeout")]
+        timeout: u64,
+    },
+    AssertText { selector: String, contains: String },
+    AssertTitle { contains: String },
+    AssertUrl { contains: String },
+    Screenshot,
+}
+
+impl BrowserStep {
+    fn name(&self) -> &'static str {
+        match self {
+            BrowserStep::Navigate { .. } => "navigate",
+            BrowserStep::Fill { .. } => "fill",
+            BrowserStep::Click { .. } => "click",
+            BrowserStep::WaitFor { .. } => "wait_for",
+            BrowserStep::AssertText { .. } => "assert_text",
+            BrowserStep::AssertTitle { .. } => "assert_title",
+            BrowserStep::AssertUrl { .. } => "assert_url",
+            BrowserStep::Screenshot => "screenshot",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StepResult {
+    index: usize,
+    action: &'static str,
+    success: bool,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    screenshot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BrowserRunResponse {
+    success: bool,
+    steps: Vec<StepResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct Session {
+    client: reqwest::Client,
+    base: String,
+}
+
+impl Session {
+    async fn call(&self, method: reqwest::Method, path: &str, body: Option<Value>) -> Result<Value, String> {
+        let mut request = self.client.request(method, format!("{}{}", self.base, path));
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await.map_err(|e| format!("WebDriver request failed: {}", outbound::describe(&e)))?;
+        let status = response.status();
+        let payload: Value = response.json().await.map_err(|e| format!("Invalid WebDriver response: {}", e))?;
+        let value = payload.get("value").cloned().unwrap_or(Value::Null);
+        if !status.is_success() {
+            let message = value
+                .get("message")
+                .and_then(Value::as_str)
+                .or_else(|| value.get("error").and_then(Value::as_str))
+                .unwrap_or("unknown error");
+            return Err(format!("WebDriver error ({}): {}", status.as_u16(), message));
+        }
+        Ok(value)
+    }
+
+    async fn find(&self, selector: &str) -> Result<String, String> {
+        let value = self
+            .call(
+                reqwest::Method::POST,
+                "/element",
+                Some(json!({ "using": "css selector", "value": selector })),
+            )
+            .await?;
+        value
+            .get(ELEMENT_KEY)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| format!("Element not found: {}", selector))
+    }
+
+    async fn run_step(&self, step: &BrowserStep) -> Result<Option<String>, String> {
+        use reqwest::Method;
+        match step {
+            BrowserStep::Navigate { url } => {
+                self.call(Method::POST, "/url", Some(json!({ "url": url }))).await?;
+            }
+            BrowserStep::Fill { selector, text } => {
+                let element = self.find(selector).await?;
+                self.call(Method::POST, &format!("/element/{}/clear", element), Some(json!({})))
+                    .await?;
+                self.call(Method::POST, &format!("/element/{}/value", element), Some(json!({ "text": text })))
+                    .await?;
+            }
+            BrowserStep::Click { selector } => {
+                let element = self.find(selector).await?;
+                self.call(Method::POST, &format!("/element/{}/click", element), Some(json!({})))
+                    .await?;
+            }
+            BrowserStep::WaitFor { selector, timeout } => {
+                let deadline = Instant::now() + Duration::from_secs(*timeout);
+                loop {
+                    match self.find(selector).await {
+                        Ok(_) => break,
+                        Err(e) if Instant::now() >= deadline => return Err(e),
+                        Err(_) => tokio::time::sleep(Duration::from_millis(250)).await,
+                    }
+                }
+            }
+            BrowserStep::AssertText { selector, contains } => {
+                let element = self.find(selector).await?;
+                let text = self.call(Method::GET, &format!("/element/{}/text", element), None).await?;
+                let text = text.as_str().unwrap_or_default();
+                if !text.contains(contains.as_str()) {
+                    return Err(format!("Text of {} is {:?}, expected it to contain {:?}", selector, text, contains));
+                }
+            }
+            BrowserStep::AssertTitle { contains } => {
+                let title = self.call(Method::GET, "/title", None).await?;
+                let title = title.as_str().unwrap_or_default();
+                if !title.contains(contains.as_str()) {
+                    return Err(format!("Title is {:?}, expected it to contain {:?}", title, contains));
+                }
+            }
+            BrowserStep::AssertUrl { contains } => {
+                let url = self.call(Method::GET, "/url", None).await?;
+                let url = url.as_str().unwrap_or_default();
+                if !url.contains(contains.as_str()) {
+                    return Err(format!("URL is {:?}, expected it to contain {:?}", url, contains));
+                }
+            }
+            BrowserStep::Screenshot => {
+                let image = self.call(Method::GET, "/screenshot", None).await?;
+                return Ok(image.as_str().map(str::to_string));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn capabilities(browser: &str, headless: bool) -> Value {
+    let mut always_match = json!({ "browserName": browser });
+    if headless {
+        match browser {
+            "chrome" | "chromium" => always_match["goog:chromeOptions"] = json!({ "args": ["--headless=new"] }),
+            "MicrosoftEdge" | "edge" => always_match["ms:edgeOptions"] = json!({ "args": ["--headless=new"] }),
+            "firefox" => always_match["moz:firefoxOptions"] = json!({ "args": ["-headless"] }),
+            _ => {}
+        }
+    }
+    json!({ "capabilities": { "alwaysMatch": always_match } })
+}
+
+fn webdriver_url(req: &BrowserRunRequest) -> String {
+    req.webdriver_url
+        .clone()
+        .or_else(|| std::env::var("AGENT_WEBDRIVER_URL").ok())
+        .unwrap_or_else(|| "http://127.0.0.1:4444".to_string())
+}
+
+async fn run_steps(req: &BrowserRunRequest, config: &AppConfig, steps: &mut Vec<StepResult>) -> Result<(), String> {
+    let webdriver_url = webdriver_url(req);
+    let client = config.outbound.client()?
+        .timeout(Duration::from_secs(req.timeout))
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let created = Session {
+        client: client.clone(),
+        base: webdriver_url.trim_end_matches('/').to_string(),
+    }
+    .call(reqwest::Method::POST, "/session", Some(capabilities(&req.browser, req.headless)))
+    .await?;
+    let session_id = created
+        .get("sessionId")
+        .and_then(Value::as_str)
+        .ok_or("WebDriver did not return a session id")?;
+    let session = Session {
+        client,
+        base: format!("{}/session/{}", webdriver_url.trim_end_matches('/'), session_id),
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(req.timeout);
+    let mut outcome = Ok(());
+    for (index, step) in req.steps.iter().enumerate() {
+        let started = Instant::now();
+        let result = match tokio::time::timeout_at(deadline.into(), session.run_step(step)).await {
+            Ok(result) => result,
+            Err(_) => Err("Browser run timed out".to_string()),
+        };
+        let failed = result.is_err();
+        let (screenshot, error) = match result {
+            Ok(screenshot) => (screenshot, None),
+            Err(e) => (None, Some(e)),
+        };
+        if let Some(e) = &error {
+            outcome = Err(format!("Step {} ({}) failed: {}", index, step.name(), e));
+        }
+        steps.push(StepResult {
+            index,
+            action: step.name(),
+            success: !failed,
+            duration_ms: started.elapsed().as_millis(),
+            screenshot,
+            error,
+        });
+        if failed {
+            break;
+        }
+    }
+
+    // Always end the session so the driver doesn't leak browser processes.
+    let _ = session.call(reqwest::Method::DELETE, "", None).await;
+    outcome
+}
+
+/// POST /browser/run - execute scripted browser steps through WebDriver.
+pub async fn run_browser(req: web::Json<BrowserRunRequest>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    if req.steps.is_empty() {
+        let error_msg = "steps must contain at least one step";
+        log_error("/browser/run", error_msg, None);
+        return Ok(HttpResponse::BadRequest().json(BrowserRunResponse {
+            success: false,
+            steps: Vec::new(),
+            error: Some(error_msg.to_string()),
+        }));
+    }
+
+    if let Err(e) = config.outbound.check(&webdriver_url(&req)) {
+        let error_msg = format!("webdriver_url refused: {}", e);
+        log_error("/browser/run", &error_msg, None);
+        return Ok(HttpResponse::Forbidden().json(BrowserRunResponse {
+            success: false,
+            steps: Vec::new(),
+            error: Some(error_msg),
+        }));
+    }
+
+    let mut steps = Vec::new();
+    match run_steps(&req, &config, &mut steps).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(BrowserRunResponse {
+            success: true,
+            steps,
+            error: None,
+        })),
+        Err(e) => {
+            log_error("/browser/run", &e, None);
+            Ok(HttpResponse::Ok().json(BrowserRunResponse {
+                success: false,
+                steps,
+                error: Some(e),
+            }))
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! What this agent can do, for controllers that adapt their requests per
+//! agent instead of finding out from a failed job.
+//!
+//! `GET /capabilities` reports the Cargo features the binary was built with,
+//! the subsystems its configuration turns on, how requests are authorized,
+//! and what the host allows: elevated rights, switching users, container
+//! runtimes and tools on `PATH`.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use serde::Serialize;
+
+use crate::api_keys::Role;
+use crate::config::AppConfig;
+use crate::confinement;
+use crate::opa::Opa;
+
+/// Cargo features this binary was built with.
+pub fn features() -> Vec<&'static str> {
+    [
+        ("desktop", cfg!(feature = "desktop")),
+        ("sync", cfg!(feature = "sync")),
+        ("tls", cfg!(feature = "tls")),
+        ("browser", cfg!(feature = "browser")),
+        ("opa", cfg!(feature = "opa")),
+        ("oidc", cfg!(feature = "oidc")),
+        ("auth-hook", cfg!(feature = "auth-hook")),
+        ("authz", cfg!(feature = "authz")),
+        ("documents", cfg!(feature = "documents")),
+        ("fetch", cfg!(feature = "fetch")),
+        ("push", cfg!(feature = "push")),
+        ("shell", cfg!(feature = "shell")),
+        ("s3", cfg!(feature = "s3")),
+        ("azure", cfg!(feature = "azure")),
+        ("gcs", cfg!(feature = "gcs")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect()
+}
+
+#[derive(Serialize)]
+struct Platform {
+    os: &'static str,
+    arch: &'static str,
+    family: &'static str,
+}
+
+/// Subsystems the configuration turns on.
+#[derive(Serialize)]
+struct Subsystems {
+    /// Job history and `/jobs/search` (`AGENT_HISTORY_DB`).
+    job_history: bool,
+    /// Per-job log files and `/jobs/{id}/log` (`AGENT_JOB_LOG_DIR`).
+    job_logs: bool,
+    /// `/jobs/{id}/output` (`AGENT_JOB_OUTPUT_MAX_BYTES`).
+    job_output: bool,
+    /// `/system/history` (`AGENT_METRICS_INTERVAL_SECS`).
+    host_metrics: bool,
+    content_store: bool,
+    /// Upload of job output to object storage.
+    offload: bool,
+    /// `/documents/run` with a configured repository.
+    documents: bool,
+    /// Named hook sets requests can refer to.
+    hooks: Vec<String>,
+    /// Startup or shutdown commands.
+    lifecycle: bool,
+    lightweight: bool,
+}
+
+/// How requests are authorized.
+#[derive(Serialize)]
+struct Policy {
+    /// `api_keys` when API keys are the only backend, `required` with
+    /// others, otherwise `open`.
+    authentication: &'static str,
+    /// `AGENT_AUTH_BACKENDS`, in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    auth_backends: Vec<&'static str>,
+    /// `server` or `local` when OPA decides on requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opa: Option<&'static str>,
+    /// Commands are checked against `AGENT_COMMAND_RULES_FILE`.
+    command_rules: bool,
+    /// Executions are put to the service at `AGENT_AUTHZ_URL`.
+    authz: bool,
+    /// `AGENT_READ_ONLY`: nothing that runs commands or changes the host.
+    read_only: bool,
+    /// Some API keys entries are `observer`s.
+    observers: bool,
+}
+
+/// What the host lets the agent do.
+#[derive(Serialize)]
+struct Abilities {
+    /// Running as root, or as an elevated Administrator on Windows.
+    elevated: bool,
+    /// API keys can run commands as another user (`run_as`).
+    run_as: bool,
+    /// Commands can start in the console user's desktop session.
+    interactive_session: bool,
+    /// `selinux` or `apparmor` when commands can be confined
+    /// (`selinux_context`, `apparmor_profile`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confinement: Option<&'static str>,
+    /// `/shell` sessions run in a pseudo-terminal (ConPTY on Windows);
+    /// commands always run with pipes.
+    pty: bool,
+    /// CPU time and peak memory are measured per job.
+    usage: bool,
+    /// `tesseract` for `/screen/ocr`.
+    ocr: bool,
+    docker: bool,
+    podman: bool,
+}
+
+#[derive(Serialize)]
+struct CapabilitiesResponse {
+    success: bool,
+    version: &'static str,
+    platform: Platform,
+    features: Vec<&'static str>,
+    subsystems: Subsystems,
+    policy: Policy,
+    abilities: Abilities,
+}
+
+/// Whether `program` is an executable file in a `PATH` directory.
+pub fn on_path(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    let names: Vec<String> = if cfg!(windows) {
+        vec![format!("{}.exe", program), format!("{}.cmd", program)]
+    } else {
+        vec![program.to_string()]
+    };
+    std::env::split_paths(&path).any(|dir| names.iter().any(|name| dir.join(name).is_file()))
+}
+
+#[cfg(unix)]
+fn elevated() -> bool {
+    // SAFETY: plain syscall without arguments.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(windows)]
+fn elevated() -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY};
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+    // SAFETY: the token handle is closed once queried, and `elevation` is
+    // the size the call is told.
+    unsafe {
+        let mut token: HANDLE = std::ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return false;
+        }
+        let mut elevation: TOKEN_ELEVATION = std::mem::zeroed();
+        let mut returned = 0u32;
+        let queried = GetTokenInformation(
+            token,
+            TokenElevation,
+            (&mut elevation as *mut TOKEN_ELEVATION).cast(),
+            std::mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut returned,
+        );
+        CloseHandle(token);
+        queried != 0 && elevation.TokenIsElevated != 0
+    }
+}
+
+/// GET /capabilities - features, subsystems, policy mode and host abilities.
+pub async fn get_capabilities(config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let elevated = elevated();
+    let mut hooks: Vec<String> = config.hooks.keys().cloned().collect();
+    hooks.sort();
+    let response = web::block(move || CapabilitiesResponse {
+        success: true,
+        version: env!("CARGO_PKG_VERSION"),
+        platform: Platform {
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            family: std::env::consts::FAMILY,
+        },
+        features: features(),
+        subsystems: Subsystems {
+            job_history: config.history_db.is_some(),
+            job_logs: config.job_log_dir.is_some(),
+            job_output: config.job_output_max_bytes > 0,
+            host_metrics: config.metrics_interval.is_some(),
+            content_store: config.content_store,
+            offload: config.offload.is_some(),
+            documents: documents_configured(&config),
+            hooks,
+            lifecycle: !config.lifecycle.startup.is_empty() || !config.lifecycle.shutdown.is_empty(),
+            lightweight: config.lightweight,
+        },
+        policy: Policy {
+            authentication: match config.auth.names().as_slice() {
+                [] => "open",
+                ["key"] => "api_keys",
+                _ => "required",
+            },
+            auth_backends: config.auth.names(),
+            opa: config.opa.as_ref().map(|opa| match opa {
+                Opa::Server { .. } => "server",
+                Opa::Local { .. } => "local",
+            }),
+            command_rules: !config.command_rules.is_empty(),
+            #[cfg(feature = "authz")]
+            authz: config.authz.is_some(),
+            #[cfg(not(feature = "authz"))]
+            authz: false,
+            read_only: config.read_only,
+            observers: config.api_keys.values().any(|key| key.role == Role::Observer),
+        },
+        abilities: Abilities {
+            elevated,
+            run_as: cfg!(unix) && elevated,
+            interactive_session: cfg!(all(windows, feature = "desktop")),
+            confinement: confinement::agent().map(|context| context.module),
+            pty: cfg!(feature = "shell"),
+            usage: cfg!(target_os = "linux"),
+            ocr: cfg!(feature = "desktop") && on_path("tesseract"),
+            docker: on_path("docker"),
+            podman: on_path("podman"),
+        },
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(feature = "documents")]
+fn documents_configured(config: &AppConfig) -> bool {
+    config.documents.is_some()
+}
+
+#[cfg(not(feature = "documents"))]
+fn documents_configured(_config: &AppConfig) -> bool {
+    false
+}
@@ -0,0 +1,180 @@
+//! Follow-up jobs: an `/execute-async` request's `on_success` and
+//! `on_failure` list further `/execute-async` requests, queued when its job
+//! exits 0 or fails (non-zero exit, timeout, or a command that couldn't
+//! start). "If the backup fails, clean up and page someone" is then one
+//! request instead of a watcher polling `/jobs`.
+//!
+//! Follow-ups are checked when the request comes in, with the caller's API
+//! key, client certificate, command rules and OPA, and a denied one rejects
+//! the whole request. They keep their own windows, guards and splay, and
+//! can't have follow-ups of their own.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+use crate::config::AppConfig;
+use crate::events::{self, CloudEvent, EventBus};
+use crate::jobs::{Job, JobRegistry, JobStatus};
+use crate::policy::{self, Denial, RuleMatch};
+use crate::time_window::Schedule;
+use crate::{log_error, queued, AsyncJob, ExecuteRequest};
+
+/// A job's follow-ups, as checked when it was submitted.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Chain {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_success: Vec<FollowUp>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_failure: Vec<FollowUp>,
+}
+
+impl Chain {
+    pub fn is_empty(&self) -> bool {
+        self.on_success.is_empty() && self.on_failure.is_empty()
+    }
+}
+
+/// A follow-up request with what its evaluation added.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FollowUp {
+    command: String,
+    /// The request after the key's defaults and overrides.
+    request: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_certificate: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    run_as: Option<String>,
+    #[serde(default)]
+    policy: Vec<RuleMatch>,
+}
+
+/// Chains of jobs that are running, by job ID.
+static PENDING: Mutex<BTreeMap<String, Chain>> = Mutex::new(BTreeMap::new());
+
+/// Check `req`'s follow-ups as `/execute-async` requests from the caller of
+/// `http_req`.
+pub async fn evaluate(req: &ExecuteRequest, http_req: &HttpRequest, config: &AppConfig) -> Result<Chain, Denial> {
+    let mut chain = Chain::default();
+    for (field, bodies, follow_ups) in [
+        ("on_success", &req.on_success, &mut chain.on_success),
+        ("on_failure", &req.on_failure, &mut chain.on_failure),
+    ] {
+        for (i, body) in bodies.iter().enumerate() {
+            let denial = |status: StatusCode, error: String| Denial {
+                status,
+                error: format!("{}[{}]: {}", field, i, error),
+                command: req.command.clone(),
+            };
+            let evaluation = policy::evaluate("/execute-async", http_req, body.clone(), config).await;
+            let follow_up = evaluation.request.map_err(|denied| denial(denied.status, denied.error))?;
+            if !follow_up.on_success.is_empty() || !follow_up.on_failure.is_empty() {
+                return Err(denial(StatusCode::BAD_REQUEST, "Follow-up jobs can't have follow-ups of their own".to_string()));
+            }
+            if let Some(field) = follow_up.sync_only_field() {
+                return Err(denial(StatusCode::BAD_REQUEST, format!("{} is only supported by /execute", field)));
+            }
+            if follow_up.command.trim().is_empty() {
+                return Err(denial(StatusCode::BAD_REQUEST, "Command must be a non-empty string".to_string()));
+            }
+            Schedule::new(&follow_up.allowed_windows).map_err(|e| denial(StatusCode::BAD_REQUEST, e))?;
+            follow_ups.push(FollowUp {
+                command: follow_up.command.trim().to_string(),
+                request: evaluation.shaped,
+                api_key: follow_up.api_key,
+                client_certificate: follow_up.client_certificate,
+                run_as: follow_up.run_as.map(|run_as| run_as.user),
+                policy: follow_up.policy,
+            });
+        }
+    }
+    Ok(chain)
+}
+
+/// Queue `chain` when job `job_id` ends.
+pub fn register(job_id: &str, chain: Chain) {
+    if !chain.is_empty() {
+        PENDING.lock().unwrap().insert(job_id.to_string(), chain);
+    }
+}
+
+/// Drop the chain of a job that never started.
+pub fn forget(job_id: &str) {
+    PENDING.lock().unwrap().remove(job_id);
+}
+
+/// Queue `follow_up` as a job of its own, after `parent`.
+fn start(
+    follow_up: &FollowUp,
+    parent: &str,
+    bus: &web::Data<EventBus>,
+    jobs: &web::Data<JobRegistry>,
+    config: &web::Data<AppConfig>,
+) -> Result<String, String> {
+    let req = queued::restore(
+        &follow_up.request,
+        follow_up.api_key.clone(),
+        follow_up.client_certificate.clone(),
+        follow_up.run_as.as_deref(),
+        follow_up.policy.clone(),
+        config,
+    )?;
+    let mut job = AsyncJob::new(uuid::Uuid::new_v4().to_string(), &req, follow_up.request.clone(), config)?;
+    job.follows = Some(parent.to_string());
+    queued::submit(job, req, bus, jobs, config)
+}
+
+/// Queue the follow-ups of every job that ends; runs until the bus closes.
+/// Subscribe before jobs can start so none is missed.
+pub async fn run(
+    mut events: broadcast::Receiver<CloudEvent>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    config: web::Data<AppConfig>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log_error("chains", &format!("Follow-up jobs fell behind and skipped {} events", missed), None);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if !matches!(event.event_type.as_str(), events::JOB_FINISHED | events::JOB_FAILED) {
+            continue;
+        }
+        let Some(parent) = event.jobid.as_deref() else {
+            continue;
+        };
+        let Some(chain) = PENDING.lock().unwrap().remove(parent) else {
+            continue;
+        };
+        let succeeded = jobs.get(parent).is_some_and(|job| job.status == JobStatus::Finished && job.return_code == Some(0));
+        let follow_ups = if event.event_type == events::JOB_FINISHED && succeeded { chain.on_success } else { chain.on_failure };
+        for follow_up in &follow_ups {
+            match start(follow_up, parent, &bus, &jobs, &config) {
+                Ok(job_id) => tracing::info!(job_id = %job_id, follows = %parent, command = %follow_up.command, "Queued follow-up job"),
+                Err(e) => {
+                    let error_msg = format!("Failed to queue follow-up of job {}: {}", parent, e);
+                    log_error("chains", &error_msg, Some(&follow_up.command));
+                    let job_id = uuid::Uuid::new_v4().to_string();
+                    let mut job = Job::new(&job_id, &follow_up.command, None);
+                    job.follows = Some(parent.to_string());
+                    jobs.insert(job);
+                    jobs.fail(&job_id, &error_msg);
+                    bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                        "command": follow_up.command,
+                        "error": error_msg,
+                    }));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! The agent's timestamp policy, so times from agents in different time
+//! zones can be lined up without guessing.
+//!
+//! Every timestamp the agent writes (responses, job records, events and
+//! `app_error.log`) is RFC 3339 with an offset, in UTC unless
+//! `AGENT_TIMESTAMP_ZONE=local` asks for host time. Execution windows are
+//! still host-local times; only how times are written follows the policy.
+
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Zone {
+    #[default]
+    Utc,
+    Local,
+}
+
+impl FromStr for Zone {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value.to_ascii_lowercase().as_str() {
+            "utc" => Ok(Zone::Utc),
+            "local" => Ok(Zone::Local),
+            _ => Err(()),
+        }
+    }
+}
+
+static ZONE: OnceLock<Zone> = OnceLock::new();
+
+/// Set the policy; called once at startup, before that times are UTC.
+pub fn init(zone: Zone) {
+    let _ = ZONE.set(zone);
+}
+
+/// `time` as RFC 3339 in the policy's zone.
+pub fn format<Tz: TimeZone>(time: &DateTime<Tz>) -> String {
+    format_opts(time, SecondsFormat::AutoSi)
+}
+
+/// `time` as RFC 3339 in the policy's zone, with `seconds` precision.
+pub fn format_opts<Tz: TimeZone>(time: &DateTime<Tz>, seconds: SecondsFormat) -> String {
+    match ZONE.get().copied().unwrap_or_default() {
+        Zone::Utc => time.with_timezone(&Utc).to_rfc3339_opts(seconds, false),
+        Zone::Local => time.with_timezone(&Local).to_rfc3339_opts(seconds, false),
+    }
+}
+
+/// The current time as RFC 3339 in the policy's zone.
+pub fn now() -> String {
+    format(&Utc::now())
+}
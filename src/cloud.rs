@@ -0,0 +1,244 @@
+//! Cloud instance metadata (provider, instance ID, region, tags), detected
+//! once at startup so fleet inventory can be joined with cloud asset
+//! databases.
+//!
+//! The AWS (IMDSv2), Azure (IMDS) and Google Cloud metadata services all
+//! answer on `169.254.169.254:80`; they are asked at once and the one that
+//! answers wins. `AGENT_CLOUD_METADATA=off` skips detection, and a
+//! `host:port` there asks a different address (e.g. a metadata mock). The
+//! requests are plain HTTP, never proxied, and only made when
+//! `AGENT_OUTBOUND_ALLOW` allows the address.
+//!
+//! The result is reported in `/health`, in the `agent.started` event and to
+//! lifecycle commands as `AGENT_CLOUD_*` variables.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::AppConfig;
+
+pub const DEFAULT_METADATA_ADDR: &str = "169.254.169.254:80";
+
+/// How long each metadata request may take; outside a cloud the address
+/// usually doesn't answer at all.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Serialize)]
+pub struct CloudMetadata {
+    /// `aws`, `azure` or `gcp`.
+    pub provider: &'static str,
+    pub instance_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    /// AWS account ID, Azure subscription ID or Google Cloud project ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Instance tags (AWS only when tags in instance metadata are enabled),
+    /// or labels on Google Cloud.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+static METADATA: OnceLock<Option<CloudMetadata>> = OnceLock::new();
+
+/// What [`detect`] found; `None` outside a cloud or before detection.
+pub fn metadata() -> Option<&'static CloudMetadata> {
+    METADATA.get().and_then(Option::as_ref)
+}
+
+/// Detect the cloud the agent runs in and keep the result for [`metadata`].
+pub async fn detect(config: &AppConfig) -> Option<&'static CloudMetadata> {
+    let found = match &config.cloud_metadata {
+        Some(addr) if allowed(config, addr) => {
+            let (aws, azure, gcp) = tokio::join!(aws(addr), azure(addr), gcp(addr));
+            aws.or(azure).or(gcp)
+        }
+        _ => None,
+    };
+    METADATA.get_or_init(|| found).as_ref()
+}
+
+fn allowed(config: &AppConfig, addr: &str) -> bool {
+    let (host, port) = addr.rsplit_once(':').unwrap_or((addr, "80"));
+    config.outbound.allow.allows(host, port.parse().unwrap_or(80))
+}
+
+/// Variables describing the instance for lifecycle commands.
+pub fn env() -> Vec<(&'static str, String)> {
+    let Some(metadata) = metadata() else {
+        return Vec::new();
+    };
+    let mut vars = vec![
+        ("AGENT_CLOUD_PROVIDER", metadata.provider.to_string()),
+        ("AGENT_CLOUD_INSTANCE_ID", metadata.instance_id.clone()),
+        ("AGENT_CLOUD_TAGS", serde_json::to_string(&metadata.tags).unwrap_or_default()),
+    ];
+    for (name, value) in [
+        ("AGENT_CLOUD_INSTANCE_NAME", &metadata.instance_name),
+        ("AGENT_CLOUD_INSTANCE_TYPE", &metadata.instance_type),
+        ("AGENT_CLOUD_REGION", &metadata.region),
+        ("AGENT_CLOUD_ZONE", &metadata.zone),
+        ("AGENT_CLOUD_ACCOUNT", &metadata.account),
+    ] {
+        if let Some(value) = value {
+            vars.push((name, value.clone()));
+        }
+    }
+    vars
+}
+
+/// A plain HTTP/1.1 request to the metadata service; the body of a 200
+/// response, else `None`.
+async fn request(addr: &str, method: &str, path: &str, headers: &[(&str, &str)]) -> Option<Vec<u8>> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, host);
+        if method != "GET" {
+            head.push_str("Content-Length: 0\r\n");
+        }
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, exchange).await.ok()?.ok()?;
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&response[..split]).to_lowercase();
+    if !head.starts_with("http/1.1 200") && !head.starts_with("http/1.0 200") {
+        return None;
+    }
+    let body = &response[split + 4..];
+    if head.contains("transfer-encoding: chunked") {
+        dechunk(body)
+    } else {
+        Some(body.to_vec())
+    }
+}
+
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = String::from_utf8_lossy(&body[..line_end]);
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(decoded);
+        }
+        let chunk = body.get(line_end + 2..line_end + 2 + size)?;
+        decoded.extend_from_slice(chunk);
+        body = body.get(line_end + 4 + size..)?;
+    }
+}
+
+async fn request_json(addr: &str, path: &str, headers: &[(&str, &str)]) -> Option<serde_json::Value> {
+    serde_json::from_slice(&request(addr, "GET", path, headers).await?).ok()
+}
+
+fn text(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(text) if !text.is_empty() => Some(text.clone()),
+        serde_json::Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+/// Percent-encode a tag key for a metadata path.
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+async fn aws(addr: &str) -> Option<CloudMetadata> {
+    let token = request(addr, "PUT", "/latest/api/token", &[("X-aws-ec2-metadata-token-ttl-seconds", "60")]).await?;
+    let token = String::from_utf8(token).ok()?;
+    let auth = [("X-aws-ec2-metadata-token", token.as_str())];
+    let document = request_json(addr, "/latest/dynamic/instance-identity/document", &auth).await?;
+
+    let mut tags = BTreeMap::new();
+    // 404 unless tags in instance metadata are enabled for the instance.
+    if let Some(keys) = request(addr, "GET", "/latest/meta-data/tags/instance", &auth).await {
+        for key in String::from_utf8_lossy(&keys).lines().filter(|key| !key.is_empty()) {
+            let path = format!("/latest/meta-data/tags/instance/{}", encode(key));
+            if let Some(value) = request(addr, "GET", &path, &auth).await {
+                tags.insert(key.to_string(), String::from_utf8_lossy(&value).into_owned());
+            }
+        }
+    }
+    Some(CloudMetadata {
+        provider: "aws",
+        instance_id: text(&document["instanceId"])?,
+        instance_name: tags.get("Name").cloned(),
+        instance_type: text(&document["instanceType"]),
+        region: text(&document["region"]),
+        zone: text(&document["availabilityZone"]),
+        account: text(&document["accountId"]),
+        tags,
+    })
+}
+
+async fn azure(addr: &str) -> Option<CloudMetadata> {
+    let compute = request_json(addr, "/metadata/instance/compute?api-version=2021-02-01", &[("Metadata", "true")]).await?;
+    let tags = compute["tagsList"]
+        .as_array()
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|tag| Some((text(&tag["name"])?, text(&tag["value"]).unwrap_or_default())))
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(CloudMetadata {
+        provider: "azure",
+        instance_id: text(&compute["vmId"])?,
+        instance_name: text(&compute["name"]),
+        instance_type: text(&compute["vmSize"]),
+        region: text(&compute["location"]),
+        zone: text(&compute["zone"]),
+        account: text(&compute["subscriptionId"]),
+        tags,
+    })
+}
+
+async fn gcp(addr: &str) -> Option<CloudMetadata> {
+    let flavor = [("Metadata-Flavor", "Google")];
+    let instance = request_json(addr, "/computeMetadata/v1/instance/?recursive=true", &flavor).await?;
+    let project = request(addr, "GET", "/computeMetadata/v1/project/project-id", &flavor)
+        .await
+        .map(|project| String::from_utf8_lossy(&project).into_owned());
+    // `projects/<number>/zones/us-central1-a`, `projects/<number>/machineTypes/e2-medium`
+    let last_segment = |value: &serde_json::Value| text(value).map(|text| text.rsplit('/').next().unwrap_or_default().to_string());
+    let zone = last_segment(&instance["zone"]);
+    let tags = instance["labels"]
+        .as_object()
+        .map(|labels| labels.iter().map(|(key, value)| (key.clone(), text(value).unwrap_or_default())).collect())
+        .unwrap_or_default();
+    Some(CloudMetadata {
+        provider: "gcp",
+        instance_id: text(&instance["id"])?,
+        instance_name: text(&instance["name"]),
+        instance_type: last_segment(&instance["machineType"]),
+        region: zone.as_ref().and_then(|zone| zone.rsplit_once('-')).map(|(region, _)| region.to_string()),
+        zone,
+        account: project,
+        tags,
+    })
+}
@@ -0,0 +1,115 @@
+//! Operator rules on which commands may run at all, whoever asks.
+//!
+//! Rules are defined in the JSON file named by `AGENT_COMMAND_RULES_FILE`:
+//!
+//! ```json
+//! {"deny": [{"name": "wipe-root", "regex": "\\brm\\s+-(rf|fr)\\s+/(\\s|$)"},
+//!           {"name": "format", "regex": "(?i)^format\\b"},
+//!           {"name": "shutdown", "glob": "*shutdown*"}],
+//!  "allow": [{"name": "deploy", "glob": "bash /opt/deploy/*.sh*"},
+//!            {"name": "status", "regex": "^systemctl status \\S+$"}]}
+//! ```
+//!
+//! A command matching any `deny` entry is refused. When `allow` has entries,
+//! a command must also match one of them. A `regex` matches anywhere in the
+//! command unless anchored; a `glob` (`*` any text, `?` one character) must
+//! match all of it. Runs of whitespace in the command count as one space, so
+//! padding doesn't slip past a rule.
+
+use regex::Regex;
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntry {
+    name: String,
+    regex: Option<String>,
+    glob: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    allow: Vec<RuleEntry>,
+    #[serde(default)]
+    deny: Vec<RuleEntry>,
+}
+
+#[derive(Clone)]
+pub struct Rule {
+    name: String,
+    pattern: Regex,
+}
+
+impl Rule {
+    fn compile(entry: RuleEntry) -> Result<Self, String> {
+        let pattern = match (&entry.regex, &entry.glob) {
+            (Some(regex), None) => regex.clone(),
+            (None, Some(glob)) => glob_regex(glob),
+            _ => return Err(format!("Rule {:?} needs exactly one of regex and glob", entry.name)),
+        };
+        let pattern = Regex::new(&pattern).map_err(|e| format!("Invalid pattern for rule {:?}: {}", entry.name, e))?;
+        Ok(Rule { name: entry.name, pattern })
+    }
+}
+
+/// An anchored regex for `glob`.
+fn glob_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+#[derive(Clone, Default)]
+pub struct CommandRules {
+    pub allow: Vec<Rule>,
+    pub deny: Vec<Rule>,
+}
+
+impl CommandRules {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// The rule that refuses `command`, with why; `Ok` names the allow rule
+    /// it matched, if there are any.
+    pub fn check(&self, command: &str) -> Result<Option<&str>, String> {
+        let command = command.split_whitespace().collect::<Vec<_>>().join(" ");
+        if let Some(rule) = self.deny.iter().find(|rule| rule.pattern.is_match(&command)) {
+            return Err(format!("The command matches denied rule {:?}", rule.name));
+        }
+        if self.allow.is_empty() {
+            return Ok(None);
+        }
+        match self.allow.iter().find(|rule| rule.pattern.is_match(&command)) {
+            Some(rule) => Ok(Some(&rule.name)),
+            None => Err("The command matches no allowed rule".to_string()),
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<CommandRules, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let file: RulesFile =
+        serde_json::from_slice(&contents).map_err(|e| format!("Invalid command rules file {}: {}", path.display(), e))?;
+    let compile = |entries: Vec<RuleEntry>| {
+        entries
+            .into_iter()
+            .map(Rule::compile)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{} in {}", e, path.display()))
+    };
+    Ok(CommandRules {
+        allow: compile(file.allow)?,
+        deny: compile(file.deny)?,
+    })
+}
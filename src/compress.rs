@@ -0,0 +1,86 @@
+//! Transparent zstd compression of stored job output and artifacts.
+//!
+//! Compressed files keep their original name plus a `.zst` suffix; readers go
+//! through [`read`], which falls back to the compressed copy and decompresses
+//! it on the fly.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const SUFFIX: &str = ".zst";
+
+/// Formats that are already compressed and gain nothing from zstd.
+const SKIP_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "zip", "gz", "zst", "xz", "7z"];
+
+pub fn compressed_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(SUFFIX);
+    PathBuf::from(name)
+}
+
+fn worth_compressing(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    !SKIP_EXTENSIONS.contains(&extension.as_str())
+}
+
+/// Replace `path` with `path.zst`; returns the new path.
+pub fn compress_file(path: &Path, level: i32) -> io::Result<PathBuf> {
+    let target = compressed_path(path);
+    let mut source = File::open(path)?;
+    let mut encoder = zstd::Encoder::new(File::create(&target)?, level)?;
+    if let Err(e) = io::copy(&mut source, &mut encoder).and_then(|_| encoder.finish()) {
+        let _ = std::fs::remove_file(&target);
+        return Err(e);
+    }
+    std::fs::remove_file(path)?;
+    Ok(target)
+}
+
+/// Compress every file in `dir` that isn't already in a compressed format.
+pub fn compress_dir(dir: &Path, level: i32) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && worth_compressing(&path) {
+            compress_file(&path, level)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write `contents` to `path`, or zstd-compressed to `path.zst` when a level
+/// is given; returns the path written.
+pub fn write(path: &Path, contents: &[u8], level: Option<i32>) -> io::Result<PathBuf> {
+    match level {
+        Some(level) => {
+            let target = compressed_path(path);
+            std::fs::write(&target, zstd::encode_all(contents, level)?)?;
+            Ok(target)
+        }
+        None => {
+            std::fs::write(path, contents)?;
+            Ok(path.to_path_buf())
+        }
+    }
+}
+
+/// Read a stored file, decompressing it if only `path.zst` exists (or `path`
+/// itself is a `.zst` file).
+pub async fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let compressed = if path.as_os_str().to_string_lossy().ends_with(SUFFIX) {
+        path.to_path_buf()
+    } else {
+        match tokio::fs::read(path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => compressed_path(path),
+            other => return other,
+        }
+    };
+    let bytes = tokio::fs::read(&compressed).await?;
+    tokio::task::spawn_blocking(move || zstd::decode_all(bytes.as_slice()))
+        .await
+        .map_err(io::Error::other)?
+}
@@ -0,0 +1,145 @@
+//! A cap on how many commands run at once (`AGENT_MAX_CONCURRENT`), so an
+//! orchestrator firing requests in a loop can't fork-bomb the host through
+//! the agent.
+//!
+//! Each `/execute` and `/execute-async` command (and their variants), and
+//! each playbook or document run, holds a slot while it runs. A request that
+//! finds every slot taken waits for one, for up to `AGENT_QUEUE_TIMEOUT_SECS`
+//! (default 30), if fewer than `AGENT_MAX_QUEUE_DEPTH` (default 0) requests
+//! are waiting already. Otherwise, or if no slot frees up in time, it is
+//! answered `429 Too Many Requests` with `Retry-After`. Jobs the agent
+//! queued itself (execution windows, splay, deferred and follow-up jobs) stay
+//! `queued` until they get a slot, however long that takes.
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::env_parse;
+
+const DEFAULT_QUEUE_TIMEOUT_SECS: u64 = 30;
+
+/// What `Retry-After` tells a refused client.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// Held while a command runs; `None` when there is no cap.
+pub type Slot = Option<OwnedSemaphorePermit>;
+
+#[derive(Clone, Default)]
+pub struct Limiter {
+    /// `None` when `AGENT_MAX_CONCURRENT` is unset.
+    slots: Option<Arc<Semaphore>>,
+    pub max_concurrent: usize,
+    pub max_queue_depth: usize,
+    pub queue_timeout: Duration,
+    /// Requests waiting for a slot.
+    waiting: Arc<AtomicUsize>,
+    /// Queued jobs waiting for a slot.
+    queued: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Counts a request as waiting until dropped, also when its client gives up.
+struct Waiting(Arc<AtomicUsize>);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Marks a queued job as waiting for a slot until dropped.
+struct Queued<'a>(&'a Mutex<HashSet<String>>, &'a str);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().remove(self.1);
+    }
+}
+
+/// `AGENT_MAX_CONCURRENT`, `AGENT_MAX_QUEUE_DEPTH` and
+/// `AGENT_QUEUE_TIMEOUT_SECS`.
+pub fn from_env() -> Limiter {
+    let max_concurrent = env_parse::<usize>("AGENT_MAX_CONCURRENT").filter(|&max| max > 0);
+    Limiter {
+        slots: max_concurrent.map(|max| Arc::new(Semaphore::new(max))),
+        max_concurrent: max_concurrent.unwrap_or(0),
+        max_queue_depth: env_parse("AGENT_MAX_QUEUE_DEPTH").unwrap_or(0),
+        queue_timeout: Duration::from_secs(env_parse("AGENT_QUEUE_TIMEOUT_SECS").unwrap_or(DEFAULT_QUEUE_TIMEOUT_SECS)),
+        waiting: Arc::new(AtomicUsize::new(0)),
+        queued: Arc::new(Mutex::new(HashSet::new())),
+    }
+}
+
+impl Limiter {
+    pub fn enabled(&self) -> bool {
+        self.slots.is_some()
+    }
+
+    /// Commands holding a slot.
+    pub fn running(&self) -> usize {
+        self.slots.as_ref().map_or(0, |slots| self.max_concurrent - slots.available_permits())
+    }
+
+    /// Requests waiting for a slot.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// Whether queued job `job_id` is due and waiting for a slot.
+    pub fn is_queued(&self, job_id: &str) -> bool {
+        self.queued.lock().unwrap().contains(job_id)
+    }
+
+    /// A slot for a request, waiting in the queue when there is room in it;
+    /// `Err` is the reason to answer 429 with.
+    pub async fn acquire(&self) -> Result<Slot, String> {
+        let Some(slots) = &self.slots else {
+            return Ok(None);
+        };
+        if let Ok(slot) = slots.clone().try_acquire_owned() {
+            return Ok(Some(slot));
+        }
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.max_queue_depth {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Err(format!(
+                "Too many commands running (at most {}, with {} more waiting); try again later",
+                self.max_concurrent,
+                self.waiting()
+            ));
+        }
+        let _waiting = Waiting(self.waiting.clone());
+        match tokio::time::timeout(self.queue_timeout, slots.clone().acquire_owned()).await {
+            Ok(Ok(slot)) => Ok(Some(slot)),
+            // The semaphore is never closed.
+            Ok(Err(_)) => Ok(None),
+            Err(_) => Err(format!(
+                "Too many commands running; no slot freed up within {}s",
+                self.queue_timeout.as_secs()
+            )),
+        }
+    }
+
+    /// A slot for queued job `job_id`, however long it takes.
+    pub async fn wait_for(&self, job_id: &str) -> Slot {
+        let slots = self.slots.as_ref()?;
+        if let Ok(slot) = slots.clone().try_acquire_owned() {
+            return Some(slot);
+        }
+        self.queued.lock().unwrap().insert(job_id.to_string());
+        let _queued = Queued(&self.queued, job_id);
+        slots.clone().acquire_owned().await.ok()
+    }
+}
+
+/// `HttpResponse::build(status)`, with `Retry-After` for 429.
+pub fn response(status: StatusCode) -> HttpResponseBuilder {
+    let mut response = HttpResponse::build(status);
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        response.insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()));
+    }
+    response
+}
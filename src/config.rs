@@ -0,0 +1,694 @@
+//! Agent configuration, read once at startup from `AGENT_*` environment
+//! variables and shared with handlers through `web::Data<AppConfig>`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::str::FromStr;
+#[cfg(feature = "authz")]
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::api_keys::{self, ApiKey};
+use crate::auth::{self, Auth};
+#[cfg(feature = "authz")]
+use crate::authz;
+use crate::clock;
+use crate::cloud;
+use crate::command_rules::{self, CommandRules};
+use crate::concurrency::{self, Limiter};
+use crate::hooks::{self, HookSet};
+use crate::deadman::{self, Switch};
+use crate::files;
+use crate::job_hooks::{self, JobHooks};
+use crate::jobs;
+use crate::lifecycle::{self, Lifecycle};
+use crate::listen;
+use crate::bandwidth::{self, Bandwidth};
+use crate::blackout::{self, Blackout};
+use crate::offload::{self, Offload};
+use crate::opa::Opa;
+use crate::dns::Dns;
+#[cfg(feature = "documents")]
+use crate::documents;
+#[cfg(feature = "push")]
+use crate::metrics_push;
+use crate::outbound::{Allowlist, Outbound, Proxy};
+#[cfg(feature = "sync")]
+use crate::shares::{self, Shares};
+#[cfg(feature = "shell")]
+use crate::shell;
+use crate::shells::{self, Shell};
+use crate::tls;
+use crate::{get_exe_dir, log_error};
+use crate::pressure::OnHostPressure;
+
+#[derive(Clone, Default)]
+pub struct AppConfig {
+    /// `AGENT_BIND`: comma-separated addresses to listen on (default
+    /// `0.0.0.0:6565`); IPv6 literals in brackets, e.g. `[::]:6565`.
+    pub bind: Vec<String>,
+    /// `AGENT_DUAL_STACK`: IPv6 listeners also accept IPv4 connections.
+    pub dual_stack: bool,
+    /// `AGENT_TLS_CERT`, `AGENT_TLS_KEY` and `AGENT_TLS_SELF_SIGNED`: serve
+    /// HTTPS; overridden by the `--tls-*` flags.
+    pub tls: tls::Settings,
+    /// `AGENT_JOB_LOG_DIR`: write each job's combined output to
+    /// `<dir>/<job_id>.log`. Disabled when unset.
+    pub job_log_dir: Option<PathBuf>,
+    /// `AGENT_JOB_OUTPUT_MAX_BYTES`: most recent stdout and stderr kept in
+    /// memory per job, for each stream, for `/jobs/{id}/output` (default
+    /// 256 KiB); `0` keeps none.
+    pub job_output_max_bytes: usize,
+    /// `AGENT_JOB_RETENTION`: jobs that are done kept in memory, with their
+    /// output, for `/jobs` (default 1000); older ones are only in history.
+    pub job_retention: usize,
+    /// `AGENT_HISTORY_DB`: SQLite job history used by `/jobs/search`.
+    /// Defaults to `job_history.db` next to the executable; `off` disables it.
+    pub history_db: Option<PathBuf>,
+    /// `AGENT_COMPRESS_LEVEL`: zstd level (1-22) for stored job logs and
+    /// diagnostic artifacts. Stored uncompressed when unset.
+    pub compress_level: Option<i32>,
+    /// `AGENT_MAX_CPU_LOAD`: don't start jobs while the load average per CPU
+    /// is above this (e.g. `1.5`).
+    pub max_cpu_load: Option<f64>,
+    /// `AGENT_MIN_FREE_MEMORY_MB`: don't start jobs below this much free memory.
+    pub min_free_memory_mb: Option<u64>,
+    /// `AGENT_MIN_FREE_DISK_MB`: don't start jobs below this much free disk
+    /// in the working directory.
+    pub min_free_disk_mb: Option<u64>,
+    /// `AGENT_ON_HOST_PRESSURE`: `reject` (default) or `defer`.
+    pub on_host_pressure: OnHostPressure,
+    /// `AGENT_MAX_DEFER_SECS`: how long a deferred job waits for the host to
+    /// recover before it is skipped (default 600).
+    pub max_defer: Duration,
+    /// `AGENT_MAX_CONCURRENT`, `AGENT_MAX_QUEUE_DEPTH` and
+    /// `AGENT_QUEUE_TIMEOUT_SECS`: how many commands run at once, and how
+    /// requests over that wait.
+    pub concurrency: Limiter,
+    /// `AGENT_EXECUTE_ASYNC_AFTER_SECS`: how long `/execute` holds the
+    /// connection before answering 202 with the job ID, for requests without
+    /// `async_after`; unlimited when unset.
+    pub execute_async_after: Option<Duration>,
+    /// `AGENT_CANCEL_ON_DISCONNECT`: kill `/execute` commands whose client
+    /// disconnects, for requests without `cancel_on_disconnect`.
+    pub cancel_on_disconnect: bool,
+    /// `AGENT_CANCEL_GRACE_SECS`: how long a job cancelled through
+    /// `DELETE /jobs/{id}` has to exit after SIGTERM before it is killed
+    /// (default 10).
+    pub cancel_grace: Duration,
+    /// `AGENT_EXECUTE_HEARTBEAT_SECS`: how often `/execute` sends heartbeat
+    /// frames while the command runs, for requests without `heartbeat`; off
+    /// when unset.
+    pub execute_heartbeat: Option<Duration>,
+    /// `AGENT_TIMESTAMP_ZONE`: `utc` (default) or `local`, the zone of every
+    /// timestamp the agent writes.
+    pub timestamp_zone: clock::Zone,
+    /// `AGENT_HOOKS_FILE`: named pre/post hook sets requests can refer to.
+    pub hooks: HashMap<String, HookSet>,
+    /// `AGENT_LIFECYCLE_FILE`: commands run at agent startup and shutdown.
+    pub lifecycle: Lifecycle,
+    /// `AGENT_JOB_HOOKS_FILE`: scripts run when any job starts, finishes or
+    /// fails.
+    pub job_hooks: JobHooks,
+    /// `AGENT_WATCHDOGS_FILE`: dead man's switches external systems ping,
+    /// by name.
+    pub watchdogs: BTreeMap<String, Switch>,
+    /// `AGENT_SHELL_IDLE_TIMEOUT_SECS`, `AGENT_SHELL_MAX_SECS` and
+    /// `AGENT_SHELL_RECORD`: limits and recording of `/shell` sessions.
+    #[cfg(feature = "shell")]
+    pub shell: shell::Settings,
+    /// `AGENT_SHARE_CREDENTIALS`: credentials the file APIs connect to
+    /// Windows file shares with.
+    #[cfg(feature = "sync")]
+    pub shares: Shares,
+    /// `AGENT_API_KEYS_FILE`: per-integration keys with request defaults and
+    /// overrides, also applied to callers other auth backends name.
+    pub api_keys: HashMap<String, ApiKey>,
+    /// `AGENT_AUTH_BACKENDS` and `AGENT_AUTH_MODE`: how callers are
+    /// authenticated. Execution endpoints are open without backends.
+    pub auth: Auth,
+    /// `AGENT_COMMAND_RULES_FILE`: commands that may or may not run, whatever
+    /// the API key.
+    pub command_rules: CommandRules,
+    /// The agent's own outbound connections: proxies from
+    /// `AGENT_HTTP_PROXY`, `AGENT_HTTPS_PROXY`, `AGENT_ALL_PROXY` and
+    /// `AGENT_NO_PROXY` (or the unprefixed variables), allowed destinations
+    /// from `AGENT_OUTBOUND_ALLOW` and resolvers from `AGENT_DNS_SERVERS`.
+    pub outbound: Outbound,
+    /// `AGENT_OPA_URL`, or `AGENT_OPA_POLICY` and `AGENT_OPA_QUERY`: Open
+    /// Policy Agent that must allow each execution.
+    pub opa: Option<Opa>,
+    /// `AGENT_AUTHZ_URL`, `AGENT_AUTHZ_TOKEN` and `AGENT_AUTHZ_CACHE_SECS`:
+    /// external service that must allow each execution.
+    #[cfg(feature = "authz")]
+    pub authz: Option<Arc<authz::Authz>>,
+    /// `AGENT_DOCUMENTS_URL`, `AGENT_DOCUMENTS_TOKEN`, `AGENT_DOCUMENTS_KEYS`
+    /// and `AGENT_DOCUMENTS_OFFLINE`: repository `/documents/run` fetches
+    /// documents from, the keys they must be signed with and whether cached
+    /// documents run while it's unreachable.
+    #[cfg(feature = "documents")]
+    pub documents: Option<documents::Repository>,
+    /// `AGENT_OFFLOAD_BACKEND`, `AGENT_S3_BUCKET`, `AGENT_AZURE_CONTAINER`,
+    /// `AGENT_GCS_BUCKET` and related variables: object storage finished
+    /// jobs' files are uploaded to.
+    pub offload: Option<Offload>,
+    /// `AGENT_BANDWIDTH_LIMIT` and `AGENT_TRANSFER_LIMIT`: bytes per second
+    /// for all file transfers together and for each one.
+    pub bandwidth: Bandwidth,
+    /// `AGENT_CONTENT_STORE`: `off` stops deduplicating artifacts and synced
+    /// files through the content store.
+    pub content_store: bool,
+    /// `AGENT_CLOUD_METADATA`: address of the cloud instance metadata service
+    /// (default `169.254.169.254:80`); `off` skips cloud detection.
+    pub cloud_metadata: Option<String>,
+    /// `AGENT_METRICS_INTERVAL_SECS`: how often host metrics are sampled for
+    /// `/system/history` (default 30, or 0 in lightweight mode); `0` disables
+    /// sampling.
+    pub metrics_interval: Option<Duration>,
+    /// `AGENT_METRICS_RETENTION_HOURS`: how much of that history is kept
+    /// (default 24).
+    pub metrics_retention: Duration,
+    /// `AGENT_METRICS_PUSH_URL`, `AGENT_METRICS_PUSH_FORMAT`,
+    /// `AGENT_METRICS_PUSH_INTERVAL_SECS`, `AGENT_METRICS_PUSH_JOB` and
+    /// `AGENT_METRICS_PUSH_TOKEN`: where and how `/metrics` is pushed.
+    #[cfg(feature = "push")]
+    pub metrics_push: Option<metrics_push::Push>,
+    /// `AGENT_HEARTBEAT_FILE`: rewritten with the time after each successful
+    /// self-check.
+    pub heartbeat_file: Option<PathBuf>,
+    /// `AGENT_HEARTBEAT_INTERVAL_SECS`: how often the agent checks itself and
+    /// sends heartbeats (default 10).
+    pub heartbeat_interval: Duration,
+    /// `AGENT_WATCHDOG_MAX_MISSES`: exit after this many failed self-checks in
+    /// a row, so the service manager restarts the agent.
+    pub watchdog_max_misses: Option<u32>,
+    /// `AGENT_LIGHTWEIGHT`: low-footprint mode for Raspberry Pi-class
+    /// devices: one HTTP worker, no host metrics sampling unless
+    /// `AGENT_METRICS_INTERVAL_SECS` is set, and a smaller SQLite cache.
+    pub lightweight: bool,
+    /// `AGENT_READ_ONLY`: refuse every request that runs commands or changes
+    /// the host.
+    pub read_only: bool,
+    /// `AGENT_WORKERS`: HTTP worker threads (default one per CPU, or 1 in
+    /// lightweight mode).
+    pub workers: usize,
+    /// `AGENT_DEFAULT_TIMEOUT_SECS`: `timeout` of execute requests that leave
+    /// it out; 0 for none.
+    pub default_timeout: u64,
+    /// `AGENT_SLOW_REQUEST_MS`: requests taking longer than this to answer
+    /// are logged as slow. Off when unset.
+    pub slow_request: Option<Duration>,
+    /// `AGENT_SLOW_JOB_SECS`: jobs running longer than this are logged as
+    /// slow. Off when unset.
+    pub slow_job: Option<Duration>,
+    /// `AGENT_NTP_SERVER`: server the host clock is checked against, e.g.
+    /// `pool.ntp.org` or `10.0.0.1:123`.
+    pub ntp_server: Option<String>,
+    /// `AGENT_BLACKOUT_DATES`, `AGENT_BLACKOUT_ICAL` and
+    /// `AGENT_BLACKOUT_REFRESH_SECS`: periods no execution window opens in.
+    pub blackout: Blackout,
+    /// `AGENT_ALLOWED_SHELLS`: shells a request may pick with `shell`.
+    pub allowed_shells: Vec<Shell>,
+    /// `AGENT_DOWNLOAD_ROOTS`: directories the `/files` endpoints serve,
+    /// resolved; empty turns them off.
+    pub download_roots: Vec<PathBuf>,
+}
+
+/// Every variable the agent takes a setting from, for checking the keys of
+/// a settings file; those it sets for commands and hooks aren't settings.
+pub const VARIABLES: &[&str] = &[
+    "AGENT_ALLOWED_SHELLS",
+    "AGENT_ALL_PROXY",
+    "AGENT_API_KEYS_FILE",
+    "AGENT_AUTHZ_CACHE_SECS",
+    "AGENT_AUTHZ_TOKEN",
+    "AGENT_AUTHZ_URL",
+    "AGENT_AUTH_BACKENDS",
+    "AGENT_AUTH_HOOK_URL",
+    "AGENT_AUTH_MODE",
+    "AGENT_AZURE_ACCOUNT",
+    "AGENT_AZURE_ACCOUNT_KEY",
+    "AGENT_AZURE_CLIENT_ID",
+    "AGENT_AZURE_CONTAINER",
+    "AGENT_AZURE_ENDPOINT",
+    "AGENT_AZURE_SAS_TOKEN",
+    "AGENT_BANDWIDTH_LIMIT",
+    "AGENT_BIND",
+    "AGENT_BLACKOUT_DATES",
+    "AGENT_BLACKOUT_ICAL",
+    "AGENT_BLACKOUT_REFRESH_SECS",
+    "AGENT_CANCEL_GRACE_SECS",
+    "AGENT_CANCEL_ON_DISCONNECT",
+    "AGENT_CLOUD_METADATA",
+    "AGENT_COMMAND_RULES_FILE",
+    "AGENT_COMPRESS_LEVEL",
+    "AGENT_CONTENT_STORE",
+    "AGENT_DEFAULT_TIMEOUT_SECS",
+    "AGENT_DNS_SERVERS",
+    "AGENT_DOCUMENTS_KEYS",
+    "AGENT_DOCUMENTS_OFFLINE",
+    "AGENT_DOCUMENTS_TOKEN",
+    "AGENT_DOCUMENTS_URL",
+    "AGENT_DOWNLOAD_ROOTS",
+    "AGENT_DUAL_STACK",
+    "AGENT_ERROR_LOG",
+    "AGENT_EXECUTE_ASYNC_AFTER_SECS",
+    "AGENT_EXECUTE_HEARTBEAT_SECS",
+    "AGENT_GCS_BUCKET",
+    "AGENT_GCS_CREDENTIALS",
+    "AGENT_GCS_ENDPOINT",
+    "AGENT_HEARTBEAT_FILE",
+    "AGENT_HEARTBEAT_INTERVAL_SECS",
+    "AGENT_HISTORY_DB",
+    "AGENT_HOOKS_FILE",
+    "AGENT_HTTPS_PROXY",
+    "AGENT_HTTP_PROXY",
+    "AGENT_JOB_HOOKS_FILE",
+    "AGENT_JOB_LOG_DIR",
+    "AGENT_JOB_OUTPUT_MAX_BYTES",
+    "AGENT_JOB_RETENTION",
+    "AGENT_LIFECYCLE_FILE",
+    "AGENT_LIGHTWEIGHT",
+    "AGENT_LOG_KEEP",
+    "AGENT_LOG_LEVEL",
+    "AGENT_LOG_MAX_BYTES",
+    "AGENT_LOG_ROTATE_DAILY",
+    "AGENT_MAX_CONCURRENT",
+    "AGENT_MAX_CPU_LOAD",
+    "AGENT_MAX_DEFER_SECS",
+    "AGENT_MAX_QUEUE_DEPTH",
+    "AGENT_METRICS_INTERVAL_SECS",
+    "AGENT_METRICS_PUSH_FORMAT",
+    "AGENT_METRICS_PUSH_INTERVAL_SECS",
+    "AGENT_METRICS_PUSH_JOB",
+    "AGENT_METRICS_PUSH_TOKEN",
+    "AGENT_METRICS_PUSH_URL",
+    "AGENT_METRICS_RETENTION_HOURS",
+    "AGENT_MIN_FREE_DISK_MB",
+    "AGENT_MIN_FREE_MEMORY_MB",
+    "AGENT_NO_PROXY",
+    "AGENT_NTP_SERVER",
+    "AGENT_OFFLOAD_BACKEND",
+    "AGENT_OFFLOAD_DELETE_LOCAL",
+    "AGENT_OFFLOAD_PREFIX",
+    "AGENT_OIDC_AUDIENCE",
+    "AGENT_OIDC_ISSUER",
+    "AGENT_OIDC_JWKS_URL",
+    "AGENT_OIDC_NAME_CLAIM",
+    "AGENT_ON_HOST_PRESSURE",
+    "AGENT_OPA_POLICY",
+    "AGENT_OPA_QUERY",
+    "AGENT_OPA_URL",
+    "AGENT_OUTBOUND_ALLOW",
+    "AGENT_QUEUE_TIMEOUT_SECS",
+    "AGENT_READ_ONLY",
+    "AGENT_S3_ACCESS_KEY_ID",
+    "AGENT_S3_BUCKET",
+    "AGENT_S3_ENDPOINT",
+    "AGENT_S3_PATH_STYLE",
+    "AGENT_S3_PREFIX",
+    "AGENT_S3_REGION",
+    "AGENT_S3_SECRET_ACCESS_KEY",
+    "AGENT_S3_SESSION_TOKEN",
+    "AGENT_SHARE_CREDENTIALS",
+    "AGENT_SHELL_IDLE_TIMEOUT_SECS",
+    "AGENT_SHELL_MAX_SECS",
+    "AGENT_SHELL_RECORD",
+    "AGENT_SLOW_JOB_SECS",
+    "AGENT_SLOW_REQUEST_MS",
+    "AGENT_TIMESTAMP_ZONE",
+    "AGENT_TLS_CERT",
+    "AGENT_TLS_CLIENT_CA",
+    "AGENT_TLS_KEY",
+    "AGENT_TLS_SELF_SIGNED",
+    "AGENT_TRANSFER_LIMIT",
+    "AGENT_WATCHDOGS_FILE",
+    "AGENT_WATCHDOG_MAX_MISSES",
+    "AGENT_WEBDRIVER_URL",
+    "AGENT_WORKERS",
+];
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+pub fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|value| value.trim().parse().ok())
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+fn opa_from_env(outbound: &Outbound) -> Option<Opa> {
+    if let Ok(url) = std::env::var("AGENT_OPA_URL") {
+        if !cfg!(feature = "opa") {
+            // Starting without the policy would let everything through.
+            let error_msg = "AGENT_OPA_URL needs the agent built with the opa feature";
+            eprintln!("{}", error_msg);
+            log_error("startup", error_msg, None);
+            std::process::exit(1);
+        }
+        #[cfg(feature = "opa")]
+        if let Err(e) = outbound.check(&url) {
+            // Every decision would fail, denying everything.
+            let error_msg = format!("AGENT_OPA_URL: {}", e);
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+        return Some(Opa::Server { url, outbound: Box::new(outbound.clone()) });
+    }
+    env_path("AGENT_OPA_POLICY").map(|policy| Opa::Local {
+        policy,
+        query: std::env::var("AGENT_OPA_QUERY").unwrap_or_else(|_| "data.agent.allow".to_string()),
+    })
+}
+
+fn auth_from_env(api_keys: &HashMap<String, ApiKey>, tls: &tls::Settings, outbound: &Outbound) -> Auth {
+    // Falling back to fewer backends could open the agent up.
+    match auth::from_env(api_keys, tls, outbound) {
+        Ok(auth) => auth,
+        Err(error_msg) => {
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn blackout_from_env() -> Blackout {
+    // Carrying on without a freeze calendar would run what it should hold.
+    match blackout::from_env() {
+        Ok(blackout) => blackout,
+        Err(error_msg) => {
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn download_roots_from_env() -> Vec<PathBuf> {
+    // Silently serving nothing, or from the wrong place, would be worse.
+    match files::roots_from_env() {
+        Ok(roots) => roots,
+        Err(error_msg) => {
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn allowed_shells_from_env() -> Vec<Shell> {
+    // A typo would otherwise allow nothing, or what was meant to be left out.
+    match shells::from_env() {
+        Ok(shells) => shells,
+        Err(error_msg) => {
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn outbound_from_env() -> Outbound {
+    // Without egress every outbound request would fail anyway, and carrying
+    // on unrestricted would defeat the allowlist.
+    let outbound = Allowlist::from_env().and_then(|allow| {
+        Ok(Outbound {
+            proxy: Proxy::from_env(),
+            allow,
+            dns: Dns::from_env()?,
+        })
+    });
+    #[cfg(feature = "http")]
+    let outbound = outbound.and_then(|outbound| outbound.client().map(|_| outbound));
+    match outbound {
+        Ok(outbound) => outbound,
+        Err(error_msg) => {
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    }
+}
+#[cfg(feature = "documents")]
+fn documents_from_env(outbound: &Outbound) -> Option<documents::Repository> {
+    let repository = documents::from_env().and_then(|repository| match &repository {
+        Some(documents::Repository { url, .. }) => outbound.check(url).map(|_| repository),
+        None => Ok(repository),
+    });
+    match repository {
+        Ok(repository) => repository,
+        Err(error_msg) => {
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "push")]
+fn metrics_push_from_env(outbound: &Outbound) -> Option<metrics_push::Push> {
+    let push = metrics_push::from_env().and_then(|push| match &push {
+        Some(metrics_push::Push { url, .. }) => outbound.check(url).map(|_| push),
+        None => Ok(push),
+    });
+    match push {
+        Ok(push) => push,
+        Err(error_msg) => {
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "push"))]
+fn metrics_push_from_env(_outbound: &Outbound) {
+    if std::env::var("AGENT_METRICS_PUSH_URL").is_ok_and(|url| !url.trim().is_empty()) {
+        let error_msg = "AGENT_METRICS_PUSH_URL needs the agent built with the push feature";
+        eprintln!("{}", error_msg);
+        log_error("startup", error_msg, None);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "authz")]
+fn authz_from_env(outbound: &Outbound) -> Option<Arc<authz::Authz>> {
+    match authz::from_env(outbound) {
+        Ok(authz) => authz.map(Arc::new),
+        Err(error_msg) => {
+            // Starting without the service would let everything through.
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "authz"))]
+fn authz_from_env(_outbound: &Outbound) {
+    if std::env::var("AGENT_AUTHZ_URL").is_ok_and(|url| !url.trim().is_empty()) {
+        let error_msg = "AGENT_AUTHZ_URL needs the agent built with the authz feature";
+        eprintln!("{}", error_msg);
+        log_error("startup", error_msg, None);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "documents"))]
+fn documents_from_env(_outbound: &Outbound) {
+    if std::env::var("AGENT_DOCUMENTS_URL").is_ok_and(|url| !url.trim().is_empty()) {
+        let error_msg = "AGENT_DOCUMENTS_URL needs the agent built with the documents feature";
+        eprintln!("{}", error_msg);
+        log_error("startup", error_msg, None);
+        std::process::exit(1);
+    }
+}
+
+/// `AGENT_BIND`, without loading the rest of the configuration.
+pub fn bind_from_env() -> Vec<String> {
+    match std::env::var("AGENT_BIND") {
+        Ok(bind) if !bind.trim().is_empty() => {
+            bind.split(',').map(str::trim).filter(|addr| !addr.is_empty()).map(str::to_string).collect()
+        }
+        _ => vec![listen::DEFAULT_BIND.to_string()],
+    }
+}
+
+impl AppConfig {
+    pub fn from_env() -> Self {
+        let outbound = outbound_from_env();
+        #[cfg(not(feature = "documents"))]
+        documents_from_env(&outbound);
+        #[cfg(not(feature = "push"))]
+        metrics_push_from_env(&outbound);
+        #[cfg(not(feature = "authz"))]
+        authz_from_env(&outbound);
+        let lightweight = matches!(std::env::var("AGENT_LIGHTWEIGHT").as_deref(), Ok("1" | "true" | "yes"));
+        let tls = tls::Settings::from_env();
+        let api_keys = match env_path("AGENT_API_KEYS_FILE").map(|path| api_keys::load(&path)) {
+            Some(Ok(keys)) => keys,
+            Some(Err(error_msg)) => {
+                // Carrying on without the keys would open execution to anyone.
+                eprintln!("{}", error_msg);
+                log_error("startup", &error_msg, None);
+                std::process::exit(1);
+            }
+            None => HashMap::new(),
+        };
+        let auth = auth_from_env(&api_keys, &tls, &outbound);
+        AppConfig {
+            bind: bind_from_env(),
+            dual_stack: matches!(std::env::var("AGENT_DUAL_STACK").as_deref(), Ok("1" | "true" | "yes")),
+            tls,
+            job_log_dir: env_path("AGENT_JOB_LOG_DIR"),
+            job_output_max_bytes: env_parse("AGENT_JOB_OUTPUT_MAX_BYTES").unwrap_or(256 * 1024),
+            job_retention: env_parse("AGENT_JOB_RETENTION").unwrap_or(jobs::DEFAULT_RETENTION),
+            history_db: match env_path("AGENT_HISTORY_DB") {
+                Some(path) if path.as_os_str() == "off" => None,
+                Some(path) => Some(path),
+                None => Some(get_exe_dir().join("job_history.db")),
+            },
+            compress_level: env_parse::<i32>("AGENT_COMPRESS_LEVEL").map(|level| level.clamp(1, 22)),
+            max_cpu_load: env_parse("AGENT_MAX_CPU_LOAD"),
+            min_free_memory_mb: env_parse("AGENT_MIN_FREE_MEMORY_MB"),
+            min_free_disk_mb: env_parse("AGENT_MIN_FREE_DISK_MB"),
+            on_host_pressure: match std::env::var("AGENT_ON_HOST_PRESSURE").as_deref() {
+                Ok("defer") => OnHostPressure::Defer,
+                _ => OnHostPressure::Reject,
+            },
+            max_defer: Duration::from_secs(env_parse("AGENT_MAX_DEFER_SECS").unwrap_or(600)),
+            concurrency: concurrency::from_env(),
+            execute_async_after: env_parse("AGENT_EXECUTE_ASYNC_AFTER_SECS").map(Duration::from_secs),
+            cancel_on_disconnect: matches!(std::env::var("AGENT_CANCEL_ON_DISCONNECT").as_deref(), Ok("1" | "true" | "yes")),
+            cancel_grace: Duration::from_secs(env_parse("AGENT_CANCEL_GRACE_SECS").unwrap_or(10)),
+            execute_heartbeat: env_parse("AGENT_EXECUTE_HEARTBEAT_SECS").map(Duration::from_secs),
+            timestamp_zone: env_parse("AGENT_TIMESTAMP_ZONE").unwrap_or_default(),
+            hooks: match env_path("AGENT_HOOKS_FILE").map(|path| hooks::load(&path)) {
+                Some(Ok(hooks)) => hooks,
+                Some(Err(error_msg)) => {
+                    // Requests naming a hook set are then rejected, never run unhooked.
+                    eprintln!("{}", error_msg);
+                    log_error("startup", &error_msg, None);
+                    HashMap::new()
+                }
+                None => HashMap::new(),
+            },
+            lifecycle: match env_path("AGENT_LIFECYCLE_FILE").map(|path| lifecycle::load(&path)) {
+                Some(Ok(lifecycle)) => lifecycle,
+                Some(Err(error_msg)) => {
+                    eprintln!("{}", error_msg);
+                    log_error("startup", &error_msg, None);
+                    Lifecycle::default()
+                }
+                None => Lifecycle::default(),
+            },
+            job_hooks: match env_path("AGENT_JOB_HOOKS_FILE").map(|path| job_hooks::load(&path)) {
+                Some(Ok(job_hooks)) => job_hooks,
+                Some(Err(error_msg)) => {
+                    eprintln!("{}", error_msg);
+                    log_error("startup", &error_msg, None);
+                    JobHooks::default()
+                }
+                None => JobHooks::default(),
+            },
+            watchdogs: match env_path("AGENT_WATCHDOGS_FILE").map(|path| deadman::load(&path)) {
+                Some(Ok(watchdogs)) => watchdogs,
+                Some(Err(error_msg)) => {
+                    eprintln!("{}", error_msg);
+                    log_error("startup", &error_msg, None);
+                    BTreeMap::new()
+                }
+                None => BTreeMap::new(),
+            },
+            #[cfg(feature = "shell")]
+            shell: shell::from_env(),
+            #[cfg(feature = "sync")]
+            shares: match env_path("AGENT_SHARE_CREDENTIALS").map(|path| shares::load(&path)) {
+                Some(Ok(shares)) => shares,
+                Some(Err(error_msg)) => {
+                    eprintln!("{}", error_msg);
+                    log_error("startup", &error_msg, None);
+                    Shares::default()
+                }
+                None => Shares::default(),
+            },
+            auth,
+            api_keys,
+            command_rules: match env_path("AGENT_COMMAND_RULES_FILE").map(|path| command_rules::load(&path)) {
+                Some(Ok(rules)) => rules,
+                Some(Err(error_msg)) => {
+                    // Carrying on without the rules would let denied commands run.
+                    eprintln!("{}", error_msg);
+                    log_error("startup", &error_msg, None);
+                    std::process::exit(1);
+                }
+                None => CommandRules::default(),
+            },
+            opa: opa_from_env(&outbound),
+            #[cfg(feature = "authz")]
+            authz: authz_from_env(&outbound),
+            #[cfg(feature = "documents")]
+            documents: documents_from_env(&outbound),
+            #[cfg(feature = "push")]
+            metrics_push: metrics_push_from_env(&outbound),
+            offload: match offload::from_env() {
+                Ok(offload) => offload,
+                Err(error_msg) => {
+                    // Jobs would run without their evidence being kept.
+                    eprintln!("{}", error_msg);
+                    log_error("startup", &error_msg, None);
+                    std::process::exit(1);
+                }
+            },
+            bandwidth: match bandwidth::from_env() {
+                Ok(bandwidth) => bandwidth,
+                Err(error_msg) => {
+                    eprintln!("{}", error_msg);
+                    log_error("startup", &error_msg, None);
+                    std::process::exit(1);
+                }
+            },
+            content_store: !matches!(std::env::var("AGENT_CONTENT_STORE").as_deref().map(str::trim), Ok("off" | "false" | "0")),
+            outbound,
+            cloud_metadata: match std::env::var("AGENT_CLOUD_METADATA").map(|value| value.trim().to_string()) {
+                Ok(value) if value == "off" => None,
+                Ok(addr) if !addr.is_empty() => Some(addr),
+                _ => Some(cloud::DEFAULT_METADATA_ADDR.to_string()),
+            },
+            metrics_interval: match env_parse("AGENT_METRICS_INTERVAL_SECS").unwrap_or(if lightweight { 0 } else { 30 }) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            metrics_retention: Duration::from_secs(env_parse::<u64>("AGENT_METRICS_RETENTION_HOURS").unwrap_or(24) * 3600),
+            heartbeat_file: env_path("AGENT_HEARTBEAT_FILE"),
+            heartbeat_interval: Duration::from_secs(env_parse::<u64>("AGENT_HEARTBEAT_INTERVAL_SECS").unwrap_or(10).max(1)),
+            watchdog_max_misses: env_parse::<u32>("AGENT_WATCHDOG_MAX_MISSES").filter(|&misses| misses > 0),
+            lightweight,
+            read_only: matches!(std::env::var("AGENT_READ_ONLY").as_deref(), Ok("1" | "true" | "yes")),
+            workers: match env_parse::<usize>("AGENT_WORKERS").filter(|&workers| workers > 0) {
+                Some(workers) => workers,
+                None if lightweight => 1,
+                None => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            },
+            default_timeout: env_parse::<u64>("AGENT_DEFAULT_TIMEOUT_SECS").unwrap_or(DEFAULT_TIMEOUT_SECS),
+            slow_request: env_parse::<u64>("AGENT_SLOW_REQUEST_MS").filter(|&ms| ms > 0).map(Duration::from_millis),
+            slow_job: env_parse::<u64>("AGENT_SLOW_JOB_SECS").filter(|&secs| secs > 0).map(Duration::from_secs),
+            ntp_server: std::env::var("AGENT_NTP_SERVER").ok().map(|server| server.trim().to_string()).filter(|server| !server.is_empty()),
+            blackout: blackout_from_env(),
+            allowed_shells: allowed_shells_from_env(),
+            download_roots: download_roots_from_env(),
+        }
+    }
+
+    /// Log file for a job, when per-job logs are enabled.
+    pub fn job_log_path(&self, job_id: &str) -> Option<PathBuf> {
+        self.job_log_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.log", job_id)))
+    }
+}
@@ -0,0 +1,156 @@
+//! SELinux and AppArmor confinement of commands, for hardened hosts where the
+//! agent's own domain or profile is not the one automation should run in.
+//!
+//! `selinux_context` runs the command in that SELinux context and
+//! `apparmor_profile` under that AppArmor profile, like `runcon` and
+//! `aa-exec` do: the forked child asks the kernel to switch on `exec`, so
+//! the transition is checked by the policy like any other and a denied one
+//! fails the spawn with `Permission denied`. Linux only. `/health` reports
+//! the agent's own context.
+
+use serde::Serialize;
+
+/// How a command is confined; nothing set leaves it in the agent's own
+/// context.
+#[derive(Clone, Default)]
+pub struct Confinement {
+    pub selinux_context: Option<String>,
+    pub apparmor_profile: Option<String>,
+}
+
+/// The security module confining the agent and its context there.
+#[derive(Serialize)]
+pub struct AgentContext {
+    /// `selinux` or `apparmor`.
+    pub module: &'static str,
+    /// e.g. `system_u:system_r:unconfined_service_t:s0` or `unconfined`.
+    pub context: String,
+    /// SELinux `enforcing` or `permissive`; AppArmor `enforce`, `complain`
+    /// or `unconfined`.
+    pub mode: String,
+}
+
+fn selinux_enabled() -> bool {
+    std::path::Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+fn apparmor_enabled() -> bool {
+    std::fs::read_to_string("/sys/module/apparmor/parameters/enabled").is_ok_and(|enabled| enabled.trim() == "Y")
+}
+
+/// `/proc/self/attr/...` contents without the trailing NUL or newline.
+fn read_attr(path: &str) -> Option<String> {
+    let value = std::fs::read_to_string(path).ok()?;
+    Some(value.trim_end_matches(['\0', '\n']).to_string())
+}
+
+/// The agent's own context, on a Linux host with SELinux or AppArmor.
+pub fn agent() -> Option<AgentContext> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    if selinux_enabled() {
+        let enforcing = std::fs::read_to_string("/sys/fs/selinux/enforce").is_ok_and(|enforce| enforce.trim() == "1");
+        return Some(AgentContext {
+            module: "selinux",
+            context: read_attr("/proc/self/attr/current")?,
+            mode: if enforcing { "enforcing" } else { "permissive" }.to_string(),
+        });
+    }
+    if apparmor_enabled() {
+        let current = read_attr("/proc/self/attr/apparmor/current").or_else(|| read_attr("/proc/self/attr/current"))?;
+        // `name (mode)`, or just `unconfined`.
+        let (context, mode) = match current.rsplit_once(" (") {
+            Some((name, mode)) => (name.to_string(), mode.trim_end_matches(')').to_string()),
+            None => (current.clone(), current),
+        };
+        return Some(AgentContext { module: "apparmor", context, mode });
+    }
+    None
+}
+
+impl Confinement {
+    fn is_set(&self) -> bool {
+        self.selinux_context.is_some() || self.apparmor_profile.is_some()
+    }
+
+    /// Whether this host can confine commands as asked.
+    pub fn check(&self) -> Result<(), String> {
+        if !self.is_set() {
+            return Ok(());
+        }
+        if !cfg!(target_os = "linux") {
+            return Err("selinux_context and apparmor_profile are only supported on Linux".to_string());
+        }
+        if self.selinux_context.is_some() && self.apparmor_profile.is_some() {
+            return Err("Set selinux_context or apparmor_profile, not both".to_string());
+        }
+        let (field, value, enabled, module) = match (&self.selinux_context, &self.apparmor_profile) {
+            (Some(context), _) => ("selinux_context", context, selinux_enabled(), "SELinux"),
+            (_, Some(profile)) => ("apparmor_profile", profile, apparmor_enabled(), "AppArmor"),
+            (None, None) => return Ok(()),
+        };
+        if value.trim().is_empty() || value.contains(['\0', '\n']) {
+            return Err(format!("Invalid {} {:?}", field, value));
+        }
+        if !enabled {
+            return Err(format!("{} is not enabled on this host", module));
+        }
+        Ok(())
+    }
+
+    /// The attribute files to try, in order, and what to write to them.
+    #[cfg(target_os = "linux")]
+    fn request(&self) -> Option<(Vec<std::ffi::CString>, Vec<u8>)> {
+        use std::ffi::CString;
+
+        let paths = |paths: &[&str]| paths.iter().map(|path| CString::new(*path).unwrap_or_default()).collect();
+        if let Some(context) = &self.selinux_context {
+            return Some((paths(&["/proc/self/attr/exec"]), context.clone().into_bytes()));
+        }
+        let profile = self.apparmor_profile.as_ref()?;
+        // The AppArmor directory is there from Linux 5.8 on, and the only
+        // way when another module also uses the shared files.
+        Some((
+            paths(&["/proc/self/attr/apparmor/exec", "/proc/self/attr/exec"]),
+            format!("exec {}", profile).into_bytes(),
+        ))
+    }
+
+    /// Switch to the requested context when `cmd` execs.
+    pub fn apply(&self, cmd: &mut tokio::process::Command) {
+        #[cfg(target_os = "linux")]
+        if let Some((paths, value)) = self.request() {
+            // SAFETY: the hook only makes async-signal-safe syscalls.
+            unsafe {
+                cmd.pre_exec(move || write_attr(&paths, &value));
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = cmd;
+    }
+}
+
+/// Write `value` to the first of `paths` that exists; runs in the forked
+/// child, so it must not allocate.
+#[cfg(target_os = "linux")]
+fn write_attr(paths: &[std::ffi::CString], value: &[u8]) -> std::io::Result<()> {
+    use std::io::Error;
+
+    let mut error = Error::from_raw_os_error(libc::ENOENT);
+    for path in paths {
+        // SAFETY: `path` is NUL-terminated and `value` outlives the calls.
+        unsafe {
+            let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                error = Error::last_os_error();
+                continue;
+            }
+            let written = libc::write(fd, value.as_ptr().cast(), value.len());
+            let result = if written < 0 { Err(Error::last_os_error()) } else { Ok(()) };
+            libc::close(fd);
+            return result;
+        }
+    }
+    Err(error)
+}
@@ -0,0 +1,283 @@
+//! Content-addressed store of job artifacts and synced files, so the same
+//! content is kept once however many jobs produce it or deployments push it.
+//!
+//! Each distinct content is a blob, `content/<sha256>` next to the
+//! executable: a read-only copy, never a link to a file outside the agent's
+//! own directories, so editing a deployed file leaves the blob alone.
+//! Finished jobs' artifacts are replaced with hard links to their blob; a
+//! blob's references are its other links. Files written by `/sync/files`
+//! are copied into the store, so pushing content the agent already has
+//! needs no blocks at all: `/sync/plan` marks it `stored` and the file is
+//! rebuilt from the blob. Blobs nothing links to are removed once unused
+//! for [`UNUSED_TTL`]. Artifacts on another filesystem than the store
+//! aren't deduplicated. `AGENT_CONTENT_STORE=off` turns the store off.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::config::AppConfig;
+use crate::{artifacts, get_exe_dir, log_error};
+
+/// How often blobs nothing links to are removed.
+const GC_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long a blob nothing links to is kept after it was last used, for
+/// `/sync/plan` to find.
+const UNUSED_TTL: Duration = Duration::from_secs(24 * 3600);
+
+pub fn store_dir() -> PathBuf {
+    get_exe_dir().join("content")
+}
+
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hex(&hasher.finalize())),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+fn is_sha256(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn blob_path(sha256: &str) -> Option<PathBuf> {
+    is_sha256(sha256).then(|| store_dir().join(sha256.to_ascii_lowercase()))
+}
+
+/// The store's blobs, leaving out copies being made.
+fn blobs() -> io::Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(store_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(is_sha256))
+        .collect())
+}
+
+fn remove_blob(blob: &Path) -> io::Result<()> {
+    // Windows won't delete a read-only file.
+    #[cfg(windows)]
+    if let Ok(metadata) = std::fs::metadata(blob) {
+        let mut permissions = metadata.permissions();
+        permissions.set_readonly(false);
+        let _ = std::fs::set_permissions(blob, permissions);
+    }
+    std::fs::remove_file(blob)
+}
+
+/// How many names the file at `path` has.
+#[cfg(unix)]
+fn links(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path)?.nlink())
+}
+
+#[cfg(windows)]
+fn links(path: &Path) -> io::Result<u64> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+
+    let file = std::fs::File::open(path)?;
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info.nNumberOfLinks as u64)
+}
+
+/// The blob holding `size` bytes hashing to `sha256`, if the store has it;
+/// finding it counts as using it.
+pub fn find(sha256: &str, size: u64) -> Option<PathBuf> {
+    let blob = blob_path(sha256)?;
+    let metadata = std::fs::metadata(&blob).ok()?;
+    if !metadata.is_file() || metadata.len() != size {
+        return None;
+    }
+    // The owner may set the times of a read-only file.
+    let _ = std::fs::File::open(&blob).and_then(|file| file.set_modified(SystemTime::now()));
+    Some(blob)
+}
+
+/// Drop a blob whose content no longer matches its name.
+pub fn evict(sha256: &str) {
+    if let Some(blob) = blob_path(sha256) {
+        let _ = remove_blob(&blob);
+    }
+}
+
+/// Copy the file at `path`, which hashes to `sha256`, into the store as the
+/// read-only blob for its content, unless the store has one already.
+pub fn add(path: &Path, sha256: &str) -> io::Result<PathBuf> {
+    let Some(blob) = blob_path(sha256) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid SHA-256"));
+    };
+    if blob.exists() {
+        return Ok(blob);
+    }
+    std::fs::create_dir_all(store_dir())?;
+    let temp = store_dir().join(format!("{}.{}.tmp", sha256, uuid::Uuid::new_v4()));
+    let copied = std::fs::copy(path, &temp).and_then(|_| {
+        if sha256_file(&temp)? != sha256 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} changed while it was stored", path.display())));
+        }
+        let mut permissions = std::fs::metadata(&temp)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&temp, permissions)?;
+        std::fs::rename(&temp, &blob)
+    });
+    if let Err(e) = copied {
+        let _ = remove_blob(&temp);
+        return Err(e);
+    }
+    Ok(blob)
+}
+
+/// Replace the artifact at `path` with a link to the blob of the same
+/// content, storing it first if there is none.
+pub fn dedupe(path: &Path) -> io::Result<()> {
+    let size = std::fs::metadata(path)?.len();
+    if links(path)? > 1 {
+        // Already a blob's.
+        return Ok(());
+    }
+    let sha256 = sha256_file(path)?;
+    let blob = match find(&sha256, size) {
+        Some(blob) if sha256_file(&blob)? == sha256 => blob,
+        Some(_) => {
+            evict(&sha256);
+            add(path, &sha256)?
+        }
+        None => add(path, &sha256)?,
+    };
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".dedupe-tmp");
+    std::fs::hard_link(&blob, &temp)?;
+    if let Err(e) = std::fs::rename(&temp, path) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Deduplicate a finished job's artifacts.
+pub async fn dedupe_job(job_id: &str, config: &AppConfig) {
+    if !config.content_store {
+        return;
+    }
+    let dir = artifacts::artifacts_dir().join(job_id);
+    let result = web::block(move || {
+        let mut errors = Vec::new();
+        for path in std::fs::read_dir(dir)?.filter_map(|e| e.ok()).map(|e| e.path()).filter(|path| path.is_file()) {
+            match dedupe(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::CrossesDevices => break,
+                Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+        Ok::<_, io::Error>(errors)
+    })
+    .await;
+    match result {
+        Ok(Ok(errors)) if errors.is_empty() => {}
+        Ok(Ok(errors)) => log_error("content", &format!("Failed to deduplicate {}", errors.join(", ")), None),
+        // No artifacts.
+        Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => {}
+        Ok(Err(e)) => log_error("content", &format!("Failed to deduplicate artifacts of {}: {}", job_id, e), None),
+        Err(e) => log_error("content", &format!("Failed to deduplicate artifacts of {}: {}", job_id, e), None),
+    }
+}
+
+#[derive(Serialize, Default)]
+struct StoreStats {
+    /// Distinct contents stored.
+    blobs: usize,
+    /// Their total size.
+    bytes: u64,
+    /// Files linked with a blob.
+    references: u64,
+    /// Bytes the references would take as separate copies, less `bytes`.
+    saved_bytes: u64,
+}
+
+fn stats() -> io::Result<StoreStats> {
+    let mut stats = StoreStats::default();
+    for path in blobs()? {
+        let (Ok(metadata), Ok(links)) = (std::fs::metadata(&path), links(&path)) else {
+            continue;
+        };
+        let references = links.saturating_sub(1);
+        stats.blobs += 1;
+        stats.bytes += metadata.len();
+        stats.references += references;
+        stats.saved_bytes += metadata.len() * references.saturating_sub(1);
+    }
+    Ok(stats)
+}
+
+/// Remove blobs nothing links to that weren't used for [`UNUSED_TTL`].
+pub fn gc() -> io::Result<()> {
+    for path in blobs()? {
+        let unused = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|elapsed| elapsed > UNUSED_TTL));
+        if unused && links(&path).is_ok_and(|links| links <= 1) {
+            let _ = remove_blob(&path);
+        }
+    }
+    Ok(())
+}
+
+/// Collect unreferenced blobs now and every [`GC_INTERVAL`].
+pub async fn run_gc() {
+    let mut interval = tokio::time::interval(GC_INTERVAL);
+    loop {
+        interval.tick().await;
+        match web::block(gc).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log_error("content", &format!("Failed to collect unreferenced content: {}", e), None),
+            Err(e) => log_error("content", &format!("Failed to collect unreferenced content: {}", e), None),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ContentResponse {
+    success: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    stats: Option<StoreStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// GET /content - how much the content store holds and saves.
+pub async fn get_content() -> ActixResult<HttpResponse> {
+    match web::block(stats).await.map_err(io::Error::other).and_then(|r| r) {
+        Ok(stats) => Ok(HttpResponse::Ok().json(ContentResponse {
+            success: true,
+            stats: Some(stats),
+            error: None,
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ContentResponse {
+            success: false,
+            stats: None,
+            error: Some(e.to_string()),
+        })),
+    }
+}
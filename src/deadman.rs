@@ -0,0 +1,290 @@
+//! Dead man's switches: external systems that must check in with
+//! `POST /watchdog/{name}/ping` every so often, and what the agent does when
+//! one goes quiet, so an isolated host can heal itself without a monitoring
+//! server watching it.
+//!
+//! They are listed in the JSON file named by `AGENT_WATCHDOGS_FILE`:
+//!
+//! ```json
+//! {"backup-sync": {"interval_secs": 3600,
+//!                  "remediation": {"command": "systemctl restart backup-sync", "timeout": 300}}}
+//! ```
+//!
+//! Each switch is armed when the agent starts. When `interval_secs` pass
+//! without a ping it fires: the miss is written to the error log, a
+//! `com.machineagent.watchdog.missed` event is emitted, and `remediation`, an
+//! `/execute-async` request, is queued as a job. It fires again every
+//! interval until the next ping, which emits
+//! `com.machineagent.watchdog.recovered`.
+
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::AppConfig;
+use crate::events::{self, EventBus};
+use crate::jobs::JobRegistry;
+use crate::{auth, clock, log_error, queued, AsyncJob, ExecuteRequest};
+
+/// Longest the monitor sleeps, so clock changes are picked up.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Clone)]
+pub struct Switch {
+    pub interval_secs: u64,
+    /// `/execute-async` request queued each time the switch fires.
+    #[serde(default)]
+    pub remediation: Option<Value>,
+}
+
+pub fn load(path: &Path) -> Result<BTreeMap<String, Switch>, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let switches: BTreeMap<String, Switch> =
+        serde_json::from_slice(&contents).map_err(|e| format!("Invalid watchdogs file {}: {}", path.display(), e))?;
+    for (name, switch) in &switches {
+        let invalid = |error: String| format!("Watchdog {:?} in {}: {}", name, path.display(), error);
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+            return Err(invalid("names may only use letters, digits, '-', '_' and '.'".to_string()));
+        }
+        if switch.interval_secs == 0 {
+            return Err(invalid("interval_secs must be at least 1".to_string()));
+        }
+        let Some(remediation) = &switch.remediation else {
+            continue;
+        };
+        let req: ExecuteRequest = serde_json::from_value(remediation.clone()).map_err(|e| invalid(format!("Invalid remediation: {}", e)))?;
+        if req.command.trim().is_empty() && req.command_base64.is_none() {
+            return Err(invalid("remediation needs a command".to_string()));
+        }
+        if !req.on_success.is_empty() || !req.on_failure.is_empty() {
+            return Err(invalid("remediation can't have on_success or on_failure".to_string()));
+        }
+        if let Some(field) = req.sync_only_field() {
+            return Err(invalid(format!("{} is only supported by /execute", field)));
+        }
+    }
+    Ok(switches)
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    /// Pinged within its interval, or not due yet since the agent started.
+    Ok,
+    /// Fired, and not pinged since.
+    Missed,
+}
+
+struct State {
+    interval: Duration,
+    last_ping: Option<String>,
+    deadline: DateTime<Local>,
+    status: Status,
+    /// Times the switch fired since the last ping.
+    misses: u32,
+    /// Job of the latest remediation.
+    remediation_job_id: Option<String>,
+}
+
+/// The switches' state; each is armed when this is created.
+pub struct Switches(Mutex<BTreeMap<String, State>>);
+
+impl Switches {
+    pub fn new(config: &AppConfig) -> Self {
+        let now = Local::now();
+        let states = config
+            .watchdogs
+            .iter()
+            .map(|(name, switch)| {
+                let interval = Duration::from_secs(switch.interval_secs);
+                let state = State {
+                    interval,
+                    last_ping: None,
+                    deadline: now + chrono::Duration::from_std(interval).unwrap_or_default(),
+                    status: Status::Ok,
+                    misses: 0,
+                    remediation_job_id: None,
+                };
+                (name.clone(), state)
+            })
+            .collect();
+        Switches(Mutex::new(states))
+    }
+}
+
+/// Queue a switch's remediation as a job; returns its ID.
+fn remediate(
+    name: &str,
+    request: &Value,
+    bus: &web::Data<EventBus>,
+    jobs: &web::Data<JobRegistry>,
+    config: &web::Data<AppConfig>,
+) -> Result<String, String> {
+    let req = queued::restore(request, None, None, None, Vec::new(), config)?;
+    let job = AsyncJob::new(uuid::Uuid::new_v4().to_string(), &req, request.clone(), config)?;
+    let job_id = queued::submit(job, req, bus, jobs, config)?;
+    tracing::info!(job_id = %job_id, watchdog = %name, "Queued watchdog remediation");
+    Ok(job_id)
+}
+
+/// Fire the switches whose deadline has passed; runs forever.
+pub async fn run(
+    switches: web::Data<Switches>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    config: web::Data<AppConfig>,
+) {
+    loop {
+        let now = Local::now();
+        let mut fired = Vec::new();
+        let mut sleep = MAX_SLEEP;
+        {
+            let mut states = switches.0.lock().unwrap();
+            for (name, state) in states.iter_mut() {
+                if state.deadline <= now {
+                    state.deadline = now + chrono::Duration::from_std(state.interval).unwrap_or_default();
+                    state.status = Status::Missed;
+                    state.misses += 1;
+                    fired.push((name.clone(), state.last_ping.clone(), state.misses));
+                }
+                sleep = sleep.min((state.deadline - now).to_std().unwrap_or_default());
+            }
+        }
+        for (name, last_ping, misses) in fired {
+            let endpoint = format!("watchdog/{}", name);
+            let silence = match &last_ping {
+                Some(last_ping) => format!("No ping since {}", last_ping),
+                None => "No ping since the agent started".to_string(),
+            };
+            log_error(&endpoint, &format!("{} ({} missed in a row)", silence, misses), None);
+            let remediation = config.watchdogs.get(&name).and_then(|switch| switch.remediation.as_ref());
+            let (job_id, error) = match remediation.map(|request| remediate(&name, request, &bus, &jobs, &config)) {
+                Some(Ok(job_id)) => (Some(job_id), None),
+                Some(Err(e)) => {
+                    let error_msg = format!("Failed to queue remediation: {}", e);
+                    log_error(&endpoint, &error_msg, None);
+                    (None, Some(error_msg))
+                }
+                None => (None, None),
+            };
+            if let Some(state) = switches.0.lock().unwrap().get_mut(&name) {
+                state.remediation_job_id = job_id.clone().or(state.remediation_job_id.take());
+            }
+            bus.publish(events::WATCHDOG_MISSED, None, serde_json::json!({
+                "name": name,
+                "last_ping": last_ping,
+                "misses": misses,
+                "remediation_job_id": job_id,
+                "error": error,
+            }));
+        }
+        tokio::time::sleep(sleep.max(Duration::from_millis(100))).await;
+    }
+}
+
+#[derive(Serialize)]
+struct PingResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// When the switch fires unless pinged again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_deadline: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl PingResponse {
+    fn error(error: String) -> Self {
+        PingResponse {
+            success: false,
+            name: None,
+            next_deadline: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// POST /watchdog/{name}/ping - the named system is alive.
+pub async fn ping(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    switches: web::Data<Switches>,
+    bus: web::Data<EventBus>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    // A ping holds off remediation, so it needs a key as running one would.
+    if let Err(error_msg) = auth::authenticate(&config, &http_req).await {
+        log_error("/watchdog", &error_msg, None);
+        return Ok(HttpResponse::Unauthorized().json(PingResponse::error(error_msg)));
+    }
+    let name = path.into_inner();
+    let now = Local::now();
+    let (recovered, next_deadline) = {
+        let mut states = switches.0.lock().unwrap();
+        let Some(state) = states.get_mut(&name) else {
+            return Ok(HttpResponse::NotFound().json(PingResponse::error(format!("No watchdog named {:?}", name))));
+        };
+        let recovered = (state.status == Status::Missed).then_some(state.misses);
+        state.last_ping = Some(clock::format(&now));
+        state.deadline = now + chrono::Duration::from_std(state.interval).unwrap_or_default();
+        state.status = Status::Ok;
+        state.misses = 0;
+        (recovered, clock::format(&state.deadline))
+    };
+    if let Some(misses) = recovered {
+        tracing::info!(watchdog = %name, misses, "Watchdog pinged again");
+        bus.publish(events::WATCHDOG_RECOVERED, None, serde_json::json!({
+            "name": name,
+            "misses": misses,
+        }));
+    }
+    Ok(HttpResponse::Ok().json(PingResponse {
+        success: true,
+        name: Some(name),
+        next_deadline: Some(next_deadline),
+        error: None,
+    }))
+}
+
+#[derive(Serialize)]
+struct WatchdogStatus {
+    name: String,
+    interval_secs: u64,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_ping: Option<String>,
+    /// When the switch fires next unless pinged.
+    deadline: String,
+    misses: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remediation_job_id: Option<String>,
+}
+
+/// GET /watchdog - the switches and when each fires next.
+pub async fn list(switches: web::Data<Switches>) -> ActixResult<HttpResponse> {
+    let watchdogs: Vec<WatchdogStatus> = switches
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, state)| WatchdogStatus {
+            name: name.clone(),
+            interval_secs: state.interval.as_secs(),
+            status: state.status,
+            last_ping: state.last_ping.clone(),
+            deadline: clock::format(&state.deadline),
+            misses: state.misses,
+            remediation_job_id: state.remediation_job_id.clone(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "watchdogs": watchdogs,
+    })))
+}
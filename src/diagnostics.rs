@@ -0,0 +1,140 @@
+//! Diagnostic bundle captured when a job fails: the tail of the command's
+//! output and of the agent log, a process listing, a (redacted) environment
+//! snapshot, disk/memory state and, on desktops, a screenshot.
+//!
+//! Every part is best effort; whatever could be collected is attached to the
+//! job as artifacts.
+
+use std::io;
+use std::path::Path;
+use tokio::process::Command as TokioCommand;
+
+use crate::{artifacts, clock, get_log_file_path};
+#[cfg(feature = "desktop")]
+use crate::screen;
+
+const TAIL_LINES: usize = 200;
+
+/// Environment variables whose names contain one of these are masked.
+const SECRET_MARKERS: [&str; 6] = ["PASSWORD", "SECRET", "TOKEN", "KEY", "CREDENTIAL", "AUTH"];
+
+/// Output of a failed execution, when it was captured.
+pub struct FailedOutput<'a> {
+    pub stdout: &'a str,
+    pub stderr: &'a str,
+}
+
+fn tail(text: &str, lines: usize) -> String {
+    let all: Vec<&str> = text.lines().collect();
+    let start = all.len().saturating_sub(lines);
+    let mut tail = all[start..].join("\n");
+    tail.push('\n');
+    tail
+}
+
+fn env_snapshot() -> String {
+    let mut vars: Vec<(String, String)> = std::env::vars()
+        .map(|(name, value)| {
+            let upper = name.to_uppercase();
+            if SECRET_MARKERS.iter().any(|m| upper.contains(m)) {
+                (name, "********".to_string())
+            } else {
+                (name, value)
+            }
+        })
+        .collect();
+    vars.sort();
+    vars.into_iter().map(|(k, v)| format!("{}={}\n", k, v)).collect()
+}
+
+/// Run a diagnostic command and return its output, or the reason it failed.
+async fn probe(program: &str, args: &[&str]) -> String {
+    match TokioCommand::new(program).args(args).output().await {
+        Ok(output) => format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => format!("{} unavailable: {}\n", program, e),
+    }
+}
+
+async fn process_tree() -> String {
+    if cfg!(target_os = "windows") {
+        return probe("tasklist", &["/v"]).await;
+    }
+    // -A rather than -e, which shows the environment on the BSDs.
+    match TokioCommand::new("ps").args(["-A", "-o", "pid,ppid,user,pcpu,pmem,etime,args"]).output().await {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).into_owned(),
+        // BusyBox (Alpine) takes neither -A nor the usage columns, and lists
+        // every process anyway.
+        _ => probe("ps", &["-o", "pid,ppid,user,etime,args"]).await,
+    }
+}
+
+async fn system_state() -> String {
+    if cfg!(target_os = "windows") {
+        probe(
+            "powershell",
+            &[
+                "-NoProfile",
+                "-NonInteractive",
+                "-Command",
+                "Get-PSDrive -PSProvider FileSystem | Format-Table -AutoSize | Out-String; \
+                 Get-CimInstance Win32_OperatingSystem | \
+                 Select-Object TotalVisibleMemorySize,FreePhysicalMemory | Format-List | Out-String",
+            ],
+        )
+        .await
+    } else if cfg!(target_os = "macos") {
+        format!("{}\n{}", probe("df", &["-h"]).await, probe("vm_stat", &[]).await)
+    } else if cfg!(target_os = "freebsd") {
+        let memory = probe("sysctl", &["hw.physmem", "hw.pagesize", "vm.stats.vm.v_free_count", "vm.stats.vm.v_inactive_count"]).await;
+        format!("{}\n{}", probe("df", &["-h"]).await, memory)
+    } else {
+        format!("{}\n{}", probe("df", &["-h"]).await, probe("free", &["-m"]).await)
+    }
+}
+
+async fn write(dir: &Path, name: &str, contents: String, written: &mut Vec<String>) {
+    if tokio::fs::write(dir.join(name), contents).await.is_ok() {
+        written.push(name.to_string());
+    }
+}
+
+/// Capture a diagnostic bundle for a failed job; returns the artifact names.
+pub async fn capture_bundle(job_id: &str, command: &str, output: Option<FailedOutput<'_>>) -> io::Result<Vec<String>> {
+    let dir = artifacts::job_dir(job_id)?;
+    let mut written = Vec::new();
+
+    let mut summary = format!(
+        "job_id: {}\ncommand: {}\ncaptured_at: {}\nplatform: {}\n",
+        job_id,
+        command,
+        clock::now(),
+        std::env::consts::OS
+    );
+    if let Ok(cwd) = std::env::current_dir() {
+        summary.push_str(&format!("cwd: {}\n", cwd.display()));
+    }
+    write(&dir, "summary.txt", summary, &mut written).await;
+
+    if let Some(output) = output {
+        write(&dir, "stdout_tail.txt", tail(output.stdout, TAIL_LINES), &mut written).await;
+        write(&dir, "stderr_tail.txt", tail(output.stderr, TAIL_LINES), &mut written).await;
+    }
+    if let Ok(log) = tokio::fs::read_to_string(get_log_file_path()).await {
+        write(&dir, "agent_log_tail.txt", tail(&log, TAIL_LINES), &mut written).await;
+    }
+    write(&dir, "processes.txt", process_tree().await, &mut written).await;
+    write(&dir, "environment.txt", env_snapshot(), &mut written).await;
+    write(&dir, "system.txt", system_state().await, &mut written).await;
+
+    // Only desktops have something to capture; headless hosts just skip it.
+    #[cfg(feature = "desktop")]
+    if screen::capture(&dir.join("screenshot.png")).await.is_ok() {
+        written.push("screenshot.png".to_string());
+    }
+
+    Ok(written)
+}
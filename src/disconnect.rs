@@ -0,0 +1,190 @@
+//! Clients that disconnect from `/execute` before the result, so abandoned
+//! requests don't leave mystery processes running.
+//!
+//! actix-web carries on with a handler whose client has gone, so each
+//! connection keeps a duplicate of its socket, which the waiting handler
+//! peeks at every second: end of stream or an error means the client closed
+//! the connection. The disconnect is always recorded on the job as
+//! `client_disconnected_at`. With `cancel_on_disconnect` (default
+//! `AGENT_CANCEL_ON_DISCONNECT`), the command is also killed, with its whole
+//! process tree: its process group on Unix, `taskkill /T` on Windows.
+
+use actix_web::dev::Extensions;
+use actix_web::{web, HttpRequest};
+use std::any::Any;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock;
+use crate::jobs::{JobRegistry, JobStatus};
+use crate::log_error;
+
+/// How often a waiting handler checks for its client.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A duplicate of a connection's socket, for noticing the client closing it.
+#[derive(Clone)]
+pub struct ClientSocket(Arc<socket2::Socket>);
+
+/// `HttpServer::on_connect` hook keeping a [`ClientSocket`] with each
+/// connection.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let stream = connection.downcast_ref::<actix_web::rt::net::TcpStream>();
+    #[cfg(feature = "tls")]
+    let stream = stream.or_else(|| {
+        connection
+            .downcast_ref::<actix_tls::accept::rustls_0_23::TlsStream<actix_web::rt::net::TcpStream>>()
+            .map(|stream| stream.get_ref().0)
+    });
+    let Some(stream) = stream else {
+        return;
+    };
+    #[cfg(unix)]
+    let socket = std::os::fd::AsFd::as_fd(stream).try_clone_to_owned().map(socket2::Socket::from);
+    #[cfg(windows)]
+    let socket = std::os::windows::io::AsSocket::as_socket(stream).try_clone_to_owned().map(socket2::Socket::from);
+    if let Ok(socket) = socket {
+        data.insert(ClientSocket(Arc::new(socket)));
+    }
+}
+
+/// Resolves once the client of `req` has closed the connection; never when
+/// its socket isn't known.
+pub async fn closed(req: &HttpRequest) {
+    let Some(socket) = req.conn_data::<ClientSocket>().cloned() else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        // The socket is non-blocking, like the connection's own.
+        let mut byte = [MaybeUninit::uninit(); 1];
+        match socket.0.peek(&mut byte) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Dropped with the request handler; unless [`DisconnectGuard::disarm`]ed
+/// first, the client went away before getting its answer.
+pub struct DisconnectGuard {
+    job_id: String,
+    jobs: web::Data<JobRegistry>,
+    cancel: bool,
+    armed: bool,
+}
+
+impl DisconnectGuard {
+    pub fn new(job_id: &str, jobs: web::Data<JobRegistry>, cancel: bool) -> Self {
+        DisconnectGuard {
+            job_id: job_id.to_string(),
+            jobs,
+            cancel,
+            armed: true,
+        }
+    }
+
+    /// The client got its answer.
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let Some(job) = self.jobs.get(&self.job_id).filter(|job| job.status == JobStatus::Running) else {
+            return;
+        };
+        let cancel = self.cancel && job.pid.is_some();
+        self.jobs.update(&self.job_id, |job| {
+            job.client_disconnected_at = Some(clock::now());
+            if cancel {
+                // Recorded as cancelled, so the job ends as failed.
+                job.cancelled_at.get_or_insert_with(clock::now);
+                job.error = Some("Cancelled: the client disconnected".to_string());
+            }
+        });
+        let error_msg = if cancel {
+            format!("Client disconnected from job {}; cancelling the command", self.job_id)
+        } else {
+            format!("Client disconnected from job {}; the command keeps running", self.job_id)
+        };
+        log_error("/execute", &error_msg, Some(&job.command));
+        if let Some(pid) = job.pid.filter(|_| cancel) {
+            if let Err(e) = kill_tree(pid) {
+                log_error("/execute", &format!("Failed to cancel job {}: {}", self.job_id, e), Some(&job.command));
+            }
+        }
+    }
+}
+
+/// Stop a cancelled job whose process `exited` waits for: SIGTERM to its
+/// process tree, then SIGKILL if it is still running after `grace`. Windows
+/// has no SIGTERM, so there the tree is killed outright.
+pub async fn stop_tree<F: Future>(pid: u32, grace: Duration, exited: Pin<&mut F>) -> F::Output {
+    let mut exited = exited;
+    if let Err(e) = terminate_tree(pid) {
+        log_error("/jobs/{id}", &format!("Failed to stop process {}: {}", pid, e), None);
+    }
+    if cfg!(unix) {
+        if let Ok(output) = tokio::time::timeout(grace, exited.as_mut()).await {
+            return output;
+        }
+        if let Err(e) = kill_tree(pid) {
+            log_error("/jobs/{id}", &format!("Failed to kill process {}: {}", pid, e), None);
+        }
+    }
+    exited.await
+}
+
+/// Send SIGTERM to `pid` and its descendants; `pid` must lead its own
+/// process group.
+#[cfg(unix)]
+fn terminate_tree(pid: u32) -> std::io::Result<()> {
+    // SAFETY: plain syscall; a negative pid addresses the process group.
+    if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+fn terminate_tree(pid: u32) -> std::io::Result<()> {
+    kill_tree(pid)
+}
+
+/// Kill `pid` and its descendants; on Unix `pid` must lead its own process
+/// group.
+#[cfg(unix)]
+pub fn kill_tree(pid: u32) -> std::io::Result<()> {
+    // SAFETY: plain syscall; a negative pid addresses the process group.
+    if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+pub fn kill_tree(pid: u32) -> std::io::Result<()> {
+    let status = std::process::Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!("taskkill exited with {}", status)))
+    }
+}
@@ -0,0 +1,249 @@
+//! Name resolution for the agent's own lookups and `GET /net/dns-lookup`.
+//!
+//! By default the system resolver is used. `AGENT_DNS_SERVERS` replaces it
+//! with specific servers, for split-horizon networks where the system one
+//! answers from the wrong view. Entries are comma-separated and tried in
+//! order:
+//!
+//! - `10.0.0.53`, `10.0.0.53:5353`, `[fd00::53]:53`: plain DNS over UDP and TCP;
+//! - `tls://1.1.1.1#cloudflare-dns.com`: DNS over TLS (port 853), verifying
+//!   the certificate for the name after `#` (the IP when omitted);
+//! - `https://1.1.1.1/dns-query#cloudflare-dns.com`: DNS over HTTPS (port
+//!   443, path `/dns-query` when omitted).
+//!
+//! Servers are given by IP address, as there is nothing to resolve their
+//! names with. Commands the agent runs keep using the system resolver.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use hickory_resolver::config::{ConnectionConfig, NameServerConfig, ResolverConfig};
+use hickory_resolver::net::runtime::TokioRuntimeProvider;
+use hickory_resolver::proto::rr::RecordType;
+use hickory_resolver::TokioResolver;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::config::AppConfig;
+use crate::log_error;
+
+/// The resolver for the agent's own lookups.
+#[derive(Clone, Default)]
+pub struct Dns {
+    /// The `AGENT_DNS_SERVERS` entries; empty for the system resolver.
+    servers: Vec<String>,
+    /// Built from `servers`; `None` for the system resolver.
+    resolver: Option<TokioResolver>,
+}
+
+fn parse_server(entry: &str) -> Result<NameServerConfig, String> {
+    let invalid = |reason: &str| format!("Invalid AGENT_DNS_SERVERS entry {:?}: {}", entry, reason);
+    let (scheme, rest) = match entry.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+        None => ("udp".to_string(), entry),
+    };
+    let (rest, server_name) = match rest.split_once('#') {
+        Some((rest, name)) => (rest, Some(name)),
+        None => (rest, None),
+    };
+    let (address, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], Some(&rest[index..])),
+        None => (rest, None),
+    };
+    let (ip, port) = match address.parse::<SocketAddr>() {
+        Ok(addr) => (addr.ip(), Some(addr.port())),
+        Err(_) => {
+            let ip = address.trim_start_matches('[').trim_end_matches(']');
+            (ip.parse::<IpAddr>().map_err(|_| invalid("expected an IP address"))?, None)
+        }
+    };
+    let server_name: Arc<str> = Arc::from(server_name.map(str::to_string).unwrap_or_else(|| ip.to_string()));
+    let mut connections = match scheme.as_str() {
+        "udp" | "dns" => vec![ConnectionConfig::udp(), ConnectionConfig::tcp()],
+        "tls" => vec![ConnectionConfig::tls(server_name)],
+        "https" => vec![ConnectionConfig::https(server_name, path.map(Arc::from))],
+        _ => return Err(invalid("expected udp://, tls:// or https://")),
+    };
+    if path.is_some() && scheme != "https" {
+        return Err(invalid("only https:// servers take a path"));
+    }
+    if let Some(port) = port {
+        for connection in &mut connections {
+            connection.port = port;
+        }
+    }
+    Ok(NameServerConfig::new(ip, true, connections))
+}
+
+impl Dns {
+    pub fn from_env() -> Result<Self, String> {
+        let servers: Vec<String> = std::env::var("AGENT_DNS_SERVERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect();
+        if servers.is_empty() {
+            return Ok(Dns::default());
+        }
+        let name_servers = servers.iter().map(|entry| parse_server(entry)).collect::<Result<Vec<_>, _>>()?;
+        let resolver = TokioResolver::builder_with_config(
+            ResolverConfig::from_name_servers(name_servers),
+            TokioRuntimeProvider::default(),
+        )
+        .build()
+        .map_err(|e| format!("Failed to set up AGENT_DNS_SERVERS: {}", e))?;
+        Ok(Dns {
+            servers,
+            resolver: Some(resolver),
+        })
+    }
+
+    /// The first address of `host`, an IP address or a name.
+    pub async fn resolve(&self, host: &str) -> Result<IpAddr, String> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(ip);
+        }
+        let lookup = self
+            .resolver()?
+            .lookup_ip(host)
+            .await
+            .map_err(|e| format!("Failed to resolve {}: {}", host, e))?;
+        lookup.iter().next().ok_or_else(|| format!("{} has no addresses", host))
+    }
+
+    /// The configured resolver, else one reading the system configuration.
+    fn resolver(&self) -> Result<TokioResolver, String> {
+        match &self.resolver {
+            Some(resolver) => Ok(resolver.clone()),
+            None => TokioResolver::builder_tokio()
+                .and_then(|builder| builder.build())
+                .map_err(|e| format!("Failed to read the system DNS configuration: {}", e)),
+        }
+    }
+}
+
+/// Resolves names for reqwest clients through `AGENT_DNS_SERVERS`.
+#[cfg(feature = "http")]
+struct Resolve(TokioResolver);
+
+#[cfg(feature = "http")]
+impl reqwest::dns::Resolve for Resolve {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            let lookup = resolver.lookup_ip(name.as_str()).await?;
+            let addrs: Vec<SocketAddr> = lookup.iter().map(|ip| SocketAddr::new(ip, 0)).collect();
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+#[cfg(feature = "http")]
+impl Dns {
+    /// Have `builder` resolve through the configured servers, if any.
+    pub fn apply(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        match &self.resolver {
+            Some(resolver) => builder.dns_resolver(Arc::new(Resolve(resolver.clone()))),
+            None => builder,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DnsLookupQuery {
+    name: String,
+    /// Record type, e.g. `A`, `AAAA`, `MX`, `TXT`, `SRV`, `PTR` (default `A`).
+    #[serde(rename = "type", default = "default_record_type")]
+    record_type: String,
+}
+
+fn default_record_type() -> String {
+    "A".to_string()
+}
+
+#[derive(Serialize)]
+struct DnsRecord {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    ttl: u32,
+    data: String,
+}
+
+#[derive(Serialize)]
+struct DnsLookupResponse {
+    success: bool,
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    /// The `AGENT_DNS_SERVERS` entries asked, or `["system"]`.
+    servers: Vec<String>,
+    records: Vec<DnsRecord>,
+    /// The name does not exist, as opposed to having no records of the type.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    nx_domain: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// GET /net/dns-lookup - resolve a name as the agent itself would.
+pub async fn dns_lookup(query: web::Query<DnsLookupQuery>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let servers = if config.outbound.dns.servers.is_empty() {
+        vec!["system".to_string()]
+    } else {
+        config.outbound.dns.servers.clone()
+    };
+    let mut response = DnsLookupResponse {
+        success: false,
+        name: query.name.clone(),
+        record_type: query.record_type.to_uppercase(),
+        servers,
+        records: Vec::new(),
+        nx_domain: false,
+        error: None,
+    };
+    let record_type = match RecordType::from_str(&response.record_type) {
+        Ok(record_type) => record_type,
+        Err(_) => {
+            response.error = Some(format!("Unknown record type {}", query.record_type));
+            return Ok(HttpResponse::BadRequest().json(response));
+        }
+    };
+    let resolver = match config.outbound.dns.resolver() {
+        Ok(resolver) => resolver,
+        Err(error_msg) => {
+            log_error("/net/dns-lookup", &error_msg, None);
+            response.error = Some(error_msg);
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+
+    match resolver.lookup(query.name.as_str(), record_type).await {
+        Ok(lookup) => {
+            response.success = true;
+            response.records = lookup
+                .answers()
+                .iter()
+                .map(|record| DnsRecord {
+                    name: record.name.to_string(),
+                    record_type: record.record_type().to_string(),
+                    ttl: record.ttl,
+                    data: record.data.to_string(),
+                })
+                .collect();
+            Ok(HttpResponse::Ok().json(response))
+        }
+        // An answer, just an empty one.
+        Err(e) if e.is_no_records_found() => {
+            response.success = true;
+            response.nx_domain = e.is_nx_domain();
+            Ok(HttpResponse::Ok().json(response))
+        }
+        Err(e) => {
+            response.error = Some(format!("Lookup failed: {}", e));
+            Ok(HttpResponse::BadGateway().json(response))
+        }
+    }
+}
@@ -0,0 +1,517 @@
+//! `machine_agent doctor`: a self-test for field deployments, which mostly
+//! fail for one of a handful of reasons.
+//!
+//! It checks the configuration (every `AGENT_*` file and value the agent
+//! would load, including the document signing keys and object storage
+//! credentials), that the shell commands run in is there, that the
+//! directories the agent writes to are writable, that the HTTPS certificate
+//! and key load, that the listen addresses can be bound, and that the servers
+//! the agent talks to (OPA, the authorization service, the document
+//! repository, object storage) answer through the configured proxies. The
+//! report is printed as a table, or as JSON with `--json`; the exit status is
+//! 1 when a check failed.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(feature = "http")]
+use std::time::{Duration, Instant};
+
+use crate::config::AppConfig;
+use crate::dns::Dns;
+use crate::outbound::{Allowlist, Outbound, Proxy};
+use crate::shells::{self, Shell};
+use crate::{api_keys, auth, bandwidth, blackout, capabilities, deadman, clock, command_rules, files, get_exe_dir, get_log_file_path, hooks, job_hooks, lifecycle, listen, logging, offload, tls};
+
+/// How long a server may take to answer the connectivity check.
+#[cfg(feature = "http")]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Variables holding whole numbers, which the agent ignores when invalid.
+const COUNTS: &[&str] = &[
+    "AGENT_JOB_OUTPUT_MAX_BYTES",
+    "AGENT_JOB_RETENTION",
+    "AGENT_MIN_FREE_MEMORY_MB",
+    "AGENT_MIN_FREE_DISK_MB",
+    "AGENT_MAX_DEFER_SECS",
+    "AGENT_MAX_CONCURRENT",
+    "AGENT_MAX_QUEUE_DEPTH",
+    "AGENT_QUEUE_TIMEOUT_SECS",
+    "AGENT_EXECUTE_ASYNC_AFTER_SECS",
+    "AGENT_CANCEL_GRACE_SECS",
+    "AGENT_EXECUTE_HEARTBEAT_SECS",
+    "AGENT_METRICS_INTERVAL_SECS",
+    "AGENT_METRICS_RETENTION_HOURS",
+    "AGENT_HEARTBEAT_INTERVAL_SECS",
+    "AGENT_WATCHDOG_MAX_MISSES",
+    "AGENT_WORKERS",
+    "AGENT_LOG_MAX_BYTES",
+    "AGENT_LOG_KEEP",
+    "AGENT_SLOW_REQUEST_MS",
+    "AGENT_SLOW_JOB_SECS",
+    "AGENT_BLACKOUT_REFRESH_SECS",
+];
+
+/// Checks whose failure makes the agent exit at startup.
+const FATAL: &[&str] = &["AGENT_API_KEYS_FILE", "AGENT_AUTH_BACKENDS", "AGENT_COMMAND_RULES_FILE", "outbound", "AGENT_OPA_URL", "AGENT_DOCUMENTS_URL", "AGENT_METRICS_PUSH_URL", "AGENT_AUTHZ_URL", "offload", "bandwidth", "blackout", "AGENT_ALLOWED_SHELLS", "AGENT_DOWNLOAD_ROOTS", "certificate", "client_ca"];
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize)]
+struct Check {
+    /// `config`, `shell`, `directory`, `listen` or `connectivity`.
+    category: &'static str,
+    name: String,
+    status: Status,
+    detail: String,
+}
+
+#[derive(Serialize)]
+struct Report {
+    version: &'static str,
+    platform: &'static str,
+    features: Vec<&'static str>,
+    checks: Vec<Check>,
+    /// No check failed.
+    ok: bool,
+}
+
+#[derive(Default)]
+struct Checks(Vec<Check>);
+
+impl Checks {
+    fn add(&mut self, category: &'static str, name: impl Into<String>, status: Status, detail: impl Into<String>) {
+        self.0.push(Check {
+            category,
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        });
+    }
+
+    fn result(&mut self, category: &'static str, name: impl Into<String>, result: Result<String, String>) {
+        match result {
+            Ok(detail) => self.add(category, name, Status::Ok, detail),
+            Err(detail) => self.add(category, name, Status::Fail, detail),
+        }
+    }
+
+    /// Load an optional config file with `load`, describing what it holds.
+    fn file<T>(&mut self, variable: &str, load: impl FnOnce(&Path) -> Result<T, String>, describe: impl FnOnce(&T) -> String) {
+        let Some(path) = std::env::var_os(variable).filter(|path| !path.is_empty()).map(PathBuf::from) else {
+            return;
+        };
+        let result = load(&path).map(|loaded| format!("{} in {}", describe(&loaded), path.display()));
+        self.result("config", variable, result);
+    }
+
+    fn failed(&self) -> bool {
+        self.0.iter().any(|check| check.status == Status::Fail)
+    }
+
+    fn fatal(&self) -> bool {
+        self.0.iter().any(|check| check.status == Status::Fail && FATAL.contains(&check.name.as_str()))
+    }
+}
+
+/// Fail `variable` when set to something that doesn't parse as `T`.
+fn parses<T: FromStr>(checks: &mut Checks, variable: &'static str, expected: &str) {
+    if let Ok(value) = std::env::var(variable) {
+        if value.trim().parse::<T>().is_err() {
+            checks.add("config", variable, Status::Fail, format!("{:?} is not {} and is ignored", value, expected));
+        }
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" })
+}
+
+/// The values and files `AppConfig::from_env` would load.
+fn check_config(checks: &mut Checks) {
+    for variable in COUNTS {
+        parses::<u64>(checks, variable, "a whole number");
+    }
+    parses::<i32>(checks, "AGENT_COMPRESS_LEVEL", "a whole number");
+    parses::<f64>(checks, "AGENT_MAX_CPU_LOAD", "a number");
+    parses::<clock::Zone>(checks, "AGENT_TIMESTAMP_ZONE", "utc or local");
+    if let Err(error_msg) = logging::filter() {
+        checks.add("config", "AGENT_LOG_LEVEL", Status::Fail, error_msg);
+    }
+    if let Ok(value) = std::env::var("AGENT_ON_HOST_PRESSURE") {
+        if !matches!(value.as_str(), "reject" | "defer") {
+            checks.add("config", "AGENT_ON_HOST_PRESSURE", Status::Fail, format!("{:?} is not reject or defer", value));
+        }
+    }
+
+    checks.file("AGENT_API_KEYS_FILE", api_keys::load, |keys| plural(keys.len(), "key"));
+    checks.file("AGENT_COMMAND_RULES_FILE", command_rules::load, |rules| {
+        format!("{} allowed and {} denied", plural(rules.allow.len(), "rule"), plural(rules.deny.len(), "rule"))
+    });
+    checks.file("AGENT_HOOKS_FILE", hooks::load, |hooks| plural(hooks.len(), "hook set"));
+    checks.file("AGENT_LIFECYCLE_FILE", lifecycle::load, |lifecycle| {
+        format!("{} startup and {} shutdown commands", lifecycle.startup.len(), lifecycle.shutdown.len())
+    });
+    checks.file("AGENT_JOB_HOOKS_FILE", job_hooks::load, |hooks| {
+        format!(
+            "{} started, {} finished and {} failed scripts",
+            hooks.started.len(),
+            hooks.finished.len(),
+            hooks.failed.len()
+        )
+    });
+    checks.file("AGENT_WATCHDOGS_FILE", deadman::load, |watchdogs| plural(watchdogs.len(), "watchdog"));
+    if std::env::var("AGENT_BLACKOUT_DATES").is_ok() || std::env::var("AGENT_BLACKOUT_ICAL").is_ok() {
+        let blackout = blackout::from_env().map(|blackout| {
+            format!("{} and {}", plural(blackout.dates.len(), "blackout date range"), plural(blackout.calendars.len(), "calendar"))
+        });
+        checks.result("config", "blackout", blackout);
+    }
+    if std::env::var("AGENT_ALLOWED_SHELLS").is_ok() {
+        let shells = shells::from_env().map(|shells| {
+            let names: Vec<&str> = shells.iter().map(|shell| shell.name()).collect();
+            format!("Requests may pick {}", if names.is_empty() { "no shell".to_string() } else { names.join(", ") })
+        });
+        checks.result("config", "AGENT_ALLOWED_SHELLS", shells);
+    }
+    if std::env::var("AGENT_DOWNLOAD_ROOTS").is_ok() {
+        let roots = files::roots_from_env().map(|roots| {
+            let roots: Vec<String> = roots.iter().map(|root| root.display().to_string()).collect();
+            format!("Downloads from {}", if roots.is_empty() { "nowhere".to_string() } else { roots.join(", ") })
+        });
+        checks.result("config", "AGENT_DOWNLOAD_ROOTS", roots);
+    }
+    #[cfg(feature = "sync")]
+    checks.file("AGENT_SHARE_CREDENTIALS", crate::shares::load, |_| "Share credentials".to_string());
+
+    let outbound = Allowlist::from_env().and_then(|allow| {
+        Ok(Outbound {
+            proxy: Proxy::from_env(),
+            allow,
+            dns: Dns::from_env()?,
+        })
+    });
+    #[cfg(feature = "http")]
+    let outbound = outbound.and_then(|outbound| outbound.client().map(|_| outbound));
+    let outbound = match outbound {
+        Ok(outbound) => {
+            checks.add("config", "outbound", Status::Ok, "Proxies, allowlist and DNS servers");
+            outbound
+        }
+        Err(e) => {
+            checks.add("config", "outbound", Status::Fail, e);
+            Outbound::default()
+        }
+    };
+
+    if let Ok(url) = std::env::var("AGENT_OPA_URL") {
+        #[cfg(feature = "opa")]
+        let result = outbound.check(&url).map(|()| url);
+        #[cfg(not(feature = "opa"))]
+        let result: Result<String, String> = {
+            let _ = url;
+            Err("Needs the agent built with the opa feature".to_string())
+        };
+        checks.result("config", "AGENT_OPA_URL", result);
+    }
+    if let Some(policy) = std::env::var_os("AGENT_OPA_POLICY").filter(|_| std::env::var("AGENT_OPA_URL").is_err()) {
+        let policy = PathBuf::from(policy);
+        if !policy.exists() {
+            checks.add("config", "AGENT_OPA_POLICY", Status::Fail, format!("{} does not exist", policy.display()));
+        } else if !capabilities::on_path("opa") {
+            checks.add("config", "AGENT_OPA_POLICY", Status::Fail, "The opa binary is not on PATH");
+        } else {
+            checks.add("config", "AGENT_OPA_POLICY", Status::Ok, format!("{} with opa eval", policy.display()));
+        }
+    }
+    #[cfg(feature = "documents")]
+    if let Some(result) = crate::documents::from_env().transpose() {
+        let result = result.and_then(|repository| outbound.check(&repository.url).map(|()| repository));
+        let result = result.map(|repository| match repository.keys.len() {
+            0 => format!("{}, documents unsigned", repository.url),
+            keys => format!("{}, {}", repository.url, plural(keys, "signing key")),
+        });
+        checks.result("config", "AGENT_DOCUMENTS_URL", result);
+    }
+    #[cfg(not(feature = "documents"))]
+    if std::env::var("AGENT_DOCUMENTS_URL").is_ok_and(|url| !url.trim().is_empty()) {
+        checks.add("config", "AGENT_DOCUMENTS_URL", Status::Fail, "Needs the agent built with the documents feature");
+    }
+    #[cfg(feature = "push")]
+    if let Some(result) = crate::metrics_push::from_env().transpose() {
+        let result = result.and_then(|push| outbound.check(&push.url).map(|()| push));
+        let result = result.map(|push| format!("{} every {}s", push.url, push.interval.as_secs()));
+        checks.result("config", "AGENT_METRICS_PUSH_URL", result);
+    }
+    #[cfg(not(feature = "push"))]
+    if std::env::var("AGENT_METRICS_PUSH_URL").is_ok_and(|url| !url.trim().is_empty()) {
+        checks.add("config", "AGENT_METRICS_PUSH_URL", Status::Fail, "Needs the agent built with the push feature");
+    }
+    #[cfg(feature = "authz")]
+    if let Some(result) = crate::authz::from_env(&outbound).transpose() {
+        checks.result("config", "AGENT_AUTHZ_URL", result.map(|authz| authz.url));
+    }
+    #[cfg(not(feature = "authz"))]
+    if std::env::var("AGENT_AUTHZ_URL").is_ok_and(|url| !url.trim().is_empty()) {
+        checks.add("config", "AGENT_AUTHZ_URL", Status::Fail, "Needs the agent built with the authz feature");
+    }
+    match offload::from_env() {
+        Ok(Some(offload)) => checks.add("config", "offload", Status::Ok, format!("Uploads to {}", offload.store.endpoint())),
+        Ok(None) => {}
+        Err(e) => checks.add("config", "offload", Status::Fail, e),
+    }
+    if let Err(e) = bandwidth::from_env() {
+        checks.add("config", "bandwidth", Status::Fail, e);
+    }
+    // A keys file that doesn't load is reported above.
+    let keys = std::env::var_os("AGENT_API_KEYS_FILE")
+        .and_then(|path| api_keys::load(Path::new(&path)).ok())
+        .unwrap_or_default();
+    let auth = auth::from_env(&keys, &tls::Settings::from_env(), &outbound).map(|auth| match auth.names().as_slice() {
+        [] => "None; every endpoint is open".to_string(),
+        names => format!("{} ({})", names.join(", "), if auth.require_all { "all" } else { "any" }),
+    });
+    checks.result("config", "AGENT_AUTH_BACKENDS", auth);
+}
+
+/// The certificate and key HTTPS is served with.
+fn check_tls(checks: &mut Checks) {
+    let settings = tls::Settings::from_env();
+    let result = match settings.files() {
+        Ok(Some((cert, key))) => certificate(&settings, &cert, &key),
+        Ok(None) => Ok("Not configured; the API is served over plain HTTP".to_string()),
+        Err(e) => Err(e),
+    };
+    checks.result("tls", "certificate", result);
+    if let (Ok(Some(_)), Some(client_ca)) = (settings.files(), &settings.client_ca) {
+        checks.result("tls", "client_ca", client_ca_bundle(client_ca));
+    }
+}
+
+#[cfg(feature = "tls")]
+fn certificate(settings: &tls::Settings, cert: &Path, key: &Path) -> Result<String, String> {
+    if settings.generates()? {
+        let dir = cert.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        return writable(dir).map(|_| format!("A self-signed certificate will be generated at {}", cert.display()));
+    }
+    tls::check(cert, key).map(|()| format!("{} with key {}", cert.display(), key.display()))
+}
+
+#[cfg(feature = "tls")]
+fn client_ca_bundle(client_ca: &Path) -> Result<String, String> {
+    tls::check_client_ca(client_ca).map(|()| format!("Client certificates must be signed by a CA in {}", client_ca.display()))
+}
+
+#[cfg(not(feature = "tls"))]
+fn client_ca_bundle(_client_ca: &Path) -> Result<String, String> {
+    Err("Client certificates need the agent built with the tls feature".to_string())
+}
+
+#[cfg(not(feature = "tls"))]
+fn certificate(_settings: &tls::Settings, _cert: &Path, _key: &Path) -> Result<String, String> {
+    Err("HTTPS needs the agent built with the tls feature".to_string())
+}
+
+/// Whether `shell` can run a command.
+fn run_shell(shell: Shell) -> Result<String, String> {
+    let (program, args) = shell.invocation();
+    let flags = args.join(" ");
+    match std::process::Command::new(program).args(args).arg("exit 0").output() {
+        Ok(output) if output.status.success() => Ok(format!("{} {} runs commands", program, flags)),
+        Ok(output) => Err(format!("{} {} \"exit 0\" exited with {}", program, flags, output.status)),
+        Err(e) => Err(format!("Cannot start {}: {}", program, e)),
+    }
+}
+
+/// The shell commands are run with, and the others requests may pick when
+/// `AGENT_ALLOWED_SHELLS` names them.
+fn check_shell(checks: &mut Checks) {
+    checks.result("shell", Shell::platform().name(), run_shell(Shell::platform()));
+    if std::env::var("AGENT_ALLOWED_SHELLS").is_err() {
+        return;
+    }
+    for shell in shells::from_env().unwrap_or_default() {
+        if shell == Shell::platform() {
+            continue;
+        }
+        match shells::check(shell, &[shell]).and_then(|()| run_shell(shell)) {
+            Ok(detail) => checks.add("shell", shell.name(), Status::Ok, detail),
+            // Only requests picking it would fail.
+            Err(detail) => checks.add("shell", shell.name(), Status::Warn, detail),
+        }
+    }
+}
+
+/// Whether the agent can create files in `dir`, creating it if needed.
+fn writable(dir: &Path) -> Result<String, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+    let probe = dir.join(format!(".machine_agent-doctor-{}", std::process::id()));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| format!("Cannot write to {}: {}", dir.display(), e))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(format!("{} is writable", dir.display()))
+}
+
+fn check_directories(checks: &mut Checks, config: &AppConfig) {
+    let exe_dir = get_exe_dir();
+    checks.result("directory", "executable directory", writable(&exe_dir));
+    let log = get_log_file_path();
+    let result = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log)
+        .map(|_| format!("{} can be appended to", log.display()))
+        .map_err(|e| format!("Cannot open {}: {}", log.display(), e));
+    checks.result("directory", "error log", result);
+    // Created on demand, so only checked once they exist.
+    for name in ["artifacts", "content", "playbooks", "documents", "recordings"] {
+        let dir = exe_dir.join(name);
+        if dir.is_dir() {
+            checks.result("directory", name, writable(&dir));
+        }
+    }
+    if let Some(dir) = &config.job_log_dir {
+        checks.result("directory", "job logs", writable(dir));
+    }
+    if let Some(db) = &config.history_db {
+        let dir = db.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        checks.result("directory", "job history", writable(dir));
+    }
+    match std::env::current_dir() {
+        Ok(dir) => {
+            // Commands run here but needn't write to it, so not a failure.
+            if let Err(e) = writable(&dir) {
+                checks.add("directory", "working directory", Status::Warn, e);
+            } else {
+                checks.add("directory", "working directory", Status::Ok, format!("{} is writable", dir.display()));
+            }
+        }
+        Err(e) => checks.add("directory", "working directory", Status::Fail, format!("Cannot determine it: {}", e)),
+    }
+}
+
+fn check_listen(checks: &mut Checks, config: &AppConfig) {
+    let result = listen::bind(&config.bind, config.dual_stack)
+        .map(|listeners| {
+            let addresses: Vec<String> = listeners.iter().map(|(_, info)| info.address.to_string()).collect();
+            format!("Can listen on {}", addresses.join(", "))
+        })
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AddrInUse => format!("{} (is the agent already running?)", e),
+            _ => e.to_string(),
+        });
+    checks.result("listen", config.bind.join(","), result);
+}
+
+/// The servers the agent sends requests to.
+#[cfg(feature = "http")]
+async fn check_connectivity(checks: &mut Checks, config: &AppConfig) {
+    let mut servers = Vec::new();
+    if let Some(crate::opa::Opa::Server { url, .. }) = &config.opa {
+        servers.push(("OPA", url.clone()));
+    }
+    #[cfg(feature = "documents")]
+    if let Some(repository) = &config.documents {
+        servers.push(("document repository", repository.url.clone()));
+    }
+    if let Some(offload) = &config.offload {
+        servers.push(("object storage", offload.store.endpoint().to_string()));
+    }
+    #[cfg(feature = "push")]
+    if let Some(push) = &config.metrics_push {
+        servers.push(("metrics push", push.url.clone()));
+    }
+    #[cfg(feature = "authz")]
+    if let Some(authz) = &config.authz {
+        servers.push(("authorization service", authz.url.clone()));
+    }
+    if servers.is_empty() {
+        return;
+    }
+    let client = match config.outbound.client().and_then(|builder| builder.timeout(CONNECT_TIMEOUT).build().map_err(|e| e.to_string())) {
+        Ok(client) => client,
+        Err(e) => {
+            checks.add("connectivity", "client", Status::Fail, e);
+            return;
+        }
+    };
+    for (server, url) in servers {
+        let started = Instant::now();
+        // Any answer, even an error status, means the server is reachable.
+        let result = match config.outbound.check(&url) {
+            Ok(()) => client
+                .get(&url)
+                .send()
+                .await
+                .map(|response| format!("{} answered {} in {} ms", url, response.status(), started.elapsed().as_millis()))
+                .map_err(|e| crate::outbound::describe(&e)),
+            Err(e) => Err(e),
+        };
+        checks.result("connectivity", server, result);
+    }
+}
+
+#[cfg(not(feature = "http"))]
+async fn check_connectivity(_checks: &mut Checks, _config: &AppConfig) {}
+
+fn print_table(report: &Report) {
+    println!("machine_agent {} doctor ({}; features: {})", report.version, report.platform, report.features.join(", "));
+    println!();
+    for check in &report.checks {
+        let status = match check.status {
+            Status::Ok => "ok",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        println!("{:<5} {:<12} {:<28} {}", status, check.category, check.name, check.detail);
+    }
+    println!();
+    let failed = report.checks.iter().filter(|check| check.status == Status::Fail).count();
+    match failed {
+        0 => println!("All checks passed"),
+        failed => println!("{} failed", plural(failed, "check")),
+    }
+}
+
+/// Run the checks and print the report; returns the exit status.
+pub async fn run(json: bool) -> i32 {
+    let mut checks = Checks::default();
+    check_config(&mut checks);
+    check_tls(&mut checks);
+    check_shell(&mut checks);
+    if checks.fatal() {
+        checks.add("config", "startup", Status::Warn, "The agent would exit at startup; directory, listen and connectivity checks skipped");
+    } else {
+        // Nothing left that would make it exit.
+        let config = AppConfig::from_env();
+        check_directories(&mut checks, &config);
+        check_listen(&mut checks, &config);
+        check_connectivity(&mut checks, &config).await;
+    }
+    let report = Report {
+        version: env!("CARGO_PKG_VERSION"),
+        platform: std::env::consts::OS,
+        features: capabilities::features(),
+        ok: !checks.failed(),
+        checks: checks.0,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+    } else {
+        print_table(&report);
+    }
+    if report.ok {
+        0
+    } else {
+        1
+    }
+}
@@ -0,0 +1,637 @@
+//! Documents: named, versioned and parameterized playbooks kept in a document
+//! repository, for teams moving off SSM Run Command (`documents` feature).
+//!
+//! `AGENT_DOCUMENTS_URL` is the repository's base URL. `POST /documents/run`
+//! names a document and optionally a version; the agent fetches
+//! `<url>/<name>/<version>` (`<url>/<name>` for the latest), checks the
+//! request's parameters against the document's declarations, substitutes
+//! them for the `{{ name }}` placeholders in its steps and runs the steps as a
+//! playbook.
+//!
+//! Fetched documents are cached next to the executable under
+//! `documents/blobs/<sha256>.json`, keyed by their content, with
+//! `documents/refs/<name>/<version>` and `documents/latest/<name>` naming
+//! the hash. A version is fetched once and served from the cache after that;
+//! when the repository can't be reached, the latest version is run from the
+//! cache too (unless `AGENT_DOCUMENTS_OFFLINE=false`), so scheduled
+//! maintenance carries on over flaky links.
+//!
+//! With `AGENT_DOCUMENTS_KEYS` set, a document must also carry a detached
+//! Ed25519 signature, `<url>/<name>/<version>.sig`, made by one of the keys in
+//! that file over the document exactly as served. Unsigned or wrongly signed
+//! documents are refused, and cached documents are checked again before they
+//! run, so a compromised repository or cache can't push its own steps.
+//!
+//! ```json
+//! {"name": "restart-service", "version": "3",
+//!  "parameters": {"service": {"type": "string", "allowed_values": ["nginx", "haproxy"]},
+//!                 "delay": {"type": "integer", "default": 0, "min": 0, "max": 600}},
+//!  "steps": [{"action": "run", "command": "sleep {{ delay }} && systemctl restart {{ service }}"}]}
+//! ```
+
+use base64::Engine;
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result as ActixResult};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::concurrency;
+use crate::config::AppConfig;
+use crate::events::EventBus;
+use crate::jobs::JobRegistry;
+use crate::playbook::{self, PlaybookRequest, Step};
+use crate::{get_exe_dir, log_error};
+
+/// Where documents are fetched from.
+#[derive(Clone)]
+pub struct Repository {
+    /// Base URL, without a trailing slash.
+    pub url: String,
+    /// `AGENT_DOCUMENTS_TOKEN`, sent as a bearer token.
+    pub token: Option<String>,
+    /// Ed25519 public keys from `AGENT_DOCUMENTS_KEYS`; when any are set,
+    /// documents must be signed by one of them.
+    pub keys: Vec<Vec<u8>>,
+    /// `AGENT_DOCUMENTS_OFFLINE`: run the cached latest version while the
+    /// repository is unreachable (default true).
+    pub offline: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Document {
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub parameters: BTreeMap<String, Parameter>,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ParameterType {
+    #[default]
+    String,
+    Integer,
+    Boolean,
+    /// Substituted space-separated.
+    StringList,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Parameter {
+    #[serde(rename = "type", default)]
+    pub kind: ParameterType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Used when the request leaves the parameter out; required without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_values: Option<Vec<Value>>,
+    /// Regex strings (and list items) must match in full.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<i64>,
+}
+
+fn placeholder() -> &'static Regex {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{\s*([A-Za-z0-9_]+)\s*\}\}").expect("valid placeholder regex"))
+}
+
+/// Names and versions end up in URLs and cache paths.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && !id.starts_with('.')
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+pub fn from_env() -> Result<Option<Repository>, String> {
+    let Some(url) = std::env::var("AGENT_DOCUMENTS_URL").ok().filter(|url| !url.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let url = url.trim().trim_end_matches('/').to_string();
+    reqwest::Url::parse(&url).map_err(|e| format!("Invalid AGENT_DOCUMENTS_URL {}: {}", url, e))?;
+    let keys = match std::env::var("AGENT_DOCUMENTS_KEYS").ok().filter(|file| !file.trim().is_empty()) {
+        Some(file) => load_keys(file.trim())?,
+        None => Vec::new(),
+    };
+    Ok(Some(Repository {
+        url,
+        token: std::env::var("AGENT_DOCUMENTS_TOKEN").ok().filter(|token| !token.is_empty()),
+        keys,
+        offline: !matches!(std::env::var("AGENT_DOCUMENTS_OFFLINE").as_deref(), Ok("0" | "false" | "no")),
+    }))
+}
+
+/// DER prefix of an Ed25519 `SubjectPublicKeyInfo`, which the raw key follows.
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+/// Public keys from `file`: PEM `PUBLIC KEY` blocks (as `openssl pkey
+/// -pubout` writes them) or base64 raw keys, one per line; `#` starts a
+/// comment.
+fn load_keys(file: &str) -> Result<Vec<Vec<u8>>, String> {
+    let invalid = |reason: String| format!("Invalid AGENT_DOCUMENTS_KEYS file {}: {}", file, reason);
+    let text = std::fs::read_to_string(file).map_err(|e| invalid(e.to_string()))?;
+    let mut keys = Vec::new();
+    let mut pem: Option<String> = None;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let encoded = match (&mut pem, line.starts_with("-----BEGIN"), line.starts_with("-----END")) {
+            (None, true, _) => {
+                pem = Some(String::new());
+                continue;
+            }
+            (Some(_), _, true) => pem.take().unwrap_or_default(),
+            (Some(body), _, _) => {
+                body.push_str(line);
+                continue;
+            }
+            (None, _, _) => line.to_string(),
+        };
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(&encoded)
+            .map_err(|e| invalid(e.to_string()))?;
+        let key = match der.strip_prefix(&ED25519_SPKI_PREFIX[..]) {
+            Some(key) => key.to_vec(),
+            None => der,
+        };
+        if key.len() != 32 {
+            return Err(invalid("expected Ed25519 public keys".to_string()));
+        }
+        keys.push(key);
+    }
+    if keys.is_empty() {
+        return Err(invalid("no keys".to_string()));
+    }
+    Ok(keys)
+}
+
+/// A detached signature, raw or base64, as `openssl pkeyutl -sign -rawin`
+/// writes it (possibly base64-encoded afterwards).
+fn decode_signature(signature: &[u8]) -> Vec<u8> {
+    if signature.len() == 64 {
+        return signature.to_vec();
+    }
+    let text = String::from_utf8_lossy(signature);
+    base64::engine::general_purpose::STANDARD
+        .decode(text.trim())
+        .unwrap_or_else(|_| signature.to_vec())
+}
+
+/// Whether `signature` over `bytes` was made by one of the repository's keys.
+fn verify(keys: &[Vec<u8>], bytes: &[u8], signature: &[u8]) -> bool {
+    let signature = decode_signature(signature);
+    keys.iter().any(|key| {
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key)
+            .verify(bytes, &signature)
+            .is_ok()
+    })
+}
+
+impl Document {
+    /// Check a fetched document is the one asked for and is well-formed.
+    fn validate(&self, name: &str, version: Option<&str>) -> Result<(), String> {
+        if self.name != name || version.is_some_and(|version| self.version != version) {
+            return Err(format!("Expected {}@{}, got {}@{}", name, version.unwrap_or("latest"), self.name, self.version));
+        }
+        if !valid_id(&self.version) {
+            return Err(format!("Invalid version {:?}", self.version));
+        }
+        if self.steps.is_empty() {
+            return Err("The document has no steps".to_string());
+        }
+        for (param, spec) in &self.parameters {
+            if let Some(pattern) = &spec.allowed_pattern {
+                Regex::new(pattern).map_err(|e| format!("Invalid allowed_pattern for {}: {}", param, e))?;
+            }
+        }
+        for step in &self.steps {
+            if let Step::Run { command, .. } = step {
+                for capture in placeholder().captures_iter(command) {
+                    if !self.parameters.contains_key(&capture[1]) {
+                        return Err(format!("Step refers to undeclared parameter {}", &capture[1]));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The text substituted for each parameter, or every problem with the
+    /// request's values.
+    fn resolve(&self, values: &serde_json::Map<String, Value>) -> Result<BTreeMap<String, String>, Vec<String>> {
+        let mut errors: Vec<String> = values
+            .keys()
+            .filter(|name| !self.parameters.contains_key(*name))
+            .map(|name| format!("Unknown parameter {}", name))
+            .collect();
+        let mut resolved = BTreeMap::new();
+        for (name, spec) in &self.parameters {
+            let Some(value) = values.get(name).or(spec.default.as_ref()) else {
+                errors.push(format!("Missing required parameter {}", name));
+                continue;
+            };
+            match check_value(spec, value) {
+                Ok(text) => {
+                    resolved.insert(name.clone(), text);
+                }
+                Err(e) => errors.push(format!("Parameter {}: {}", name, e)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(resolved)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The steps with placeholders replaced.
+    fn substitute(&self, values: &BTreeMap<String, String>) -> Vec<Step> {
+        self.steps
+            .iter()
+            .map(|step| match step {
+                Step::Run { command, continue_on_error } => Step::Run {
+                    command: placeholder()
+                        .replace_all(command, |capture: &regex::Captures| values[&capture[1]].clone())
+                        .into_owned(),
+                    continue_on_error: *continue_on_error,
+                },
+                other => other.clone(),
+            })
+            .collect()
+    }
+}
+
+fn check_text(spec: &Parameter, text: &str) -> Result<(), String> {
+    if let Some(pattern) = &spec.allowed_pattern {
+        let full = Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| e.to_string())?;
+        if !full.is_match(text) {
+            return Err(format!("{:?} does not match {}", text, pattern));
+        }
+    }
+    Ok(())
+}
+
+fn check_value(spec: &Parameter, value: &Value) -> Result<String, String> {
+    if let Some(allowed) = &spec.allowed_values {
+        let items: Vec<&Value> = match value {
+            Value::Array(items) => items.iter().collect(),
+            value => vec![value],
+        };
+        if let Some(item) = items.iter().find(|item| !allowed.contains(item)) {
+            return Err(format!("{} is not one of the allowed values", item));
+        }
+    }
+    match (spec.kind, value) {
+        (ParameterType::String, Value::String(text)) => {
+            check_text(spec, text)?;
+            Ok(text.clone())
+        }
+        (ParameterType::Integer, Value::Number(number)) => {
+            let number = number.as_i64().ok_or("expected an integer")?;
+            if spec.min.is_some_and(|min| number < min) || spec.max.is_some_and(|max| number > max) {
+                return Err(format!(
+                    "{} is outside {}..{}",
+                    number,
+                    spec.min.map(|min| min.to_string()).unwrap_or_default(),
+                    spec.max.map(|max| max.to_string()).unwrap_or_default()
+                ));
+            }
+            Ok(number.to_string())
+        }
+        (ParameterType::Boolean, Value::Bool(flag)) => Ok(flag.to_string()),
+        (ParameterType::StringList, Value::Array(items)) => {
+            let mut texts = Vec::new();
+            for item in items {
+                let text = item.as_str().ok_or("expected a list of strings")?;
+                check_text(spec, text)?;
+                texts.push(text.to_string());
+            }
+            Ok(texts.join(" "))
+        }
+        (ParameterType::String, _) => Err("expected a string".to_string()),
+        (ParameterType::Integer, _) => Err("expected an integer".to_string()),
+        (ParameterType::Boolean, _) => Err("expected true or false".to_string()),
+        (ParameterType::StringList, _) => Err("expected a list of strings".to_string()),
+    }
+}
+
+fn documents_dir() -> PathBuf {
+    get_exe_dir().join("documents")
+}
+
+fn blob_path(hash: &str) -> PathBuf {
+    documents_dir().join("blobs").join(format!("{}.json", hash))
+}
+
+fn signature_path(path: &Path) -> PathBuf {
+    path.with_extension("json.sig")
+}
+
+/// The file naming the hash of `name`'s `version`, or of its latest version.
+fn ref_path(name: &str, version: Option<&str>) -> PathBuf {
+    match version {
+        Some(version) => documents_dir().join("refs").join(name).join(version),
+        None => documents_dir().join("latest").join(name),
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, bytes)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Write-then-rename so a crash never leaves a truncated file.
+fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, bytes)?;
+    std::fs::rename(temp, path)
+}
+
+/// A cached document and its hash, checked against the hash and the
+/// repository's keys when set.
+fn load_cached(repository: &Repository, name: &str, version: Option<&str>) -> Option<(Document, String)> {
+    let hash = std::fs::read_to_string(ref_path(name, version)).ok()?.trim().to_string();
+    let path = blob_path(&hash);
+    let bytes = std::fs::read(&path).ok()?;
+    let label = format!("{}@{}", name, version.unwrap_or("latest"));
+    if sha256_hex(&bytes) != hash {
+        log_error("/documents/run", &format!("Cached document {} is corrupt; fetching it again", label), None);
+        return None;
+    }
+    if !repository.keys.is_empty() {
+        let signature = std::fs::read(signature_path(&path)).unwrap_or_default();
+        if !verify(&repository.keys, &bytes, &signature) {
+            log_error(
+                "/documents/run",
+                &format!("Cached document {} is not signed by a trusted key; fetching it again", label),
+                None,
+            );
+            return None;
+        }
+    }
+    let document: Document = serde_json::from_slice(&bytes).ok()?;
+    document.validate(name, version).ok()?;
+    Some((document, hash))
+}
+
+/// Keep the document as it was served, so its hash and signature still
+/// match, and point its version (and `latest`, if it was fetched as the
+/// latest) at it. Returns the hash.
+fn save_cached(document: &Document, bytes: &[u8], signature: Option<&[u8]>, latest: bool) -> std::io::Result<String> {
+    let hash = sha256_hex(bytes);
+    let path = blob_path(&hash);
+    // The signature goes first so a document is never cached without it.
+    if let Some(signature) = signature {
+        write_atomic(&signature_path(&path), signature)?;
+    }
+    write_atomic(&path, bytes)?;
+    write_atomic(&ref_path(&document.name, Some(&document.version)), hash.as_bytes())?;
+    if latest {
+        write_atomic(&ref_path(&document.name, None), hash.as_bytes())?;
+    }
+    Ok(hash)
+}
+
+/// GET `url` from the repository; `Ok(None)` on 404. Errors are 503 when
+/// the repository can't be reached or fails, 502 otherwise.
+async fn get(repository: &Repository, url: &str, config: &AppConfig) -> Result<Option<Vec<u8>>, (StatusCode, String)> {
+    let bad_gateway = |e: String| (StatusCode::BAD_GATEWAY, e);
+    let unavailable = |e: String| (StatusCode::SERVICE_UNAVAILABLE, e);
+    config.outbound.check(url).map_err(bad_gateway)?;
+    let client = config.outbound.client().and_then(|builder| builder.build().map_err(|e| e.to_string())).map_err(bad_gateway)?;
+    let mut request = client.get(url);
+    if let Some(token) = &repository.token {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| unavailable(format!("Failed to fetch {}: {}", url, crate::outbound::describe(&e))))?;
+    match response.status() {
+        status if status.is_success() => {}
+        reqwest::StatusCode::NOT_FOUND => return Ok(None),
+        status if status.is_server_error() => return Err(unavailable(format!("Failed to fetch {}: {}", url, status))),
+        status => return Err(bad_gateway(format!("Failed to fetch {}: {}", url, status))),
+    }
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| unavailable(format!("Failed to fetch {}: {}", url, crate::outbound::describe(&e))))?;
+    Ok(Some(bytes.to_vec()))
+}
+
+/// The document, as served, and its signature when the repository has keys.
+async fn fetch(repository: &Repository, name: &str, version: Option<&str>, config: &AppConfig) -> Result<(Document, Vec<u8>, Option<Vec<u8>>), (StatusCode, String)> {
+    let bad_gateway = |e: String| (StatusCode::BAD_GATEWAY, e);
+    let url = match version {
+        Some(version) => format!("{}/{}/{}", repository.url, name, version),
+        None => format!("{}/{}", repository.url, name),
+    };
+    let Some(bytes) = get(repository, &url, config).await? else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Document {}@{} not found", name, version.unwrap_or("latest")),
+        ));
+    };
+    let document: Document =
+        serde_json::from_slice(&bytes).map_err(|e| bad_gateway(format!("Invalid document from {}: {}", url, e)))?;
+    document
+        .validate(name, version)
+        .map_err(|e| bad_gateway(format!("Invalid document from {}: {}", url, e)))?;
+    if repository.keys.is_empty() {
+        return Ok((document, bytes, None));
+    }
+
+    // By the version served, so a new latest version can't slip in between.
+    let signature_url = format!("{}/{}/{}.sig", repository.url, name, document.version);
+    let signature = get(repository, &signature_url, config).await?.unwrap_or_default();
+    if !verify(&repository.keys, &bytes, &signature) {
+        return Err(bad_gateway(format!(
+            "Document {}@{} is not signed by a trusted key",
+            name, document.version
+        )));
+    }
+    Ok((document, bytes, Some(signature)))
+}
+
+/// How a document was found.
+struct Loaded {
+    document: Document,
+    sha256: String,
+    /// Run from the cache because the repository couldn't be reached.
+    offline: bool,
+}
+
+/// A pinned version from the cache, else the document fetched (and cached);
+/// the cached latest version when the repository can't be reached.
+async fn load(repository: &Repository, name: &str, version: Option<&str>, config: &AppConfig) -> Result<Loaded, (StatusCode, String)> {
+    if let Some((document, sha256)) = version.and_then(|version| load_cached(repository, name, Some(version))) {
+        return Ok(Loaded {
+            document,
+            sha256,
+            offline: false,
+        });
+    }
+    let (document, bytes, signature) = match fetch(repository, name, version, config).await {
+        Ok(fetched) => fetched,
+        Err((StatusCode::SERVICE_UNAVAILABLE, error_msg)) if repository.offline && version.is_none() => {
+            let Some((document, sha256)) = load_cached(repository, name, None) else {
+                return Err((StatusCode::SERVICE_UNAVAILABLE, error_msg));
+            };
+            log_error(
+                "/documents/run",
+                &format!("{}; running the cached {}@{}", error_msg, name, document.version),
+                None,
+            );
+            return Ok(Loaded {
+                document,
+                sha256,
+                offline: true,
+            });
+        }
+        Err(e) => return Err(e),
+    };
+    let sha256 = match save_cached(&document, &bytes, signature.as_deref(), version.is_none()) {
+        Ok(sha256) => sha256,
+        Err(e) => {
+            log_error("/documents/run", &format!("Failed to cache document {}@{}: {}", name, document.version, e), None);
+            sha256_hex(&bytes)
+        }
+    };
+    Ok(Loaded {
+        document,
+        sha256,
+        offline: false,
+    })
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DocumentRequest {
+    name: String,
+    /// Defaults to the repository's latest version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(default)]
+    parameters: serde_json::Map<String, Value>,
+}
+
+#[derive(Serialize, Default)]
+struct DocumentResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    /// SHA-256 of the document as served.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// Run from the cache because the repository couldn't be reached.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    offline: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Every problem with the request's parameters.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    parameter_errors: Vec<String>,
+}
+
+fn failure(status: StatusCode, error_msg: String) -> HttpResponse {
+    concurrency::response(status).json(DocumentResponse {
+        error: Some(error_msg),
+        ..Default::default()
+    })
+}
+
+/// POST /documents/run - fetch a document, fill in its parameters and run it
+/// as a playbook.
+pub async fn run_document(
+    http_req: HttpRequest,
+    req: web::Json<DocumentRequest>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let req = req.into_inner();
+    let Some(repository) = &config.documents else {
+        return Ok(failure(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Documents are not configured (AGENT_DOCUMENTS_URL)".to_string(),
+        ));
+    };
+    if !valid_id(&req.name) || req.version.as_deref().is_some_and(|version| !valid_id(version)) {
+        return Ok(failure(StatusCode::BAD_REQUEST, "Invalid document name or version".to_string()));
+    }
+
+    let Loaded {
+        document,
+        sha256,
+        offline,
+    } = match load(repository, &req.name, req.version.as_deref(), &config).await {
+        Ok(loaded) => loaded,
+        Err((status, error_msg)) => {
+            log_error("/documents/run", &error_msg, None);
+            return Ok(failure(status, error_msg));
+        }
+    };
+    let values = match document.resolve(&req.parameters) {
+        Ok(values) => values,
+        Err(errors) => {
+            return Ok(HttpResponse::BadRequest().json(DocumentResponse {
+                name: Some(document.name),
+                version: Some(document.version),
+                error: Some("Invalid parameters".to_string()),
+                parameter_errors: errors,
+                ..Default::default()
+            }))
+        }
+    };
+
+    let steps = document.substitute(&values);
+    // Policies see the commands as they will run.
+    let request = serde_json::json!({
+        "name": document.name,
+        "version": document.version,
+        "parameters": req.parameters,
+        "steps": steps,
+    });
+    let playbook = PlaybookRequest {
+        name: Some(format!("document: {}@{}", document.name, document.version)),
+        steps,
+    };
+    match playbook::start("/documents/run", &http_req, playbook, &request, &bus, &jobs, &config).await {
+        Ok(job_id) => Ok(HttpResponse::Ok().json(DocumentResponse {
+            success: true,
+            job_id: Some(job_id),
+            name: Some(document.name),
+            version: Some(document.version),
+            sha256: Some(sha256),
+            offline,
+            status: Some("running".to_string()),
+            ..Default::default()
+        })),
+        Err((status, error_msg)) => Ok(failure(status, error_msg)),
+    }
+}
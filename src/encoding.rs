@@ -0,0 +1,72 @@
+//! Base64-encoded command and environment values, for bytes a JSON string
+//! can't carry: legacy encodings, binary tokens.
+//!
+//! `command_base64` is the command as base64 instead of `command`, and
+//! `env_base64` sets environment variables like `env` does, from base64
+//! values. They are decoded when the request is evaluated and passed to the
+//! process as they are, through `OsString`s. Everything else (policy rules,
+//! logs, job records) sees the command with invalid UTF-8 replaced by U+FFFD.
+//! Windows takes command lines and environments as UTF-16, so there the
+//! decoded bytes must be UTF-8.
+
+use base64::Engine;
+use std::ffi::OsString;
+
+use crate::ExecuteRequest;
+
+fn decode(field: &str, value: &str) -> Result<OsString, String> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(value.trim())
+        .map_err(|e| format!("{} is not valid base64: {}", field, e))?;
+    if bytes.contains(&0) {
+        return Err(format!("{} must not contain NUL bytes", field));
+    }
+    os_string(bytes).map_err(|()| format!("{} must decode to UTF-8 on Windows", field))
+}
+
+#[cfg(unix)]
+fn os_string(bytes: Vec<u8>) -> Result<OsString, ()> {
+    Ok(std::os::unix::ffi::OsStringExt::from_vec(bytes))
+}
+
+#[cfg(windows)]
+fn os_string(bytes: Vec<u8>) -> Result<OsString, ()> {
+    String::from_utf8(bytes).map(OsString::from).map_err(|_| ())
+}
+
+/// Decode `req`'s base64 fields into its `command_os` and `env_os`, and set
+/// `command` to the decoded command's text.
+pub fn decode_request(req: &mut ExecuteRequest) -> Result<(), String> {
+    let uses_env = !req.env.is_empty() || !req.env_base64.is_empty();
+    if req.interactive_session && (req.command_base64.is_some() || uses_env) {
+        return Err("command_base64, env and env_base64 are not supported with interactive_session".to_string());
+    }
+    if let Some(encoded) = &req.command_base64 {
+        if !req.command.is_empty() {
+            return Err("Set command or command_base64, not both".to_string());
+        }
+        let command = decode("command_base64", encoded)?;
+        req.command = command.to_string_lossy().into_owned();
+        req.command_os = Some(command);
+    }
+    let mut env = Vec::new();
+    for (name, value) in &req.env {
+        env.push((name.clone(), OsString::from(value)));
+    }
+    for (name, value) in &req.env_base64 {
+        if req.env.contains_key(name) {
+            return Err(format!("{} is set in both env and env_base64", name));
+        }
+        env.push((name.clone(), decode(&format!("env_base64.{}", name), value)?));
+    }
+    for (name, value) in &env {
+        if name.is_empty() || name.contains(['=', '\0']) {
+            return Err(format!("Invalid environment variable name {:?}", name));
+        }
+        if value.to_string_lossy().contains('\0') {
+            return Err(format!("{} must not contain NUL bytes", name));
+        }
+    }
+    req.env_os = env;
+    Ok(())
+}
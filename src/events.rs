@@ -0,0 +1,141 @@
+//! Agent and job events, emitted as CloudEvents 1.0 envelopes.
+//!
+//! Every event produced by the agent goes through the [`EventBus`], so all
+//! sinks (the `/events` SSE stream today) see the same structured-mode
+//! envelope with consistent type names and the `host` / `jobid` extensions.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use futures_util::stream;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::clock;
+
+pub const SPEC_VERSION: &str = "1.0";
+pub const SOURCE: &str = "/machine-agent";
+
+pub const AGENT_STARTED: &str = "com.machineagent.agent.started";
+pub const AGENT_STOPPING: &str = "com.machineagent.agent.stopping";
+pub const JOB_QUEUED: &str = "com.machineagent.job.queued";
+pub const JOB_STARTED: &str = "com.machineagent.job.started";
+pub const JOB_PROGRESS: &str = "com.machineagent.job.progress";
+pub const JOB_FINISHED: &str = "com.machineagent.job.finished";
+pub const JOB_FAILED: &str = "com.machineagent.job.failed";
+pub const JOB_SKIPPED: &str = "com.machineagent.job.skipped";
+pub const WATCHDOG_MISSED: &str = "com.machineagent.watchdog.missed";
+pub const WATCHDOG_RECOVERED: &str = "com.machineagent.watchdog.recovered";
+
+const BUS_CAPACITY: usize = 256;
+
+#[derive(Clone, Serialize)]
+pub struct CloudEvent {
+    pub specversion: &'static str,
+    pub id: String,
+    pub source: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub time: String,
+    pub datacontenttype: &'static str,
+    /// Extension: hostname of the agent that produced the event.
+    pub host: String,
+    /// Extension: job the event refers to. CloudEvents extension names must be
+    /// lowercase alphanumerics, hence `jobid` rather than `job_id`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jobid: Option<String>,
+    pub data: serde_json::Value,
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<CloudEvent>,
+    host: String,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BUS_CAPACITY);
+        EventBus {
+            sender,
+            host: hostname(),
+        }
+    }
+
+    pub fn publish(&self, event_type: &str, job_id: Option<&str>, data: serde_json::Value) {
+        let event = CloudEvent {
+            specversion: SPEC_VERSION,
+            id: uuid::Uuid::new_v4().to_string(),
+            source: SOURCE.to_string(),
+            event_type: event_type.to_string(),
+            time: clock::now(),
+            datacontenttype: "application/json",
+            host: self.host.clone(),
+            jobid: job_id.map(str::to_string),
+            data,
+        };
+        // No subscribers is not an error; the event is simply dropped.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CloudEvent> {
+        self.sender.subscribe()
+    }
+}
+
+pub fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|s| s.trim().to_string())
+        })
+        .filter(|s| !s.is_empty())
+        // FreeBSD and macOS have no /etc/hostname.
+        .or_else(system_hostname)
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(unix)]
+fn system_hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is valid for its length; the name is NUL-terminated
+    // when it fits.
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return None;
+    }
+    let end = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..end]).into_owned()).filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+fn system_hostname() -> Option<String> {
+    None
+}
+
+fn sse_frame(event: &CloudEvent) -> web::Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    web::Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.id, event.event_type, data
+    ))
+}
+
+/// GET /events - stream all agent events as Server-Sent Events.
+pub async fn stream_events(bus: web::Data<EventBus>) -> ActixResult<HttpResponse> {
+    let rx = bus.subscribe();
+    let body = stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((Ok::<_, actix_web::Error>(sse_frame(&event)), rx)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body))
+}
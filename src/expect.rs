@@ -0,0 +1,124 @@
+//! Expect-style prompt/response automation.
+//!
+//! Each rule pairs a regex with the text to type when it matches the job's
+//! output, so tools that insist on interactive confirmation (installer EULAs,
+//! "Are you sure? [y/N]") can run unattended. Output is read from the job's
+//! pipes, so prompts written straight to a terminal device (e.g. ssh password
+//! prompts on /dev/tty) are not seen.
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// Output kept for matching prompts that span several reads.
+const MAX_BUFFER: usize = 8 * 1024;
+
+#[derive(Deserialize, Clone)]
+pub struct ExpectRule {
+    pub pattern: String,
+    /// Text written to stdin on a match; include `\n` to press Enter.
+    pub response: String,
+    /// Keep answering every time the pattern appears (default: answer once).
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+struct CompiledRule {
+    regex: Regex,
+    response: String,
+    repeat: bool,
+    fired: bool,
+}
+
+pub struct Expecter {
+    rules: Vec<CompiledRule>,
+    buffer: String,
+}
+
+impl Expecter {
+    pub fn new(rules: &[ExpectRule]) -> Result<Self, String> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.pattern)
+                    .map_err(|e| format!("Invalid expect pattern {:?}: {}", rule.pattern, e))?;
+                if regex.is_match("") {
+                    return Err(format!("Expect pattern {:?} must not match empty output", rule.pattern));
+                }
+                Ok(CompiledRule {
+                    regex,
+                    response: rule.response.clone(),
+                    repeat: rule.repeat,
+                    fired: false,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Expecter {
+            rules,
+            buffer: String::new(),
+        })
+    }
+
+    /// Add output and return the responses to send, in match order.
+    pub fn feed(&mut self, text: &str) -> Vec<String> {
+        self.buffer.push_str(text);
+        let mut responses = Vec::new();
+
+        loop {
+            let earliest = self
+                .rules
+                .iter()
+                .enumerate()
+                .filter(|(_, rule)| rule.repeat || !rule.fired)
+                // A zero-width match (`\b`, `^`) consumes nothing, so answering
+                // it would loop forever.
+                .filter_map(|(i, rule)| {
+                    let found = rule.regex.find_iter(&self.buffer).find(|m| !m.is_empty());
+                    found.map(|m| (m.start(), m.end(), i))
+                })
+                .min();
+            let Some((_, end, index)) = earliest else {
+                break;
+            };
+            let rule = &mut self.rules[index];
+            rule.fired = true;
+            responses.push(rule.response.clone());
+            // Consume through the match so the same prompt isn't answered twice.
+            self.buffer.drain(..end);
+        }
+
+        if self.buffer.len() > MAX_BUFFER {
+            let mut cut = self.buffer.len() - MAX_BUFFER;
+            while !self.buffer.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.buffer.drain(..cut);
+        }
+        responses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, response: &str, repeat: bool) -> ExpectRule {
+        ExpectRule {
+            pattern: pattern.to_string(),
+            response: response.to_string(),
+            repeat,
+        }
+    }
+
+    #[test]
+    fn zero_width_matches_are_not_answered() {
+        let mut expecter = Expecter::new(&[rule(r"\b", "y\n", true)]).unwrap();
+        assert!(expecter.feed("Are you sure? [y/N]").is_empty());
+    }
+
+    #[test]
+    fn repeating_rules_answer_each_prompt() {
+        let mut expecter = Expecter::new(&[rule(r"\[y/N\]", "y\n", true)]).unwrap();
+        assert_eq!(expecter.feed("Continue? [y/N] Really? [y/N]"), vec!["y\n", "y\n"]);
+        assert_eq!(expecter.feed("Again? [y/N]"), vec!["y\n"]);
+    }
+}
@@ -0,0 +1,471 @@
+//! Downloads of a URL to a file on the agent (`fetch` feature), split into
+//! byte ranges fetched over several connections at once, so large artifacts
+//! pulled from distant repositories aren't held back by one connection's
+//! window.
+//!
+//! `POST /fetch` names the `url` and the absolute `dest`. When the server
+//! answers range requests, the file is fetched in `chunk_size` ranges by up
+//! to `connections` connections, and a range that fails (connection error,
+//! stalled or short body, 5xx, or a hash that doesn't match its entry in
+//! `chunk_sha256`) is fetched again on its own, up to `retries` times.
+//! Other servers get one plain GET, retried as a whole. Ranges carry
+//! `If-Range`, so a file that changes on the server mid-download fails the
+//! download instead of mixing versions. The file is checked against
+//! `sha256`, if given, and moved into place, so a failed download never
+//! leaves a partial file.
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result as ActixResult};
+use futures_util::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::bandwidth::Transfer;
+use crate::config::AppConfig;
+use crate::{content, log_error, sync};
+
+const DEFAULT_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+const MIN_CHUNK_SIZE: u64 = 64 * 1024;
+const DEFAULT_CONNECTIONS: usize = 4;
+const MAX_CONNECTIONS: usize = 16;
+const DEFAULT_RETRIES: u32 = 3;
+/// A connection that sends nothing for this long is given up and retried.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub struct FetchRequest {
+    url: String,
+    /// Absolute path of the file to write.
+    dest: String,
+    #[serde(default)]
+    sha256: Option<String>,
+    /// Bytes per range (default 8 MiB).
+    #[serde(default)]
+    chunk_size: Option<u64>,
+    /// SHA-256 of each `chunk_size` range, in order.
+    #[serde(default)]
+    chunk_sha256: Vec<String>,
+    /// Ranges fetched at once (default 4, at most 16).
+    #[serde(default)]
+    connections: Option<usize>,
+    /// Attempts after the first for each range (default 3).
+    #[serde(default)]
+    retries: Option<u32>,
+    /// Sent with every request, e.g. `Authorization` for the repository.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Octal permissions, e.g. `755` (Unix).
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct FetchResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// Ranges the file was fetched in; absent when the server doesn't
+    /// answer range requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunks: Option<usize>,
+    /// Attempts that failed and were retried.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retried: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn failure(status: StatusCode, error_msg: String) -> HttpResponse {
+    HttpResponse::build(status).json(FetchResponse {
+        error: Some(error_msg),
+        ..Default::default()
+    })
+}
+
+/// Why an attempt failed, and whether trying again may help.
+enum AttemptError {
+    Retry(String),
+    Fatal(String),
+}
+
+/// Where the file comes from, once redirects are followed.
+struct Source {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    headers: HeaderMap,
+    /// ETag or Last-Modified for `If-Range`.
+    validator: Option<HeaderValue>,
+}
+
+impl Source {
+    fn get(&self, range: Option<(u64, u64)>) -> reqwest::RequestBuilder {
+        let mut request = self.client.get(self.url.clone()).headers(self.headers.clone());
+        if let Some((start, end)) = range {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-{}", start, end));
+            if let Some(validator) = &self.validator {
+                request = request.header(reqwest::header::IF_RANGE, validator.clone());
+            }
+        }
+        request
+    }
+}
+
+fn header_map(headers: &BTreeMap<String, String>) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("Invalid header name {:?}", name))?;
+        let value = HeaderValue::from_str(value).map_err(|_| format!("Invalid value for header {}", name))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+fn send_error(url: &reqwest::Url, e: &reqwest::Error) -> AttemptError {
+    AttemptError::Retry(format!("Failed to fetch {}: {}", url, crate::outbound::describe(e)))
+}
+
+fn status_error(url: &reqwest::Url, status: reqwest::StatusCode) -> AttemptError {
+    let error_msg = format!("Failed to fetch {}: {}", url, status);
+    if status.is_server_error() || matches!(status.as_u16(), 408 | 429) {
+        AttemptError::Retry(error_msg)
+    } else {
+        AttemptError::Fatal(error_msg)
+    }
+}
+
+/// The total size from a `Content-Range: bytes 0-0/<size>` header.
+fn total_size(response: &reqwest::Response) -> Option<u64> {
+    let range = response.headers().get(reqwest::header::CONTENT_RANGE)?.to_str().ok()?;
+    range.strip_prefix("bytes ")?.split_once('/')?.1.trim().parse().ok()
+}
+
+/// Write `response`'s body to `file` from `start`; returns its length and
+/// SHA-256.
+async fn write_body(file: &mut tokio::fs::File, start: u64, response: reqwest::Response, transfer: &Transfer) -> Result<(u64, String), AttemptError> {
+    let local = |e: io::Error| AttemptError::Fatal(e.to_string());
+    file.seek(io::SeekFrom::Start(start)).await.map_err(local)?;
+    let url = response.url().clone();
+    let mut body = std::pin::pin!(transfer.throttle(response.bytes_stream()));
+    let mut hasher = Sha256::new();
+    let mut written = 0;
+    while let Some(bytes) = body.next().await {
+        let bytes = bytes.map_err(|e| send_error(&url, &e))?;
+        hasher.update(&bytes);
+        file.write_all(&bytes).await.map_err(local)?;
+        written += bytes.len() as u64;
+    }
+    file.flush().await.map_err(local)?;
+    Ok((written, content::hex(&hasher.finalize())))
+}
+
+/// Fetch bytes `start..=end` into `temp` once.
+async fn fetch_range(source: &Source, temp: &Path, (start, end): (u64, u64), expected: Option<&str>, transfer: &Transfer) -> Result<(), AttemptError> {
+    let response = source.get(Some((start, end))).send().await.map_err(|e| send_error(&source.url, &e))?;
+    match response.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => {}
+        // `If-Range` no longer matches.
+        reqwest::StatusCode::OK => return Err(AttemptError::Fatal(format!("{} changed during the download", source.url))),
+        status => return Err(status_error(&source.url, status)),
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(temp)
+        .await
+        .map_err(|e| AttemptError::Fatal(e.to_string()))?;
+    let (written, sha256) = write_body(&mut file, start, response, transfer).await?;
+    if written != end - start + 1 {
+        return Err(AttemptError::Retry(format!("Range {}-{} ended after {} bytes", start, end, written)));
+    }
+    if let Some(expected) = expected.filter(|expected| !sha256.eq_ignore_ascii_case(expected)) {
+        return Err(AttemptError::Retry(format!("Range {}-{}: checksum mismatch: expected {}, got {}", start, end, expected, sha256)));
+    }
+    Ok(())
+}
+
+/// Wait before attempt `attempt` (from 1): 0.5 s, 1 s, 2 s, ...
+async fn backoff(attempt: u32) {
+    tokio::time::sleep(Duration::from_millis(500 << (attempt - 1).min(6))).await;
+}
+
+/// Fetch every range into `temp`, `connections` at a time, each retried on
+/// its own.
+async fn fetch_ranges(source: &Source, temp: &Path, ranges: &[(u64, u64)], req: &FetchRequest, transfer: &Transfer, retried: &AtomicU32) -> Result<(), String> {
+    let next = AtomicUsize::new(0);
+    let retries = req.retries.unwrap_or(DEFAULT_RETRIES);
+    let worker = || async {
+        loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let Some(&range) = ranges.get(index) else {
+                return Ok(());
+            };
+            let expected = req.chunk_sha256.get(index).map(String::as_str);
+            let mut attempt = 0;
+            loop {
+                match fetch_range(source, temp, range, expected, transfer).await {
+                    Ok(()) => break,
+                    Err(AttemptError::Retry(e)) if attempt < retries => {
+                        attempt += 1;
+                        retried.fetch_add(1, Ordering::Relaxed);
+                        log_error("/fetch", &format!("{}; retrying ({}/{})", e, attempt, retries), None);
+                        backoff(attempt).await;
+                    }
+                    Err(AttemptError::Retry(e) | AttemptError::Fatal(e)) => return Err::<(), String>(e),
+                }
+            }
+        }
+    };
+    let connections = req.connections.unwrap_or(DEFAULT_CONNECTIONS).clamp(1, MAX_CONNECTIONS).min(ranges.len());
+    futures_util::future::try_join_all((0..connections).map(|_| worker())).await?;
+    Ok(())
+}
+
+/// Write `response`, the answer to a plain GET, into `temp`, fetching the
+/// whole file again when that fails.
+async fn fetch_whole(source: &Source, temp: &Path, response: reqwest::Response, req: &FetchRequest, transfer: &Transfer, retried: &AtomicU32) -> Result<u64, String> {
+    let retries = req.retries.unwrap_or(DEFAULT_RETRIES);
+    let mut response = Some(response);
+    let mut attempt = 0;
+    loop {
+        let result = async {
+            let response = match response.take() {
+                Some(response) => response,
+                None => {
+                    let response = source.get(None).send().await.map_err(|e| send_error(&source.url, &e))?;
+                    if !response.status().is_success() {
+                        return Err(status_error(&source.url, response.status()));
+                    }
+                    response
+                }
+            };
+            let expected = response.content_length();
+            let mut file = tokio::fs::File::create(temp).await.map_err(|e| AttemptError::Fatal(e.to_string()))?;
+            let (written, _) = write_body(&mut file, 0, response, transfer).await?;
+            match expected {
+                Some(expected) if expected != written => Err(AttemptError::Retry(format!("The body ended after {} of {} bytes", written, expected))),
+                _ => Ok(written),
+            }
+        }
+        .await;
+        match result {
+            Ok(written) => return Ok(written),
+            Err(AttemptError::Retry(e)) if attempt < retries => {
+                attempt += 1;
+                retried.fetch_add(1, Ordering::Relaxed);
+                log_error("/fetch", &format!("{}; retrying ({}/{})", e, attempt, retries), None);
+                backoff(attempt).await;
+            }
+            Err(AttemptError::Retry(e) | AttemptError::Fatal(e)) => return Err(e),
+        }
+    }
+}
+
+/// The SHA-256 of each `chunk_size` piece of the file at `path`.
+fn chunk_hashes(path: &Path, chunk_size: u64) -> io::Result<Vec<String>> {
+    use std::io::Read;
+    let mut file = std::fs::File::open(path)?;
+    let mut hashes = Vec::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let mut hasher = Sha256::new();
+        let mut remaining = chunk_size;
+        while remaining > 0 {
+            match file.read(&mut buffer[..remaining.min(64 * 1024) as usize])? {
+                0 => break,
+                read => {
+                    hasher.update(&buffer[..read]);
+                    remaining -= read as u64;
+                }
+            }
+        }
+        if remaining == chunk_size {
+            return Ok(hashes);
+        }
+        hashes.push(content::hex(&hasher.finalize()));
+        if remaining > 0 {
+            return Ok(hashes);
+        }
+    }
+}
+
+/// Download into `temp`; returns the size and the number of ranges.
+async fn download(req: &FetchRequest, temp: &Path, config: &AppConfig, retried: &AtomicU32) -> Result<(u64, Option<usize>), (StatusCode, String)> {
+    let bad_gateway = |e: String| (StatusCode::BAD_GATEWAY, e);
+    let headers = header_map(&req.headers).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let chunk_size = req.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(MIN_CHUNK_SIZE);
+    let client = config
+        .outbound
+        .client()
+        .and_then(|builder| builder.read_timeout(READ_TIMEOUT).build().map_err(|e| e.to_string()))
+        .map_err(bad_gateway)?;
+    let url = reqwest::Url::parse(&req.url).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid URL {}: {}", req.url, e)))?;
+    let mut source = Source {
+        client,
+        url,
+        headers,
+        validator: None,
+    };
+    let transfer = config.bandwidth.transfer();
+
+    // One byte tells whether the server answers ranges and how large the
+    // file is; a server that doesn't sends the whole file instead.
+    let mut attempt = 0;
+    let probe = loop {
+        let result = match source.get(Some((0, 0))).send().await {
+            Ok(response) if response.status().is_success() || response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE => Ok(response),
+            Ok(response) => Err(status_error(&source.url, response.status())),
+            Err(e) => Err(send_error(&source.url, &e)),
+        };
+        match result {
+            Ok(response) => break response,
+            Err(AttemptError::Retry(e)) if attempt < req.retries.unwrap_or(DEFAULT_RETRIES) => {
+                attempt += 1;
+                retried.fetch_add(1, Ordering::Relaxed);
+                log_error("/fetch", &format!("{}; retrying ({}/{})", e, attempt, req.retries.unwrap_or(DEFAULT_RETRIES)), None);
+                backoff(attempt).await;
+            }
+            Err(AttemptError::Retry(e) | AttemptError::Fatal(e)) => return Err(bad_gateway(e)),
+        }
+    };
+    source.url = probe.url().clone();
+    let size = (probe.status() == reqwest::StatusCode::PARTIAL_CONTENT).then(|| total_size(&probe)).flatten();
+    let Some(size) = size else {
+        let response = match probe.status() {
+            reqwest::StatusCode::OK => probe,
+            // Empty files can't satisfy a range; ask for the whole file.
+            _ => source.get(None).send().await.map_err(|e| bad_gateway(format!("Failed to fetch {}: {}", source.url, crate::outbound::describe(&e))))?,
+        };
+        if !response.status().is_success() {
+            return Err(bad_gateway(format!("Failed to fetch {}: {}", source.url, response.status())));
+        }
+        let size = fetch_whole(&source, temp, response, req, &transfer, retried).await.map_err(bad_gateway)?;
+        if !req.chunk_sha256.is_empty() {
+            let path = temp.to_path_buf();
+            let hashes = web::block(move || chunk_hashes(&path, chunk_size))
+                .await
+                .map_err(io::Error::other)
+                .and_then(|r| r)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read {}: {}", temp.display(), e)))?;
+            let matches = hashes.len() == req.chunk_sha256.len() && hashes.iter().zip(&req.chunk_sha256).all(|(a, b)| a.eq_ignore_ascii_case(b));
+            if !matches {
+                return Err(bad_gateway("The downloaded file doesn't match chunk_sha256".to_string()));
+            }
+        }
+        return Ok((size, None));
+    };
+
+    let count = size.div_ceil(chunk_size);
+    if !req.chunk_sha256.is_empty() && req.chunk_sha256.len() as u64 != count {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("chunk_sha256 has {} entries, but the file has {} chunks of {} bytes", req.chunk_sha256.len(), count, chunk_size),
+        ));
+    }
+    let headers = probe.headers();
+    source.validator = headers
+        .get(reqwest::header::ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(reqwest::header::LAST_MODIFIED))
+        .cloned();
+    drop(probe);
+
+    let file = std::fs::File::create(temp).and_then(|file| file.set_len(size));
+    file.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create {}: {}", temp.display(), e)))?;
+    let ranges: Vec<(u64, u64)> = (0..count).map(|index| (index * chunk_size, ((index + 1) * chunk_size).min(size) - 1)).collect();
+    fetch_ranges(&source, temp, &ranges, req, &transfer, retried).await.map_err(bad_gateway)?;
+    Ok((size, Some(ranges.len())))
+}
+
+/// POST /fetch - download a URL to a file, in parallel ranges when the
+/// server allows.
+pub async fn fetch(http_req: HttpRequest, req: web::Json<FetchRequest>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let started = Instant::now();
+    let req = req.into_inner();
+    // Without `headers`, which may carry credentials.
+    let request = serde_json::json!({"url": req.url, "dest": req.dest, "sha256": req.sha256});
+    let run_as = match sync::authorize("/fetch", &http_req, &request, &config).await {
+        Ok(run_as) => run_as,
+        Err((status, error_msg)) => {
+            log_error("/fetch", &error_msg, None);
+            return Ok(failure(status, error_msg));
+        }
+    };
+    let checked = sync::dest_dir(&req.dest).and_then(|dest| {
+        let mode = req.mode.as_deref().map(sync::parse_mode).transpose()?;
+        Ok((dest, mode))
+    });
+    let (dest, mode) = match checked {
+        Ok(checked) => checked,
+        Err(e) => return Ok(failure(StatusCode::BAD_REQUEST, e)),
+    };
+    if let Err(e) = config.outbound.check(&req.url) {
+        return Ok(failure(StatusCode::FORBIDDEN, e));
+    }
+    if let Err(error_msg) = config.shares.connect(&dest).await {
+        log_error("/fetch", &error_msg, None);
+        return Ok(failure(StatusCode::BAD_GATEWAY, error_msg));
+    }
+    if let Some(parent) = dest.parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            let error_msg = format!("Failed to create {}: {}", parent.display(), e);
+            log_error("/fetch", &error_msg, None);
+            return Ok(failure(StatusCode::INTERNAL_SERVER_ERROR, error_msg));
+        }
+    }
+
+    let mut temp = dest.as_os_str().to_owned();
+    temp.push(".fetch-tmp");
+    let temp = PathBuf::from(temp);
+    let retried = AtomicU32::new(0);
+    let result = async {
+        let (size, chunks) = download(&req, &temp, &config, &retried).await?;
+        let path = temp.clone();
+        let sha256 = web::block(move || content::sha256_file(&path))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|r| r)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read {}: {}", temp.display(), e)))?;
+        if let Some(expected) = req.sha256.as_ref().filter(|expected| !sha256.eq_ignore_ascii_case(expected)) {
+            return Err((StatusCode::BAD_GATEWAY, format!("Checksum mismatch: expected {}, got {}", expected, sha256)));
+        }
+        let local = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+        if let Some(mode) = mode {
+            sync::set_mode(&temp, mode).map_err(|e| local(format!("Failed to set mode: {}", e)))?;
+        }
+        if let Some(run_as) = &run_as {
+            run_as.chown(&temp).map_err(|e| local(format!("Failed to hand the file to {}: {}", run_as.user, e)))?;
+        }
+        std::fs::rename(&temp, &dest).map_err(|e| local(format!("Failed to replace {}: {}", dest.display(), e)))?;
+        Ok((size, chunks, sha256))
+    }
+    .await;
+
+    match result {
+        Ok((size, chunks, sha256)) => Ok(HttpResponse::Ok().json(FetchResponse {
+            success: true,
+            dest: Some(req.dest),
+            size: Some(size),
+            sha256: Some(sha256),
+            chunks,
+            retried: Some(retried.into_inner()),
+            duration_ms: Some(started.elapsed().as_millis()),
+            error: None,
+        })),
+        Err((status, error_msg)) => {
+            let _ = std::fs::remove_file(&temp);
+            log_error("/fetch", &error_msg, None);
+            Ok(failure(status, error_msg))
+        }
+    }
+}
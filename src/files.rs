@@ -0,0 +1,431 @@
+//! `GET /files/download?path=...` - stream a file from the host back, so
+//! build artifacts and logs a command left behind can be collected without
+//! running `cat` through `/execute`. `GET /files/list` and `GET /files/stat`
+//! describe directories and single paths, so callers can check what a
+//! command left behind without parsing `ls` or `dir` output.
+//!
+//! Only paths under the directories in `AGENT_DOWNLOAD_ROOTS` are served;
+//! without it the endpoints are off. Paths are resolved (symlinks included)
+//! before they are checked, so a link can't lead out of a root. Downloads
+//! carry `Content-Length`, answer single `Range` requests (honouring
+//! `If-Range`) and, with `gzip=true`, are gzip-compressed on the fly.
+
+use actix_web::http::header::{self, HttpDate};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::config::AppConfig;
+use crate::listing::{self, ListQuery, ListSpec};
+use crate::{auth, clock, log_error, policy};
+
+/// Size of the pieces files are read and sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// `AGENT_DOWNLOAD_ROOTS`: comma-separated directories downloads may come
+/// from, resolved; none when unset.
+pub fn roots_from_env() -> Result<Vec<PathBuf>, String> {
+    let Ok(roots) = std::env::var("AGENT_DOWNLOAD_ROOTS") else {
+        return Ok(Vec::new());
+    };
+    let mut resolved = Vec::new();
+    for root in roots.split(',').map(str::trim).filter(|root| !root.is_empty()) {
+        let path = Path::new(root);
+        if !path.is_absolute() {
+            return Err(format!("AGENT_DOWNLOAD_ROOTS must list absolute paths, not {}", root));
+        }
+        let canonical = std::fs::canonicalize(path).map_err(|e| format!("Invalid download root {}: {}", root, e))?;
+        if !canonical.is_dir() {
+            return Err(format!("Invalid download root {}: not a directory", root));
+        }
+        resolved.push(canonical);
+    }
+    Ok(resolved)
+}
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    path: String,
+    /// Compress the response with gzip; `Range` is ignored then.
+    #[serde(default)]
+    gzip: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PathQuery {
+    path: String,
+}
+
+/// What `path` names, when it is under one of `roots`. With `follow` false
+/// a symlink at the end of `path` is itself the answer, not its target.
+fn resolve(path: &str, roots: &[PathBuf], follow: bool) -> Result<PathBuf, (StatusCode, String)> {
+    if roots.is_empty() {
+        return Err((StatusCode::FORBIDDEN, "File access is off (set AGENT_DOWNLOAD_ROOTS)".to_string()));
+    }
+    let requested = Path::new(path);
+    if !requested.is_absolute() {
+        return Err((StatusCode::BAD_REQUEST, format!("path must be an absolute path, not {}", path)));
+    }
+    let outside = || (StatusCode::FORBIDDEN, format!("{} is not under a download root", path));
+    // Whether files outside the roots exist is none of the caller's business.
+    let inside = !requested.components().any(|component| component == Component::ParentDir)
+        && roots.iter().any(|root| requested.starts_with(root));
+    let resolved = match (follow, requested.parent(), requested.file_name()) {
+        (false, Some(parent), Some(name)) => std::fs::canonicalize(parent).and_then(|parent| {
+            let link = parent.join(name);
+            std::fs::symlink_metadata(&link).map(|_| link)
+        }),
+        _ => std::fs::canonicalize(requested),
+    };
+    let canonical = match resolved {
+        Ok(canonical) => canonical,
+        Err(_) if !inside => return Err(outside()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err((StatusCode::NOT_FOUND, format!("No such file: {}", path))),
+        Err(e) => return Err((StatusCode::FORBIDDEN, format!("Cannot open {}: {}", path, e))),
+    };
+    if !roots.iter().any(|root| canonical.starts_with(root)) {
+        return Err(outside());
+    }
+    Ok(canonical)
+}
+
+/// A file, directory or link, as `/files/list` and `/files/stat` describe it.
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    /// `file`, `dir`, `symlink` or `other` (devices, sockets, pipes).
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// In bytes; of the link itself for a symlink.
+    size: u64,
+    mtime: Option<String>,
+    /// Octal permission bits, e.g. `755`. Unix only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    permissions: Option<String>,
+    readonly: bool,
+    /// Where a symlink points, as written.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+}
+
+impl Entry {
+    /// `path`, from metadata that doesn't follow a final symlink.
+    fn new(path: &Path, metadata: &std::fs::Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_symlink() {
+            "symlink"
+        } else if file_type.is_dir() {
+            "dir"
+        } else if file_type.is_file() {
+            "file"
+        } else {
+            "other"
+        };
+        Entry {
+            name: path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| path.display().to_string()),
+            kind,
+            size: metadata.len(),
+            mtime: metadata.modified().ok().map(|modified| clock::format(&chrono::DateTime::<chrono::Utc>::from(modified))),
+            permissions: mode_of(metadata).map(|mode| format!("{:o}", mode)),
+            readonly: metadata.permissions().readonly(),
+            target: file_type
+                .is_symlink()
+                .then(|| std::fs::read_link(path).ok())
+                .flatten()
+                .map(|target| target.display().to_string()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+const ENTRY_LIST: ListSpec = ListSpec {
+    key: "name",
+    sort_keys: &["name", "size", "mtime", "type"],
+    default_sort: "name",
+};
+
+#[derive(Serialize)]
+struct ListResponse {
+    success: bool,
+    path: String,
+    entries: Vec<serde_json::Value>,
+    total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+/// What a `Range` header asks of a file of `size` bytes.
+enum Range {
+    /// No header, one this doesn't parse, or several ranges: the whole file.
+    Whole,
+    /// First and last byte, inclusive.
+    Part(u64, u64),
+    /// Starts past the end of the file.
+    Unsatisfiable,
+}
+
+fn parse_range(header: &str, size: u64) -> Range {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Range::Whole;
+    };
+    let Some((first, last)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return Range::Whole;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // The last `last` bytes.
+        return match last.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if size == 0 => Range::Unsatisfiable,
+            Ok(suffix) => Range::Part(size.saturating_sub(suffix), size - 1),
+            Err(_) => Range::Whole,
+        };
+    }
+    let Ok(first) = first.parse::<u64>() else {
+        return Range::Whole;
+    };
+    let last = match last {
+        "" => u64::MAX,
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= first => last,
+            _ => return Range::Whole,
+        },
+    };
+    if first >= size {
+        return Range::Unsatisfiable;
+    }
+    Range::Part(first, last.min(size - 1))
+}
+
+/// `length` bytes of `file` from where it is, in chunks.
+fn read_chunks(file: tokio::fs::File, length: u64) -> impl Stream<Item = io::Result<Bytes>> {
+    stream::unfold(Some(file.take(length)), |file| async move {
+        let mut file = file?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok(Bytes::from(buffer)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+/// `chunks` gzip-compressed.
+fn gzip<S>(chunks: S) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    stream::unfold(Some((chunks, encoder)), |state| async move {
+        let (mut chunks, mut encoder) = state?;
+        loop {
+            match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    if let Err(e) = encoder.write_all(&chunk) {
+                        return Some((Err(e), None));
+                    }
+                    let compressed = std::mem::take(encoder.get_mut());
+                    if !compressed.is_empty() {
+                        return Some((Ok(Bytes::from(compressed)), Some((chunks, encoder))));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => return Some((encoder.finish().map(Bytes::from), None)),
+            }
+        }
+    })
+}
+
+fn error_response(status: StatusCode, error: String) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({
+        "success": false,
+        "error": error,
+    }))
+}
+
+/// Authenticate the request, put it to OPA and resolve `path`: what the
+/// three endpoints have in common.
+async fn authorize(
+    endpoint: &str,
+    http_req: &HttpRequest,
+    config: &AppConfig,
+    path: &str,
+    follow: bool,
+    request: serde_json::Value,
+) -> Result<PathBuf, HttpResponse> {
+    let caller = match auth::authenticate(config, http_req).await {
+        Ok(caller) => caller,
+        Err(error_msg) => {
+            log_error(endpoint, &error_msg, None);
+            return Err(error_response(StatusCode::UNAUTHORIZED, error_msg));
+        }
+    };
+    if let Some(opa) = &config.opa {
+        if let Err((status, error_msg)) = policy::consult_opa(opa, endpoint, http_req, caller.as_ref().map(|caller| caller.name.as_str()), &request).await {
+            log_error(endpoint, &error_msg, None);
+            return Err(error_response(status, error_msg));
+        }
+    }
+    resolve(path, &config.download_roots, follow).map_err(|(status, error_msg)| {
+        if status == StatusCode::FORBIDDEN {
+            log_error(endpoint, &error_msg, None);
+        }
+        error_response(status, error_msg)
+    })
+}
+
+/// GET /files/list?path=... - the entries of a directory under one of the
+/// download roots, paginated.
+pub async fn list(http_req: HttpRequest, query: web::Query<PathQuery>, list: web::Query<ListQuery>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let query = query.into_inner();
+    let request = serde_json::json!({"path": query.path});
+    let dir = match authorize("/files/list", &http_req, &config, &query.path, true, request).await {
+        Ok(dir) => dir,
+        Err(response) => return Ok(response),
+    };
+    if !dir.is_dir() {
+        return Ok(error_response(StatusCode::BAD_REQUEST, format!("{} is not a directory", query.path)));
+    }
+    let read = web::block(move || -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            // Gone since it was listed.
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            entries.push(Entry::new(&entry.path(), &metadata));
+        }
+        Ok(entries)
+    })
+    .await;
+    let entries = match read {
+        Ok(Ok(entries)) => entries,
+        Ok(Err(e)) => return Ok(error_response(StatusCode::FORBIDDEN, format!("Cannot list {}: {}", query.path, e))),
+        Err(e) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    match listing::paginate(&entries, &list, &ENTRY_LIST) {
+        Ok(page) => Ok(HttpResponse::Ok().json(ListResponse {
+            success: true,
+            path: query.path,
+            entries: page.items,
+            total: page.total,
+            next_cursor: page.next_cursor,
+        })),
+        Err(e) => Ok(error_response(StatusCode::BAD_REQUEST, e)),
+    }
+}
+
+/// GET /files/stat?path=... - one path under one of the download roots; a
+/// symlink is described itself, not what it points to.
+pub async fn stat(http_req: HttpRequest, query: web::Query<PathQuery>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let query = query.into_inner();
+    let request = serde_json::json!({"path": query.path});
+    let path = match authorize("/files/stat", &http_req, &config, &query.path, false, request).await {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    match std::fs::symlink_metadata(&path) {
+        Ok(metadata) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "path": query.path,
+            "entry": Entry::new(&path, &metadata),
+        }))),
+        Err(e) => Ok(error_response(StatusCode::FORBIDDEN, format!("Cannot stat {}: {}", query.path, e))),
+    }
+}
+
+/// GET /files/download?path=... - a file under one of the download roots.
+pub async fn download(http_req: HttpRequest, query: web::Query<DownloadQuery>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let query = query.into_inner();
+    let request = serde_json::json!({"path": query.path, "gzip": query.gzip});
+    let path = match authorize("/files/download", &http_req, &config, &query.path, true, request).await {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    if !path.is_file() {
+        return Ok(error_response(StatusCode::BAD_REQUEST, format!("{} is not a file", query.path)));
+    }
+    let opened = match tokio::fs::File::open(&path).await {
+        Ok(file) => file.metadata().await.map(|metadata| (file, metadata)),
+        Err(e) => Err(e),
+    };
+    let (mut file, metadata) = match opened {
+        Ok(opened) => opened,
+        Err(e) => return Ok(error_response(StatusCode::FORBIDDEN, format!("Cannot open {}: {}", query.path, e))),
+    };
+
+    let size = metadata.len();
+    let modified = metadata.modified().ok();
+    let mtime = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}.{:x}\"", size, mtime.as_secs(), mtime.subsec_nanos());
+    let last_modified = modified.map(|modified| HttpDate::from(modified).to_string());
+    let name = path.file_name().map(|name| name.to_string_lossy().replace(['"', '\\'], "_")).unwrap_or_default();
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("application/octet-stream")
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header((header::ETAG, etag.clone()));
+    if let Some(last_modified) = &last_modified {
+        response.insert_header((header::LAST_MODIFIED, last_modified.clone()));
+    }
+    tracing::info!(path = %path.display(), gzip = query.gzip, "File download");
+    if query.gzip {
+        response.insert_header((header::CONTENT_ENCODING, "gzip"));
+        let body = gzip(Box::pin(read_chunks(file, size)));
+        return Ok(response.streaming(config.bandwidth.throttle(body)));
+    }
+
+    // A range of a file that changed since the client's copy would mix versions.
+    let unchanged = match http_req.headers().get(header::IF_RANGE).and_then(|value| value.to_str().ok()) {
+        Some(validator) => validator == etag || Some(validator) == last_modified.as_deref(),
+        None => true,
+    };
+    let range = match http_req.headers().get(header::RANGE).and_then(|value| value.to_str().ok()) {
+        Some(range) if unchanged => parse_range(range, size),
+        _ => Range::Whole,
+    };
+    let (start, length) = match range {
+        Range::Whole => (0, size),
+        Range::Part(first, last) => {
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", first, last, size)));
+            (first, last - first + 1)
+        }
+        Range::Unsatisfiable => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                .finish());
+        }
+    };
+    if let Err(e) = file.seek(io::SeekFrom::Start(start)).await {
+        return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Cannot read {}: {}", query.path, e)));
+    }
+    Ok(response
+        .no_chunking(length)
+        .streaming(config.bandwidth.throttle(read_chunks(file, length))))
+}
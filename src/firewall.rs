@@ -0,0 +1,290 @@
+//! Inbound firewall rules for the agent's ports, so installing the agent on a
+//! host with a firewall doesn't need a manual step to let controllers in.
+//!
+//! `machine_agent firewall add` (from an installer) and `machine_agent
+//! firewall remove` (from an uninstaller), or `POST` and `DELETE
+//! /admin/firewall`, open and close the TCP ports of `AGENT_BIND` in the
+//! host's firewall:
+//!
+//! - Windows Firewall: an inbound rule named `machine_agent` per port, with
+//!   `netsh advfirewall`;
+//! - ufw, when active: `ufw allow <port>/tcp`;
+//! - firewalld, when running: `<port>/tcp` in the default zone, at runtime and
+//!   permanently.
+//!
+//! Without an active firewall there is nothing to open, which is not an error.
+//! Changing the firewall needs root, or Administrator on Windows.
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result as ActixResult};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::net::ToSocketAddrs;
+use std::process::Command;
+
+use crate::config::{self, AppConfig};
+use crate::{auth, capabilities, log_error, policy};
+
+/// The Windows Firewall rule name, and the ufw rule comment.
+const RULE_NAME: &str = "machine_agent";
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    WindowsFirewall,
+    Ufw,
+    Firewalld,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::WindowsFirewall => "Windows Firewall",
+            Backend::Ufw => "ufw",
+            Backend::Firewalld => "firewalld",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Change {
+    Add,
+    Remove,
+}
+
+#[derive(Serialize)]
+pub struct PortRule {
+    port: u16,
+    /// Whether the firewall lets the port in, after any change.
+    open: bool,
+}
+
+/// The firewall and the agent's ports in it.
+#[derive(Serialize)]
+pub struct FirewallState {
+    /// `None` when no supported firewall is active.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<Backend>,
+    ports: Vec<PortRule>,
+}
+
+/// Run a firewall tool; its output, or why it failed.
+fn run(program: &str, args: &[String]) -> Result<String, String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if output.status.success() {
+        return Ok(stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let message = if stderr.trim().is_empty() { stdout.trim() } else { stderr.trim() };
+    Err(format!("{} {} failed ({}): {}", program, args.join(" "), output.status, message))
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// The active firewall, if any.
+pub fn detect() -> Result<Option<Backend>, String> {
+    if cfg!(windows) {
+        return Ok(Some(Backend::WindowsFirewall));
+    }
+    if capabilities::on_path("ufw") {
+        // Needs root, so a failure here is reported rather than skipped.
+        let status = run("ufw", &args(&["status"]))?;
+        if status.starts_with("Status: active") {
+            return Ok(Some(Backend::Ufw));
+        }
+    }
+    if capabilities::on_path("firewall-cmd") {
+        // Exits 252 with "not running" when stopped.
+        if run("firewall-cmd", &args(&["--state"])).is_ok_and(|state| state.trim() == "running") {
+            return Ok(Some(Backend::Firewalld));
+        }
+    }
+    Ok(None)
+}
+
+/// The TCP ports of the configured listen addresses.
+pub fn ports(bind: &[String]) -> Result<Vec<u16>, String> {
+    let mut ports = BTreeSet::new();
+    for address in bind {
+        let resolved = address
+            .to_socket_addrs()
+            .map_err(|e| format!("Invalid bind address {:?}: {}", address, e))?;
+        ports.extend(resolved.map(|addr| addr.port()));
+    }
+    Ok(ports.into_iter().collect())
+}
+
+fn netsh_rule(action: &str, port: u16, extra: &[&str]) -> Vec<String> {
+    let mut command = args(&["advfirewall", "firewall", action, "rule"]);
+    command.push(format!("name={}", RULE_NAME));
+    command.extend(args(extra));
+    command.push("protocol=TCP".to_string());
+    command.push(format!("localport={}", port));
+    command
+}
+
+fn is_open(backend: Backend, port: u16) -> Result<bool, String> {
+    match backend {
+        Backend::WindowsFirewall => {
+            let mut command = args(&["advfirewall", "firewall", "show", "rule"]);
+            command.push(format!("name={}", RULE_NAME));
+            // Fails with "No rules match the specified criteria" when absent.
+            let Ok(rules) = run("netsh", &command) else {
+                return Ok(false);
+            };
+            Ok(rules.lines().any(|line| {
+                line.split_once(':')
+                    .is_some_and(|(field, value)| field.trim() == "LocalPort" && value.trim() == port.to_string())
+            }))
+        }
+        Backend::Ufw => {
+            let status = run("ufw", &args(&["status"]))?;
+            let rule = format!("{}/tcp", port);
+            Ok(status.lines().any(|line| {
+                let mut fields = line.split_whitespace();
+                fields.next() == Some(rule.as_str()) && fields.next() == Some("ALLOW")
+            }))
+        }
+        Backend::Firewalld => {
+            // Exits 1 with "no" when the port isn't open.
+            Ok(run("firewall-cmd", &[format!("--query-port={}/tcp", port)]).is_ok())
+        }
+    }
+}
+
+fn change_port(backend: Backend, change: Change, port: u16) -> Result<(), String> {
+    match (backend, change) {
+        (Backend::WindowsFirewall, Change::Add) => {
+            // netsh adds duplicates, so replace any earlier rule.
+            let _ = run("netsh", &netsh_rule("delete", port, &[]));
+            run("netsh", &netsh_rule("add", port, &["dir=in", "action=allow"])).map(|_| ())
+        }
+        (Backend::WindowsFirewall, Change::Remove) => match run("netsh", &netsh_rule("delete", port, &[])) {
+            Err(e) if e.contains("No rules match") => Ok(()),
+            result => result.map(|_| ()),
+        },
+        (Backend::Ufw, Change::Add) => run("ufw", &args(&["allow", &format!("{}/tcp", port), "comment", RULE_NAME])).map(|_| ()),
+        (Backend::Ufw, Change::Remove) => run("ufw", &args(&["delete", "allow", &format!("{}/tcp", port)])).map(|_| ()),
+        (Backend::Firewalld, change) => {
+            let flag = match change {
+                Change::Add => format!("--add-port={}/tcp", port),
+                Change::Remove => format!("--remove-port={}/tcp", port),
+            };
+            // Runtime and permanent configuration are separate; change both so
+            // neither a reload nor a reboot undoes it.
+            run("firewall-cmd", std::slice::from_ref(&flag))?;
+            run("firewall-cmd", &["--permanent".to_string(), flag]).map(|_| ())
+        }
+    }
+}
+
+/// Open or close `ports`, or only report them with `change` unset.
+pub fn apply(bind: &[String], change: Option<Change>) -> Result<FirewallState, String> {
+    let ports = ports(bind)?;
+    let Some(backend) = detect()? else {
+        return Ok(FirewallState { backend: None, ports: Vec::new() });
+    };
+    let mut rules = Vec::new();
+    for port in ports {
+        if let Some(change) = change {
+            change_port(backend, change, port)?;
+        }
+        rules.push(PortRule {
+            port,
+            open: is_open(backend, port)?,
+        });
+    }
+    Ok(FirewallState { backend: Some(backend), ports: rules })
+}
+
+/// `machine_agent firewall add|remove|status`; returns the exit status.
+pub fn run_command(action: &str) -> i32 {
+    let change = match action {
+        "add" => Some(Change::Add),
+        "remove" => Some(Change::Remove),
+        _ => None,
+    };
+    match apply(&config::bind_from_env(), change) {
+        Ok(FirewallState { backend: None, .. }) => {
+            println!("No active firewall (Windows Firewall, ufw or firewalld); nothing to change");
+            0
+        }
+        Ok(FirewallState { backend: Some(backend), ports }) => {
+            for rule in ports {
+                println!("{}: TCP port {} {}", backend.name(), rule.port, if rule.open { "open" } else { "closed" });
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+struct FirewallResponse {
+    success: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    state: Option<FirewallState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn failure(status: StatusCode, error_msg: String) -> HttpResponse {
+    HttpResponse::build(status).json(FirewallResponse {
+        error: Some(error_msg),
+        ..Default::default()
+    })
+}
+
+async fn respond(config: web::Data<AppConfig>, change: Option<Change>) -> ActixResult<HttpResponse> {
+    match web::block(move || apply(&config.bind, change)).await? {
+        Ok(state) => Ok(HttpResponse::Ok().json(FirewallResponse {
+            success: true,
+            state: Some(state),
+            error: None,
+        })),
+        Err(error_msg) => {
+            log_error("/admin/firewall", &error_msg, None);
+            Ok(failure(StatusCode::INTERNAL_SERVER_ERROR, error_msg))
+        }
+    }
+}
+
+/// Check the caller's API key and OPA before the firewall is changed.
+async fn authorize(http_req: &HttpRequest, method: &str, config: &AppConfig) -> Result<(), (StatusCode, String)> {
+    let caller = auth::authenticate(config, http_req).await.map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
+    if let Some(opa) = &config.opa {
+        let request = serde_json::json!({ "method": method });
+        policy::consult_opa(opa, "/admin/firewall", http_req, caller.as_ref().map(|caller| caller.name.as_str()), &request).await?;
+    }
+    Ok(())
+}
+
+/// GET /admin/firewall - the active firewall and whether the agent's ports
+/// are open in it.
+pub async fn get_firewall(config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    respond(config, None).await
+}
+
+/// POST /admin/firewall - open the agent's ports.
+pub async fn open_ports(http_req: HttpRequest, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    if let Err((status, error_msg)) = authorize(&http_req, "POST", &config).await {
+        return Ok(failure(status, error_msg));
+    }
+    respond(config, Some(Change::Add)).await
+}
+
+/// DELETE /admin/firewall - remove the rules opening the agent's ports.
+pub async fn close_ports(http_req: HttpRequest, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    if let Err((status, error_msg)) = authorize(&http_req, "DELETE", &config).await {
+        return Ok(failure(status, error_msg));
+    }
+    respond(config, Some(Change::Remove)).await
+}
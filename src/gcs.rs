@@ -0,0 +1,169 @@
+//! Google Cloud Storage for [`crate::offload`], through the JSON API.
+//! Requests are authorized with a service account key, or, without one, the
+//! instance's service account through the metadata server.
+
+use base64::Engine;
+use futures_util::future::BoxFuture;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::bandwidth::Bandwidth;
+use crate::offload::{self, env_var, uri_encode, Store, TokenCache};
+use crate::outbound::Outbound;
+
+/// Access the uploads need.
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// The default service account's token on the metadata server.
+const METADATA_TOKEN_URL: &str = "http://169.254.169.254/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// The fields of a service account key file the agent uses.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    #[serde(rename = "type")]
+    key_type: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+enum Credential {
+    ServiceAccount {
+        client_email: String,
+        key: Box<ring::signature::RsaKeyPair>,
+        token_uri: String,
+    },
+    Metadata,
+}
+
+pub struct Gcs {
+    /// `https://storage.googleapis.com`, or an emulator's URL; without a
+    /// trailing slash.
+    endpoint: String,
+    bucket: String,
+    credential: Credential,
+    token: TokenCache,
+}
+
+fn load_key(file: &str) -> Result<Credential, String> {
+    let invalid = |reason: String| format!("Invalid service account key {}: {}", file, reason);
+    let text = std::fs::read_to_string(file).map_err(|e| invalid(e.to_string()))?;
+    let key: ServiceAccountKey = serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    if key.key_type != "service_account" {
+        return Err(invalid(format!("expected a service_account key, not {}", key.key_type)));
+    }
+    let der: String = key
+        .private_key
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = base64::engine::general_purpose::STANDARD
+        .decode(der.trim())
+        .map_err(|e| invalid(e.to_string()))?;
+    let rsa = ring::signature::RsaKeyPair::from_pkcs8(&der).map_err(|e| invalid(e.to_string()))?;
+    Ok(Credential::ServiceAccount {
+        client_email: key.client_email,
+        key: Box::new(rsa),
+        token_uri: key.token_uri,
+    })
+}
+
+impl Gcs {
+    pub fn from_env() -> Result<Self, String> {
+        let bucket = env_var(&["AGENT_GCS_BUCKET"]).ok_or("The gcs offload backend needs AGENT_GCS_BUCKET")?;
+        let credential = match env_var(&["AGENT_GCS_CREDENTIALS", "GOOGLE_APPLICATION_CREDENTIALS"]) {
+            Some(file) => load_key(&file)?,
+            None => Credential::Metadata,
+        };
+        Ok(Gcs {
+            endpoint: env_var(&["AGENT_GCS_ENDPOINT"])
+                .unwrap_or_else(|| "https://storage.googleapis.com".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            bucket,
+            credential,
+            token: TokenCache::default(),
+        })
+    }
+
+    async fn fetch_token(&self, outbound: &Outbound) -> Result<(String, u64), String> {
+        let response = match &self.credential {
+            Credential::ServiceAccount {
+                client_email,
+                key,
+                token_uri,
+            } => {
+                let assertion = jwt(client_email, key, token_uri)?;
+                outbound.check(token_uri)?;
+                let client = outbound.client()?.build().map_err(|e| e.to_string())?;
+                client
+                    .post(token_uri)
+                    .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(format!(
+                        "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion={}",
+                        assertion
+                    ))
+                    .send()
+                    .await
+            }
+            Credential::Metadata => {
+                outbound.check(METADATA_TOKEN_URL)?;
+                // The metadata server is only reachable directly.
+                let client = outbound.client()?.no_proxy().build().map_err(|e| e.to_string())?;
+                client.get(METADATA_TOKEN_URL).header("Metadata-Flavor", "Google").send().await
+            }
+        }
+        .map_err(|e| format!("Token request failed: {}", crate::outbound::describe(&e)))?;
+        offload::parse_token(response).await
+    }
+}
+
+/// A signed JWT asking `token_uri` for an access token.
+fn jwt(client_email: &str, key: &ring::signature::RsaKeyPair, token_uri: &str) -> Result<String, String> {
+    let encode = |bytes: &[u8]| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+    let now = chrono::Utc::now().timestamp();
+    let header = serde_json::json!({"alg": "RS256", "typ": "JWT"});
+    let claims = serde_json::json!({
+        "iss": client_email,
+        "scope": SCOPE,
+        "aud": token_uri,
+        "iat": now,
+        "exp": now + 3600,
+    });
+    let message = format!("{}.{}", encode(header.to_string().as_bytes()), encode(claims.to_string().as_bytes()));
+    let mut signature = vec![0; key.public().modulus_len()];
+    key.sign(
+        &ring::signature::RSA_PKCS1_SHA256,
+        &ring::rand::SystemRandom::new(),
+        message.as_bytes(),
+        &mut signature,
+    )
+    .map_err(|_| "Failed to sign the token request".to_string())?;
+    Ok(format!("{}.{}", message, encode(&signature)))
+}
+
+impl Store for Gcs {
+    fn put<'a>(&'a self, outbound: &'a Outbound, bandwidth: &'a Bandwidth, key: &'a str, path: &'a Path, length: u64) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let url = format!(
+                "{}/upload/storage/v1/b/{}/o?uploadType=media&name={}",
+                self.endpoint,
+                uri_encode(&self.bucket, false),
+                uri_encode(key, false)
+            );
+            outbound.check(&url)?;
+            let token = self.token.get(|| self.fetch_token(outbound)).await?;
+            let client = outbound.client()?.build().map_err(|e| e.to_string())?;
+            let request = client
+                .post(&url)
+                .bearer_auth(token)
+                .header(reqwest::header::CONTENT_TYPE, "application/octet-stream");
+            offload::send_file(request, bandwidth, path, length).await?;
+            Ok(format!("{}/{}/{}", self.endpoint, uri_encode(&self.bucket, false), uri_encode(key, true)))
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+}
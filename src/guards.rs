@@ -0,0 +1,78 @@
+//! Preconditions evaluated before a job is spawned. When one does not hold,
+//! the job is recorded as `skipped` with the reason instead of being run.
+
+use serde::Deserialize;
+use std::path::PathBuf;
+
+use crate::host;
+use crate::jobs::JobRegistry;
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Guard {
+    FileExists { path: PathBuf },
+    FileAbsent { path: PathBuf },
+    ProcessRunning { name: String },
+    ProcessNotRunning { name: String },
+    /// At least `min_free_mb` available on the filesystem holding `path`.
+    FreeDisk { path: PathBuf, min_free_mb: u64 },
+    /// No running job holds the named lock (see `lock` on the request).
+    LockFree { name: String },
+}
+
+impl Guard {
+    /// `Err` carries why the guard does not hold, or why it couldn't be checked.
+    async fn check(&self, jobs: &JobRegistry) -> Result<(), String> {
+        match self {
+            Guard::FileExists { path } if !path.exists() => Err(format!("{} does not exist", path.display())),
+            Guard::FileAbsent { path } if path.exists() => Err(format!("{} exists", path.display())),
+            Guard::ProcessRunning { name } | Guard::ProcessNotRunning { name } => {
+                let running = host::process_running(name)
+                    .await
+                    .map_err(|e| format!("Could not check for process {}: {}", name, e))?;
+                match (self, running) {
+                    (Guard::ProcessRunning { .. }, false) => Err(format!("Process {} is not running", name)),
+                    (Guard::ProcessNotRunning { .. }, true) => Err(format!("Process {} is running", name)),
+                    _ => Ok(()),
+                }
+            }
+            Guard::FreeDisk { path, min_free_mb } => {
+                let free = host::free_disk_bytes(path)
+                    .await
+                    .map_err(|e| format!("Could not check free disk on {}: {}", path.display(), e))?;
+                let free_mb = free / (1024 * 1024);
+                if free_mb < *min_free_mb {
+                    Err(format!("Only {} MB free on {} (need {} MB)", free_mb, path.display(), min_free_mb))
+                } else {
+                    Ok(())
+                }
+            }
+            Guard::LockFree { name } => match jobs.lock_holder(name) {
+                Some(holder) => Err(format!("Lock {:?} is held by job {}", name, holder)),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Locks named by `lock_free` guards.
+pub fn lock_names(guards: &[Guard]) -> Vec<String> {
+    guards
+        .iter()
+        .filter_map(|guard| match guard {
+            Guard::LockFree { name } => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Check guards in order; returns the reason of the first one that fails.
+pub async fn evaluate(guards: &[Guard], jobs: &JobRegistry) -> Option<String> {
+    for guard in guards {
+        if let Err(reason) = guard.check(jobs).await {
+            return Some(reason);
+        }
+    }
+    None
+}
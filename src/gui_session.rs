@@ -0,0 +1,152 @@
+//! Launching commands in the interactive desktop session.
+//!
+//! When the agent runs as a Windows service it lives in session 0, which has
+//! no access to the user's desktop. For GUI automation a command can instead be
+//! started with the token of the user logged on to the active console session
+//! (`WTSQueryUserToken` + `CreateProcessAsUserW`). This requires the agent to
+//! run as LocalSystem and the `desktop` feature, and is unavailable on other
+//! platforms.
+
+use std::io;
+use std::path::Path;
+
+pub struct SessionProcess {
+    pub pid: u32,
+    #[cfg(all(windows, feature = "desktop"))]
+    handle: usize,
+}
+
+#[cfg(all(windows, feature = "desktop"))]
+pub fn spawn(command: &str, cwd: &Path) -> io::Result<SessionProcess> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr::{null, null_mut};
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::Security::{
+        DuplicateTokenEx, SecurityImpersonation, TokenPrimary, TOKEN_ALL_ACCESS,
+    };
+    use windows_sys::Win32::System::Environment::{CreateEnvironmentBlock, DestroyEnvironmentBlock};
+    use windows_sys::Win32::System::RemoteDesktop::{WTSGetActiveConsoleSessionId, WTSQueryUserToken};
+    use windows_sys::Win32::System::Threading::{
+        CreateProcessAsUserW, CREATE_NO_WINDOW, CREATE_UNICODE_ENVIRONMENT, PROCESS_INFORMATION,
+        STARTUPINFOW,
+    };
+
+    fn wide(s: &OsStr) -> Vec<u16> {
+        s.encode_wide().chain(Some(0)).collect()
+    }
+
+    unsafe {
+        let session_id = WTSGetActiveConsoleSessionId();
+        if session_id == u32::MAX {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No active console session"));
+        }
+
+        let mut user_token: HANDLE = null_mut();
+        if WTSQueryUserToken(session_id, &mut user_token) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut primary_token: HANDLE = null_mut();
+        let duplicated = DuplicateTokenEx(
+            user_token,
+            TOKEN_ALL_ACCESS,
+            null(),
+            SecurityImpersonation,
+            TokenPrimary,
+            &mut primary_token,
+        );
+        CloseHandle(user_token);
+        if duplicated == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Give the child the user's environment rather than the service's.
+        let mut environment = null_mut();
+        if CreateEnvironmentBlock(&mut environment, primary_token, 0) == 0 {
+            environment = null_mut();
+        }
+
+        let mut desktop = wide(OsStr::new("winsta0\\default"));
+        let mut startup: STARTUPINFOW = std::mem::zeroed();
+        startup.cb = std::mem::size_of::<STARTUPINFOW>() as u32;
+        startup.lpDesktop = desktop.as_mut_ptr();
+        let mut info: PROCESS_INFORMATION = std::mem::zeroed();
+        let mut command_line = wide(OsStr::new(&format!("cmd /C {}", command)));
+        let cwd = wide(cwd.as_os_str());
+
+        let created = CreateProcessAsUserW(
+            primary_token,
+            null(),
+            command_line.as_mut_ptr(),
+            null(),
+            null(),
+            0,
+            CREATE_UNICODE_ENVIRONMENT | CREATE_NO_WINDOW,
+            environment,
+            cwd.as_ptr(),
+            &startup,
+            &mut info,
+        );
+        let create_error = io::Error::last_os_error();
+        if !environment.is_null() {
+            DestroyEnvironmentBlock(environment);
+        }
+        CloseHandle(primary_token);
+        if created == 0 {
+            return Err(create_error);
+        }
+        CloseHandle(info.hThread);
+
+        Ok(SessionProcess {
+            pid: info.dwProcessId,
+            handle: info.hProcess as usize,
+        })
+    }
+}
+
+#[cfg(not(all(windows, feature = "desktop")))]
+pub fn spawn(_command: &str, _cwd: &Path) -> io::Result<SessionProcess> {
+    let error_msg = if cfg!(windows) {
+        "interactive_session needs the agent built with the desktop feature"
+    } else {
+        "interactive_session is only supported on Windows"
+    };
+    Err(io::Error::new(io::ErrorKind::Unsupported, error_msg))
+}
+
+impl SessionProcess {
+    /// Wait for the process to exit and return its exit code.
+    #[cfg(all(windows, feature = "desktop"))]
+    pub async fn wait(self) -> io::Result<i32> {
+        use windows_sys::Win32::Foundation::HANDLE;
+        use windows_sys::Win32::System::Threading::{GetExitCodeProcess, WaitForSingleObject, INFINITE};
+
+        tokio::task::spawn_blocking(move || unsafe {
+            // Keep the whole value (and its Drop) alive until the wait is over.
+            let process = self;
+            let handle = process.handle as HANDLE;
+            WaitForSingleObject(handle, INFINITE);
+            let mut code = 0u32;
+            if GetExitCodeProcess(handle, &mut code) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(code as i32)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    #[cfg(not(all(windows, feature = "desktop")))]
+    pub async fn wait(self) -> io::Result<i32> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+#[cfg(all(windows, feature = "desktop"))]
+impl Drop for SessionProcess {
+    fn drop(&mut self) {
+        unsafe {
+            windows_sys::Win32::Foundation::CloseHandle(self.handle as windows_sys::Win32::Foundation::HANDLE);
+        }
+    }
+}
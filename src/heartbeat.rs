@@ -0,0 +1,134 @@
+//! Heartbeat frames for long `/execute` requests, so proxies and load
+//! balancers that cut idle connections don't kill a command's only answer.
+//!
+//! With `heartbeat` (seconds, default `AGENT_EXECUTE_HEARTBEAT_SECS`), the
+//! agent answers `200` at once and streams a frame every `heartbeat` seconds
+//! until the result, which is the last frame. `whitespace` frames are a bare
+//! newline, so the body is still the usual JSON response; `ndjson` frames
+//! are JSON lines with the job ID and elapsed seconds, for clients that show
+//! progress. The status can't change once streaming started: clients check
+//! `success` instead. Line events (see [`crate::line_events`]) are sent in
+//! the same stream, which may then also be Server-Sent Events.
+
+use actix_web::web::Bytes;
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use crate::line_events;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// A newline per frame; the body parses as the plain JSON response.
+    #[default]
+    Whitespace,
+    /// A JSON line per frame, the result being the last line.
+    Ndjson,
+    /// Server-Sent Events, the result being the `result` event; only for
+    /// line events.
+    #[serde(skip_deserializing)]
+    Sse,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Whitespace => "application/json",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Sse => "text/event-stream",
+        }
+    }
+
+    fn frame(self, job_id: &str, started: Instant) -> Bytes {
+        match self {
+            Format::Whitespace => Bytes::from_static(b"\n"),
+            Format::Ndjson => {
+                let frame = serde_json::json!({
+                    "heartbeat": true,
+                    "job_id": job_id,
+                    "elapsed_secs": started.elapsed().as_secs(),
+                });
+                Bytes::from(format!("{}\n", frame))
+            }
+            Format::Sse => {
+                let data = serde_json::json!({ "job_id": job_id, "elapsed_secs": started.elapsed().as_secs() });
+                event("heartbeat", &data.to_string())
+            }
+        }
+    }
+
+    fn line(self, line: &line_events::LineEvent) -> Bytes {
+        let data = serde_json::to_string(line).unwrap_or_default();
+        match self {
+            Format::Sse => event(line.stream.name(), &data),
+            _ => Bytes::from(format!("{}\n", data)),
+        }
+    }
+}
+
+/// A Server-Sent Event; `data` is one line of JSON.
+fn event(name: &str, data: &str) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+/// Frames every `period`, and `lines`, until `result` resolves, then its
+/// body as the last frame; the stream just ends when it resolves to `None`.
+/// Server-Sent Events start with a `started` event carrying the job ID.
+pub fn stream<F>(
+    format: Format,
+    job_id: &str,
+    period: Option<Duration>,
+    lines: Option<line_events::Receiver>,
+    result: F,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    F: Future<Output = Option<Vec<u8>>> + 'static,
+{
+    let job_id = job_id.to_string();
+    let started = Instant::now();
+    // The first tick is immediate, so the status goes out straight away.
+    let interval = period.map(tokio::time::interval);
+    let result: Pin<Box<F>> = Box::pin(result);
+    let start = (format == Format::Sse).then(|| Ok(event("started", &serde_json::json!({ "job_id": job_id }).to_string())));
+    stream::iter(start).chain(stream::unfold(Some((result, interval, lines)), move |state| {
+        let job_id = job_id.clone();
+        async move {
+            let (mut result, mut interval, mut lines) = state?;
+            loop {
+                tokio::select! {
+                    // Every line is sent before the command's result.
+                    biased;
+                    event = recv(&mut lines), if lines.is_some() => match event {
+                        Some(line) => return Some((Ok(format.line(&line)), Some((result, interval, lines)))),
+                        None => lines = None,
+                    },
+                    body = &mut result => {
+                        let mut body = body?;
+                        match format {
+                            Format::Ndjson => body.push(b'\n'),
+                            Format::Sse => return Some((Ok(event("result", &String::from_utf8_lossy(&body))), None)),
+                            Format::Whitespace => {}
+                        }
+                        return Some((Ok(Bytes::from(body)), None));
+                    }
+                    _ = tick(&mut interval), if interval.is_some() => {
+                        return Some((Ok(format.frame(&job_id, started)), Some((result, interval, lines))));
+                    }
+                }
+            }
+        }
+    }))
+}
+
+async fn recv(lines: &mut Option<line_events::Receiver>) -> Option<line_events::LineEvent> {
+    lines.as_mut()?.recv().await
+}
+
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    if let Some(interval) = interval {
+        interval.tick().await;
+    }
+}
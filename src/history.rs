@@ -0,0 +1,234 @@
+//! Persistent job history in SQLite, with full-text search (FTS5) over
+//! commands and captured output.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::clock;
+use crate::jobs::{Job, JobStatus};
+use crate::listing::{self, ListQuery, ListSpec};
+use crate::log_error;
+
+/// Matches considered per search; pages are cut from these.
+const MAX_MATCHES: u32 = 1000;
+
+const SEARCH_LIST: ListSpec = ListSpec {
+    key: "id",
+    sort_keys: &["rank", "started_at", "finished_at", "return_code", "id"],
+    default_sort: "rank",
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS jobs (
+        id TEXT PRIMARY KEY,
+        command TEXT NOT NULL,
+        status TEXT NOT NULL,
+        started_at TEXT NOT NULL,
+        finished_at TEXT,
+        return_code INTEGER,
+        error TEXT
+    );
+    CREATE VIRTUAL TABLE IF NOT EXISTS jobs_fts USING fts5(id UNINDEXED, command, output);
+";
+
+/// WAL with `synchronous=NORMAL` syncs only at checkpoints; the cache is
+/// capped at 512 KiB and the WAL file truncated back to 1 MiB.
+const LIGHTWEIGHT_PRAGMAS: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    PRAGMA cache_size = -512;
+    PRAGMA journal_size_limit = 1048576;
+";
+
+pub struct JobHistory {
+    conn: Mutex<Connection>,
+}
+
+impl JobHistory {
+    pub fn open(path: &Path, lightweight: bool) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        if lightweight {
+            // Fewer fsyncs for SD cards and a small page cache; WAL keeps
+            // that crash-safe.
+            conn.execute_batch(LIGHTWEIGHT_PRAGMAS)?;
+        }
+        conn.execute_batch(SCHEMA)?;
+        Ok(JobHistory {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Note a job that has started or been queued, so it can be reconciled
+    /// if the agent dies before it completes. Never overwrites a completed
+    /// job, whose record may have been written first.
+    pub fn record_start(&self, job: &Job) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO jobs (id, command, status, started_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (id) DO UPDATE SET status = excluded.status
+             WHERE jobs.status IN ('running', 'queued')",
+            params![job.id, job.command, job.status.name(), job.started_at],
+        )?;
+        Ok(())
+    }
+
+    /// Mark jobs a previous run of the agent left running or queued as
+    /// interrupted, except those in `keep` (resumed playbooks); returns the
+    /// ids and commands marked.
+    pub fn interrupt_unfinished(&self, keep: &[String], error: &str) -> rusqlite::Result<Vec<(String, String)>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let unfinished: Vec<(String, String)> = tx
+            .prepare("SELECT id, command FROM jobs WHERE status IN ('running', 'queued')")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|(id, _)| !keep.contains(id))
+            .collect();
+        let now = clock::now();
+        for (id, command) in &unfinished {
+            tx.execute(
+                "UPDATE jobs SET status = ?2, finished_at = ?3, error = ?4 WHERE id = ?1",
+                params![id, JobStatus::Interrupted.name(), now, error],
+            )?;
+            tx.execute("DELETE FROM jobs_fts WHERE id = ?1", params![id])?;
+            tx.execute("INSERT INTO jobs_fts (id, command, output) VALUES (?1, ?2, '')", params![id, command])?;
+        }
+        tx.commit()?;
+        Ok(unfinished)
+    }
+
+    /// Store a finished job together with its captured output.
+    pub fn record(&self, job: &Job, output: &str) -> rusqlite::Result<()> {
+        let status = job.status.name();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO jobs (id, command, status, started_at, finished_at, return_code, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![job.id, job.command, status, job.started_at, job.finished_at, job.return_code, job.error],
+        )?;
+        tx.execute("DELETE FROM jobs_fts WHERE id = ?1", params![job.id])?;
+        tx.execute(
+            "INSERT INTO jobs_fts (id, command, output) VALUES (?1, ?2, ?3)",
+            params![job.id, job.command, output],
+        )?;
+        tx.commit()
+    }
+
+    fn search(&self, query: &str) -> rusqlite::Result<Vec<SearchHit>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT j.id, j.command, j.status, j.started_at, j.finished_at, j.return_code,
+                    snippet(jobs_fts, 2, '[', ']', '...', 16), rank
+             FROM jobs_fts JOIN jobs j ON j.id = jobs_fts.id
+             WHERE jobs_fts MATCH ?1
+             ORDER BY rank
+             LIMIT ?2",
+        )?;
+        let hits = stmt
+            .query_map(params![query, MAX_MATCHES], |row| {
+                Ok(SearchHit {
+                    id: row.get(0)?,
+                    command: row.get(1)?,
+                    status: row.get(2)?,
+                    started_at: row.get(3)?,
+                    finished_at: row.get(4)?,
+                    return_code: row.get(5)?,
+                    snippet: row.get(6)?,
+                    rank: row.get(7)?,
+                })
+            })?
+            .collect();
+        hits
+    }
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    id: String,
+    command: String,
+    status: String,
+    started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    return_code: Option<i32>,
+    snippet: String,
+    /// FTS5 relevance; lower is a better match.
+    rank: f64,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    success: bool,
+    query: String,
+    results: Vec<serde_json::Value>,
+    total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl SearchResponse {
+    fn error(query: String, error: String) -> Self {
+        SearchResponse {
+            success: false,
+            query,
+            results: Vec::new(),
+            total: 0,
+            next_cursor: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// GET /jobs/search?q=... - full-text search over job commands and output,
+/// best matches first, paginated.
+///
+/// `q` uses SQLite FTS5 query syntax, e.g. `"disk full"` or `timeout OR refused`.
+pub async fn search_jobs(
+    query: web::Query<SearchQuery>,
+    list: web::Query<ListQuery>,
+    history: Option<web::Data<JobHistory>>,
+) -> ActixResult<HttpResponse> {
+    let query = query.into_inner();
+    let Some(history) = history else {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .json(SearchResponse::error(query.q, "Job history is disabled".to_string())));
+    };
+
+    let q = query.q.clone();
+    let result = web::block(move || history.search(&q)).await;
+    let hits = match result {
+        Ok(Ok(hits)) => hits,
+        Ok(Err(e)) => {
+            // Almost always a malformed FTS5 query.
+            let error_msg = format!("Invalid search query: {}", e);
+            log_error("/jobs/search", &error_msg, None);
+            return Ok(HttpResponse::BadRequest().json(SearchResponse::error(query.q, error_msg)));
+        }
+        Err(e) => return Ok(HttpResponse::InternalServerError().json(SearchResponse::error(query.q, e.to_string()))),
+    };
+
+    match listing::paginate(&hits, &list, &SEARCH_LIST) {
+        Ok(page) => Ok(HttpResponse::Ok().json(SearchResponse {
+            success: true,
+            query: query.q,
+            results: page.items,
+            total: page.total,
+            next_cursor: page.next_cursor,
+            error: None,
+        })),
+        Err(e) => Ok(HttpResponse::BadRequest().json(SearchResponse::error(query.q, e))),
+    }
+}
@@ -0,0 +1,139 @@
+//! Pre/post hooks around jobs, e.g. take an LVM or VSS snapshot before a risky
+//! command and drop it (success) or roll back to it (failure) afterwards.
+//!
+//! Hook sets are defined in the JSON file named by `AGENT_HOOKS_FILE`:
+//!
+//! ```json
+//! {"snapshot": {"pre": "lvcreate -s -n agent_$AGENT_JOB_ID -L 2G vg0/root",
+//!               "on_success": "lvremove -y vg0/agent_$AGENT_JOB_ID",
+//!               "on_failure": "lvconvert --merge vg0/agent_$AGENT_JOB_ID"}}
+//! ```
+//!
+//! and requests pick one with `"hooks": "snapshot"`. Hooks run through the
+//! shell with `AGENT_JOB_ID`, `AGENT_HOOK_STAGE` and (post hooks)
+//! `AGENT_EXIT_CODE` set.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command as TokioCommand;
+
+/// Output kept per hook run in the job record.
+const MAX_HOOK_OUTPUT: usize = 4 * 1024;
+
+#[derive(Deserialize, Clone)]
+pub struct HookSet {
+    /// Runs before the command; if it fails the command is not run.
+    pub pre: Option<String>,
+    /// Runs after the command exited 0.
+    pub on_success: Option<String>,
+    /// Runs after the command exited non-zero or could not be run.
+    pub on_failure: Option<String>,
+    #[serde(default = "default_hook_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_hook_timeout() -> u64 {
+    300
+}
+
+pub fn load(path: &Path) -> Result<HashMap<String, HookSet>, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&contents).map_err(|e| format!("Invalid hooks file {}: {}", path.display(), e))
+}
+
+#[derive(Serialize, Clone)]
+pub struct HookResult {
+    pub stage: String,
+    pub command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_code: Option<i32>,
+    pub output: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl HookResult {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none() && self.return_code == Some(0)
+    }
+}
+
+async fn run_hook(command: &str, stage: &str, job_id: &str, exit_code: Option<i32>, timeout: Duration) -> HookResult {
+    let mut cmd = if cfg!(target_os = "windows") {
+        let mut cmd = TokioCommand::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = TokioCommand::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    cmd.env("AGENT_JOB_ID", job_id).env("AGENT_HOOK_STAGE", stage).kill_on_drop(true);
+    if let Some(code) = exit_code {
+        cmd.env("AGENT_EXIT_CODE", code.to_string());
+    }
+
+    let mut result = HookResult {
+        stage: stage.to_string(),
+        command: command.to_string(),
+        return_code: None,
+        output: String::new(),
+        error: None,
+    };
+    match tokio::time::timeout(timeout, cmd.output()).await {
+        Ok(Ok(output)) => {
+            let text = format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let mut start = text.len().saturating_sub(MAX_HOOK_OUTPUT);
+            while !text.is_char_boundary(start) {
+                start += 1;
+            }
+            result.output = text[start..].to_string();
+            result.return_code = output.status.code();
+        }
+        Ok(Err(e)) => result.error = Some(e.to_string()),
+        Err(_) => result.error = Some(format!("Timed out after {}s", timeout.as_secs())),
+    }
+    result
+}
+
+impl HookSet {
+    /// Run the pre hook, if any. `Err` carries its result when it failed.
+    pub async fn pre(&self, job_id: &str) -> Result<Option<HookResult>, HookResult> {
+        let Some(command) = &self.pre else {
+            return Ok(None);
+        };
+        let result = run_hook(command, "pre", job_id, None, Duration::from_secs(self.timeout_secs)).await;
+        if result.succeeded() {
+            Ok(Some(result))
+        } else {
+            Err(result)
+        }
+    }
+
+    /// Run `on_success` or `on_failure` depending on how the command ended.
+    pub async fn post(&self, job_id: &str, exit_code: Option<i32>) -> Option<HookResult> {
+        let (stage, command) = if exit_code == Some(0) {
+            ("on_success", self.on_success.as_ref()?)
+        } else {
+            ("on_failure", self.on_failure.as_ref()?)
+        };
+        Some(run_hook(command, stage, job_id, exit_code, Duration::from_secs(self.timeout_secs)).await)
+    }
+}
+
+/// Describe a failed hook for job errors and logs.
+pub fn failure(result: &HookResult) -> io::Error {
+    let detail = match (&result.error, result.return_code) {
+        (Some(error), _) => error.clone(),
+        (None, Some(code)) => format!("exited with {}", code),
+        (None, None) => "terminated by a signal".to_string(),
+    };
+    io::Error::other(format!("{} hook failed: {}", result.stage, detail))
+}
@@ -0,0 +1,168 @@
+//! Point-in-time host state used to decide whether a job may start.
+
+use std::io;
+use std::path::Path;
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(windows)]
+pub async fn free_disk_bytes(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let mut available = 0u64;
+    // SAFETY: `wide` is NUL-terminated and the out pointer is valid for the call.
+    let ok = unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(not(windows))]
+pub async fn free_disk_bytes(path: &Path) -> io::Result<u64> {
+    // POSIX output: "Filesystem 1024-blocks Used Available Capacity Mounted on"
+    let output = tokio::process::Command::new("df").arg("-Pk").arg(path).output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| io::Error::other("Unexpected df output"))
+}
+
+/// Whether a process with this executable name is running.
+pub async fn process_running(name: &str) -> io::Result<bool> {
+    if cfg!(target_os = "windows") {
+        let image = if name.to_lowercase().ends_with(".exe") {
+            name.to_string()
+        } else {
+            format!("{}.exe", name)
+        };
+        let output = tokio::process::Command::new("tasklist")
+            .args(["/FI", &format!("IMAGENAME eq {}", image), "/NH", "/FO", "CSV"])
+            .output()
+            .await?;
+        let listing = String::from_utf8_lossy(&output.stdout).to_lowercase();
+        Ok(listing.contains(&format!("\"{}\"", image.to_lowercase())))
+    } else {
+        // pgrep exits 1 when nothing matched and >1 on errors.
+        let status = tokio::process::Command::new("pgrep")
+            .arg("-x")
+            .arg(name)
+            .stdout(std::process::Stdio::null())
+            .status()
+            .await?;
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(io::Error::other(format!("pgrep failed ({})", status))),
+        }
+    }
+}
+
+/// One-minute load average divided by the number of CPUs (1.0 = every core
+/// busy). Windows has no load average; the current processor utilisation is
+/// used instead.
+pub async fn cpu_load() -> io::Result<f64> {
+    let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1) as f64;
+    if cfg!(target_os = "windows") {
+        // Formatted with the invariant culture: European locales would
+        // print a decimal comma.
+        let output = tokio::process::Command::new("powershell")
+            .args([
+                "-NoProfile",
+                "-NonInteractive",
+                "-Command",
+                "(Get-CimInstance Win32_Processor | Measure-Object -Property LoadPercentage -Average).Average.ToString([cultureinfo]::InvariantCulture)",
+            ])
+            .output()
+            .await?;
+        let percent = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| io::Error::other("Unexpected processor load output"))?;
+        return Ok(percent / 100.0);
+    }
+    let loadavg = if cfg!(target_os = "linux") {
+        tokio::fs::read_to_string("/proc/loadavg").await?
+    } else {
+        // BSD/macOS: "{ 1.23 1.10 1.00 }"
+        let output = tokio::process::Command::new("sysctl").args(["-n", "vm.loadavg"]).output().await?;
+        String::from_utf8_lossy(&output.stdout).replace(['{', '}'], "")
+    };
+    loadavg
+        .split_whitespace()
+        .next()
+        .and_then(|load| load.parse::<f64>().ok())
+        .map(|load| load / cpus)
+        .ok_or_else(|| io::Error::other("Unexpected load average output"))
+}
+
+/// Memory available for new processes without swapping.
+#[cfg(windows)]
+pub async fn free_memory_bytes() -> io::Result<u64> {
+    use windows_sys::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    // SAFETY: MEMORYSTATUSEX is plain data; dwLength is set as the API requires.
+    let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+    status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+    if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(status.ullAvailPhys)
+}
+
+/// Memory available for new processes without swapping.
+#[cfg(not(windows))]
+pub async fn free_memory_bytes() -> io::Result<u64> {
+    if cfg!(target_os = "linux") {
+        let meminfo = tokio::fs::read_to_string("/proc/meminfo").await?;
+        return meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix("MemAvailable:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+            .ok_or_else(|| io::Error::other("MemAvailable missing from /proc/meminfo"));
+    }
+    if cfg!(target_os = "macos") {
+        // "Mach Virtual Memory Statistics: (page size of 16384 bytes)", then
+        // "Pages free:   12345." and friends.
+        let output = tokio::process::Command::new("vm_stat").output().await?;
+        let text = String::from_utf8_lossy(&output.stdout);
+        let page_size = text
+            .split("page size of ")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|size| size.parse::<u64>().ok())
+            .unwrap_or(4096);
+        let pages = |label: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(label))
+                .and_then(|value| value.trim().trim_end_matches('.').parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        return Ok((pages("Pages free:") + pages("Pages inactive:") + pages("Pages speculative:")) * page_size);
+    }
+    if cfg!(target_os = "freebsd") {
+        // One value per line, in the order asked for.
+        let output = tokio::process::Command::new("sysctl")
+            .args(["-n", "hw.pagesize", "vm.stats.vm.v_free_count", "vm.stats.vm.v_inactive_count"])
+            .output()
+            .await?;
+        let values: Vec<u64> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .collect();
+        return match values[..] {
+            [page_size, free, inactive] => Ok((free + inactive) * page_size),
+            _ => Err(io::Error::other("Unexpected sysctl output")),
+        };
+    }
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Free memory is not available on this platform"))
+}
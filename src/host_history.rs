@@ -0,0 +1,185 @@
+//! Recent host metrics kept in memory, so what the host looked like around a
+//! failed job can be seen without external monitoring.
+//!
+//! A background task samples CPU load, free memory and free disk every
+//! `AGENT_METRICS_INTERVAL_SECS` into a ring buffer holding
+//! `AGENT_METRICS_RETENTION_HOURS`; `/system/history` returns a time range of
+//! it. Samples are lost when the agent restarts; an interval of 0 disables
+//! sampling.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock;
+use crate::host;
+use crate::jobs::{JobRegistry, JobStatus};
+
+/// Samples shown before a job started and after it finished.
+const JOB_MARGIN: chrono::Duration = chrono::Duration::minutes(10);
+
+#[derive(Serialize, Clone)]
+pub struct Sample {
+    #[serde(skip)]
+    time: DateTime<Local>,
+    pub at: String,
+    /// Load average per CPU (processor utilisation on Windows).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_load: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_memory_mb: Option<u64>,
+    /// Free disk in the agent's working directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_disk_mb: Option<u64>,
+    pub running_jobs: usize,
+}
+
+pub struct HostHistory {
+    interval: Duration,
+    capacity: usize,
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl HostHistory {
+    pub fn new(interval: Duration, retention: Duration) -> Self {
+        let capacity = (retention.as_secs() / interval.as_secs().max(1)).max(1) as usize;
+        HostHistory {
+            interval,
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn push(&self, sample: Sample) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Samples taken within `[since, until]`, oldest first.
+    fn range(&self, since: Option<DateTime<Local>>, until: Option<DateTime<Local>>) -> Vec<Sample> {
+        self.samples
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|sample| since.is_none_or(|since| sample.time >= since))
+            .filter(|sample| until.is_none_or(|until| sample.time <= until))
+            .cloned()
+            .collect()
+    }
+}
+
+async fn sample(jobs: &JobRegistry) -> Sample {
+    const MB: u64 = 1024 * 1024;
+    let cwd = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+    let time = Local::now();
+    Sample {
+        time,
+        at: clock::format(&time),
+        cpu_load: host::cpu_load().await.ok(),
+        free_memory_mb: host::free_memory_bytes().await.ok().map(|bytes| bytes / MB),
+        free_disk_mb: host::free_disk_bytes(&cwd).await.ok().map(|bytes| bytes / MB),
+        running_jobs: jobs.list().iter().filter(|job| job.status == JobStatus::Running).count(),
+    }
+}
+
+/// Sample the host forever; spawned once at startup.
+pub async fn run_sampler(history: web::Data<HostHistory>, jobs: web::Data<JobRegistry>) {
+    let mut ticker = tokio::time::interval(history.interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        history.push(sample(&jobs).await);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    /// RFC 3339 start of the range; the oldest sample when unset.
+    since: Option<String>,
+    /// RFC 3339 end of the range; the newest sample when unset.
+    until: Option<String>,
+    /// Show the samples around this job instead.
+    job_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    success: bool,
+    interval_secs: u64,
+    samples: Vec<Sample>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn parse_time(name: &str, value: &str) -> Result<DateTime<Local>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Local))
+        .map_err(|e| format!("Invalid {} {:?}: {}", name, value, e))
+}
+
+fn bad_request(error: String) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, error)
+}
+
+/// One end of a queried range.
+type Bound = DateTime<Local>;
+
+/// The range a query asks for.
+fn query_range(
+    query: &HistoryQuery,
+    jobs: &JobRegistry,
+) -> Result<(Option<Bound>, Option<Bound>), (StatusCode, String)> {
+    if let Some(job_id) = &query.job_id {
+        let Some(job) = jobs.get(job_id) else {
+            return Err((StatusCode::NOT_FOUND, "Job not found".to_string()));
+        };
+        let started = parse_time("started_at", &job.started_at).map_err(bad_request)?;
+        let finished = match &job.finished_at {
+            Some(finished) => Some(parse_time("finished_at", finished).map_err(bad_request)? + JOB_MARGIN),
+            None => None,
+        };
+        return Ok((Some(started - JOB_MARGIN), finished));
+    }
+    let since = query.since.as_deref().map(|since| parse_time("since", since)).transpose();
+    let until = query.until.as_deref().map(|until| parse_time("until", until)).transpose();
+    Ok((since.map_err(bad_request)?, until.map_err(bad_request)?))
+}
+
+/// GET /system/history - recent host metrics samples, oldest first.
+pub async fn get_history(
+    query: web::Query<HistoryQuery>,
+    history: Option<web::Data<HostHistory>>,
+    jobs: web::Data<JobRegistry>,
+) -> ActixResult<HttpResponse> {
+    let Some(history) = history else {
+        return Ok(HttpResponse::ServiceUnavailable().json(HistoryResponse {
+            success: false,
+            interval_secs: 0,
+            samples: Vec::new(),
+            error: Some("Host metrics history is disabled (AGENT_METRICS_INTERVAL_SECS=0)".to_string()),
+        }));
+    };
+    let interval_secs = history.interval.as_secs();
+    match query_range(&query, &jobs) {
+        Ok((since, until)) => Ok(HttpResponse::Ok().json(HistoryResponse {
+            success: true,
+            interval_secs,
+            samples: history.range(since, until),
+            error: None,
+        })),
+        Err((status, error)) => Ok(HttpResponse::build(status).json(HistoryResponse {
+            success: false,
+            interval_secs,
+            samples: Vec::new(),
+            error: Some(error),
+        })),
+    }
+}
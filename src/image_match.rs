@@ -0,0 +1,365 @@
+//! Waiting for a template image to appear on screen.
+//!
+//! Matching is normalized cross-correlation on grayscale pixels: a coarse pass
+//! over a downscaled copy of the screen finds the best candidate, which is then
+//! refined at full resolution. Scores are in [-1, 1]; 1 is an exact match.
+//! Trying the template at several `scales` copes with DPI/resolution changes.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::log_error;
+use crate::screen::{self, Region};
+
+/// Coarse-pass downscale never shrinks the template below this many pixels.
+const MIN_COARSE_TEMPLATE_SIDE: usize = 8;
+const MAX_COARSE_FACTOR: usize = 4;
+
+#[derive(Deserialize)]
+pub struct WaitForImageRequest {
+    /// Base64-encoded PNG of the element to look for.
+    template: String,
+    #[serde(default = "default_confidence")]
+    confidence: f64,
+    /// Seconds to keep looking before giving up.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+    /// Only search inside this screen region.
+    region: Option<Region>,
+    /// Template scale factors to try, e.g. `[1.0, 1.25, 1.5]`.
+    #[serde(default = "default_scales")]
+    scales: Vec<f64>,
+}
+
+fn default_confidence() -> f64 {
+    0.9
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_interval_ms() -> u64 {
+    500
+}
+
+fn default_scales() -> Vec<f64> {
+    vec![1.0]
+}
+
+#[derive(Serialize)]
+struct WaitForImageResponse {
+    success: bool,
+    found: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    y: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    center_x: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    center_y: Option<i64>,
+    /// Best score seen, whether or not it reached the threshold.
+    confidence: f64,
+    attempts: u32,
+    elapsed_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct Gray {
+    width: usize,
+    height: usize,
+    data: Vec<f64>,
+}
+
+impl Gray {
+    fn from_image(image: &image::DynamicImage) -> Self {
+        let luma = image.to_luma8();
+        Gray {
+            width: luma.width() as usize,
+            height: luma.height() as usize,
+            data: luma.into_raw().into_iter().map(f64::from).collect(),
+        }
+    }
+
+    fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        let mut data = Vec::with_capacity(width * height);
+        for row in y..y + height {
+            data.extend_from_slice(&self.data[row * self.width + x..row * self.width + x + width]);
+        }
+        Gray { width, height, data }
+    }
+
+    /// Box-filter downscale by an integer factor.
+    fn downscale(&self, factor: usize) -> Self {
+        let width = self.width / factor;
+        let height = self.height / factor;
+        let area = (factor * factor) as f64;
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0.0;
+                for dy in 0..factor {
+                    let row = (y * factor + dy) * self.width + x * factor;
+                    sum += self.data[row..row + factor].iter().sum::<f64>();
+                }
+                data.push(sum / area);
+            }
+        }
+        Gray { width, height, data }
+    }
+}
+
+struct Match {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    score: f64,
+}
+
+/// Normalized cross-correlation of `template` against every position of
+/// `screen` with top-left corner in the given (inclusive) ranges.
+fn best_ncc(screen: &Gray, template: &Gray, xs: (usize, usize), ys: (usize, usize)) -> Option<(usize, usize, f64)> {
+    if template.width > screen.width || template.height > screen.height {
+        return None;
+    }
+    let n = (template.width * template.height) as f64;
+    let t_mean = template.data.iter().sum::<f64>() / n;
+    let t_centered: Vec<f64> = template.data.iter().map(|v| v - t_mean).collect();
+    let t_norm = t_centered.iter().map(|v| v * v).sum::<f64>().sqrt();
+
+    // Integral images of the screen for O(1) window sums.
+    let iw = screen.width + 1;
+    let mut sum = vec![0.0; iw * (screen.height + 1)];
+    let mut sq = vec![0.0; iw * (screen.height + 1)];
+    for y in 0..screen.height {
+        for x in 0..screen.width {
+            let v = screen.data[y * screen.width + x];
+            let i = (y + 1) * iw + x + 1;
+            sum[i] = v + sum[i - 1] + sum[i - iw] - sum[i - iw - 1];
+            sq[i] = v * v + sq[i - 1] + sq[i - iw] - sq[i - iw - 1];
+        }
+    }
+    let window = |table: &[f64], x: usize, y: usize| {
+        let (x2, y2) = (x + template.width, y + template.height);
+        table[y2 * iw + x2] - table[y * iw + x2] - table[y2 * iw + x] + table[y * iw + x]
+    };
+
+    let x_max = xs.1.min(screen.width - template.width);
+    let y_max = ys.1.min(screen.height - template.height);
+    let mut best: Option<(usize, usize, f64)> = None;
+    for y in ys.0..=y_max {
+        for x in xs.0..=x_max {
+            let w_sum = window(&sum, x, y);
+            let w_var = window(&sq, x, y) - w_sum * w_sum / n;
+            let denominator = t_norm * w_var.max(0.0).sqrt();
+            let score = if denominator < 1e-9 {
+                // Flat template or flat window: only a flat-on-flat match counts.
+                if t_norm < 1e-9 && w_var < 1e-9 { 1.0 } else { 0.0 }
+            } else {
+                let mut dot = 0.0;
+                for ty in 0..template.height {
+                    let row = (y + ty) * screen.width + x;
+                    let t_row = &t_centered[ty * template.width..(ty + 1) * template.width];
+                    dot += screen.data[row..row + template.width]
+                        .iter()
+                        .zip(t_row)
+                        .map(|(s, t)| s * t)
+                        .sum::<f64>();
+                }
+                dot / denominator
+            };
+            if best.is_none_or(|(_, _, b)| score > b) {
+                best = Some((x, y, score));
+            }
+        }
+    }
+    best
+}
+
+fn find_template(screen: &Gray, template: &Gray) -> Option<Match> {
+    let min_side = template.width.min(template.height);
+    let factor = (min_side / MIN_COARSE_TEMPLATE_SIDE).clamp(1, MAX_COARSE_FACTOR);
+
+    let (cx, cy) = if factor > 1 {
+        let (x, y, _) = best_ncc(
+            &screen.downscale(factor),
+            &template.downscale(factor),
+            (0, usize::MAX),
+            (0, usize::MAX),
+        )?;
+        (x * factor, y * factor)
+    } else {
+        (0, 0)
+    };
+
+    let (xs, ys) = if factor > 1 {
+        let margin = factor * 2;
+        ((cx.saturating_sub(margin), cx + margin), (cy.saturating_sub(margin), cy + margin))
+    } else {
+        ((0, usize::MAX), (0, usize::MAX))
+    };
+    let (x, y, score) = best_ncc(screen, template, xs, ys)?;
+    Some(Match {
+        x,
+        y,
+        width: template.width,
+        height: template.height,
+        score,
+    })
+}
+
+/// Search one screenshot for the template at each scale; returns the best match.
+fn search(screenshot: &image::DynamicImage, templates: &[Gray], region: Option<Region>) -> Option<Match> {
+    let full = Gray::from_image(screenshot);
+    let (offset_x, offset_y, screen) = match region {
+        Some(r) => {
+            let x = (r.x.max(0) as usize).min(full.width);
+            let y = (r.y.max(0) as usize).min(full.height);
+            let width = (r.width.max(0) as usize).min(full.width - x);
+            let height = (r.height.max(0) as usize).min(full.height - y);
+            (x, y, full.crop(x, y, width, height))
+        }
+        None => (0, 0, full),
+    };
+
+    templates
+        .iter()
+        .filter_map(|t| find_template(&screen, t))
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+        .map(|m| Match {
+            x: m.x + offset_x,
+            y: m.y + offset_y,
+            ..m
+        })
+}
+
+fn error_response(error: String, attempts: u32, started: Instant) -> WaitForImageResponse {
+    WaitForImageResponse {
+        success: false,
+        found: false,
+        x: None,
+        y: None,
+        width: None,
+        height: None,
+        center_x: None,
+        center_y: None,
+        confidence: 0.0,
+        attempts,
+        elapsed_ms: started.elapsed().as_millis(),
+        error: Some(error),
+    }
+}
+
+/// POST /screen/wait-for-image - poll the screen until the template appears.
+pub async fn wait_for_image(req: web::Json<WaitForImageRequest>) -> ActixResult<HttpResponse> {
+    let started = Instant::now();
+
+    let template = match base64::engine::general_purpose::STANDARD
+        .decode(req.template.trim())
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| image::load_from_memory(&bytes).map_err(|e| e.to_string()))
+    {
+        Ok(image) => image,
+        Err(e) => {
+            let error_msg = format!("template must be a base64-encoded PNG: {}", e);
+            log_error("/screen/wait-for-image", &error_msg, None);
+            return Ok(HttpResponse::BadRequest().json(error_response(error_msg, 0, started)));
+        }
+    };
+
+    let templates: Vec<Gray> = req
+        .scales
+        .iter()
+        .filter(|s| s.is_finite() && **s > 0.0)
+        .map(|&scale| {
+            let width = ((template.width() as f64 * scale).round() as u32).max(1);
+            let height = ((template.height() as f64 * scale).round() as u32).max(1);
+            Gray::from_image(&template.resize_exact(width, height, image::imageops::FilterType::Triangle))
+        })
+        .collect();
+    if templates.is_empty() {
+        let error_msg = "scales must contain at least one positive number".to_string();
+        return Ok(HttpResponse::BadRequest().json(error_response(error_msg, 0, started)));
+    }
+    let templates = std::sync::Arc::new(templates);
+
+    let deadline = started + Duration::from_secs(req.timeout);
+    let shot = std::env::temp_dir().join(format!("machine_agent_match_{}.png", uuid::Uuid::new_v4()));
+    let mut attempts = 0;
+    let mut best_score = f64::MIN;
+
+    loop {
+        attempts += 1;
+        let result = match screen::capture(&shot).await {
+            Ok(()) => image::open(&shot).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let _ = tokio::fs::remove_file(&shot).await;
+        let screenshot = match result {
+            Ok(image) => image,
+            Err(e) => {
+                let error_msg = format!("Screen capture failed: {}", e);
+                log_error("/screen/wait-for-image", &error_msg, None);
+                return Ok(HttpResponse::InternalServerError().json(error_response(error_msg, attempts, started)));
+            }
+        };
+
+        let search_templates = templates.clone();
+        let region = req.region;
+        let found = tokio::task::spawn_blocking(move || search(&screenshot, &search_templates, region))
+            .await
+            .ok()
+            .flatten();
+
+        if let Some(m) = found {
+            best_score = best_score.max(m.score);
+            if m.score >= req.confidence {
+                return Ok(HttpResponse::Ok().json(WaitForImageResponse {
+                    success: true,
+                    found: true,
+                    x: Some(m.x as i64),
+                    y: Some(m.y as i64),
+                    width: Some(m.width as i64),
+                    height: Some(m.height as i64),
+                    center_x: Some((m.x + m.width / 2) as i64),
+                    center_y: Some((m.y + m.height / 2) as i64),
+                    confidence: m.score,
+                    attempts,
+                    elapsed_ms: started.elapsed().as_millis(),
+                    error: None,
+                }));
+            }
+        }
+
+        if Instant::now() + Duration::from_millis(req.interval_ms) >= deadline {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(req.interval_ms)).await;
+    }
+
+    Ok(HttpResponse::Ok().json(WaitForImageResponse {
+        success: true,
+        found: false,
+        x: None,
+        y: None,
+        width: None,
+        height: None,
+        center_x: None,
+        center_y: None,
+        confidence: best_score.max(0.0),
+        attempts,
+        elapsed_ms: started.elapsed().as_millis(),
+        error: None,
+    }))
+}
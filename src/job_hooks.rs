@@ -0,0 +1,187 @@
+//! Local scripts run when any job starts, finishes or fails, so a site can
+//! tie the agent into its own systems (a CMDB, a chat bot, an audit trail)
+//! without a first-class integration.
+//!
+//! They are listed in the JSON file named by `AGENT_JOB_HOOKS_FILE`:
+//!
+//! ```json
+//! {"started": [{"command": "/opt/site/job-started.sh"}],
+//!  "finished": [{"command": "/opt/site/record.sh", "timeout_secs": 30}],
+//!  "failed": [{"command": "/opt/site/page.sh"}]}
+//! ```
+//!
+//! `finished` scripts run for jobs that exited 0, `failed` ones for jobs that
+//! exited non-zero, timed out or could not be run. Unlike the hook sets of
+//! `AGENT_HOOKS_FILE` they apply to every job, run after the fact and can't
+//! change its outcome. Scripts run through the shell with the job described
+//! in `AGENT_JOB_*` variables and its CloudEvent as JSON on stdin; a script
+//! that fails or times out is logged, and the next one still runs.
+
+use actix_web::web;
+use serde::Deserialize;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command as TokioCommand;
+use tokio::sync::broadcast;
+
+use crate::config::AppConfig;
+use crate::events::{self, CloudEvent};
+use crate::jobs::{JobRegistry, JobStatus};
+use crate::{cloud, log_error, timekeeping};
+
+/// Script output quoted in the error log when it fails.
+const MAX_ERROR_OUTPUT: usize = 1024;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct JobHooks {
+    #[serde(default)]
+    pub started: Vec<JobHook>,
+    #[serde(default)]
+    pub finished: Vec<JobHook>,
+    #[serde(default)]
+    pub failed: Vec<JobHook>,
+}
+
+impl JobHooks {
+    pub fn is_empty(&self) -> bool {
+        self.started.is_empty() && self.finished.is_empty() && self.failed.is_empty()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct JobHook {
+    pub command: String,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+pub fn load(path: &Path) -> Result<JobHooks, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&contents).map_err(|e| format!("Invalid job hooks file {}: {}", path.display(), e))
+}
+
+/// The job's description for its hook scripts.
+fn job_env(event: &CloudEvent, stage: &str, job_id: &str, jobs: &JobRegistry) -> Vec<(&'static str, String)> {
+    let mut vars = vec![("AGENT_JOB_EVENT", stage.to_string()), ("AGENT_JOB_ID", job_id.to_string())];
+    let text = |name: &str| event.data.get(name).and_then(|value| value.as_str()).map(str::to_string);
+    let Some(job) = jobs.get(job_id) else {
+        // Only the event to go on.
+        vars.extend(text("command").map(|command| ("AGENT_JOB_COMMAND", command)));
+        vars.extend(text("error").map(|error| ("AGENT_JOB_ERROR", error)));
+        return vars;
+    };
+    vars.push(("AGENT_JOB_COMMAND", job.command));
+    vars.push(("AGENT_JOB_STATUS", job.status.name().to_string()));
+    vars.push(("AGENT_JOB_STARTED_AT", job.started_at));
+    for (name, value) in [
+        ("AGENT_JOB_FINISHED_AT", job.finished_at),
+        ("AGENT_JOB_RETURN_CODE", job.return_code.map(|code| code.to_string())),
+        ("AGENT_JOB_ERROR", text("error")),
+        ("AGENT_JOB_TAG", job.tag),
+        ("AGENT_JOB_API_KEY", job.api_key),
+        ("AGENT_JOB_RUN_AS", job.run_as),
+        ("AGENT_JOB_LIFECYCLE", job.lifecycle),
+        ("AGENT_JOB_LOG_FILE", job.log_file),
+    ] {
+        if let Some(value) = value {
+            vars.push((name, value));
+        }
+    }
+    vars
+}
+
+async fn run_hook(hook: &JobHook, vars: &[(&'static str, String)], event: &[u8]) -> Result<(), String> {
+    let mut cmd = if cfg!(target_os = "windows") {
+        let mut cmd = TokioCommand::new("cmd");
+        cmd.args(["/C", &hook.command]);
+        cmd
+    } else {
+        let mut cmd = TokioCommand::new("sh");
+        cmd.arg("-c").arg(&hook.command);
+        cmd
+    };
+    cmd.envs(vars.iter().map(|(name, value)| (*name, value)))
+        .envs(cloud::env())
+        .envs(timekeeping::env())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = cmd.spawn().map_err(|e| format!("Failed to run job hook: {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A script that doesn't read its stdin closes the pipe early.
+        let _ = stdin.write_all(event).await;
+    }
+    let timeout = Duration::from_secs(hook.timeout_secs);
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return Err(format!("Job hook failed: {}", e)),
+        Err(_) => return Err(format!("Job hook timed out after {}s", timeout.as_secs())),
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let message = if stderr.trim().is_empty() { stdout.trim() } else { stderr.trim() };
+    let message: String = message.chars().take(MAX_ERROR_OUTPUT).collect();
+    Err(format!("Job hook exited with {:?}: {}", output.status.code(), message))
+}
+
+async fn run_hooks(hooks: &[JobHook], stage: &'static str, event: CloudEvent, jobs: &JobRegistry) {
+    let Some(job_id) = event.jobid.as_deref() else {
+        return;
+    };
+    let vars = job_env(&event, stage, job_id, jobs);
+    let json = serde_json::to_vec(&event).unwrap_or_default();
+    for hook in hooks {
+        if let Err(e) = run_hook(hook, &vars, &json).await {
+            log_error(&format!("job_hooks/{}", stage), &e, Some(&hook.command));
+        }
+    }
+}
+
+/// Whether a finished job exited 0.
+fn succeeded(event: &CloudEvent, jobs: &JobRegistry) -> bool {
+    match event.jobid.as_deref().and_then(|job_id| jobs.get(job_id)) {
+        Some(job) => job.status == JobStatus::Finished && job.return_code == Some(0),
+        None => event.data.get("return_code").and_then(|code| code.as_i64()) == Some(0),
+    }
+}
+
+/// Run the hooks for every job event from `events`; runs until the bus
+/// closes. Subscribe before jobs can start so none is missed.
+pub async fn run(mut events: broadcast::Receiver<CloudEvent>, config: web::Data<AppConfig>, jobs: web::Data<JobRegistry>) {
+    let hooks = &config.job_hooks;
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log_error("job_hooks", &format!("Job hooks fell behind and skipped {} events", missed), None);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let (stage, stage_hooks) = match event.event_type.as_str() {
+            events::JOB_STARTED => ("started", &hooks.started),
+            events::JOB_FINISHED if succeeded(&event, &jobs) => ("finished", &hooks.finished),
+            events::JOB_FINISHED | events::JOB_FAILED => ("failed", &hooks.failed),
+            _ => continue,
+        };
+        if stage_hooks.is_empty() {
+            continue;
+        }
+        // Each event's scripts run in order, but a slow one doesn't hold up
+        // other jobs'.
+        let (stage_hooks, jobs) = (stage_hooks.clone(), jobs.clone());
+        tokio::spawn(async move {
+            run_hooks(&stage_hooks, stage, event, &jobs).await;
+        });
+    }
+}
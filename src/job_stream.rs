@@ -0,0 +1,180 @@
+//! `GET /jobs/{id}/stream` - a job's output as Server-Sent Events while it
+//! runs - and the short-lived tokens that open it, so a browser dashboard
+//! can attach to a job without the API key in its frontend code.
+//!
+//! A backend holding the key asks `POST /jobs/{id}/stream-token` for a
+//! token, and hands it to the browser, which opens
+//! `/jobs/{id}/stream?token=...` with `EventSource` (which can't send
+//! headers). A token is an HMAC of the job ID and an expiry, signed with a
+//! key the agent makes up at startup: it opens that job's stream, output
+//! and log ([`TOKEN_ROUTES`]) and nothing else, for `ttl_secs` (default
+//! 300, at most 3600), and no longer once the agent restarts.
+
+use actix_web::dev::{Path, ResourceDef, ServiceRequest};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use base64::Engine;
+use futures_util::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+use crate::config::AppConfig;
+use crate::jobs::{Job, JobRegistry};
+use crate::output::Stream;
+use crate::{auth, clock, log_error};
+
+const DEFAULT_TTL_SECS: u64 = 300;
+const MAX_TTL_SECS: u64 = 3600;
+
+/// Routes a token for the job opens.
+pub const TOKEN_ROUTES: &[&str] = &["/jobs/{id}/stream", "/jobs/{id}/output", "/jobs/{id}/log"];
+
+/// Signs tokens; made up at startup.
+fn signing_key() -> &'static [u8] {
+    static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+    KEY.get_or_init(|| [uuid::Uuid::new_v4().into_bytes(), uuid::Uuid::new_v4().into_bytes()].concat())
+}
+
+fn mac(payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key()).expect("HMAC takes keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// A token for `job_id`'s stream, valid until `expires` (Unix seconds).
+fn issue(job_id: &str, expires: i64) -> String {
+    let payload = format!("{}.{}", expires, job_id);
+    let signature = mac(&payload).finalize().into_bytes();
+    let encode = |bytes: &[u8]| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+    format!("{}.{}", encode(payload.as_bytes()), encode(&signature))
+}
+
+/// Check `token` opens `job_id`'s routes now.
+fn verify(token: &str, job_id: &str) -> Result<(), String> {
+    let invalid = || "Invalid stream token".to_string();
+    let decode = |part: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(part).map_err(|_| invalid());
+    let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+    let payload = String::from_utf8(decode(payload)?).map_err(|_| invalid())?;
+    mac(&payload).verify_slice(&decode(signature)?).map_err(|_| invalid())?;
+    let (expires, token_job) = payload.split_once('.').ok_or_else(invalid)?;
+    if token_job != job_id {
+        return Err("The stream token is for another job".to_string());
+    }
+    if expires.parse::<i64>().map_err(|_| invalid())? < chrono::Utc::now().timestamp() {
+        return Err("The stream token has expired".to_string());
+    }
+    Ok(())
+}
+
+fn failure(status: StatusCode, error: String) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({
+        "success": false,
+        "error": error,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    ttl_secs: Option<u64>,
+}
+
+/// POST /jobs/{id}/stream-token?ttl_secs=... - a token that opens the job's
+/// stream, output and log without other credentials.
+pub async fn stream_token(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<TokenQuery>,
+    jobs: web::Data<JobRegistry>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    if let Err(error_msg) = auth::authenticate(&config, &http_req).await {
+        log_error("/jobs/{id}/stream-token", &error_msg, None);
+        return Ok(failure(StatusCode::UNAUTHORIZED, error_msg));
+    }
+    if jobs.get(&job_id).is_none() {
+        return Ok(failure(StatusCode::NOT_FOUND, "Job not found".to_string()));
+    }
+    let ttl_secs = query.ttl_secs.unwrap_or(DEFAULT_TTL_SECS).clamp(1, MAX_TTL_SECS);
+    let expires = chrono::Utc::now() + chrono::Duration::seconds(ttl_secs as i64);
+    let token = issue(&job_id, expires.timestamp());
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "job_id": job_id,
+        "token": token,
+        "expires_at": clock::format(&expires),
+        "stream_url": format!("/jobs/{}/stream?token={}", job_id, token),
+    })))
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    token: Option<String>,
+}
+
+/// Whether `req`, for `route`, may go ahead on the stream token it
+/// carries; `None` when it carries none or `route` takes none, and needs
+/// other credentials.
+pub fn check_token(req: &ServiceRequest, route: &str) -> Option<Result<(), String>> {
+    if !TOKEN_ROUTES.contains(&route) {
+        return None;
+    }
+    let token = web::Query::<StreamQuery>::from_query(req.query_string()).ok()?.into_inner().token?;
+    // Middleware runs before routing fills in `match_info`.
+    let mut path = Path::new(req.path());
+    ResourceDef::new(route).capture_match_info(&mut path);
+    Some(verify(&token, path.get("id").unwrap_or_default()))
+}
+
+fn frame(event: &str, data: &serde_json::Value) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+fn output_frame(stream: Stream, text: &str) -> Bytes {
+    let event = match stream {
+        Stream::Stdout => "stdout",
+        Stream::Stderr => "stderr",
+    };
+    frame(event, &serde_json::json!({ "text": text }))
+}
+
+fn job_frame(event: &str, job: &Job) -> Bytes {
+    frame(event, &serde_json::to_value(job).unwrap_or_default())
+}
+
+/// GET /jobs/{id}/stream?token=... - the job as a `job` event, the output it
+/// has printed and prints as `stdout` and `stderr` events, then the job as
+/// it ended as a `done` event.
+pub async fn stream_job(path: web::Path<String>, jobs: web::Data<JobRegistry>) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    let (Some(job), Some((stdout, stderr, live))) = (jobs.get(&job_id), jobs.watch(&job_id)) else {
+        return Ok(failure(StatusCode::NOT_FOUND, "Job not found".to_string()));
+    };
+
+    let mut backlog = vec![job_frame("job", &job)];
+    if !stdout.is_empty() {
+        backlog.push(output_frame(Stream::Stdout, &stdout));
+    }
+    if !stderr.is_empty() {
+        backlog.push(output_frame(Stream::Stderr, &stderr));
+    }
+    let live = stream::unfold(live, |live| async move {
+        let mut live = live?;
+        let frame = match live.recv().await {
+            Ok((stream, text)) => output_frame(stream, &text),
+            Err(broadcast::error::RecvError::Lagged(missed)) => frame("lagged", &serde_json::json!({ "missed": missed })),
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((frame, Some(live)))
+    });
+    let done = stream::once(async move { jobs.get(&job_id).map(|job| job_frame("done", &job)).unwrap_or_default() });
+    let body = stream::iter(backlog).chain(live).chain(done).map(Ok::<_, actix_web::Error>);
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body))
+}
@@ -0,0 +1,1002 @@
+//! In-memory registry of executions, keyed by job id.
+
+use actix_web::{http::StatusCode, web, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::ChildStdin;
+use tokio::sync::broadcast;
+
+use crate::clock;
+use crate::compress;
+use crate::config::AppConfig;
+use crate::history::JobHistory;
+use crate::hooks::HookResult;
+use crate::latency;
+use crate::metrics::{Family, Histogram, Sample};
+use crate::listing::{self, ListQuery, ListSpec};
+use crate::log_error;
+use crate::offload::StoredObject;
+use crate::output::Stream;
+use crate::playbook::StepResult;
+use crate::policy::RuleMatch;
+use crate::pressure::HostCheck;
+use crate::progress::Progress;
+use crate::usage::Usage;
+
+/// Output kept per job for the history search index.
+const MAX_INDEXED_OUTPUT: usize = 1024 * 1024;
+
+/// Jobs that are done kept in memory by default (`AGENT_JOB_RETENTION`).
+pub const DEFAULT_RETENTION: usize = 1000;
+
+/// How long `/jobs/{id}/wait` waits by default, and at most.
+const DEFAULT_WAIT: u64 = 60;
+const MAX_WAIT: u64 = 600;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for its execution window to open.
+    Queued,
+    Running,
+    Finished,
+    Failed,
+    /// A guard did not hold, so the command was never started.
+    Skipped,
+    /// The agent stopped or one of its tasks panicked while the job was
+    /// running or queued; its outcome is unknown.
+    Interrupted,
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "finished" => Ok(JobStatus::Finished),
+            "failed" => Ok(JobStatus::Failed),
+            "skipped" => Ok(JobStatus::Skipped),
+            "interrupted" => Ok(JobStatus::Interrupted),
+            _ => Err(format!(
+                "Unknown status {:?}; expected queued, running, finished, failed, skipped or interrupted",
+                value
+            )),
+        }
+    }
+}
+
+impl JobStatus {
+    /// As in job records, e.g. `finished`.
+    pub fn name(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Finished => "finished",
+            JobStatus::Failed => "failed",
+            JobStatus::Skipped => "skipped",
+            JobStatus::Interrupted => "interrupted",
+        }
+    }
+
+    /// Whether the job has reached its final state.
+    pub fn is_done(self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub command: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    pub started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<Progress>,
+    /// Label grouping the job with others like it in `/metrics`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Agent lifecycle stage (`startup` or `shutdown`) that ran the job.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lifecycle: Option<String>,
+    /// Name of the API key the job was submitted with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Subject of the client certificate the job was submitted with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<String>,
+    /// Local user the command ran as, when forced by the API key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_as: Option<String>,
+    /// Policy rules that matched the request.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub policy: Vec<RuleMatch>,
+    /// Job whose `on_success` or `on_failure` queued this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follows: Option<String>,
+    /// Named lock held while the job runs, checked by `lock_free` guards.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock: Option<String>,
+    /// Locks a queued job's `lock_free` guards will check, for `/admin/queue`.
+    #[serde(skip)]
+    pub lock_guards: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<String>,
+    /// Pre/post hooks that ran around the command.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookResult>,
+    /// Per-step results of a playbook job.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steps: Option<Vec<StepResult>>,
+    /// Host load check made before starting, when thresholds are configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_check: Option<HostCheck>,
+    /// When a queued job is expected to start.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queued_until: Option<String>,
+    /// Random delay added to the job's start, from the request's `splay_secs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub splay_secs: Option<u64>,
+    /// CPU time and peak memory of the command, where measured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// Per-job output log on disk, when enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<String>,
+    /// Files stamped with this job's provenance.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stamped_files: Vec<String>,
+    /// Files uploaded to object storage.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<StoredObject>,
+    /// When the client of an `/execute` request disconnected before getting
+    /// the result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_disconnected_at: Option<String>,
+    /// When `DELETE /jobs/{id}` asked for the job to be cancelled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancelled_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Job {
+    pub fn new(id: &str, command: &str, pid: Option<u32>) -> Self {
+        Job {
+            id: id.to_string(),
+            command: command.to_string(),
+            status: JobStatus::Running,
+            pid,
+            started_at: clock::now(),
+            finished_at: None,
+            return_code: None,
+            progress: None,
+            tag: None,
+            lifecycle: None,
+            api_key: None,
+            client_certificate: None,
+            run_as: None,
+            policy: Vec::new(),
+            follows: None,
+            lock: None,
+            lock_guards: Vec::new(),
+            skip_reason: None,
+            hooks: Vec::new(),
+            steps: None,
+            host_check: None,
+            queued_until: None,
+            splay_secs: None,
+            usage: None,
+            log_file: None,
+            stamped_files: Vec::new(),
+            objects: Vec::new(),
+            client_disconnected_at: None,
+            cancelled_at: None,
+            error: None,
+        }
+    }
+
+    /// How long the job ran, once it is done.
+    pub fn duration(&self) -> Option<Duration> {
+        let finished_at = self.finished_at.as_deref()?;
+        let started = chrono::DateTime::parse_from_rfc3339(&self.started_at).ok()?;
+        let finished = chrono::DateTime::parse_from_rfc3339(finished_at).ok()?;
+        (finished - started).to_std().ok()
+    }
+}
+
+/// Resource usage of all measured jobs with one tag.
+#[derive(Clone, Copy, Default)]
+pub struct UsageTotals {
+    pub jobs: u64,
+    pub cpu_seconds: f64,
+    pub peak_memory_bytes_sum: u64,
+    pub peak_memory_bytes_max: u64,
+}
+
+/// Upper bounds of the job duration buckets, in seconds.
+const DURATION_BUCKETS: [f64; 12] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 14400.0];
+
+/// Upper bounds of the job output size buckets, in bytes.
+const OUTPUT_BUCKETS: [f64; 9] = [0.0, 1024.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0];
+
+/// Outcomes, durations and output sizes of all jobs, for `/metrics`.
+struct JobStats {
+    /// Per outcome: `success`, `failure`, `skipped` or `interrupted`.
+    outcomes: BTreeMap<&'static str, u64>,
+    duration: Histogram,
+    output_bytes: Histogram,
+}
+
+impl Default for JobStats {
+    fn default() -> Self {
+        JobStats {
+            outcomes: BTreeMap::new(),
+            duration: Histogram::new(&DURATION_BUCKETS),
+            output_bytes: Histogram::new(&OUTPUT_BUCKETS),
+        }
+    }
+}
+
+/// Output pieces a watcher may fall behind by before it misses some.
+const WATCH_CAPACITY: usize = 1024;
+
+pub type StdinHandle = Arc<tokio::sync::Mutex<Option<ChildStdin>>>;
+
+/// Output a running job prints, for as long as it runs.
+pub type Watcher = broadcast::Receiver<(Stream, String)>;
+
+/// Notified when a running job is to be cancelled; the task that owns the
+/// job's process stops it.
+pub type CancelHandle = Arc<tokio::sync::Notify>;
+
+/// The most recent output of a job, for `/jobs/{id}/output`.
+#[derive(Clone, Default)]
+struct RetainedOutput {
+    stdout: String,
+    stderr: String,
+    /// Bytes dropped from the start of each stream to stay within the limit.
+    stdout_dropped: usize,
+    stderr_dropped: usize,
+}
+
+/// Append `text` to `buffer`, then drop its oldest bytes beyond `limit`.
+fn push_tail(buffer: &mut String, dropped: &mut usize, text: &str, limit: usize) {
+    buffer.push_str(text);
+    if buffer.len() > limit {
+        let mut cut = buffer.len() - limit;
+        while !buffer.is_char_boundary(cut) {
+            cut += 1;
+        }
+        buffer.drain(..cut);
+        *dropped += cut;
+    }
+}
+
+/// Id of a running job in `jobs` holding the named lock.
+fn holder(jobs: &HashMap<String, Job>, lock: &str) -> Option<String> {
+    jobs.values()
+        .find(|job| job.status == JobStatus::Running && job.lock.as_deref() == Some(lock))
+        .map(|job| job.id.clone())
+}
+
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Open stdin pipes of running jobs started with `keep_stdin_open`.
+    stdin: Mutex<HashMap<String, StdinHandle>>,
+    /// Running jobs that can be cancelled.
+    cancel: Mutex<HashMap<String, CancelHandle>>,
+    /// Output of running jobs, collected only while history is enabled.
+    output: Mutex<HashMap<String, String>>,
+    /// Recent stdout and stderr of every job, up to `output_limit` bytes each.
+    retained: Mutex<HashMap<String, RetainedOutput>>,
+    /// Output of running jobs someone streams, as it is printed.
+    watchers: Mutex<HashMap<String, broadcast::Sender<(Stream, String)>>>,
+    output_limit: usize,
+    /// Jobs that are done, the one done longest ago first; beyond
+    /// `retention` they are forgotten, with their output.
+    done_jobs: Mutex<VecDeque<String>>,
+    retention: usize,
+    /// Usage per tag (`""` for untagged jobs), kept after jobs are gone.
+    usage: Mutex<BTreeMap<String, UsageTotals>>,
+    /// Bytes of stdout and stderr each running job has printed.
+    output_bytes: Mutex<HashMap<String, u64>>,
+    stats: Mutex<JobStats>,
+    history: Option<Arc<JobHistory>>,
+    /// Woken whenever a job reaches its final state.
+    done: tokio::sync::Notify,
+}
+
+impl JobRegistry {
+    pub fn with_history(history: Option<Arc<JobHistory>>) -> Self {
+        JobRegistry {
+            history,
+            retention: DEFAULT_RETENTION,
+            ..Default::default()
+        }
+    }
+
+    /// Keep the `retention` jobs done last in memory, at least one; older
+    /// ones are only in the history store.
+    pub fn with_retention(mut self, retention: usize) -> Self {
+        self.retention = retention.max(1);
+        self
+    }
+
+    /// Keep up to `limit` bytes of each job's stdout and stderr; 0 keeps none.
+    pub fn with_output_limit(mut self, limit: usize) -> Self {
+        self.output_limit = limit;
+        self
+    }
+
+    pub fn insert(&self, job: Job) {
+        self.record_start(&job);
+        self.jobs.lock().unwrap().insert(job.id.clone(), job);
+    }
+
+    /// Insert `job` unless a running job holds one of `locks`, checking and
+    /// inserting under one lock so two jobs can't both find a lock free;
+    /// `Err` is why the job can't run.
+    pub fn try_acquire_locks(&self, job: &Job, locks: &[String]) -> Result<(), String> {
+        {
+            let mut registry = self.jobs.lock().unwrap();
+            for lock in locks {
+                if let Some(holder) = holder(&registry, lock) {
+                    return Err(format!("Lock {:?} is held by job {}", lock, holder));
+                }
+            }
+            registry.insert(job.id.clone(), job.clone());
+        }
+        self.record_start(job);
+        Ok(())
+    }
+
+    fn record_start(&self, job: &Job) {
+        let Some(history) = self.history.clone() else {
+            return;
+        };
+        if matches!(job.status, JobStatus::Running | JobStatus::Queued) {
+            let job = job.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = history.record_start(&job) {
+                    log_error("history", &format!("Failed to record job start: {}", e), Some(&job.command));
+                }
+            });
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Id of a running job holding the named lock.
+    pub fn lock_holder(&self, lock: &str) -> Option<String> {
+        holder(&self.jobs.lock().unwrap(), lock)
+    }
+
+    /// Apply `f` to a job if it exists.
+    pub fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+        }
+    }
+
+    pub fn attach_stdin(&self, id: &str, stdin: ChildStdin) {
+        let handle = Arc::new(tokio::sync::Mutex::new(Some(stdin)));
+        self.stdin.lock().unwrap().insert(id.to_string(), handle);
+    }
+
+    pub fn stdin(&self, id: &str) -> Option<StdinHandle> {
+        self.stdin.lock().unwrap().get(id).cloned()
+    }
+
+    /// Make a running job cancellable; its task waits on the handle.
+    pub fn attach_cancel(&self, id: &str) -> CancelHandle {
+        let handle = CancelHandle::default();
+        self.cancel.lock().unwrap().insert(id.to_string(), handle.clone());
+        handle
+    }
+
+    /// Ask a running job's task to stop it; false if it can't be cancelled.
+    pub fn cancel(&self, id: &str) -> bool {
+        let Some(handle) = self.cancel.lock().unwrap().get(id).cloned() else {
+            return false;
+        };
+        self.update(id, |job| {
+            job.cancelled_at.get_or_insert_with(clock::now);
+        });
+        handle.notify_one();
+        true
+    }
+
+    /// Whether `DELETE /jobs/{id}`, or a client disconnecting with
+    /// cancel-on-disconnect, cancelled the job.
+    pub fn is_cancelled(&self, id: &str) -> bool {
+        self.get(id).is_some_and(|job| job.cancelled_at.is_some())
+    }
+
+    pub fn append_output(&self, id: &str, stream: Stream, text: &str) {
+        *self.output_bytes.lock().unwrap().entry(id.to_string()).or_default() += text.len() as u64;
+        {
+            // Held while sending, so a new watcher sees each piece once.
+            let mut retained = self.retained.lock().unwrap();
+            if self.output_limit > 0 {
+                let retained = retained.entry(id.to_string()).or_default();
+                match stream {
+                    Stream::Stdout => push_tail(&mut retained.stdout, &mut retained.stdout_dropped, text, self.output_limit),
+                    Stream::Stderr => push_tail(&mut retained.stderr, &mut retained.stderr_dropped, text, self.output_limit),
+                }
+            }
+            if let Some(watcher) = self.watchers.lock().unwrap().get(id) {
+                let _ = watcher.send((stream, text.to_string()));
+            }
+        }
+        if self.history.is_none() {
+            return;
+        }
+        let mut output = self.output.lock().unwrap();
+        let buffer = output.entry(id.to_string()).or_default();
+        let room = MAX_INDEXED_OUTPUT.saturating_sub(buffer.len());
+        if room > 0 {
+            let mut end = room.min(text.len());
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            buffer.push_str(&text[..end]);
+        }
+    }
+
+    /// Record a job's usage on it and in its tag's totals.
+    pub fn record_usage(&self, id: &str, usage: Usage) {
+        let mut tag = None;
+        self.update(id, |job| {
+            job.usage = Some(usage);
+            tag = Some(job.tag.clone().unwrap_or_default());
+        });
+        let Some(tag) = tag else {
+            return;
+        };
+        let mut totals = self.usage.lock().unwrap();
+        let totals = totals.entry(tag).or_default();
+        totals.jobs += 1;
+        totals.cpu_seconds += usage.cpu_seconds;
+        totals.peak_memory_bytes_sum += usage.peak_memory_bytes;
+        totals.peak_memory_bytes_max = totals.peak_memory_bytes_max.max(usage.peak_memory_bytes);
+    }
+
+    pub fn usage_totals(&self) -> BTreeMap<String, UsageTotals> {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Count a job that is done in the outcome, duration and output size
+    /// metrics.
+    fn count(&self, job: &Job) {
+        let output_bytes = self.output_bytes.lock().unwrap().remove(&job.id).unwrap_or_default();
+        let outcome = match job.status {
+            JobStatus::Finished if job.return_code == Some(0) => "success",
+            JobStatus::Skipped => "skipped",
+            JobStatus::Interrupted => "interrupted",
+            _ => "failure",
+        };
+        let mut stats = self.stats.lock().unwrap();
+        *stats.outcomes.entry(outcome).or_default() += 1;
+        if job.status == JobStatus::Skipped {
+            return;
+        }
+        if let Some(duration) = job.duration() {
+            stats.duration.observe(duration.as_secs_f64());
+        }
+        stats.output_bytes.observe(output_bytes as f64);
+    }
+
+    /// Job outcome counts, duration and output size histograms, and how many
+    /// jobs are running and queued now.
+    pub fn stats_families(&self) -> Vec<Family> {
+        let (mut running, mut queued) = (0, 0);
+        for job in self.jobs.lock().unwrap().values() {
+            match job.status {
+                JobStatus::Running => running += 1,
+                JobStatus::Queued => queued += 1,
+                _ => {}
+            }
+        }
+        let stats = self.stats.lock().unwrap();
+        vec![
+            Family {
+                name: "machine_agent_jobs_total",
+                kind: "counter",
+                help: "Jobs done, per outcome: success (exit code 0), failure, skipped or interrupted.",
+                samples: stats
+                    .outcomes
+                    .iter()
+                    .map(|(outcome, count)| Sample::new("", vec![("outcome", outcome.to_string())], *count as f64))
+                    .collect(),
+            },
+            Family {
+                name: "machine_agent_job_duration_seconds",
+                kind: "histogram",
+                help: "How long jobs ran, from start to finish.",
+                samples: stats.duration.samples(Vec::new()),
+            },
+            Family {
+                name: "machine_agent_job_output_bytes",
+                kind: "histogram",
+                help: "Bytes of stdout plus stderr jobs printed.",
+                samples: stats.output_bytes.samples(Vec::new()),
+            },
+            Family {
+                name: "machine_agent_jobs_running",
+                kind: "gauge",
+                help: "Jobs running now.",
+                samples: vec![Sample::new("", Vec::new(), running as f64)],
+            },
+            Family {
+                name: "machine_agent_jobs_queued",
+                kind: "gauge",
+                help: "Jobs waiting to start now.",
+                samples: vec![Sample::new("", Vec::new(), queued as f64)],
+            },
+        ]
+    }
+
+    pub fn finish(&self, id: &str, return_code: Option<i32>) {
+        self.stdin.lock().unwrap().remove(id);
+        self.cancel.lock().unwrap().remove(id);
+        self.update(id, |job| {
+            job.status = JobStatus::Finished;
+            job.return_code = return_code;
+            job.finished_at = Some(clock::now());
+        });
+        self.persist(id);
+    }
+
+    pub fn fail(&self, id: &str, error: &str) {
+        self.stdin.lock().unwrap().remove(id);
+        self.cancel.lock().unwrap().remove(id);
+        self.update(id, |job| {
+            job.status = JobStatus::Failed;
+            job.error = Some(error.to_string());
+            job.finished_at = Some(clock::now());
+        });
+        self.persist(id);
+    }
+
+    pub fn interrupt(&self, id: &str, error: &str) {
+        self.stdin.lock().unwrap().remove(id);
+        self.cancel.lock().unwrap().remove(id);
+        self.update(id, |job| {
+            job.status = JobStatus::Interrupted;
+            job.error = Some(error.to_string());
+            job.finished_at = Some(clock::now());
+        });
+        self.persist(id);
+    }
+
+    /// Mark jobs left unfinished by a previous run of the agent as interrupted
+    /// in history; called once at startup, after resuming playbooks.
+    pub fn reconcile_history(&self) -> Vec<(String, String)> {
+        let Some(history) = &self.history else {
+            return Vec::new();
+        };
+        let keep: Vec<String> = self.jobs.lock().unwrap().keys().cloned().collect();
+        match history.interrupt_unfinished(&keep, "The agent stopped while the job was running") {
+            Ok(interrupted) => interrupted,
+            Err(e) => {
+                log_error("startup", &format!("Failed to reconcile job history: {}", e), None);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn skip(&self, id: &str, reason: &str) {
+        self.update(id, |job| {
+            job.status = JobStatus::Skipped;
+            job.skip_reason = Some(reason.to_string());
+            job.finished_at = Some(clock::now());
+        });
+        self.persist(id);
+    }
+
+    /// The job once it is done, or as it is after `timeout`; `None` if
+    /// there is no such job.
+    pub async fn wait(&self, id: &str, timeout: Duration) -> Option<Job> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let done = self.done.notified();
+            let mut done = std::pin::pin!(done);
+            // Registered before looking, so a job finishing in between still wakes us.
+            done.as_mut().enable();
+            let job = self.get(id)?;
+            if job.status.is_done() || tokio::time::timeout_at(deadline, done).await.is_err() {
+                return self.get(id);
+            }
+        }
+    }
+
+    /// The output `id` has printed so far (stdout, stderr), and what it
+    /// prints from now on until it is done; `None` when there is no such job.
+    pub fn watch(&self, id: &str) -> Option<(String, String, Option<Watcher>)> {
+        let retained = self.retained.lock().unwrap();
+        let job = self.get(id)?;
+        let output = retained.get(id).cloned().unwrap_or_default();
+        let live = (!job.status.is_done()).then(|| {
+            let mut watchers = self.watchers.lock().unwrap();
+            watchers.entry(id.to_string()).or_insert_with(|| broadcast::channel(WATCH_CAPACITY).0).subscribe()
+        });
+        Some((output.stdout, output.stderr, live))
+    }
+
+    /// Remember `id` as done, and forget the jobs done longest ago beyond
+    /// `retention`, with their output.
+    fn retire(&self, id: &str) {
+        let forgotten: Vec<String> = {
+            let mut done_jobs = self.done_jobs.lock().unwrap();
+            if !done_jobs.iter().any(|done| done == id) {
+                done_jobs.push_back(id.to_string());
+            }
+            let excess = done_jobs.len().saturating_sub(self.retention);
+            done_jobs.drain(..excess).collect()
+        };
+        if forgotten.is_empty() {
+            return;
+        }
+        // In the order `watch` takes them.
+        let mut retained = self.retained.lock().unwrap();
+        let mut jobs = self.jobs.lock().unwrap();
+        for id in &forgotten {
+            retained.remove(id);
+            jobs.remove(id);
+        }
+    }
+
+    /// Wake waiters, and hand a completed job and its output over to the
+    /// history store.
+    fn persist(&self, id: &str) {
+        self.done.notify_waiters();
+        {
+            // Ends the streams of whoever watches the job. Under the lock
+            // `watch` looks at the job under, so none is added after this.
+            let _retained = self.retained.lock().unwrap();
+            self.watchers.lock().unwrap().remove(id);
+        }
+        let Some(job) = self.get(id) else {
+            return;
+        };
+        latency::job_done(&job);
+        self.count(&job);
+        self.retire(id);
+        let Some(history) = self.history.clone() else {
+            return;
+        };
+        let output = self.output.lock().unwrap().remove(id).unwrap_or_default();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = history.record(&job, &output) {
+                log_error("history", &format!("Failed to record job: {}", e), Some(&job.command));
+            }
+        });
+    }
+}
+
+#[derive(Serialize)]
+struct JobResponse {
+    success: bool,
+    #[serde(flatten)]
+    job: Option<Job>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// GET /jobs/{id} - current state of a job, including reported progress.
+pub async fn get_job(path: web::Path<String>, jobs: web::Data<JobRegistry>) -> ActixResult<HttpResponse> {
+    match jobs.get(&path.into_inner()) {
+        Some(job) => Ok(HttpResponse::Ok().json(JobResponse {
+            success: true,
+            job: Some(job),
+            error: None,
+        })),
+        None => Ok(HttpResponse::NotFound().json(JobResponse {
+            success: false,
+            job: None,
+            error: Some("Job not found".to_string()),
+        })),
+    }
+}
+
+/// DELETE /jobs/{id} - cancel a running job: its process tree gets SIGTERM,
+/// then SIGKILL after `AGENT_CANCEL_GRACE_SECS` (killed outright on Windows).
+pub async fn cancel_job(path: web::Path<String>, jobs: web::Data<JobRegistry>) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    let (status, error) = match jobs.get(&job_id) {
+        None => (StatusCode::NOT_FOUND, "Job not found"),
+        Some(job) if job.status.is_done() => (StatusCode::CONFLICT, "Job is not running"),
+        Some(_) if !jobs.cancel(&job_id) => (
+            StatusCode::CONFLICT,
+            "Job can't be cancelled; only /execute, /execute-async and /shell jobs that are running can",
+        ),
+        Some(_) => {
+            return Ok(HttpResponse::Accepted().json(JobResponse {
+                success: true,
+                job: jobs.get(&job_id),
+                error: None,
+            }));
+        }
+    };
+    Ok(HttpResponse::build(status).json(JobResponse {
+        success: false,
+        job: None,
+        error: Some(error.to_string()),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct WaitQuery {
+    /// Seconds to wait at most.
+    timeout: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct WaitResponse {
+    success: bool,
+    #[serde(flatten)]
+    job: Option<Job>,
+    /// The job was still queued or running when the timeout elapsed.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    timed_out: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// GET /jobs/{id}/wait - block until the job is done or `timeout` seconds
+/// have passed, then return it as GET /jobs/{id} does.
+pub async fn wait_job(path: web::Path<String>, query: web::Query<WaitQuery>, jobs: web::Data<JobRegistry>) -> ActixResult<HttpResponse> {
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(DEFAULT_WAIT).min(MAX_WAIT));
+    match jobs.wait(&path.into_inner(), timeout).await {
+        Some(job) => Ok(HttpResponse::Ok().json(WaitResponse {
+            success: true,
+            timed_out: !job.status.is_done(),
+            job: Some(job),
+            error: None,
+        })),
+        None => Ok(HttpResponse::NotFound().json(WaitResponse {
+            success: false,
+            job: None,
+            timed_out: false,
+            error: Some("Job not found".to_string()),
+        })),
+    }
+}
+
+const JOB_LIST: ListSpec = ListSpec {
+    key: "id",
+    sort_keys: &["started_at", "finished_at", "status", "command", "return_code", "id"],
+    default_sort: "-started_at",
+};
+
+#[derive(Serialize)]
+struct JobListResponse {
+    success: bool,
+    jobs: Vec<serde_json::Value>,
+    total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct JobFilter {
+    /// Comma-separated statuses to list, e.g. `running,queued`.
+    status: Option<String>,
+}
+
+/// GET /jobs - jobs known to this agent, newest first, paginated.
+pub async fn list_jobs(filter: web::Query<JobFilter>, query: web::Query<ListQuery>, jobs: web::Data<JobRegistry>) -> ActixResult<HttpResponse> {
+    let statuses = filter
+        .status
+        .as_deref()
+        .map(|statuses| statuses.split(',').map(|status| status.trim().parse()).collect::<Result<Vec<JobStatus>, _>>())
+        .transpose();
+    let listed = statuses.and_then(|statuses| {
+        let mut listed = jobs.list();
+        if let Some(statuses) = statuses {
+            listed.retain(|job| statuses.contains(&job.status));
+        }
+        listing::paginate(&listed, &query, &JOB_LIST)
+    });
+    match listed {
+        Ok(page) => Ok(HttpResponse::Ok().json(JobListResponse {
+            success: true,
+            jobs: page.items,
+            total: page.total,
+            next_cursor: page.next_cursor,
+            error: None,
+        })),
+        Err(e) => Ok(HttpResponse::BadRequest().json(JobListResponse {
+            success: false,
+            jobs: Vec::new(),
+            total: 0,
+            next_cursor: None,
+            error: Some(e),
+        })),
+    }
+}
+
+#[derive(Serialize)]
+struct JobOutputResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<JobStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    /// Bytes of the stream's start that were dropped to stay within
+    /// `AGENT_JOB_OUTPUT_MAX_BYTES`.
+    #[serde(skip_serializing_if = "is_zero")]
+    stdout_dropped_bytes: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    stderr_dropped_bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+/// GET /jobs/{id}/output - the stdout and stderr a job has printed so far,
+/// while it runs and after it is done.
+pub async fn get_job_output(path: web::Path<String>, jobs: web::Data<JobRegistry>) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    let error = match jobs.get(&job_id) {
+        None => "Job not found".to_string(),
+        Some(_) if jobs.output_limit == 0 => "Job output is not kept (AGENT_JOB_OUTPUT_MAX_BYTES=0)".to_string(),
+        Some(job) => {
+            let output = jobs.retained.lock().unwrap().get(&job_id).cloned().unwrap_or_default();
+            return Ok(HttpResponse::Ok().json(JobOutputResponse {
+                success: true,
+                status: Some(job.status),
+                stdout: Some(output.stdout),
+                stderr: Some(output.stderr),
+                stdout_dropped_bytes: output.stdout_dropped,
+                stderr_dropped_bytes: output.stderr_dropped,
+                error: None,
+            }));
+        }
+    };
+    Ok(HttpResponse::NotFound().json(JobOutputResponse {
+        success: false,
+        status: None,
+        stdout: None,
+        stderr: None,
+        stdout_dropped_bytes: 0,
+        stderr_dropped_bytes: 0,
+        error: Some(error),
+    }))
+}
+
+/// GET /jobs/{id}/log - the job's output log as plain text, decompressed if
+/// it is stored compressed.
+pub async fn get_job_log(path: web::Path<String>, jobs: web::Data<JobRegistry>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    let error = match jobs.get(&job_id) {
+        None => "Job not found".to_string(),
+        Some(Job { log_file: None, .. }) => "Job has no log file (set AGENT_JOB_LOG_DIR)".to_string(),
+        Some(Job { log_file: Some(log_file), objects, .. }) => match compress::read(std::path::Path::new(&log_file)).await {
+            Ok(bytes) => {
+                let mut response = HttpResponse::Ok();
+                response.content_type("text/plain; charset=utf-8");
+                return Ok(config.bandwidth.respond(response, bytes));
+            }
+            Err(e) => match objects.iter().find(|object| object.file == log_file) {
+                // Deleted locally after upload (AGENT_OFFLOAD_DELETE_LOCAL).
+                Some(object) => format!("Job log was moved to {}", object.url),
+                None => {
+                    log_error("/jobs/{id}/log", &format!("Failed to read job log: {}", e), None);
+                    "Job log is no longer available".to_string()
+                }
+            },
+        },
+    };
+    Ok(HttpResponse::NotFound().json(JobResponse {
+        success: false,
+        job: None,
+        error: Some(error),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct StdinRequest {
+    #[serde(default)]
+    data: String,
+    /// Close the pipe after writing, signalling end of input.
+    #[serde(default)]
+    close: bool,
+}
+
+#[derive(Serialize)]
+struct StdinResponse {
+    success: bool,
+    job_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_written: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    closed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl StdinResponse {
+    fn error(job_id: String, error: &str) -> Self {
+        StdinResponse {
+            success: false,
+            job_id,
+            bytes_written: None,
+            closed: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// POST /jobs/{id}/stdin - write to the stdin of a running job.
+pub async fn write_stdin(
+    path: web::Path<String>,
+    req: web::Json<StdinRequest>,
+    jobs: web::Data<JobRegistry>,
+) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    if jobs.get(&job_id).is_none() {
+        return Ok(HttpResponse::NotFound().json(StdinResponse::error(job_id, "Job not found")));
+    }
+    let Some(handle) = jobs.stdin(&job_id) else {
+        return Ok(HttpResponse::Conflict().json(StdinResponse::error(
+            job_id,
+            "Job is not running with an open stdin (start it with keep_stdin_open)",
+        )));
+    };
+
+    let mut guard = handle.lock().await;
+    let Some(stdin) = guard.as_mut() else {
+        return Ok(HttpResponse::Conflict().json(StdinResponse::error(job_id, "stdin has already been closed")));
+    };
+
+    let written = async {
+        stdin.write_all(req.data.as_bytes()).await?;
+        stdin.flush().await
+    }
+    .await;
+    if let Err(e) = written {
+        let error_msg = format!("Failed to write to stdin: {}", e);
+        log_error("/jobs/{id}/stdin", &error_msg, None);
+        *guard = None;
+        return Ok(HttpResponse::Conflict().json(StdinResponse::error(job_id, &error_msg)));
+    }
+    if req.close {
+        // Dropping the pipe closes it, so the child sees EOF.
+        *guard = None;
+    }
+
+    Ok(HttpResponse::Ok().json(StdinResponse {
+        success: true,
+        job_id,
+        bytes_written: Some(req.data.len()),
+        closed: Some(req.close),
+        error: None,
+    }))
+}
@@ -0,0 +1,106 @@
+//! How long the agent takes to answer, per route, and warnings about slow
+//! requests and jobs, so a regression in the agent itself shows in its own
+//! telemetry.
+//!
+//! Every request is counted per method, route pattern (e.g. `/jobs/{id}`)
+//! and status, and its latency in a histogram per method and route, exported
+//! by `/metrics`. A request taking longer than
+//! `AGENT_SLOW_REQUEST_MS` to answer, or a job running longer than
+//! `AGENT_SLOW_JOB_SECS`, is logged as a warning with what it was.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::jobs::Job;
+use crate::metrics::{Family, Histogram, Sample};
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 13] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Per method and route.
+static HISTOGRAMS: Mutex<BTreeMap<(String, String), Histogram>> = Mutex::new(BTreeMap::new());
+
+/// Requests per method, route and status.
+static REQUESTS: Mutex<BTreeMap<(String, String, u16), u64>> = Mutex::new(BTreeMap::new());
+
+struct Thresholds {
+    request: Option<Duration>,
+    job: Option<Duration>,
+}
+
+static THRESHOLDS: OnceLock<Thresholds> = OnceLock::new();
+
+/// Set the slow request and job thresholds; called once at startup.
+pub fn init(request: Option<Duration>, job: Option<Duration>) {
+    let _ = THRESHOLDS.set(Thresholds { request, job });
+}
+
+/// Count a request answered with `status` after `elapsed`; whether it was
+/// slow.
+pub fn observe(method: &str, route: &str, status: u16, elapsed: Duration) -> bool {
+    *REQUESTS.lock().unwrap().entry((method.to_string(), route.to_string(), status)).or_default() += 1;
+    HISTOGRAMS
+        .lock()
+        .unwrap()
+        .entry((method.to_string(), route.to_string()))
+        .or_insert_with(|| Histogram::new(&BUCKETS))
+        .observe(elapsed.as_secs_f64());
+    THRESHOLDS.get().and_then(|thresholds| thresholds.request).is_some_and(|threshold| elapsed > threshold)
+}
+
+/// Warn about `job` if it ran longer than `AGENT_SLOW_JOB_SECS`.
+pub fn job_done(job: &Job) {
+    let Some(threshold) = THRESHOLDS.get().and_then(|thresholds| thresholds.job) else {
+        return;
+    };
+    let Some(elapsed) = job.duration() else {
+        return;
+    };
+    if elapsed > threshold {
+        tracing::warn!(
+            job_id = %job.id,
+            command = %job.command,
+            tag = job.tag.as_deref(),
+            api_key = job.api_key.as_deref(),
+            status = job.status.name(),
+            return_code = job.return_code,
+            duration_secs = (elapsed.as_secs_f64() * 1000.0).round() / 1000.0,
+            threshold_secs = threshold.as_secs(),
+            "Slow job"
+        );
+    }
+}
+
+/// The request counts and duration histograms, for `/metrics`.
+pub fn families() -> Vec<Family> {
+    let requests = REQUESTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((method, route, status), count)| {
+            let labels = vec![("method", method.clone()), ("route", route.clone()), ("status", status.to_string())];
+            Sample::new("", labels, *count as f64)
+        })
+        .collect();
+    let durations = HISTOGRAMS
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|((method, route), histogram)| histogram.samples(vec![("method", method.clone()), ("route", route.clone())]))
+        .collect();
+    vec![
+        Family {
+            name: "machine_agent_http_requests_total",
+            kind: "counter",
+            help: "Requests answered, per method, route and status.",
+            samples: requests,
+        },
+        Family {
+            name: "machine_agent_http_request_duration_seconds",
+            kind: "histogram",
+            help: "Time until the agent started answering a request, per method and route.",
+            samples: durations,
+        },
+    ]
+}
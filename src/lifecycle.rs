@@ -0,0 +1,164 @@
+//! Commands the agent runs at its own startup and graceful shutdown, e.g.
+//! registering with an inventory or draining from a load balancer.
+//!
+//! They are listed in the JSON file named by `AGENT_LIFECYCLE_FILE`:
+//!
+//! ```json
+//! {"startup": [{"command": "/opt/agent/register.sh"}],
+//!  "shutdown": [{"command": "/opt/agent/drain.sh", "timeout_secs": 120}]}
+//! ```
+//!
+//! Each runs as a job like any other (recorded in `/jobs` and history, with
+//! events and a log file) with `lifecycle` set to its stage. Startup commands
+//! run once the server is listening; shutdown commands run on SIGINT/SIGTERM
+//! (Ctrl+C on Windows) while requests are still served, and the server stops
+//! after them. Commands of a stage run in order; a failure is logged and the
+//! next one still runs.
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command as TokioCommand;
+
+use crate::config::AppConfig;
+use crate::events::{self, EventBus};
+use crate::jobs::{Job, JobRegistry};
+use crate::output::Stream;
+use crate::{cloud, compress, log_error, timekeeping};
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Lifecycle {
+    #[serde(default)]
+    pub startup: Vec<LifecycleCommand>,
+    #[serde(default)]
+    pub shutdown: Vec<LifecycleCommand>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LifecycleCommand {
+    pub command: String,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout() -> u64 {
+    300
+}
+
+pub fn load(path: &Path) -> Result<Lifecycle, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&contents).map_err(|e| format!("Invalid lifecycle file {}: {}", path.display(), e))
+}
+
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Startup,
+    Shutdown,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Startup => "startup",
+            Stage::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Run a stage's commands in order, each as its own job.
+pub async fn run_stage(stage: Stage, bus: &EventBus, jobs: &JobRegistry, config: &AppConfig) {
+    let commands = match stage {
+        Stage::Startup => &config.lifecycle.startup,
+        Stage::Shutdown => &config.lifecycle.shutdown,
+    };
+    for command in commands {
+        run_command(stage, command, bus, jobs, config).await;
+    }
+}
+
+async fn run_command(stage: Stage, lifecycle: &LifecycleCommand, bus: &EventBus, jobs: &JobRegistry, config: &AppConfig) {
+    let command = lifecycle.command.as_str();
+    let endpoint = format!("lifecycle/{}", stage.name());
+    let job_id = uuid::Uuid::new_v4().to_string();
+    let mut job = Job::new(&job_id, command, None);
+    job.lifecycle = Some(stage.name().to_string());
+    jobs.insert(job);
+    bus.publish(events::JOB_STARTED, Some(&job_id), serde_json::json!({
+        "command": command,
+        "lifecycle": stage.name(),
+    }));
+
+    let mut cmd = if cfg!(target_os = "windows") {
+        let mut cmd = TokioCommand::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = TokioCommand::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    cmd.current_dir(std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
+        .env("AGENT_JOB_ID", &job_id)
+        .env("AGENT_LIFECYCLE_STAGE", stage.name())
+        .envs(cloud::env())
+        .envs(timekeeping::env())
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+
+    let timeout = Duration::from_secs(lifecycle.timeout_secs);
+    let error = match tokio::time::timeout(timeout, cmd.output()).await {
+        Ok(Ok(output)) => {
+            jobs.append_output(&job_id, Stream::Stdout, &String::from_utf8_lossy(&output.stdout));
+            jobs.append_output(&job_id, Stream::Stderr, &String::from_utf8_lossy(&output.stderr));
+            if let Some(log_path) = config.job_log_path(&job_id) {
+                let contents = [output.stdout.as_slice(), output.stderr.as_slice()].concat();
+                match compress::write(&log_path, &contents, config.compress_level) {
+                    Ok(written) => jobs.update(&job_id, |job| job.log_file = Some(written.display().to_string())),
+                    Err(e) => log_error(&endpoint, &format!("Failed to write job log: {}", e), Some(command)),
+                }
+            }
+            let return_code = output.status.code();
+            if !output.status.success() {
+                log_error(&endpoint, &format!("Command exited with {:?}", return_code), Some(command));
+            }
+            jobs.finish(&job_id, return_code);
+            bus.publish(events::JOB_FINISHED, Some(&job_id), serde_json::json!({
+                "command": command,
+                "return_code": return_code,
+                "lifecycle": stage.name(),
+            }));
+            return;
+        }
+        Ok(Err(e)) => format!("Command execution failed: {}", e),
+        Err(_) => format!("Timed out after {}s", timeout.as_secs()),
+    };
+    log_error(&endpoint, &error, Some(command));
+    jobs.fail(&job_id, &error);
+    bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+        "command": command,
+        "error": error,
+        "lifecycle": stage.name(),
+    }));
+}
+
+/// Resolve on the first SIGINT or SIGTERM (Ctrl+C on Windows).
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,73 @@
+//! `/execute` output as one JSON object per line, for clients that pipe it
+//! into jq or a log processor rather than unpacking one giant string.
+//!
+//! A request with `Accept: application/x-ndjson` is answered `200` at once
+//! with an NDJSON stream: an event for each line the command prints, as it
+//! prints it, with the stream it came from and when, then the usual response
+//! without `stdout` and `stderr` as the last line. `Accept:
+//! text/event-stream`, or `POST /execute-stream`, sends the same as
+//! Server-Sent Events: `stdout` and `stderr` events, then a `result` event.
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+use crate::clock;
+use crate::heartbeat::Format;
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Serialize)]
+pub struct LineEvent {
+    pub stream: Stream,
+    /// The line without its line ending.
+    pub line: String,
+    pub timestamp: String,
+}
+
+pub type Sender = tokio::sync::mpsc::UnboundedSender<LineEvent>;
+pub type Receiver = tokio::sync::mpsc::UnboundedReceiver<LineEvent>;
+
+impl Stream {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// How the client of `req` asked for line events, if it did.
+pub fn wanted(req: &HttpRequest) -> Option<Format> {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+        .find_map(|media_type| {
+            if media_type.eq_ignore_ascii_case("application/x-ndjson") {
+                Some(Format::Ndjson)
+            } else if media_type.eq_ignore_ascii_case("text/event-stream") {
+                Some(Format::Sse)
+            } else {
+                None
+            }
+        })
+}
+
+/// Send an event for `line`, as read with its line ending.
+pub fn send(sender: &Sender, stream: Stream, line: &[u8]) {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    // Nobody listening any more is fine; the output is still collected.
+    let _ = sender.send(LineEvent {
+        stream,
+        line: String::from_utf8_lossy(line).into_owned(),
+        timestamp: clock::now(),
+    });
+}
@@ -0,0 +1,83 @@
+//! The sockets the API listens on.
+//!
+//! `AGENT_BIND` is a comma-separated list of `host:port` addresses, IPv6
+//! literals in brackets (`0.0.0.0:6565,[::]:6565`). IPv6 listeners accept IPv6
+//! only, so an IPv4 and an IPv6 wildcard can share a port; with
+//! `AGENT_DUAL_STACK=true` they accept IPv4-mapped connections too, so `[::]`
+//! alone serves both.
+
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs};
+
+pub const DEFAULT_BIND: &str = "0.0.0.0:6565";
+
+/// Pending connections per listener, as actix-web uses by default.
+const BACKLOG: i32 = 1024;
+
+/// An effective listener, as reported by `/health`.
+#[derive(Serialize, Clone)]
+pub struct ListenerInfo {
+    pub address: SocketAddr,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dual_stack: bool,
+    /// Serves HTTPS.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub tls: bool,
+}
+
+impl ListenerInfo {
+    /// Where the agent can reach itself through this listener.
+    pub fn loopback(&self) -> SocketAddr {
+        let ip = match self.address.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        SocketAddr::new(ip, self.address.port())
+    }
+}
+
+fn listen(addr: SocketAddr, dual_stack: bool) -> io::Result<(TcpListener, ListenerInfo)> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Same as actix-web: restarting shouldn't wait for TIME_WAIT to clear.
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    let listener: TcpListener = socket.into();
+    let info = ListenerInfo {
+        address: listener.local_addr()?,
+        dual_stack: addr.is_ipv6() && dual_stack,
+        tls: false,
+    };
+    Ok((listener, info))
+}
+
+/// Bind every configured address; a single one failing is fatal, as a
+/// missing listener would leave part of the network silently unserved.
+pub fn bind(addresses: &[String], dual_stack: bool) -> io::Result<Vec<(TcpListener, ListenerInfo)>> {
+    let mut listeners = Vec::new();
+    for address in addresses {
+        let resolved: Vec<SocketAddr> = address
+            .to_socket_addrs()
+            .map_err(|e| io::Error::new(e.kind(), format!("Invalid bind address {:?}: {}", address, e)))?
+            .collect();
+        if resolved.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Bind address {:?} did not resolve", address),
+            ));
+        }
+        for addr in resolved {
+            let listener = listen(addr, dual_stack)
+                .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind {}: {}", addr, e)))?;
+            listeners.push(listener);
+        }
+    }
+    Ok(listeners)
+}
@@ -0,0 +1,163 @@
+//! Cursor pagination, sorting and field selection shared by list endpoints:
+//! `?limit=100&sort=-started_at&fields=id,status&cursor=<next_cursor>`.
+//!
+//! Cursors are keyset-based (the sort value and key of the last item
+//! returned), so pages stay stable while new items are being added.
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize, Default)]
+pub struct ListQuery {
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    /// Sort key, prefixed with `-` for descending order.
+    pub sort: Option<String>,
+    /// Comma-separated fields to return; all fields when unset.
+    pub fields: Option<String>,
+}
+
+/// How the items of one list endpoint can be ordered.
+pub struct ListSpec {
+    /// Field that uniquely identifies an item; breaks ties between equal sort values.
+    pub key: &'static str,
+    pub sort_keys: &'static [&'static str],
+    pub default_sort: &'static str,
+}
+
+pub struct Page {
+    pub items: Vec<Value>,
+    /// Number of items across all pages.
+    pub total: usize,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    value: Value,
+    key: Value,
+}
+
+fn encode_cursor(cursor: &Cursor) -> String {
+    let json = serde_json::to_vec(cursor).unwrap_or_default();
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+}
+
+fn decode_cursor(cursor: &str) -> Result<Cursor, String> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| "Invalid cursor".to_string())
+}
+
+/// Order JSON scalars: null < bool < number < string; anything else sorts last.
+fn compare(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            _ => 4,
+        }
+    }
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => {
+            let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Sort `items`, skip past `query.cursor` and return one page of them,
+/// reduced to `query.fields`. Errors describe a bad `sort` or `cursor`.
+pub fn paginate<T: Serialize>(items: &[T], query: &ListQuery, spec: &ListSpec) -> Result<Page, String> {
+    let sort = query.sort.as_deref().unwrap_or(spec.default_sort);
+    let (field, descending) = match sort.strip_prefix('-') {
+        Some(field) => (field, true),
+        None => (sort, false),
+    };
+    if !spec.sort_keys.contains(&field) {
+        return Err(format!(
+            "Cannot sort by {:?}; expected one of: {}",
+            field,
+            spec.sort_keys.join(", ")
+        ));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let position = |row: &Map<String, Value>| {
+        (
+            row.get(field).cloned().unwrap_or(Value::Null),
+            row.get(spec.key).cloned().unwrap_or(Value::Null),
+        )
+    };
+    let order = |a: &(Value, Value), b: &(Value, Value)| {
+        let ordering = compare(&a.0, &b.0).then_with(|| compare(&a.1, &b.1));
+        if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    };
+
+    let mut rows: Vec<Map<String, Value>> = items
+        .iter()
+        .filter_map(|item| match serde_json::to_value(item) {
+            Ok(Value::Object(row)) => Some(row),
+            _ => None,
+        })
+        .collect();
+    rows.sort_by(|a, b| order(&position(a), &position(b)));
+    let total = rows.len();
+
+    if let Some(cursor) = &query.cursor {
+        let cursor = decode_cursor(cursor)?;
+        if cursor.sort != sort {
+            return Err("Cursor was issued for a different sort order".to_string());
+        }
+        let after = (cursor.value, cursor.key);
+        rows.retain(|row| order(&position(row), &after) == Ordering::Greater);
+    }
+
+    let next_cursor = (rows.len() > limit).then(|| {
+        let (value, key) = position(&rows[limit - 1]);
+        encode_cursor(&Cursor {
+            sort: sort.to_string(),
+            value,
+            key,
+        })
+    });
+    rows.truncate(limit);
+
+    let fields: Option<Vec<&str>> = query
+        .fields
+        .as_deref()
+        .map(|fields| fields.split(',').map(str::trim).filter(|f| !f.is_empty()).collect());
+    let items = rows
+        .into_iter()
+        .map(|mut row| {
+            if let Some(fields) = &fields {
+                row.retain(|name, _| fields.contains(&name.as_str()));
+            }
+            Value::Object(row)
+        })
+        .collect();
+
+    Ok(Page {
+        items,
+        total,
+        next_cursor,
+    })
+}
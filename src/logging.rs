@@ -0,0 +1,217 @@
+//! The agent's log: JSON lines in `app_error.log` (or `AGENT_ERROR_LOG`),
+//! written through `tracing`.
+//!
+//! `AGENT_LOG_LEVEL` sets what is written: a level (`error`, `warn`, `info`,
+//! `debug`, `trace`; default `info`) or `tracing` filter directives such as
+//! `warn,machine_agent=debug`. Every request gets a span with its method and
+//! path, so errors logged while handling it carry both, and an `info` line
+//! with its status and latency once answered. Times follow the timestamp
+//! policy (see [`crate::clock`]).
+//!
+//! The file is rotated once it reaches `AGENT_LOG_MAX_BYTES` (default
+//! 10 MiB; `0` for no limit) and, with `AGENT_LOG_ROTATE_DAILY`, when the
+//! date changes: it is renamed to `app_error.log.<UTC time>` and a new one
+//! started. The newest `AGENT_LOG_KEEP` (default 5) rotated files are kept.
+
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::USER_AGENT;
+use chrono::{DateTime, Utc};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::Span;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::env_parse;
+use crate::{clock, get_log_file_path, latency};
+
+const DEFAULT_LEVEL: &str = "info";
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
+
+/// Timestamps as everything else the agent writes them.
+struct PolicyTime;
+
+impl FormatTime for PolicyTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        write!(w, "{}", clock::now())
+    }
+}
+
+/// The filter `AGENT_LOG_LEVEL` asks for.
+pub fn filter() -> Result<EnvFilter, String> {
+    match std::env::var("AGENT_LOG_LEVEL") {
+        Ok(level) if !level.trim().is_empty() => {
+            EnvFilter::try_new(level.trim()).map_err(|e| format!("Invalid AGENT_LOG_LEVEL {:?}: {}", level, e))
+        }
+        _ => Ok(EnvFilter::new(DEFAULT_LEVEL)),
+    }
+}
+
+/// The log file, renamed aside when it grows too large or the day changes.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// The date, in the timestamp policy's zone, of the last write.
+    day: String,
+    /// `0` for no limit.
+    max_bytes: u64,
+    daily: bool,
+    keep: usize,
+}
+
+fn day(time: DateTime<Utc>) -> String {
+    clock::format(&time)[..10].to_string()
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now());
+        Ok(RotatingFile {
+            path,
+            file,
+            size: metadata.len(),
+            day: day(modified),
+            max_bytes: env_parse("AGENT_LOG_MAX_BYTES").unwrap_or(DEFAULT_MAX_BYTES),
+            daily: matches!(std::env::var("AGENT_LOG_ROTATE_DAILY").as_deref(), Ok("1" | "true" | "yes")),
+            keep: env_parse("AGENT_LOG_KEEP").unwrap_or(DEFAULT_KEEP),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(Utc::now().format(".%Y%m%dT%H%M%S%.3fZ").to_string());
+        std::fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        prune(&self.path, self.keep);
+        Ok(())
+    }
+}
+
+/// Delete all but the newest `keep` rotated copies of `path`.
+fn prune(path: &Path, keep: usize) {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|name| name.to_str())) else {
+        return;
+    };
+    let prefix = format!("{}.", name);
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut rotated: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_str().is_some_and(|entry| entry.starts_with(&prefix)))
+        .map(|entry| entry.path())
+        .collect();
+    // The UTC times in the names sort oldest first.
+    rotated.sort();
+    let excess = rotated.len().saturating_sub(keep);
+    for old in &rotated[..excess] {
+        let _ = std::fs::remove_file(old);
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let today = day(Utc::now());
+        let full = self.max_bytes > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_bytes;
+        if full || (self.daily && today != self.day) {
+            // Carry on in the same file rather than lose the line, and try
+            // again once another `max_bytes` have been written.
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate {}: {}", self.path.display(), e);
+                self.size = 0;
+            }
+        }
+        self.day = today;
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Start logging to the log file; stderr when it can't be opened.
+pub fn init() {
+    let (filter, invalid) = match filter() {
+        Ok(filter) => (filter, None),
+        Err(error_msg) => (EnvFilter::new(DEFAULT_LEVEL), Some(error_msg)),
+    };
+    let builder = tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(filter)
+        .with_timer(PolicyTime)
+        .with_current_span(false)
+        .with_span_list(true);
+    let log_file = get_log_file_path();
+    match RotatingFile::open(log_file.clone()) {
+        Ok(file) => builder.with_writer(Mutex::new(file)).init(),
+        Err(e) => {
+            eprintln!("Failed to open log file {}: {}; logging to stderr", log_file.display(), e);
+            builder.with_writer(std::io::stderr).init();
+        }
+    }
+    if let Some(error_msg) = invalid {
+        eprintln!("{}", error_msg);
+        tracing::warn!("{}", error_msg);
+    }
+}
+
+/// A request being handled.
+pub struct Request {
+    /// The span it is handled in.
+    pub span: Span,
+    started: Instant,
+    method: String,
+    /// The route pattern, e.g. `/jobs/{id}`.
+    route: String,
+}
+
+impl Request {
+    pub fn start(req: &ServiceRequest) -> Self {
+        let span = tracing::info_span!("request", method = req.method().as_str(), path = req.path());
+        Request {
+            span,
+            started: Instant::now(),
+            method: req.method().to_string(),
+            route: req.match_pattern().unwrap_or_else(|| "unmatched".to_string()),
+        }
+    }
+
+    /// Log how the request was answered and how long that took, and count
+    /// it in the latency histograms (see [`crate::latency`]).
+    pub fn finish<B>(&self, response: &Result<ServiceResponse<B>, actix_web::Error>) {
+        let elapsed = self.started.elapsed();
+        let (status, req) = match response {
+            Ok(response) => (response.status(), Some(response.request())),
+            Err(e) => (e.as_response_error().status_code(), None),
+        };
+        let latency_ms = (elapsed.as_secs_f64() * 1_000_000.0).round() / 1000.0;
+        let slow = latency::observe(&self.method, &self.route, status.as_u16(), elapsed);
+        // Entered rather than given as `parent:`, which the span list leaves out.
+        let _entered = self.span.enter();
+        tracing::info!(status = status.as_u16(), latency_ms, "Request answered");
+        if slow {
+            tracing::warn!(
+                route = %self.route,
+                query = req.map(|req| req.query_string()).filter(|query| !query.is_empty()),
+                client = req.and_then(|req| req.peer_addr()).map(|addr| addr.ip().to_string()),
+                user_agent = req.and_then(|req| req.headers().get(USER_AGENT)).and_then(|agent| agent.to_str().ok()),
+                status = status.as_u16(),
+                latency_ms,
+                "Slow request"
+            );
+        }
+    }
+}
@@ -1,22 +1,373 @@
-use actix_web::{web, App, HttpServer, HttpResponse, Result as ActixResult};
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpRequest, HttpServer, HttpResponse, Result as ActixResult};
+use actix_web::dev::Service;
+use futures_util::FutureExt;
+use tracing::Instrument;
 use serde::{Deserialize, Serialize};
-use std::process::{Command, Stdio};
-use std::io::Write;
-use std::fs::OpenOptions;
+use std::process::Stdio;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use chrono::Local;
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command as TokioCommand;
 
+mod admin;
+mod api_keys;
+mod artifacts;
+mod asciicast;
+mod auth;
+#[cfg(feature = "auth-hook")]
+mod auth_hook;
+#[cfg(feature = "authz")]
+mod authz;
+#[cfg(feature = "azure")]
+mod azure_blob;
+mod bandwidth;
+mod blackout;
+mod capabilities;
+mod chains;
+#[cfg(feature = "browser")]
+mod browser;
+mod clock;
+mod cloud;
+mod command_rules;
+mod compress;
+mod concurrency;
+mod config;
+mod confinement;
+mod content;
+mod deadman;
+mod diagnostics;
+mod disconnect;
+mod dns;
+mod doctor;
+mod encoding;
+#[cfg(feature = "documents")]
+mod documents;
+mod events;
+mod expect;
+#[cfg(feature = "fetch")]
+mod fetch;
+mod files;
+mod firewall;
+#[cfg(feature = "gcs")]
+mod gcs;
+mod guards;
+mod heartbeat;
+mod gui_session;
+mod history;
+mod hooks;
+mod host;
+mod host_history;
+#[cfg(feature = "desktop")]
+mod image_match;
+mod job_hooks;
+mod job_stream;
+mod jobs;
+mod latency;
+mod lifecycle;
+mod line_events;
+mod listen;
+mod listing;
+mod logging;
+mod metrics;
+#[cfg(feature = "push")]
+mod metrics_push;
+#[cfg(feature = "desktop")]
+mod ocr;
+mod offload;
+#[cfg(feature = "oidc")]
+mod oidc;
+mod opa;
+mod outbound;
+mod output;
+mod overrides;
+mod playbook;
+mod policy;
+mod pressure;
+mod progress;
+mod provenance;
+mod queued;
+mod read_only;
+mod recent_errors;
+mod result_cache;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "desktop")]
+mod screen;
+mod script;
+#[cfg(feature = "shell")]
+mod shell;
+#[cfg(feature = "sync")]
+mod shares;
+mod shells;
+mod supervisor;
+#[cfg(feature = "sync")]
+mod sync;
+mod time_sync;
+mod time_window;
+mod timekeeping;
+mod timestamps;
+mod tls;
+mod usage;
+mod watchdog;
+
+use config::AppConfig;
+use events::EventBus;
+use jobs::{Job, JobRegistry};
+use result_cache::{CachedResult, ResultCache};
+
 #[derive(Deserialize)]
 struct ExecuteRequest {
+    #[serde(default)]
     command: String,
+    /// The command as base64, for bytes that aren't UTF-8.
+    #[serde(default)]
+    command_base64: Option<String>,
+    /// Extra environment variables for the command.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Extra environment variables with base64 values.
+    #[serde(default)]
+    env_base64: BTreeMap<String, String>,
+    /// Start the command with only `env` and `env_base64`, not the agent's
+    /// environment.
+    #[serde(default)]
+    clear_env: bool,
+    /// Directory to run the command in, instead of the agent's.
+    #[serde(default)]
+    cwd: Option<String>,
+    /// Written to the command's stdin, which is then closed (unless
+    /// `keep_stdin_open`).
+    #[serde(default)]
+    stdin: Option<String>,
+    /// Interpreter to run the command in: `sh`, `bash`, `zsh`, `cmd`,
+    /// `powershell` or `pwsh`; the platform's shell when absent.
+    #[serde(default)]
+    shell: Option<shells::Shell>,
+    /// Run `command` as a script with this interpreter, from a temporary
+    /// file; set by `/execute-script`.
+    #[serde(default)]
+    interpreter: Option<script::Interpreter>,
+    /// Seconds before the command's process tree is killed; 0 for none.
     #[serde(default = "default_timeout")]
-    #[allow(dead_code)]
     timeout: u64,
+    /// Windows only: launch in the active user's desktop session instead of
+    /// the agent's own (session 0 when running as a service).
+    #[serde(default)]
+    interactive_session: bool,
+    /// Linux only: run the command in this SELinux context.
+    #[serde(default)]
+    selinux_context: Option<String>,
+    /// Linux only: run the command under this AppArmor profile.
+    #[serde(default)]
+    apparmor_profile: Option<String>,
+    /// Attach a diagnostic bundle to the job if the command fails.
+    #[serde(default)]
+    capture_on_failure: bool,
+    /// Stamp each captured output line with the host time and the seconds
+    /// since the command started.
+    #[serde(default)]
+    timestamps: bool,
+    /// `/execute-async` only: keep a stdin pipe open for POST /jobs/{id}/stdin.
+    #[serde(default)]
+    keep_stdin_open: bool,
+    /// `/execute-async` only: prompt/response rules applied to the output.
+    #[serde(default)]
+    expect: Vec<expect::ExpectRule>,
+    /// `/execute-async` only: record the output as an asciicast artifact.
+    #[serde(default)]
+    record_cast: bool,
+    /// Preconditions checked before spawning; the job is skipped if one fails.
+    #[serde(default)]
+    guards: Vec<guards::Guard>,
+    /// Named lock held while the job runs, for other jobs' `lock_free` guards.
+    #[serde(default)]
+    lock: Option<String>,
+    /// Host-local windows the command may start in; unrestricted when empty.
+    #[serde(default)]
+    allowed_windows: Vec<time_window::TimeWindow>,
+    /// Outside every window: reject, or (`/execute-async` only) queue.
+    #[serde(default)]
+    on_window_miss: time_window::OnWindowMiss,
+    /// `/execute-async` only: start after a random delay of up to this many
+    /// seconds (once the window opens, when queued for one), so the same
+    /// request sent to a fleet doesn't start everywhere at once.
+    #[serde(default)]
+    splay_secs: Option<u64>,
+    /// `/execute-async` only: for a queued job that came due while the agent
+    /// was down, `skip` it (default) or `run_once` when the agent is back.
+    #[serde(default)]
+    on_missed: time_window::OnMissed,
+    /// `/execute-async` only: requests queued as jobs of their own when this
+    /// one exits 0.
+    #[serde(default)]
+    on_success: Vec<serde_json::Value>,
+    /// `/execute-async` only: requests queued when this one fails.
+    #[serde(default)]
+    on_failure: Vec<serde_json::Value>,
+    /// Name of a hook set from `AGENT_HOOKS_FILE` to run around the command.
+    #[serde(default)]
+    hooks: Option<String>,
+    /// Files the command writes, stamped with the job's provenance when it exits.
+    #[serde(default)]
+    outputs: Vec<String>,
+    /// Label grouping the job with others like it in `/metrics`.
+    #[serde(default)]
+    tag: Option<String>,
+    /// `/execute` only: answer with the result of the same command run in
+    /// the last `cache_ttl` seconds, if any, and cache this one.
+    #[serde(default)]
+    cache_ttl: Option<u64>,
+    /// `/execute` only: answer 202 with the job ID if the command is still
+    /// running after this many seconds, instead of holding the connection.
+    #[serde(default)]
+    async_after: Option<u64>,
+    /// `/execute` only: kill the command if the client disconnects before
+    /// the result (default `AGENT_CANCEL_ON_DISCONNECT`).
+    #[serde(default)]
+    cancel_on_disconnect: Option<bool>,
+    /// `/execute` only: answer 200 at once and send a heartbeat frame every
+    /// this many seconds until the result (default
+    /// `AGENT_EXECUTE_HEARTBEAT_SECS`).
+    #[serde(default)]
+    heartbeat: Option<u64>,
+    /// `/execute` only: what the heartbeat frames look like.
+    #[serde(default)]
+    heartbeat_format: heartbeat::Format,
+    /// `/execute` only: answer with the command's stdout (default true).
+    #[serde(default = "default_true")]
+    include_stdout: bool,
+    /// `/execute` only: answer with the command's stderr (default true).
+    #[serde(default = "default_true")]
+    include_stderr: bool,
+    /// `/execute` only: answer with stderr but not stdout.
+    #[serde(default)]
+    stderr_only: bool,
+    /// `/execute` only: answer with the exit code but no output or artifacts.
+    #[serde(default)]
+    return_exit_code_only: bool,
+    /// Name of the API key the request was made with.
+    #[serde(skip)]
+    api_key: Option<String>,
+    /// Subject of the client certificate the request came with.
+    #[serde(skip)]
+    client_certificate: Option<String>,
+    /// User forced by the API key; never taken from the request.
+    #[serde(skip)]
+    run_as: Option<api_keys::RunAs>,
+    /// The API key's `max_timeout`, which also bounds commands that run as
+    /// jobs.
+    #[serde(skip)]
+    max_timeout: Option<u64>,
+    /// Policy rules that matched the request, recorded on its job.
+    #[serde(skip)]
+    policy: Vec<policy::RuleMatch>,
+    /// The decoded `command_base64`.
+    #[serde(skip)]
+    command_os: Option<OsString>,
+    /// `env` and the decoded `env_base64`.
+    #[serde(skip)]
+    env_os: Vec<(String, OsString)>,
 }
 
+/// `AGENT_DEFAULT_TIMEOUT_SECS`, set once at startup.
+static DEFAULT_TIMEOUT: OnceLock<u64> = OnceLock::new();
+
 fn default_timeout() -> u64 {
-    30
+    DEFAULT_TIMEOUT.get().copied().unwrap_or(config::DEFAULT_TIMEOUT_SECS)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl ExecuteRequest {
+    /// Check `clear_env`, `cwd`, `interpreter`, `shell` and `stdin`, and resolve relative `outputs`
+    /// against `cwd`, as the command sees them.
+    fn prepare_process(&mut self) -> Result<(), String> {
+        if self.interactive_session && (self.clear_env || self.interpreter.is_some() || self.shell.is_some() || self.stdin.is_some()) {
+            return Err("clear_env, interpreter, shell and stdin are not supported with interactive_session".to_string());
+        }
+        if self.interpreter.is_some() && self.shell.is_some() {
+            return Err("Set shell or interpreter, not both".to_string());
+        }
+        let Some(cwd) = &self.cwd else {
+            return Ok(());
+        };
+        if !std::path::Path::new(cwd).is_dir() {
+            return Err(format!("cwd {:?} is not a directory", cwd));
+        }
+        for output in &mut self.outputs {
+            if std::path::Path::new(output.as_str()).is_relative() {
+                *output = std::path::Path::new(cwd).join(&*output).display().to_string();
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the command runs.
+    fn current_dir(&self) -> PathBuf {
+        match &self.cwd {
+            Some(cwd) => PathBuf::from(cwd),
+            None => std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+        }
+    }
+
+    /// The first field set that only `/execute` supports.
+    fn sync_only_field(&self) -> Option<&'static str> {
+        let sync_only = [
+            (self.cache_ttl.is_some(), "cache_ttl"),
+            (self.async_after.is_some(), "async_after"),
+            (self.cancel_on_disconnect.is_some(), "cancel_on_disconnect"),
+            (self.heartbeat.is_some(), "heartbeat"),
+            (self.heartbeat_format != heartbeat::Format::default(), "heartbeat_format"),
+            (!self.include_stdout, "include_stdout"),
+            (!self.include_stderr, "include_stderr"),
+            (self.stderr_only, "stderr_only"),
+            (self.return_exit_code_only, "return_exit_code_only"),
+            (self.interpreter.is_some(), "interpreter"),
+        ];
+        sync_only.iter().find(|(set, _)| *set).map(|(_, field)| *field)
+    }
+
+    /// What the command runs in.
+    fn shell(&self) -> shells::Shell {
+        self.shell.unwrap_or_else(shells::Shell::platform)
+    }
+
+    /// The name of the shell or script interpreter running the command.
+    fn runs_in(&self) -> &'static str {
+        match self.interpreter {
+            Some(interpreter) => interpreter.name(),
+            None => self.shell().name(),
+        }
+    }
+
+    fn confinement(&self) -> confinement::Confinement {
+        confinement::Confinement {
+            selinux_context: self.selinux_context.clone(),
+            apparmor_profile: self.apparmor_profile.clone(),
+        }
+    }
+
+    /// Drop the parts of `response` the request asked to leave out; the job
+    /// keeps all of its output.
+    fn shape(&self, mut response: ExecuteResponse) -> ExecuteResponse {
+        if self.return_exit_code_only {
+            response.artifacts = None;
+        }
+        if !self.include_stdout || self.stderr_only || self.return_exit_code_only {
+            response.stdout = None;
+        }
+        if !self.include_stderr || self.return_exit_code_only {
+            response.stderr = None;
+        }
+        response
+    }
 }
 
 #[derive(Serialize)]
@@ -24,6 +375,8 @@ struct ExecuteResponse {
     success: bool,
     command: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     stdout: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stderr: Option<String>,
@@ -33,6 +386,18 @@ struct ExecuteResponse {
     executed: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifacts: Option<Vec<String>>,
+    /// Why the command was not run, when a guard failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skip_reason: Option<String>,
+    /// The result is a cached one of `job_id`, not a new run.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+    /// The command was killed after `timeout`; the output is what it printed
+    /// until then.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    timed_out: bool,
 }
 
 #[derive(Serialize)]
@@ -40,6 +405,8 @@ struct AsyncExecuteResponse {
     success: bool,
     message: Option<String>,
     command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job_id: Option<String>,
     pid: u32,
     started_at: String,
     status: String,
@@ -51,6 +418,37 @@ struct AsyncExecuteResponse {
 struct HealthResponse {
     status: String,
     platform: String,
+    /// Addresses the API is listening on.
+    listeners: Vec<listen::ListenerInfo>,
+    /// Optional capabilities compiled into this build.
+    features: Vec<&'static str>,
+    /// The cloud instance the agent runs on, when detected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cloud: Option<&'static cloud::CloudMetadata>,
+    /// The SELinux or AppArmor context the agent runs in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confinement: Option<confinement::AgentContext>,
+    started_at: String,
+    uptime_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    boot_time: Option<String>,
+    /// Whether the host clock can be trusted, and how far off it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<timekeeping::ClockStatus>,
+}
+
+#[derive(Serialize, Default)]
+struct HealthDetailsResponse {
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    health: Option<HealthResponse>,
+    /// Internal errors since the agent started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors_total: Option<u64>,
+    /// The last internal errors, newest first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recent_errors: Option<Vec<recent_errors::RecentError>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -59,73 +457,88 @@ struct HomeResponse {
     endpoints: std::collections::HashMap<String, String>,
 }
 
-fn get_log_file_path() -> PathBuf {
+/// Directory containing the agent executable; logs and recordings live here.
+fn get_exe_dir() -> PathBuf {
     let exe_path = std::env::current_exe()
         .unwrap_or_else(|_| PathBuf::from("."));
-    let default_path = PathBuf::from(".");
-    let log_dir = exe_path.parent().unwrap_or(&default_path);
-    log_dir.join("app_error.log")
+    exe_path.parent().map(PathBuf::from).unwrap_or_else(|| PathBuf::from("."))
 }
 
-fn log_error(endpoint: &str, error_msg: &str, command: Option<&str>) {
-    let log_file = get_log_file_path();
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    
-    let mut file = match OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&log_file) {
-        Ok(f) => f,
-        Err(_) => {
-            eprintln!("Failed to open log file: {:?}", log_file);
-            return;
-        }
-    };
-    
-    let log_entry = if let Some(cmd) = command {
-        format!("{} - ERROR - {} - {} - Command: {}\n", timestamp, endpoint, error_msg, cmd)
-    } else {
-        format!("{} - ERROR - {} - {}\n", timestamp, endpoint, error_msg)
-    };
-    
-    if let Err(e) = file.write_all(log_entry.as_bytes()) {
-        eprintln!("Failed to write to log file: {}", e);
+/// `AGENT_ERROR_LOG`, or `app_error.log` next to the executable.
+fn get_log_file_path() -> PathBuf {
+    match std::env::var_os("AGENT_ERROR_LOG").filter(|path| !path.is_empty()) {
+        Some(path) => PathBuf::from(path),
+        None => get_exe_dir().join("app_error.log"),
     }
 }
 
+/// Log an error, with the command it concerns; see [`logging`].
+fn log_error(endpoint: &str, error_msg: &str, command: Option<&str>) {
+    recent_errors::record(&clock::now(), endpoint, error_msg);
+    tracing::error!(endpoint, command, "{}", error_msg);
+}
+
 fn log_error_with_traceback(endpoint: &str, error_msg: &str, traceback: &str, command: Option<&str>) {
-    let log_file = get_log_file_path();
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-    
-    let mut file = match OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&log_file) {
-        Ok(f) => f,
-        Err(_) => {
-            eprintln!("Failed to open log file: {:?}", log_file);
-            return;
-        }
-    };
-    
-    let log_entry = if let Some(cmd) = command {
-        format!("{} - ERROR - {} - {} - Command: {}\nTraceback: {}\n", 
-                timestamp, endpoint, error_msg, cmd, traceback)
-    } else {
-        format!("{} - ERROR - {} - {}\nTraceback: {}\n", 
-                timestamp, endpoint, error_msg, traceback)
-    };
-    
-    if let Err(e) = file.write_all(log_entry.as_bytes()) {
-        eprintln!("Failed to write to log file: {}", e);
-    }
+    recent_errors::record(&clock::now(), endpoint, error_msg);
+    tracing::error!(endpoint, command, traceback, "{}", error_msg);
 }
 
 async fn home() -> ActixResult<HttpResponse> {
     let mut endpoints = std::collections::HashMap::new();
     endpoints.insert("/execute".to_string(), "POST - Execute a command and wait for response".to_string());
+    endpoints.insert("/execute-stream".to_string(), "POST - Execute a command, streaming its output lines and result as Server-Sent Events".to_string());
+    endpoints.insert("/execute-script".to_string(), "POST - Run a multi-line script with bash, powershell, python or node".to_string());
     endpoints.insert("/execute-async".to_string(), "POST - Execute a command asynchronously (fire and forget)".to_string());
     endpoints.insert("/health".to_string(), "GET - Check API health".to_string());
+    endpoints.insert("/health/details".to_string(), "GET - Health with the agent's recent internal errors (authenticated)".to_string());
+    endpoints.insert("/capabilities".to_string(), "GET - Compiled-in features, enabled subsystems, policy mode and host abilities".to_string());
+    #[cfg(feature = "desktop")]
+    endpoints.insert("/screen/recordings".to_string(), "POST - Start recording the desktop for a job (GET/stop under /screen/recordings/{job_id})".to_string());
+    endpoints.insert("/watchdog".to_string(), "GET - Dead man's switches and when each fires next (POST /watchdog/{name}/ping to check in)".to_string());
+    endpoints.insert("/policy/explain".to_string(), "GET - Explain which policy rules a hypothetical request matches and the outcome".to_string());
+    endpoints.insert("/playbooks/run".to_string(), "POST - Run steps in order as one job, resuming after reboot steps".to_string());
+    #[cfg(feature = "documents")]
+    endpoints.insert("/documents/run".to_string(), "POST - Fetch a versioned document, validate its parameters and run it as a playbook".to_string());
+    #[cfg(feature = "desktop")]
+    endpoints.insert("/screen/ocr".to_string(), "POST - Capture the screen and return OCR text with bounding boxes".to_string());
+    #[cfg(feature = "desktop")]
+    endpoints.insert("/screen/wait-for-image".to_string(), "POST - Wait until a template image appears on screen and return its coordinates".to_string());
+    #[cfg(feature = "browser")]
+    endpoints.insert("/browser/run".to_string(), "POST - Run scripted browser steps through WebDriver".to_string());
+    endpoints.insert("/jobs/search".to_string(), "GET - Full-text search over job history (q=...)".to_string());
+    endpoints.insert("/jobs".to_string(), "GET - List jobs (status, cursor, limit, sort, fields)".to_string());
+    endpoints.insert("/jobs/{id}".to_string(), "GET - Job status, exit code and reported progress; DELETE - Cancel a running job".to_string());
+    endpoints.insert("/jobs/{id}/wait".to_string(), "GET - Wait until a job is done, up to timeout seconds (default 60, at most 600), and return it".to_string());
+    endpoints.insert("/jobs/{id}/output".to_string(), "GET - Stdout and stderr a job has printed so far (AGENT_JOB_OUTPUT_MAX_BYTES kept per stream)".to_string());
+    endpoints.insert("/jobs/{id}/stream".to_string(), "GET - A job's output as Server-Sent Events while it runs (token=... or an API key)".to_string());
+    endpoints.insert("/jobs/{id}/stream-token".to_string(), "POST - Short-lived token that opens one job's stream (ttl_secs=...)".to_string());
+    endpoints.insert("/jobs/{id}/log".to_string(), "GET - Job output log (requires AGENT_JOB_LOG_DIR)".to_string());
+    endpoints.insert("/jobs/{id}/stdin".to_string(), "POST - Write to the stdin of a running job started with keep_stdin_open".to_string());
+    endpoints.insert("/jobs/{id}/artifacts".to_string(), "GET - List a job's artifacts (download under /jobs/{id}/artifacts/{name})".to_string());
+    endpoints.insert("/files/download".to_string(), "GET - Download a file under AGENT_DOWNLOAD_ROOTS (path=..., gzip=true), with range requests".to_string());
+    endpoints.insert("/files/list".to_string(), "GET - List a directory under AGENT_DOWNLOAD_ROOTS (path=...), paginated".to_string());
+    endpoints.insert("/files/stat".to_string(), "GET - Type, size, mtime and permissions of a path under AGENT_DOWNLOAD_ROOTS (path=...)".to_string());
+    endpoints.insert("/provenance".to_string(), "GET - Which job wrote a file (path=...), from its provenance stamp".to_string());
+    endpoints.insert("/content".to_string(), "GET - Size of the content store and the space deduplication saves".to_string());
+    #[cfg(feature = "sync")]
+    endpoints.insert("/sync/plan".to_string(), "POST - Compare a directory manifest with the agent's copy and list what to send".to_string());
+    #[cfg(feature = "sync")]
+    endpoints.insert("/sync/files".to_string(), "PUT - Write one file of a directory sync from its changed blocks".to_string());
+    #[cfg(feature = "sync")]
+    endpoints.insert("/sync/links".to_string(), "POST - Create the symlinks of a directory sync".to_string());
+    #[cfg(feature = "sync")]
+    endpoints.insert("/sync/delete".to_string(), "POST - Remove files a directory sync no longer has".to_string());
+    #[cfg(feature = "fetch")]
+    endpoints.insert("/fetch".to_string(), "POST - Download a URL to a file in parallel ranges, each retried and checked".to_string());
+    endpoints.insert("/net/dns-lookup".to_string(), "GET - Resolve a name through the agent's resolvers (name, type)".to_string());
+    endpoints.insert("/system/history".to_string(), "GET - Recent host CPU, memory and disk samples (since, until or job_id)".to_string());
+    endpoints.insert("/metrics".to_string(), "GET - Prometheus metrics, including job CPU time and peak memory per tag".to_string());
+    endpoints.insert("/admin/queue".to_string(), "GET - Queued jobs with why and when they start, running jobs, held locks and workers".to_string());
+    endpoints.insert("/admin/firewall".to_string(), "GET, POST, DELETE - Whether the agent's ports are open in the host firewall; open or close them".to_string());
+    endpoints.insert("/admin/time-sync".to_string(), "GET, POST - Whether the host's time service keeps the clock synchronized; resynchronize it".to_string());
+    #[cfg(feature = "shell")]
+    endpoints.insert("/shell".to_string(), "GET - Interactive shell in a pseudo-terminal over a WebSocket (shell, cwd, cols, rows, record)".to_string());
+    endpoints.insert("/events".to_string(), "GET - Stream agent and job events (CloudEvents over SSE)".to_string());
     
     Ok(HttpResponse::Ok().json(HomeResponse {
         message: "Machine Agent API".to_string(),
@@ -133,14 +546,135 @@ async fn home() -> ActixResult<HttpResponse> {
     }))
 }
 
-async fn health() -> ActixResult<HttpResponse> {
-    Ok(HttpResponse::Ok().json(HealthResponse {
+fn health_response(listeners: &[listen::ListenerInfo]) -> HealthResponse {
+    HealthResponse {
         status: "healthy".to_string(),
         platform: std::env::consts::OS.to_string(),
+        listeners: listeners.to_vec(),
+        features: capabilities::features(),
+        cloud: cloud::metadata(),
+        confinement: confinement::agent(),
+        started_at: timekeeping::started_at(),
+        uptime_secs: timekeeping::uptime_secs(),
+        boot_time: timekeeping::boot_time(),
+        clock: timekeeping::status(),
+    }
+}
+
+async fn health(listeners: web::Data<Vec<listen::ListenerInfo>>) -> ActixResult<HttpResponse> {
+    Ok(HttpResponse::Ok().json(health_response(&listeners)))
+}
+
+/// GET /health/details - `/health` with the agent's recent internal errors,
+/// for API key holders.
+async fn health_details(
+    http_req: HttpRequest,
+    listeners: web::Data<Vec<listen::ListenerInfo>>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    if let Err(error_msg) = auth::authenticate(&config, &http_req).await {
+        return Ok(HttpResponse::Unauthorized().json(HealthDetailsResponse {
+            error: Some(error_msg),
+            ..Default::default()
+        }));
+    }
+    let (total, recent) = recent_errors::snapshot();
+    Ok(HttpResponse::Ok().json(HealthDetailsResponse {
+        health: Some(health_response(&listeners)),
+        errors_total: Some(total),
+        recent_errors: Some(recent),
+        error: None,
     }))
 }
 
-async fn execute_command(req: web::Json<ExecuteRequest>) -> ActixResult<HttpResponse> {
+async fn execute_command(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    results: web::Data<ResultCache>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let line_format = line_events::wanted(&http_req);
+    execute(http_req, body, bus, jobs, results, config, line_format).await
+}
+
+/// POST /execute-stream - `/execute`, answered with the command's output
+/// lines and then its result as Server-Sent Events.
+async fn execute_command_stream(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    results: web::Data<ResultCache>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    execute(http_req, body, bus, jobs, results, config, Some(heartbeat::Format::Sse)).await
+}
+
+/// POST /execute-script - `/execute` with a multi-line `script` for an
+/// `interpreter` instead of a `command`.
+async fn execute_script(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    results: web::Data<ResultCache>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let mut body = body.into_inner();
+    if let Err(error_msg) = script::into_command(&mut body) {
+        log_error("/execute-script", &error_msg, None);
+        return Ok(HttpResponse::BadRequest().json(ExecuteResponse {
+            success: false,
+            command: String::new(),
+            job_id: None,
+            stdout: None,
+            stderr: None,
+            return_code: None,
+            executed: None,
+            error: Some(error_msg),
+            artifacts: None,
+            skip_reason: None,
+            cached: false,
+            timed_out: false,
+        }));
+    }
+    let line_format = line_events::wanted(&http_req);
+    execute(http_req, web::Json(body), bus, jobs, results, config, line_format).await
+}
+
+/// Run an `/execute` request, streaming line events as `line_format` when
+/// set.
+async fn execute(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    results: web::Data<ResultCache>,
+    config: web::Data<AppConfig>,
+    line_format: Option<heartbeat::Format>,
+) -> ActixResult<HttpResponse> {
+    let req = match policy::evaluate("/execute", &http_req, body.into_inner(), &config).await.request {
+        Ok(req) => req,
+        Err(denial) => {
+            log_error("/execute", &denial.error, Some(&denial.command));
+            return Ok(HttpResponse::build(denial.status).json(ExecuteResponse {
+                success: false,
+                command: denial.command,
+                job_id: None,
+                stdout: None,
+                stderr: None,
+                return_code: None,
+                executed: None,
+                error: Some(denial.error),
+                artifacts: None,
+                skip_reason: None,
+                cached: false,
+                timed_out: false,
+            }));
+        }
+    };
     let command = req.command.trim();
     
     if command.is_empty() {
@@ -149,61 +683,604 @@ async fn execute_command(req: web::Json<ExecuteRequest>) -> ActixResult<HttpResp
         return Ok(HttpResponse::BadRequest().json(ExecuteResponse {
             success: false,
             command: command.to_string(),
+            job_id: None,
+            stdout: None,
+            stderr: None,
+            return_code: None,
+            executed: None,
+            error: Some(error_msg.to_string()),
+            artifacts: None,
+            skip_reason: None,
+            cached: false,
+            timed_out: false,
+        }));
+    }
+    
+    if !req.expect.is_empty() || req.record_cast || req.splay_secs.is_some_and(|secs| secs > 0) {
+        let error_msg = if req.record_cast {
+            "record_cast is only supported by /execute-async"
+        } else if req.expect.is_empty() {
+            "splay_secs is only supported by /execute-async"
+        } else {
+            "expect is only supported by /execute-async"
+        };
+        log_error("/execute", error_msg, Some(command));
+        return Ok(HttpResponse::BadRequest().json(ExecuteResponse {
+            success: false,
+            command: command.to_string(),
+            job_id: None,
             stdout: None,
             stderr: None,
             return_code: None,
             executed: None,
             error: Some(error_msg.to_string()),
+            artifacts: None,
+            skip_reason: None,
+            cached: false,
+            timed_out: false,
+        }));
+    }
+    
+    // Interactive session runs aren't cached.
+    let cache = req.cache_ttl.filter(|_| !req.interactive_session).map(|ttl| {
+        let command = req.command_os.as_deref().unwrap_or(command.as_ref());
+        let key = result_cache::key(
+            command,
+            req.runs_in(),
+            &req.env_os,
+            req.clear_env,
+            &req.current_dir(),
+            req.stdin.as_deref(),
+            req.run_as.as_ref().map(|run_as| run_as.user.as_str()),
+        );
+        (key, Duration::from_secs(ttl))
+    });
+    if let Some(cached) = cache.as_ref().and_then(|(key, ttl)| results.get(key, *ttl)) {
+        return Ok(HttpResponse::Ok().json(req.shape(ExecuteResponse {
+            success: true,
+            command: command.to_string(),
+            job_id: Some(cached.job_id),
+            stdout: Some(cached.stdout),
+            stderr: Some(cached.stderr),
+            return_code: cached.return_code,
+            executed: Some(true),
+            error: None,
+            artifacts: cached.artifacts,
+            skip_reason: None,
+            cached: true,
+            timed_out: false,
+        })));
+    }
+    
+    let schedule = if req.on_window_miss == time_window::OnWindowMiss::Queue {
+        Err("on_window_miss=queue is only supported by /execute-async".to_string())
+    } else if req.on_missed != time_window::OnMissed::default() {
+        Err("on_missed is only supported by /execute-async".to_string())
+    } else if !req.on_success.is_empty() || !req.on_failure.is_empty() {
+        Err("on_success and on_failure are only supported by /execute-async".to_string())
+    } else {
+        time_window::Schedule::new(&req.allowed_windows)
+    };
+    let schedule = match schedule {
+        Ok(schedule) => schedule,
+        Err(error_msg) => {
+            log_error("/execute", &error_msg, Some(command));
+            return Ok(HttpResponse::BadRequest().json(ExecuteResponse {
+                success: false,
+                command: command.to_string(),
+                job_id: None,
+                stdout: None,
+                stderr: None,
+                return_code: None,
+                executed: None,
+                error: Some(error_msg),
+                artifacts: None,
+                skip_reason: None,
+                cached: false,
+                timed_out: false,
+            }));
+        }
+    };
+    if !schedule.allows(Local::now()) {
+        let error_msg = window_miss_error(&schedule);
+        log_error("/execute", &error_msg, Some(command));
+        return Ok(HttpResponse::Conflict().json(ExecuteResponse {
+            success: false,
+            command: command.to_string(),
+            job_id: None,
+            stdout: None,
+            stderr: None,
+            return_code: None,
+            executed: None,
+            error: Some(error_msg),
+            artifacts: None,
+            skip_reason: None,
+            cached: false,
+            timed_out: false,
+        }));
+    }
+    
+    let hook_set = match resolve_hooks(&req, &config) {
+        Ok(hook_set) => hook_set,
+        Err(error_msg) => {
+            log_error("/execute", &error_msg, Some(command));
+            return Ok(HttpResponse::BadRequest().json(ExecuteResponse {
+                success: false,
+                command: command.to_string(),
+                job_id: None,
+                stdout: None,
+                stderr: None,
+                return_code: None,
+                executed: None,
+                error: Some(error_msg),
+                artifacts: None,
+                skip_reason: None,
+                cached: false,
+                timed_out: false,
+            }));
+        }
+    };
+    
+    // Guards and host load are checked once there is a slot.
+    let slot = match config.concurrency.acquire().await {
+        Ok(slot) => slot,
+        Err(error_msg) => {
+            log_error("/execute", &error_msg, Some(command));
+            return Ok(concurrency::response(StatusCode::TOO_MANY_REQUESTS).json(ExecuteResponse {
+                success: false,
+                command: command.to_string(),
+                job_id: None,
+                stdout: None,
+                stderr: None,
+                return_code: None,
+                executed: Some(false),
+                error: Some(error_msg),
+                artifacts: None,
+                skip_reason: None,
+                cached: false,
+                timed_out: false,
+            }));
+        }
+    };
+    
+    let job_id = uuid::Uuid::new_v4().to_string();
+    let mut job = Job::new(&job_id, command, None);
+    job.tag = req.tag.clone();
+    job.lock = req.lock.clone();
+    job.api_key = req.api_key.clone();
+    job.client_certificate = req.client_certificate.clone();
+    job.run_as = req.run_as.as_ref().map(|run_as| run_as.user.clone());
+    job.policy = req.policy.clone();
+    let mut skip_reason = guards::evaluate(&req.guards, &jobs).await;
+    if skip_reason.is_none() {
+        if let Some(mut check) = pressure::check(&config).await {
+            if let Some(reason) = check.reason.clone() {
+                // A synchronous request can't wait for the host to recover.
+                check.decision = pressure::Decision::Rejected;
+                job.host_check = Some(check);
+                skip_job(job, &reason, &bus, &jobs);
+                log_error("/execute", &format!("Host under pressure: {}", reason), Some(command));
+                return Ok(HttpResponse::ServiceUnavailable().json(ExecuteResponse {
+                    success: false,
+                    command: command.to_string(),
+                    job_id: Some(job_id),
+                    stdout: None,
+                    stderr: None,
+                    return_code: None,
+                    executed: Some(false),
+                    error: Some(format!("Host under pressure: {}", reason)),
+                    artifacts: None,
+                    skip_reason: Some(reason),
+                    cached: false,
+                    timed_out: false,
+                }));
+            }
+            job.host_check = Some(check);
+        }
+        // Checked again as the job takes them: another may have meanwhile.
+        skip_reason = jobs.try_acquire_locks(&job, &guards::lock_names(&req.guards)).err();
+    }
+    if let Some(reason) = skip_reason {
+        skip_job(job, &reason, &bus, &jobs);
+        return Ok(HttpResponse::Ok().json(ExecuteResponse {
+            success: true,
+            command: command.to_string(),
+            job_id: Some(job_id),
+            stdout: None,
+            stderr: None,
+            return_code: None,
+            executed: Some(false),
+            error: None,
+            artifacts: None,
+            skip_reason: Some(reason),
+            cached: false,
+            timed_out: false,
         }));
     }
+    bus.publish(events::JOB_STARTED, Some(&job_id), serde_json::json!({ "command": command }));
+    
+    if req.interactive_session {
+        return Ok(execute_in_interactive_session(command, job_id, &req.current_dir(), &req.outputs, &bus, &jobs, &config).await);
+    }
+    
+    if let Some(hook_set) = &hook_set {
+        if let Err(e) = run_pre_hook(hook_set, &job_id, &jobs).await {
+            log_error("/execute", &e.to_string(), Some(command));
+            jobs.fail(&job_id, &e.to_string());
+            bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                "command": command,
+                "error": e.to_string(),
+            }));
+            return Ok(HttpResponse::InternalServerError().json(ExecuteResponse {
+                success: false,
+                command: command.to_string(),
+                job_id: Some(job_id),
+                stdout: None,
+                stderr: None,
+                return_code: None,
+                executed: Some(false),
+                error: Some(e.to_string()),
+                artifacts: None,
+                skip_reason: None,
+                cached: false,
+                timed_out: false,
+            }));
+        }
+    }
+    
+    let script = match req.interpreter {
+        Some(interpreter) => {
+            let source = req.command_os.as_deref().unwrap_or(command.as_ref());
+            match script::ScriptFile::write(interpreter, source, req.run_as.as_ref()) {
+                Ok(script) => Some(script),
+                Err(e) => {
+                    let error_msg = format!("Failed to write script: {}", e);
+                    log_error("/execute", &error_msg, Some(command));
+                    jobs.fail(&job_id, &error_msg);
+                    bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                        "command": command,
+                        "error": error_msg,
+                    }));
+                    return Ok(HttpResponse::InternalServerError().json(ExecuteResponse {
+                        success: false,
+                        command: command.to_string(),
+                        job_id: Some(job_id),
+                        stdout: None,
+                        stderr: None,
+                        return_code: None,
+                        executed: Some(false),
+                        error: Some(error_msg),
+                        artifacts: None,
+                        skip_reason: None,
+                        cached: false,
+                        timed_out: false,
+                    }));
+                }
+            }
+        }
+        None => None,
+    };
     
     // Execute the command
-    let output = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(["/C", command])
-            .current_dir(std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
-            .output()
+    let mut cmd = match &script {
+        Some(script) => script.command(),
+        None => req.shell().command(req.command_os.as_deref().unwrap_or(command.as_ref())),
+    };
+    cmd.current_dir(req.current_dir());
+    if req.clear_env {
+        cmd.env_clear();
+    }
+    cmd.envs(req.env_os.iter().map(|(name, value)| (name, value)));
+    if let Some(run_as) = &req.run_as {
+        run_as.apply(&mut cmd);
+    }
+    req.confinement().apply(&mut cmd);
+    let command = command.to_string();
+    let command = command.as_str();
+    let async_after = req.async_after.map(Duration::from_secs).or(config.execute_async_after);
+    let cancel_on_disconnect = req.cancel_on_disconnect.unwrap_or(config.cancel_on_disconnect);
+    let heartbeat = req.heartbeat.map(Duration::from_secs).or(config.execute_heartbeat).filter(|period| !period.is_zero());
+    let heartbeat_format = req.heartbeat_format;
+    // So the whole tree can be killed on a timeout, disconnect or cancel.
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let (lines, line_receiver) = if line_format.is_some() {
+        let (lines, line_receiver) = tokio::sync::mpsc::unbounded_channel();
+        (Some(lines), Some(line_receiver))
     } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .current_dir(std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
-            .output()
+        (None, None)
+    };
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let run = run_command(cmd, job_id.clone(), command.to_string(), req, hook_set, cache, lines, bus.clone(), jobs.clone(), results, config);
+    supervisor::spawn_job_task(job_id.clone(), jobs.clone(), bus.get_ref().clone(), async move {
+        let _ = sender.send(run.await);
+        // Removes the script, which may outlive the request with async_after.
+        drop(script);
+        drop(slot);
+    });
+    let disconnect = disconnect::DisconnectGuard::new(&job_id, jobs.clone(), cancel_on_disconnect);
+    let wait = async move {
+        match async_after {
+            Some(async_after) => tokio::time::timeout(async_after, receiver).await.ok(),
+            None => Some(receiver.await),
+        }
     };
+    let result = {
+        let (job_id, command, jobs) = (job_id.clone(), command.to_string(), jobs.clone());
+        async move {
+            let response = tokio::select! {
+                response = wait => response,
+                // Nobody to answer; dropping `disconnect` records it on the job.
+                () = disconnect::closed(&http_req) => return None,
+            };
+            disconnect.disarm();
+            Some(execute_result(response, &job_id, &command, async_after, &jobs))
+        }
+    };
+    if heartbeat.is_some() || line_receiver.is_some() {
+        let format = line_format.unwrap_or(heartbeat_format);
+        let body = heartbeat::stream(format, &job_id, heartbeat, line_receiver, async move {
+            result.await.map(|(_, body)| body)
+        });
+        return Ok(HttpResponse::Ok()
+            .content_type(format.content_type())
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(body));
+    }
+    match result.await {
+        Some((status, body)) => Ok(HttpResponse::build(status).content_type("application/json").body(body)),
+        None => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+/// The status and body answering a synchronous request: its result, or 202
+/// with the job ID when it was still running after `async_after`.
+fn execute_result(
+    response: Option<Result<ExecuteResponse, tokio::sync::oneshot::error::RecvError>>,
+    job_id: &str,
+    command: &str,
+    async_after: Option<Duration>,
+    jobs: &JobRegistry,
+) -> (StatusCode, Vec<u8>) {
+    let Some(response) = response else {
+        // The command carries on as a job; its result is under /jobs/{id}.
+        let job = jobs.get(job_id);
+        let response = AsyncExecuteResponse {
+            success: true,
+            message: Some(format!("Still running after {}s; see /jobs/{}", async_after.unwrap_or_default().as_secs(), job_id)),
+            command: command.to_string(),
+            pid: job.as_ref().and_then(|job| job.pid).unwrap_or(0),
+            started_at: job.map(|job| job.started_at).unwrap_or_default(),
+            job_id: Some(job_id.to_string()),
+            status: "running".to_string(),
+            error: None,
+        };
+        return (StatusCode::ACCEPTED, serde_json::to_vec(&response).unwrap_or_default());
+    };
+    let (status, response) = match response {
+        Ok(response) if response.success => (StatusCode::OK, response),
+        Ok(response) if response.timed_out => (StatusCode::GATEWAY_TIMEOUT, response),
+        Ok(response) => (StatusCode::INTERNAL_SERVER_ERROR, response),
+        // The supervisor has marked the job interrupted.
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, ExecuteResponse {
+            success: false,
+            command: command.to_string(),
+            job_id: Some(job_id.to_string()),
+            stdout: None,
+            stderr: None,
+            return_code: None,
+            executed: None,
+            error: Some("The agent task running the job panicked (see app_error.log)".to_string()),
+            artifacts: None,
+            skip_reason: None,
+            cached: false,
+            timed_out: false,
+        }),
+    };
+    (status, serde_json::to_vec(&response).unwrap_or_default())
+}
+
+/// Run a synchronous request's command and finish its job; the response is
+/// 200 when `success` is set, 500 otherwise.
+#[allow(clippy::too_many_arguments)]
+async fn run_command(
+    mut cmd: TokioCommand,
+    job_id: String,
+    command: String,
+    req: ExecuteRequest,
+    hook_set: Option<hooks::HookSet>,
+    cache: Option<(String, Duration)>,
+    lines: Option<line_events::Sender>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    results: web::Data<ResultCache>,
+    config: web::Data<AppConfig>,
+) -> ExecuteResponse {
+    let command = command.as_str();
+    let streamed = lines.is_some();
+    let timestamps = req.timestamps.then(Instant::now);
+    let stdin = req.stdin.clone().map(String::into_bytes);
+    let mut output = {
+        let (jobs, job_id) = (jobs.clone(), job_id.clone());
+        std::pin::pin!(async move {
+            usage::output(&mut cmd, |pid| jobs.update(&job_id, |job| job.pid = Some(pid)), stdin, lines, timestamps).await
+        })
+    };
+    let mut timed_out = false;
+    let cancel = jobs.attach_cancel(&job_id);
+    let deadline = async {
+        match (req.timeout > 0).then(|| Duration::from_secs(req.timeout)) {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    // The output ends with what was printed so far once the command has
+    // exited, and at most `output::DRAIN_GRACE` later if something it left
+    // behind holds the pipes.
+    let output = tokio::select! {
+        output = output.as_mut() => output,
+        () = deadline => {
+            timed_out = true;
+            if let Some(pid) = jobs.get(&job_id).and_then(|job| job.pid) {
+                if let Err(e) = disconnect::kill_tree(pid) {
+                    log_error("/execute", &format!("Failed to kill timed out job {}: {}", job_id, e), Some(command));
+                }
+            }
+            output.await
+        }
+        () = cancel.notified() => match jobs.get(&job_id).and_then(|job| job.pid) {
+            Some(pid) => disconnect::stop_tree(pid, config.cancel_grace, output).await,
+            None => output.await,
+        },
+    };
+    let cancelled = !timed_out && jobs.is_cancelled(&job_id);
     
     match output {
-        Ok(result) => {
+        Ok((result, usage)) => {
+            if let Some(usage) = usage {
+                jobs.record_usage(&job_id, usage);
+            }
             let stdout = String::from_utf8_lossy(&result.stdout).to_string();
             let stderr = String::from_utf8_lossy(&result.stderr).to_string();
-            let return_code = result.status.code();
+            let return_code = if timed_out { None } else { result.status.code() };
+            let error = if timed_out {
+                Some(format!("Timed out after {}s", req.timeout))
+            } else {
+                // A client that disconnected left its reason.
+                cancelled.then(|| jobs.get(&job_id).and_then(|job| job.error).unwrap_or_else(|| CANCELLED.to_string()))
+            };
+            let artifacts = if req.capture_on_failure && !result.status.success() {
+                let output = diagnostics::FailedOutput { stdout: &stdout, stderr: &stderr };
+                capture_diagnostics("/execute", &job_id, command, Some(output), &config).await
+            } else {
+                None
+            };
+            jobs.append_output(&job_id, output::Stream::Stdout, &stdout);
+            jobs.append_output(&job_id, output::Stream::Stderr, &stderr);
+            if let Some(last) = stdout.lines().rev().find_map(|line| progress::parse_line(timestamps::strip(line))) {
+                jobs.update(&job_id, |job| job.progress = Some(last));
+            }
+            if let Some(log_path) = config.job_log_path(&job_id) {
+                let contents = [result.stdout.as_slice(), result.stderr.as_slice()].concat();
+                match compress::write(&log_path, &contents, config.compress_level) {
+                    Ok(written) => jobs.update(&job_id, |job| job.log_file = Some(written.display().to_string())),
+                    Err(e) => log_error("/execute", &format!("Failed to write job log: {}", e), Some(command)),
+                }
+            }
+            run_post_hook(hook_set.as_ref(), &job_id, return_code, &jobs).await;
+            provenance::stamp_job(&job_id, &req.outputs, &jobs).await;
+            offload::offload_job(&job_id, &req.outputs, &jobs, &config).await;
+            content::dedupe_job(&job_id, &config).await;
+            match &error {
+                Some(error) => {
+                    log_error("/execute", error, Some(command));
+                    jobs.fail(&job_id, error);
+                    bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                        "command": command,
+                        "error": error,
+                        "artifacts": artifacts,
+                    }));
+                }
+                None => {
+                    jobs.finish(&job_id, return_code);
+                    bus.publish(events::JOB_FINISHED, Some(&job_id), serde_json::json!({
+                        "command": command,
+                        "return_code": return_code,
+                        "artifacts": artifacts,
+                    }));
+                }
+            }
+            if let Some((key, _)) = cache.filter(|_| error.is_none()) {
+                results.insert(key, CachedResult {
+                    job_id: job_id.clone(),
+                    stdout: stdout.clone(),
+                    stderr: stderr.clone(),
+                    return_code,
+                    artifacts: artifacts.clone(),
+                });
+            }
             
-            Ok(HttpResponse::Ok().json(ExecuteResponse {
-                success: true,
+            let mut response = req.shape(ExecuteResponse {
+                success: error.is_none(),
                 command: command.to_string(),
+                job_id: Some(job_id),
                 stdout: Some(stdout),
                 stderr: Some(stderr),
                 return_code,
                 executed: Some(true),
-                error: None,
-            }))
+                error,
+                artifacts,
+                skip_reason: None,
+                cached: false,
+                timed_out,
+            });
+            if streamed {
+                // Already sent as line events.
+                response.stdout = None;
+                response.stderr = None;
+            }
+            response
         }
         Err(e) => {
             let error_msg = format!("Command execution failed: {}", e);
             log_error_with_traceback("/execute", &error_msg, &format!("{:?}", e), Some(command));
-            Ok(HttpResponse::InternalServerError().json(ExecuteResponse {
+            let artifacts = if req.capture_on_failure {
+                capture_diagnostics("/execute", &job_id, command, None, &config).await
+            } else {
+                None
+            };
+            run_post_hook(hook_set.as_ref(), &job_id, None, &jobs).await;
+            provenance::stamp_job(&job_id, &req.outputs, &jobs).await;
+            offload::offload_job(&job_id, &req.outputs, &jobs, &config).await;
+            content::dedupe_job(&job_id, &config).await;
+            jobs.fail(&job_id, &e.to_string());
+            bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                "command": command,
+                "error": e.to_string(),
+                "artifacts": artifacts,
+            }));
+            req.shape(ExecuteResponse {
                 success: false,
                 command: command.to_string(),
+                job_id: Some(job_id),
                 stdout: None,
                 stderr: None,
                 return_code: None,
                 executed: None,
                 error: Some(e.to_string()),
-            }))
+                artifacts,
+                skip_reason: None,
+                cached: false,
+                timed_out: false,
+            })
         }
     }
 }
 
-async fn execute_command_async(req: web::Json<ExecuteRequest>) -> ActixResult<HttpResponse> {
+async fn execute_command_async(
+    http_req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let evaluation = policy::evaluate("/execute-async", &http_req, body.into_inner(), &config).await;
+    let req = match evaluation.request {
+        Ok(req) => req,
+        Err(denial) => {
+            log_error("/execute-async", &denial.error, Some(&denial.command));
+            return Ok(HttpResponse::build(denial.status).json(AsyncExecuteResponse {
+                success: false,
+                message: None,
+                command: denial.command,
+                job_id: None,
+                pid: 0,
+                started_at: String::new(),
+                status: String::new(),
+                error: Some(denial.error),
+            }));
+        }
+    };
     let command = req.command.trim();
     
     if command.is_empty() {
@@ -213,6 +1290,7 @@ async fn execute_command_async(req: web::Json<ExecuteRequest>) -> ActixResult<Ht
             success: false,
             message: None,
             command: command.to_string(),
+            job_id: None,
             pid: 0,
             started_at: String::new(),
             status: String::new(),
@@ -220,52 +1298,264 @@ async fn execute_command_async(req: web::Json<ExecuteRequest>) -> ActixResult<Ht
         }));
     }
     
-    // Execute the command asynchronously
-    let current_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
-    let cmd = if cfg!(target_os = "windows") {
-        TokioCommand::new("cmd")
-            .args(["/C", command])
-            .current_dir(&current_dir)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-    } else {
-        TokioCommand::new("sh")
-            .arg("-c")
-            .arg(command)
-            .current_dir(&current_dir)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-    };
-    
-    match cmd {
-        Ok(mut child) => {
-            let pid = child.id().unwrap_or(0);
-            let started_at = Local::now().to_rfc3339();
-            
-            // Detach the process - don't wait for it
-            tokio::spawn(async move {
-                let _ = child.wait().await;
-            });
-            
-            Ok(HttpResponse::Ok().json(AsyncExecuteResponse {
+    if let Some(field) = req.sync_only_field() {
+        let error_msg = format!("{} is only supported by /execute", field);
+        log_error("/execute-async", &error_msg, Some(command));
+        return Ok(HttpResponse::BadRequest().json(AsyncExecuteResponse {
+            success: false,
+            message: None,
+            command: command.to_string(),
+            job_id: None,
+            pid: 0,
+            started_at: String::new(),
+            status: String::new(),
+            error: Some(error_msg),
+        }));
+    }
+    
+    let mut job = match AsyncJob::new(uuid::Uuid::new_v4().to_string(), &req, evaluation.shaped, &config) {
+        Ok(job) => job,
+        Err(error_msg) => {
+            log_error("/execute-async", &error_msg, Some(command));
+            return Ok(HttpResponse::BadRequest().json(AsyncExecuteResponse {
+                success: false,
+                message: None,
+                command: command.to_string(),
+                job_id: None,
+                pid: 0,
+                started_at: String::new(),
+                status: String::new(),
+                error: Some(error_msg),
+            }));
+        }
+    };
+    job.chain = match chains::evaluate(&req, &http_req, &config).await {
+        Ok(chain) => chain,
+        Err(denial) => {
+            log_error("/execute-async", &denial.error, Some(command));
+            return Ok(HttpResponse::build(denial.status).json(AsyncExecuteResponse {
+                success: false,
+                message: None,
+                command: command.to_string(),
+                job_id: None,
+                pid: 0,
+                started_at: String::new(),
+                status: String::new(),
+                error: Some(denial.error),
+            }));
+        }
+    };
+    
+    let schedule = match time_window::Schedule::new(&req.allowed_windows) {
+        Ok(schedule) => schedule,
+        Err(error_msg) => {
+            log_error("/execute-async", &error_msg, Some(command));
+            return Ok(HttpResponse::BadRequest().json(AsyncExecuteResponse {
+                success: false,
+                message: None,
+                command: command.to_string(),
+                job_id: None,
+                pid: 0,
+                started_at: String::new(),
+                status: String::new(),
+                error: Some(error_msg),
+            }));
+        }
+    };
+    if !schedule.allows(Local::now()) {
+        if req.on_window_miss == time_window::OnWindowMiss::Reject {
+            let error_msg = window_miss_error(&schedule);
+            log_error("/execute-async", &error_msg, Some(command));
+            return Ok(HttpResponse::Conflict().json(AsyncExecuteResponse {
+                success: false,
+                message: None,
+                command: command.to_string(),
+                job_id: None,
+                pid: 0,
+                started_at: String::new(),
+                status: String::new(),
+                error: Some(error_msg),
+            }));
+        }
+        let job_id = job.job_id.clone();
+        let queued_until = schedule
+            .next_open(Local::now())
+            .map(|opens| clock::format(&(opens + chrono::Duration::from_std(job.splay.unwrap_or_default()).unwrap_or_default())));
+        queue_job(&job, queued_until.clone(), &req.guards, &bus, &jobs);
+        supervisor::spawn_job_task(
+            job_id.clone(),
+            jobs.clone(),
+            bus.get_ref().clone(),
+            run_queued(schedule, job, req.guards.clone(), bus, jobs, config),
+        );
+        return Ok(HttpResponse::Accepted().json(AsyncExecuteResponse {
+            success: true,
+            message: Some(format!(
+                "{}; queued until {}",
+                window_miss_reason(),
+                queued_until.as_deref().unwrap_or("the next window")
+            )),
+            command: command.to_string(),
+            job_id: Some(job_id),
+            pid: 0,
+            started_at: String::new(),
+            status: "queued".to_string(),
+            error: None,
+        }));
+    }
+    
+    if let Some(splay) = job.splay {
+        let job_id = job.job_id.clone();
+        let queued_until = clock::format(&(Local::now() + chrono::Duration::from_std(splay).unwrap_or_default()));
+        queue_job(&job, Some(queued_until.clone()), &req.guards, &bus, &jobs);
+        supervisor::spawn_job_task(
+            job_id.clone(),
+            jobs.clone(),
+            bus.get_ref().clone(),
+            run_queued(schedule, job, req.guards.clone(), bus, jobs, config),
+        );
+        return Ok(HttpResponse::Accepted().json(AsyncExecuteResponse {
+            success: true,
+            message: Some(format!("Starting after a {}s splay, at {}", splay.as_secs(), queued_until)),
+            command: command.to_string(),
+            job_id: Some(job_id),
+            pid: 0,
+            started_at: String::new(),
+            status: "queued".to_string(),
+            error: None,
+        }));
+    }
+
+    // Guards and host load are checked once there is a slot.
+    let slot = match config.concurrency.acquire().await {
+        Ok(slot) => slot,
+        Err(error_msg) => {
+            log_error("/execute-async", &error_msg, Some(command));
+            return Ok(concurrency::response(StatusCode::TOO_MANY_REQUESTS).json(AsyncExecuteResponse {
+                success: false,
+                message: None,
+                command: command.to_string(),
+                job_id: None,
+                pid: 0,
+                started_at: String::new(),
+                status: String::new(),
+                error: Some(error_msg),
+            }));
+        }
+    };
+
+    if let Some(reason) = guards::evaluate(&req.guards, &jobs).await {
+        skip_job(Job::new(&job.job_id, command, None), &reason, &bus, &jobs);
+        return Ok(HttpResponse::Ok().json(AsyncExecuteResponse {
+            success: true,
+            message: Some(format!("Command skipped: {}", reason)),
+            command: command.to_string(),
+            job_id: Some(job.job_id),
+            pid: 0,
+            started_at: String::new(),
+            status: "skipped".to_string(),
+            error: None,
+        }));
+    }
+    
+    if let Some(check) = pressure::check(&config).await {
+        let reason = check.reason.clone().unwrap_or_default();
+        let decision = check.decision;
+        job.host_check = Some(check);
+        if decision == pressure::Decision::Rejected {
+            let job_id = job.job_id.clone();
+            skip_job(job.into_record(None), &reason, &bus, &jobs);
+            log_error("/execute-async", &format!("Host under pressure: {}", reason), Some(command));
+            return Ok(HttpResponse::ServiceUnavailable().json(AsyncExecuteResponse {
+                success: false,
+                message: None,
+                command: command.to_string(),
+                job_id: Some(job_id),
+                pid: 0,
+                started_at: String::new(),
+                status: "skipped".to_string(),
+                error: Some(format!("Host under pressure: {}", reason)),
+            }));
+        }
+        if decision == pressure::Decision::Deferred {
+            let job_id = job.job_id.clone();
+            queue_job(&job, None, &req.guards, &bus, &jobs);
+            supervisor::spawn_job_task(
+                job_id.clone(),
+                jobs.clone(),
+                bus.get_ref().clone(),
+                run_queued(schedule, job, req.guards.clone(), bus, jobs, config),
+            );
+            return Ok(HttpResponse::Accepted().json(AsyncExecuteResponse {
                 success: true,
-                message: Some("Command started successfully".to_string()),
+                message: Some(format!("Host under pressure ({}); deferred", reason)),
                 command: command.to_string(),
-                pid,
-                started_at,
-                status: "running".to_string(),
+                job_id: Some(job_id),
+                pid: 0,
+                started_at: String::new(),
+                status: "queued".to_string(),
                 error: None,
-            }))
+            }));
+        }
+    }
+    
+    if let Err(reason) = job.take_locks(&req.guards, &jobs) {
+        let job_id = job.job_id.clone();
+        skip_job(job.into_record(None), &reason, &bus, &jobs);
+        return Ok(HttpResponse::Ok().json(AsyncExecuteResponse {
+            success: true,
+            message: Some(format!("Command skipped: {}", reason)),
+            command: command.to_string(),
+            job_id: Some(job_id),
+            pid: 0,
+            started_at: String::new(),
+            status: "skipped".to_string(),
+            error: None,
+        }));
+    }
+    
+    let job_id = job.job_id.clone();
+    let interactive_session = job.interactive_session;
+    job.slot = slot;
+    let started = start_async_job(job, &bus, &jobs, &config).await;
+    if let Err(e) = &started {
+        // Registered by take_locks, but never started.
+        if jobs.get(&job_id).is_some_and(|job| job.status == jobs::JobStatus::Running) {
+            jobs.fail(&job_id, &e.to_string());
+        }
+    }
+    match started {
+        Ok(pid) => Ok(HttpResponse::Ok().json(AsyncExecuteResponse {
+            success: true,
+            message: Some(if interactive_session {
+                "Command started in interactive session".to_string()
+            } else {
+                "Command started successfully".to_string()
+            }),
+            command: command.to_string(),
+            job_id: Some(job_id),
+            pid,
+            started_at: clock::now(),
+            status: "running".to_string(),
+            error: None,
+        })),
+        Err(e) if interactive_session => {
+            let error_msg = format!("Failed to start command in interactive session: {}", e);
+            log_error_with_traceback("/execute-async", &error_msg, &format!("{:?}", e), Some(command));
+            Ok(session_error_response(command, None, &e))
         }
         Err(e) => {
             let error_msg = format!("Failed to start command: {}", e);
             log_error_with_traceback("/execute-async", &error_msg, &format!("{:?}", e), Some(command));
+            // The caller hears about it here; a failed pre hook has already
+            // queued the chain's on_failure.
+            chains::forget(&job_id);
             Ok(HttpResponse::InternalServerError().json(AsyncExecuteResponse {
                 success: false,
                 message: None,
                 command: command.to_string(),
+                // Jobs whose pre hook failed, or that took locks, are recorded.
+                job_id: jobs.get(&job_id).map(|job| job.id),
                 pid: 0,
                 started_at: String::new(),
                 status: String::new(),
@@ -275,6 +1565,724 @@ async fn execute_command_async(req: web::Json<ExecuteRequest>) -> ActixResult<Ht
     }
 }
 
+/// Error for a request that arrived outside its execution windows.
+fn window_miss_error(schedule: &time_window::Schedule) -> String {
+    match schedule.next_open(Local::now()) {
+        Some(opens) => format!("{}; next window opens at {}", window_miss_reason(), clock::format(&opens)),
+        None => window_miss_reason(),
+    }
+}
+
+/// Why no execution window is open right now.
+fn window_miss_reason() -> String {
+    match blackout::covering(Local::now()) {
+        Some(period) => format!("In {}", period),
+        None => "Outside the allowed execution windows".to_string(),
+    }
+}
+
+/// An `/execute-async` job, owned so that starting it can be deferred.
+struct AsyncJob {
+    job_id: String,
+    command: String,
+    /// The decoded `command_base64`, run instead of `command`.
+    command_os: Option<OsString>,
+    shell: shells::Shell,
+    env: Vec<(String, OsString)>,
+    clear_env: bool,
+    current_dir: PathBuf,
+    stdin: Option<String>,
+    tag: Option<String>,
+    lock: Option<String>,
+    api_key: Option<String>,
+    client_certificate: Option<String>,
+    run_as: Option<api_keys::RunAs>,
+    confinement: confinement::Confinement,
+    policy: Vec<policy::RuleMatch>,
+    interactive_session: bool,
+    keep_stdin_open: bool,
+    expecter: Option<expect::Expecter>,
+    capture_on_failure: bool,
+    record_cast: bool,
+    timestamps: bool,
+    /// The API key's `max_timeout`, after which the job's process tree is
+    /// killed.
+    timeout: Option<Duration>,
+    /// Random delay before the job starts.
+    splay: Option<Duration>,
+    host_check: Option<pressure::HostCheck>,
+    hook_set: Option<hooks::HookSet>,
+    hook_results: Vec<hooks::HookResult>,
+    outputs: Vec<String>,
+    /// The request after the key's defaults and overrides, kept with the
+    /// job while it is queued.
+    request: serde_json::Value,
+    /// Jobs queued when this one ends.
+    chain: chains::Chain,
+    /// Job whose outcome queued this one.
+    follows: Option<String>,
+    /// Held from when the job may start until it is done.
+    slot: concurrency::Slot,
+}
+
+impl AsyncJob {
+    fn new(job_id: String, req: &ExecuteRequest, request: serde_json::Value, config: &AppConfig) -> Result<Self, String> {
+        let expecter = if req.expect.is_empty() || req.interactive_session {
+            None
+        } else {
+            Some(expect::Expecter::new(&req.expect)?)
+        };
+        Ok(AsyncJob {
+            job_id,
+            command: req.command.trim().to_string(),
+            command_os: req.command_os.clone(),
+            shell: req.shell(),
+            env: req.env_os.clone(),
+            clear_env: req.clear_env,
+            current_dir: req.current_dir(),
+            stdin: req.stdin.clone(),
+            tag: req.tag.clone(),
+            lock: req.lock.clone(),
+            api_key: req.api_key.clone(),
+            client_certificate: req.client_certificate.clone(),
+            run_as: req.run_as.clone(),
+            confinement: req.confinement(),
+            policy: req.policy.clone(),
+            interactive_session: req.interactive_session,
+            keep_stdin_open: req.keep_stdin_open,
+            expecter,
+            capture_on_failure: req.capture_on_failure,
+            record_cast: req.record_cast && !req.interactive_session,
+            timestamps: req.timestamps,
+            timeout: req.max_timeout.filter(|&secs| secs > 0).map(Duration::from_secs),
+            splay: req.splay_secs.filter(|&secs| secs > 0).map(time_window::splay),
+            host_check: None,
+            hook_set: resolve_hooks(req, config)?,
+            hook_results: Vec::new(),
+            outputs: req.outputs.clone(),
+            request,
+            chain: chains::Chain::default(),
+            follows: None,
+            slot: None,
+        })
+    }
+
+    /// Registry entry for this job.
+    fn into_record(self, pid: Option<u32>) -> Job {
+        let mut record = Job::new(&self.job_id, &self.command, pid);
+        record.tag = self.tag;
+        record.lock = self.lock;
+        record.api_key = self.api_key;
+        record.client_certificate = self.client_certificate;
+        record.run_as = self.run_as.map(|run_as| run_as.user);
+        record.policy = self.policy;
+        record.follows = self.follows;
+        record.host_check = self.host_check;
+        record.splay_secs = self.splay.map(|splay| splay.as_secs());
+        record.hooks = self.hook_results;
+        record
+    }
+
+    /// Register this job as running, holding its `lock`, unless a running
+    /// job holds one of the locks `guards` need; `Err` is why it is skipped.
+    /// The `lock_free` guards were checked before, but another job may have
+    /// taken a lock since.
+    fn take_locks(&self, guards: &[guards::Guard], jobs: &JobRegistry) -> Result<(), String> {
+        let locks = guards::lock_names(guards);
+        if locks.is_empty() {
+            return Ok(());
+        }
+        let mut record = Job::new(&self.job_id, &self.command, None);
+        record.tag = self.tag.clone();
+        record.lock = self.lock.clone();
+        record.api_key = self.api_key.clone();
+        record.client_certificate = self.client_certificate.clone();
+        record.run_as = self.run_as.as_ref().map(|run_as| run_as.user.clone());
+        record.policy = self.policy.clone();
+        record.follows = self.follows.clone();
+        record.host_check = self.host_check.clone();
+        jobs.try_acquire_locks(&record, &locks)
+    }
+}
+
+/// Error of a job cancelled through `DELETE /jobs/{id}`.
+const CANCELLED: &str = "Cancelled";
+
+/// How often a deferred job re-checks the host.
+const DEFER_RETRY: Duration = Duration::from_secs(15);
+
+/// Record a job as waiting to be started by `run_queued`.
+fn queue_job(
+    job: &AsyncJob,
+    queued_until: Option<String>,
+    guards: &[guards::Guard],
+    bus: &EventBus,
+    jobs: &JobRegistry,
+) {
+    let mut queued = Job::new(&job.job_id, &job.command, None);
+    queued.status = jobs::JobStatus::Queued;
+    queued.queued_until = queued_until.clone();
+    queued.tag = job.tag.clone();
+    queued.lock_guards = guards::lock_names(guards);
+    queued.api_key = job.api_key.clone();
+    queued.client_certificate = job.client_certificate.clone();
+    queued.run_as = job.run_as.as_ref().map(|run_as| run_as.user.clone());
+    queued.policy = job.policy.clone();
+    queued.follows = job.follows.clone();
+    queued.host_check = job.host_check.clone();
+    queued.splay_secs = job.splay.map(|splay| splay.as_secs());
+    jobs.insert(queued);
+    if let Err(e) = queued::save(job, queued_until.as_deref()) {
+        log_error("/execute-async", &format!("Failed to save queued job: {}", e), Some(&job.command));
+    }
+    bus.publish(events::JOB_QUEUED, Some(&job.job_id), serde_json::json!({
+        "command": job.command,
+        "queued_until": queued_until,
+        "splay_secs": job.splay.map(|splay| splay.as_secs()),
+    }));
+}
+
+/// Start a queued job once its execution window is open, its guards hold and
+/// the host is not under pressure (waiting up to `max_defer` for that).
+async fn run_queued(
+    schedule: time_window::Schedule,
+    mut job: AsyncJob,
+    guards: Vec<guards::Guard>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    config: web::Data<AppConfig>,
+) {
+    let mut splay = job.splay;
+    loop {
+        // Re-check at least every minute so clock changes and DST are picked up.
+        while !schedule.allows(Local::now()) {
+            let now = Local::now();
+            let wait = schedule
+                .next_open(now)
+                .and_then(|opens| (opens - now).to_std().ok())
+                .unwrap_or(Duration::MAX)
+                .min(Duration::from_secs(60));
+            tokio::time::sleep(wait).await;
+        }
+        // Waited out once; if the window closes meanwhile, the job starts as
+        // soon as the next one opens.
+        let Some(delay) = splay.take() else {
+            break;
+        };
+        let starts = Local::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+        jobs.update(&job.job_id, |queued| queued.queued_until = Some(clock::format(&starts)));
+        tokio::time::sleep(delay).await;
+    }
+
+    if config.read_only {
+        queued::remove(&job.job_id);
+        skip_job(Job::new(&job.job_id, &job.command, None), read_only::SKIPPED, &bus, &jobs);
+        return;
+    }
+    if let Some(reason) = guards::evaluate(&guards, &jobs).await {
+        queued::remove(&job.job_id);
+        skip_job(Job::new(&job.job_id, &job.command, None), &reason, &bus, &jobs);
+        return;
+    }
+    
+    let deadline = tokio::time::Instant::now() + config.max_defer;
+    loop {
+        let Some(mut check) = pressure::check(&config).await else {
+            break;
+        };
+        let Some(reason) = check.reason.clone() else {
+            job.host_check = Some(check);
+            break;
+        };
+        if check.decision == pressure::Decision::Rejected || tokio::time::Instant::now() >= deadline {
+            check.decision = pressure::Decision::Rejected;
+            job.host_check = Some(check);
+            log_error("/execute-async", &format!("Host under pressure: {}", reason), Some(&job.command));
+            queued::remove(&job.job_id);
+            skip_job(job.into_record(None), &reason, &bus, &jobs);
+            return;
+        }
+        jobs.update(&job.job_id, |queued| queued.host_check = Some(check));
+        tokio::time::sleep(DEFER_RETRY).await;
+    }
+    
+    job.slot = config.concurrency.wait_for(&job.job_id).await;
+    let job_id = job.job_id.clone();
+    let command = job.command.clone();
+    queued::remove(&job_id);
+    if let Err(reason) = job.take_locks(&guards, &jobs) {
+        skip_job(job.into_record(None), &reason, &bus, &jobs);
+        return;
+    }
+    if let Err(e) = start_async_job(job, &bus, &jobs, &config).await {
+        let error_msg = format!("Failed to start queued command: {}", e);
+        log_error_with_traceback("/execute-async", &error_msg, &format!("{:?}", e), Some(&command));
+        if jobs.get(&job_id).is_some_and(|job| job.status == jobs::JobStatus::Failed) {
+            // Already recorded by start_async_job (failed pre hook).
+            return;
+        }
+        jobs.fail(&job_id, &e.to_string());
+        bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+            "command": command,
+            "error": e.to_string(),
+        }));
+    }
+}
+
+/// Spawn an async job and the task that waits for it; returns its pid.
+async fn start_async_job(
+    mut job: AsyncJob,
+    bus: &web::Data<EventBus>,
+    jobs: &web::Data<JobRegistry>,
+    config: &web::Data<AppConfig>,
+) -> std::io::Result<u32> {
+    chains::register(&job.job_id, std::mem::take(&mut job.chain));
+    if job.interactive_session {
+        return start_in_interactive_session(job, bus, jobs, config);
+    }
+    let event_command = job.command.clone();
+    let command = event_command.as_str();
+    
+    if let Some(hook_set) = &job.hook_set {
+        match hook_set.pre(&job.job_id).await {
+            Ok(result) => job.hook_results.extend(result),
+            Err(result) => {
+                let e = hooks::failure(&result);
+                let job_id = job.job_id.clone();
+                job.hook_results.push(result);
+                jobs.insert(job.into_record(None));
+                jobs.fail(&job_id, &e.to_string());
+                bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                    "command": command,
+                    "error": e.to_string(),
+                }));
+                return Err(e);
+            }
+        }
+    }
+    
+    let keep_stdin_open = job.keep_stdin_open || job.expecter.is_some();
+    let stdin = if keep_stdin_open || job.stdin.is_some() { Stdio::piped() } else { Stdio::null() };
+    let mut cmd = job.shell.command(job.command_os.as_deref().unwrap_or(command.as_ref()));
+    if job.clear_env {
+        cmd.env_clear();
+    }
+    cmd.envs(job.env.iter().map(|(name, value)| (name, value)));
+    if let Some(run_as) = &job.run_as {
+        run_as.apply(&mut cmd);
+    }
+    job.confinement.apply(&mut cmd);
+    // So the whole tree can be cancelled.
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let mut child = cmd
+        .current_dir(&job.current_dir)
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    
+    let timestamps = job.timestamps.then(Instant::now);
+    let pid = child.id().unwrap_or(0);
+    let job_id = job.job_id.clone();
+    let timeout = job.timeout;
+    let capture_on_failure = job.capture_on_failure;
+    let expecter = job.expecter.take();
+    let hook_set = job.hook_set.take();
+    let slot = job.slot.take();
+    let outputs = std::mem::take(&mut job.outputs);
+    let input = job.stdin.take();
+    let cast = if job.record_cast {
+        let recorder = match artifacts::job_dir(&job_id) {
+            Ok(dir) => asciicast::Recorder::create(&dir.join(asciicast::FILE_NAME), command).await,
+            Err(e) => Err(e),
+        };
+        match recorder {
+            Ok(recorder) => Some(tokio::sync::Mutex::new(recorder)),
+            Err(e) => {
+                log_error("/execute-async", &format!("Failed to create asciicast: {}", e), Some(command));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut record = job.into_record(Some(pid));
+    let log = match config.job_log_path(&job_id) {
+        Some(log_path) => match tokio::fs::File::create(&log_path).await {
+            Ok(file) => {
+                record.log_file = Some(log_path.display().to_string());
+                Some(tokio::sync::Mutex::new(file))
+            }
+            Err(e) => {
+                log_error("/execute-async", &format!("Failed to create job log: {}", e), Some(command));
+                None
+            }
+        },
+        None => None,
+    };
+    jobs.insert(record);
+    bus.publish(events::JOB_STARTED, Some(&job_id), serde_json::json!({
+        "command": command,
+        "pid": pid,
+    }));
+    
+    if let Some(stdin) = child.stdin.take() {
+        jobs.attach_stdin(&job_id, stdin);
+    }
+    if let Some(input) = input {
+        write_initial_stdin(&job_id, input, keep_stdin_open, jobs);
+    }
+    let cancel = jobs.attach_cancel(&job_id);
+    
+    // Output is read for ::progress:: lines, expect rules and /jobs/{id}/output
+    let output = Arc::new(output::JobOutput {
+        job_id: job_id.clone(),
+        jobs: jobs.clone(),
+        bus: bus.clone(),
+        expect: expecter.map(Mutex::new),
+        log,
+        cast,
+        timestamps,
+    });
+    let mut pumps = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        pumps.push(tokio::spawn(output::pump(stdout, output::Stream::Stdout, output.clone())));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        pumps.push(tokio::spawn(output::pump(stderr, output::Stream::Stderr, output)));
+    }
+    
+    // Detach the process - don't wait for it
+    let bus = bus.clone();
+    let jobs = jobs.clone();
+    let config = config.clone();
+    supervisor::spawn_job_task(job_id.clone(), jobs.clone(), bus.get_ref().clone(), async move {
+        let mut timed_out = false;
+        let deadline = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let exit = {
+            let mut exited = std::pin::pin!(usage::wait(&mut child));
+            tokio::select! {
+                exit = exited.as_mut() => exit,
+                () = cancel.notified(), if pid != 0 => disconnect::stop_tree(pid, config.cancel_grace, exited).await,
+                () = deadline, if pid != 0 => {
+                    timed_out = true;
+                    if let Err(e) = disconnect::kill_tree(pid) {
+                        log_error("/execute-async", &format!("Failed to kill timed out job {}: {}", job_id, e), Some(&event_command));
+                    }
+                    exited.await
+                }
+            }
+        };
+        if output::drain(pumps).await {
+            compress_job_output(&job_id, &jobs, &config).await;
+        }
+        match exit {
+            Ok((status, usage)) => {
+                if let Some(usage) = usage {
+                    jobs.record_usage(&job_id, usage);
+                }
+                let artifacts = if capture_on_failure && !status.success() {
+                    capture_diagnostics("/execute-async", &job_id, &event_command, None, &config).await
+                } else {
+                    None
+                };
+                run_post_hook(hook_set.as_ref(), &job_id, status.code(), &jobs).await;
+                provenance::stamp_job(&job_id, &outputs, &jobs).await;
+                offload::offload_job(&job_id, &outputs, &jobs, &config).await;
+                content::dedupe_job(&job_id, &config).await;
+                let error_msg = if timed_out {
+                    Some(format!("Timed out after {}s", timeout.unwrap_or_default().as_secs()))
+                } else {
+                    jobs.is_cancelled(&job_id).then(|| CANCELLED.to_string())
+                };
+                if let Some(error_msg) = error_msg {
+                    jobs.fail(&job_id, &error_msg);
+                    bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                        "command": event_command,
+                        "error": error_msg,
+                        "artifacts": artifacts,
+                    }));
+                    return;
+                }
+                jobs.finish(&job_id, status.code());
+                bus.publish(events::JOB_FINISHED, Some(&job_id), serde_json::json!({
+                    "command": event_command,
+                    "return_code": status.code(),
+                    "artifacts": artifacts,
+                }));
+            }
+            Err(e) => {
+                let artifacts = if capture_on_failure {
+                    capture_diagnostics("/execute-async", &job_id, &event_command, None, &config).await
+                } else {
+                    None
+                };
+                run_post_hook(hook_set.as_ref(), &job_id, None, &jobs).await;
+                provenance::stamp_job(&job_id, &outputs, &jobs).await;
+                offload::offload_job(&job_id, &outputs, &jobs, &config).await;
+                content::dedupe_job(&job_id, &config).await;
+                jobs.fail(&job_id, &e.to_string());
+                bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                    "command": event_command,
+                    "error": e.to_string(),
+                    "artifacts": artifacts,
+                }));
+            }
+        }
+        drop(slot);
+    });
+    
+    Ok(pid)
+}
+
+/// Write a job's `stdin` from the request, then close the pipe unless it is
+/// to be kept open. Writes through `/jobs/{id}/stdin` and expect responses
+/// wait until it is written.
+fn write_initial_stdin(job_id: &str, input: String, keep_open: bool, jobs: &JobRegistry) {
+    let Some(mut stdin) = jobs.stdin(job_id).and_then(|handle| handle.try_lock_owned().ok()) else {
+        return;
+    };
+    tokio::spawn(async move {
+        if let Some(pipe) = stdin.as_mut() {
+            // A command that doesn't read its stdin closes the pipe early.
+            let _ = pipe.write_all(input.as_bytes()).await;
+            let _ = pipe.flush().await;
+        }
+        if !keep_open {
+            *stdin = None;
+        }
+    });
+}
+
+/// Look up the hook set a request names.
+fn resolve_hooks(req: &ExecuteRequest, config: &AppConfig) -> Result<Option<hooks::HookSet>, String> {
+    let Some(name) = &req.hooks else {
+        return Ok(None);
+    };
+    if req.interactive_session {
+        return Err("hooks are not supported with interactive_session".to_string());
+    }
+    match config.hooks.get(name) {
+        Some(hook_set) => Ok(Some(hook_set.clone())),
+        None => Err(format!("Unknown hook set {:?} (see AGENT_HOOKS_FILE)", name)),
+    }
+}
+
+/// Run a job's pre hook and record it; `Err` when the command must not run.
+async fn run_pre_hook(hook_set: &hooks::HookSet, job_id: &str, jobs: &JobRegistry) -> std::io::Result<()> {
+    let result = hook_set.pre(job_id).await;
+    let failed = result.as_ref().err().map(hooks::failure);
+    if let Some(result) = result.unwrap_or_else(Some) {
+        jobs.update(job_id, |job| job.hooks.push(result));
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+/// Run the post hook matching a job's exit code and record it.
+async fn run_post_hook(hook_set: Option<&hooks::HookSet>, job_id: &str, exit_code: Option<i32>, jobs: &JobRegistry) {
+    let Some(hook_set) = hook_set else {
+        return;
+    };
+    let Some(result) = hook_set.post(job_id, exit_code).await else {
+        return;
+    };
+    if !result.succeeded() {
+        log_error("hooks", &hooks::failure(&result).to_string(), Some(&result.command));
+    }
+    jobs.update(job_id, |job| job.hooks.push(result));
+}
+
+/// Record a job whose guards did not hold.
+fn skip_job(job: Job, reason: &str, bus: &EventBus, jobs: &JobRegistry) {
+    let job_id = job.id.clone();
+    let command = job.command.clone();
+    jobs.insert(job);
+    jobs.skip(&job_id, reason);
+    bus.publish(events::JOB_SKIPPED, Some(&job_id), serde_json::json!({
+        "command": command,
+        "reason": reason,
+    }));
+}
+
+/// Swap a finished job's log file and recording for zstd-compressed copies.
+async fn compress_job_output(job_id: &str, jobs: &JobRegistry, config: &AppConfig) {
+    let Some(level) = config.compress_level else {
+        return;
+    };
+    let Some(job) = jobs.get(job_id) else {
+        return;
+    };
+    let cast = artifacts::artifacts_dir().join(job_id).join(asciicast::FILE_NAME);
+    if cast.exists() {
+        let result = web::block(move || compress::compress_file(&cast, level)).await;
+        if let Err(e) = result.map_err(std::io::Error::other).and_then(|r| r) {
+            log_error("/execute-async", &format!("Failed to compress asciicast: {}", e), Some(&job.command));
+        }
+    }
+    let Some(log_file) = job.log_file else {
+        return;
+    };
+    let result = web::block(move || compress::compress_file(std::path::Path::new(&log_file), level)).await;
+    match result.map_err(std::io::Error::other).and_then(|r| r) {
+        Ok(compressed) => jobs.update(job_id, |job| job.log_file = Some(compressed.display().to_string())),
+        Err(e) => log_error("/execute-async", &format!("Failed to compress job log: {}", e), Some(&job.command)),
+    }
+}
+
+/// Capture a failure bundle for a job, logging (not propagating) any error.
+async fn capture_diagnostics(
+    endpoint: &str,
+    job_id: &str,
+    command: &str,
+    output: Option<diagnostics::FailedOutput<'_>>,
+    config: &AppConfig,
+) -> Option<Vec<String>> {
+    match diagnostics::capture_bundle(job_id, command, output).await {
+        Ok(artifacts) => {
+            if let Some(level) = config.compress_level {
+                let dir = artifacts::artifacts_dir().join(job_id);
+                let result = web::block(move || compress::compress_dir(&dir, level)).await;
+                if let Err(e) = result.map_err(std::io::Error::other).and_then(|r| r) {
+                    log_error(endpoint, &format!("Failed to compress diagnostics: {}", e), Some(command));
+                }
+            }
+            Some(artifacts)
+        }
+        Err(e) => {
+            log_error(endpoint, &format!("Failed to capture diagnostics: {}", e), Some(command));
+            None
+        }
+    }
+}
+
+fn session_error_response(command: &str, job_id: Option<String>, e: &std::io::Error) -> HttpResponse {
+    let body = ExecuteResponse {
+        success: false,
+        command: command.to_string(),
+        job_id,
+        stdout: None,
+        stderr: None,
+        return_code: None,
+        executed: None,
+        error: Some(e.to_string()),
+        artifacts: None,
+        skip_reason: None,
+        cached: false,
+        timed_out: false,
+    };
+    if e.kind() == std::io::ErrorKind::Unsupported {
+        HttpResponse::BadRequest().json(body)
+    } else {
+        HttpResponse::InternalServerError().json(body)
+    }
+}
+
+async fn execute_in_interactive_session(
+    command: &str,
+    job_id: String,
+    current_dir: &std::path::Path,
+    outputs: &[String],
+    bus: &EventBus,
+    jobs: &JobRegistry,
+    config: &AppConfig,
+) -> HttpResponse {
+    let result = match gui_session::spawn(command, current_dir) {
+        Ok(process) => process.wait().await,
+        Err(e) => Err(e),
+    };
+    
+    match result {
+        Ok(code) => {
+            provenance::stamp_job(&job_id, outputs, jobs).await;
+            offload::offload_job(&job_id, outputs, jobs, config).await;
+            jobs.finish(&job_id, Some(code));
+            bus.publish(events::JOB_FINISHED, Some(&job_id), serde_json::json!({
+                "command": command,
+                "return_code": code,
+            }));
+            // GUI processes have no captured output; only the exit code is reported.
+            HttpResponse::Ok().json(ExecuteResponse {
+                success: true,
+                command: command.to_string(),
+                job_id: Some(job_id),
+                stdout: None,
+                stderr: None,
+                return_code: Some(code),
+                executed: Some(true),
+                error: None,
+                artifacts: None,
+                skip_reason: None,
+                cached: false,
+                timed_out: false,
+            })
+        }
+        Err(e) => {
+            let error_msg = format!("Interactive session execution failed: {}", e);
+            log_error_with_traceback("/execute", &error_msg, &format!("{:?}", e), Some(command));
+            jobs.fail(&job_id, &e.to_string());
+            bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                "command": command,
+                "error": e.to_string(),
+            }));
+            session_error_response(command, Some(job_id), &e)
+        }
+    }
+}
+
+fn start_in_interactive_session(
+    mut job: AsyncJob,
+    bus: &web::Data<EventBus>,
+    jobs: &web::Data<JobRegistry>,
+    config: &web::Data<AppConfig>,
+) -> std::io::Result<u32> {
+    let process = gui_session::spawn(&job.command, &job.current_dir)?;
+    
+    let pid = process.pid;
+    let event_job_id = job.job_id.clone();
+    let event_command = job.command.clone();
+    let outputs = job.outputs.clone();
+    let slot = job.slot.take();
+    jobs.insert(job.into_record(Some(pid)));
+    bus.publish(events::JOB_STARTED, Some(&event_job_id), serde_json::json!({
+        "command": event_command,
+        "pid": pid,
+        "interactive_session": true,
+    }));
+    
+    let bus = bus.clone();
+    let jobs = jobs.clone();
+    let config = config.clone();
+    supervisor::spawn_job_task(event_job_id.clone(), jobs.clone(), bus.get_ref().clone(), async move {
+        match process.wait().await {
+            Ok(code) => {
+                provenance::stamp_job(&event_job_id, &outputs, &jobs).await;
+                offload::offload_job(&event_job_id, &outputs, &jobs, &config).await;
+                jobs.finish(&event_job_id, Some(code));
+                bus.publish(events::JOB_FINISHED, Some(&event_job_id), serde_json::json!({
+                    "command": event_command,
+                    "return_code": code,
+                }));
+            }
+            Err(e) => {
+                jobs.fail(&event_job_id, &e.to_string());
+                bus.publish(events::JOB_FAILED, Some(&event_job_id), serde_json::json!({
+                    "command": event_command,
+                    "error": e.to_string(),
+                }));
+            }
+        }
+        drop(slot);
+    });
+    
+    Ok(pid)
+}
+
 fn print_logo() {
     println!("\n");
     println!("╔══════════════════════════════════════════════════════════╗");
@@ -300,17 +2308,291 @@ fn print_logo() {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    timekeeping::init();
+    supervisor::install_panic_hook();
+    let overrides = match overrides::apply(clap::Parser::parse()) {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    logging::init();
+    match overrides.command {
+        Some(overrides::Command::Doctor { json }) => std::process::exit(doctor::run(json).await),
+        Some(overrides::Command::Firewall { action }) => std::process::exit(firewall::run_command(&action)),
+        None => {}
+    }
     print_logo();
-    println!("Error logs will be written to: app_error.log");
+    if let Some(path) = &overrides.config_file {
+        println!("Settings loaded from: {}", path.display());
+    }
+    println!("Logs will be written to: {}", get_log_file_path().display());
     
-    HttpServer::new(|| {
-        App::new()
+    let config = AppConfig::from_env();
+    let _ = DEFAULT_TIMEOUT.set(config.default_timeout);
+    clock::init(config.timestamp_zone);
+    latency::init(config.slow_request, config.slow_job);
+    if let Some(dir) = &config.job_log_dir {
+        std::fs::create_dir_all(dir)?;
+        println!("Job output logs will be written to: {}", dir.display());
+    }
+    let config = web::Data::new(config);
+    if let Some(cloud) = cloud::detect(&config).await {
+        println!("Running on {} instance {}", cloud.provider, cloud.instance_id);
+    }
+    timekeeping::check(&config).await;
+    let failing_calendars = blackout::init(&config).await;
+    if !config.blackout.calendars.is_empty() {
+        tokio::spawn(blackout::run(config.clone(), failing_calendars));
+    }
+    tokio::spawn(timekeeping::run(config.clone()));
+    
+    let bus = EventBus::new();
+    bus.publish(events::AGENT_STARTED, None, serde_json::json!({
+        "platform": std::env::consts::OS,
+        "version": env!("CARGO_PKG_VERSION"),
+        "cloud": cloud::metadata(),
+        "boot_time": timekeeping::boot_time(),
+        "clock": timekeeping::status(),
+    }));
+    
+    #[cfg(feature = "desktop")]
+    let recordings = web::Data::new(screen::RecordingRegistry::default());
+    let results = web::Data::new(ResultCache::default());
+    let history = match &config.history_db {
+        Some(path) => match history::JobHistory::open(path, config.lightweight) {
+            Ok(history) => {
+                println!("Job history will be stored in: {}", path.display());
+                Some(Arc::new(history))
+            }
+            Err(e) => {
+                let error_msg = format!("Failed to open job history database: {}", e);
+                eprintln!("{}", error_msg);
+                log_error("startup", &error_msg, None);
+                None
+            }
+        },
+        None => None,
+    };
+    let jobs = web::Data::new(JobRegistry::with_history(history.clone())
+        .with_output_limit(config.job_output_max_bytes)
+        .with_retention(config.job_retention));
+    // Before any job can end.
+    tokio::spawn(chains::run(bus.subscribe(), web::Data::new(bus.clone()), jobs.clone(), config.clone()));
+    playbook::resume_pending(&bus, &jobs, &config);
+    queued::resume_pending(&web::Data::new(bus.clone()), &jobs, &config);
+    for (job_id, command) in jobs.reconcile_history() {
+        log_error("startup", &format!("Job {} was interrupted by the agent stopping", job_id), Some(&command));
+    }
+    let host_history = config.metrics_interval.map(|interval| {
+        let host_history = web::Data::new(host_history::HostHistory::new(interval, config.metrics_retention));
+        tokio::spawn(host_history::run_sampler(host_history.clone(), jobs.clone()));
+        host_history
+    });
+    if config.content_store {
+        tokio::spawn(content::run_gc());
+    }
+    #[cfg(feature = "push")]
+    tokio::spawn(metrics_push::run(config.clone(), jobs.clone()));
+    if !config.job_hooks.is_empty() {
+        tokio::spawn(job_hooks::run(bus.subscribe(), config.clone(), jobs.clone()));
+    }
+    let watchdogs = web::Data::new(deadman::Switches::new(&config));
+    if !config.watchdogs.is_empty() {
+        tokio::spawn(deadman::run(watchdogs.clone(), web::Data::new(bus.clone()), jobs.clone(), config.clone()));
+    }
+    let history = history.map(web::Data::from);
+    let lifecycle_bus = bus.clone();
+    let lifecycle_jobs = jobs.clone();
+    let lifecycle_config = config.clone();
+    let workers = config.workers;
+    
+    let listeners = match listen::bind(&config.bind, config.dual_stack) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            log_error("startup", &e.to_string(), None);
+            return Err(e);
+        }
+    };
+    let mut listener_info: Vec<listen::ListenerInfo> = listeners.iter().map(|(_, info)| info.clone()).collect();
+    let tls = match tls::load(&config.tls, &listener_info) {
+        Ok(tls) => tls,
+        Err(error_msg) => {
+            eprintln!("{}", error_msg);
+            log_error("startup", &error_msg, None);
+            std::process::exit(1);
+        }
+    };
+    if let Some(tls) = &tls {
+        if tls.generated {
+            println!("Generated a self-signed certificate: {}", tls.cert.display());
+        }
+        println!("Serving HTTPS with {}", tls.cert.display());
+    }
+    for info in &mut listener_info {
+        info.tls = tls.is_some();
+        println!("Listening on {}{}", info.address, if info.dual_stack { " (dual-stack)" } else { "" });
+    }
+    let loopback = tls.as_ref().map(|tls| tls.loopback.clone()).unwrap_or_default();
+    let self_check_addr = listener_info[0].loopback().to_string();
+    let listener_data = web::Data::new(listener_info);
+    
+    let mut server = HttpServer::new(move || {
+        let app = App::new()
+            .wrap(actix_web::middleware::from_fn(read_only::guard))
+            .wrap(actix_web::middleware::from_fn(auth::guard))
+            .wrap_fn(|req, srv| {
+                let request = logging::Request::start(&req);
+                srv.call(req).instrument(request.span.clone()).map(move |response| {
+                    request.finish(&response);
+                    response
+                })
+            })
+            .app_data(web::Data::new(bus.clone()))
+            .app_data(listener_data.clone())
+            .app_data(results.clone())
+            .app_data(jobs.clone())
+            .app_data(config.clone())
+            .app_data(watchdogs.clone())
+            .configure(|cfg| {
+                if let Some(history) = &history {
+                    cfg.app_data(history.clone());
+                }
+                if let Some(host_history) = &host_history {
+                    cfg.app_data(host_history.clone());
+                }
+            })
             .route("/", web::get().to(home))
             .route("/health", web::get().to(health))
+            .route("/health/details", web::get().to(health_details))
+            .route("/capabilities", web::get().to(capabilities::get_capabilities))
             .route("/execute", web::post().to(execute_command))
+            .route("/execute-stream", web::post().to(execute_command_stream))
+            .route("/execute-script", web::post().to(execute_script))
             .route("/execute-async", web::post().to(execute_command_async))
+            .route("/admin/queue", web::get().to(admin::get_queue))
+            .route("/admin/firewall", web::get().to(firewall::get_firewall))
+            .route("/admin/firewall", web::post().to(firewall::open_ports))
+            .route("/admin/firewall", web::delete().to(firewall::close_ports))
+            .route("/admin/time-sync", web::get().to(time_sync::get_time_sync))
+            .route("/admin/time-sync", web::post().to(time_sync::resync_time))
+            .route("/events", web::get().to(events::stream_events))
+            .route("/metrics", web::get().to(metrics::get_metrics))
+            .route("/jobs", web::get().to(jobs::list_jobs))
+            .route("/jobs/search", web::get().to(history::search_jobs))
+            .route("/jobs/{id}", web::get().to(jobs::get_job))
+            .route("/jobs/{id}", web::delete().to(jobs::cancel_job))
+            .route("/jobs/{id}/wait", web::get().to(jobs::wait_job))
+            .route("/jobs/{id}/output", web::get().to(jobs::get_job_output))
+            .route("/jobs/{id}/stream", web::get().to(job_stream::stream_job))
+            .route("/jobs/{id}/stream-token", web::post().to(job_stream::stream_token))
+            .route("/jobs/{id}/log", web::get().to(jobs::get_job_log))
+            .route("/jobs/{id}/stdin", web::post().to(jobs::write_stdin))
+            .route("/jobs/{id}/artifacts", web::get().to(artifacts::list_artifacts))
+            .route("/jobs/{id}/artifacts/{name}", web::get().to(artifacts::get_artifact))
+            .route("/files/download", web::get().to(files::download))
+            .route("/files/list", web::get().to(files::list))
+            .route("/files/stat", web::get().to(files::stat))
+            .route("/playbooks/run", web::post().to(playbook::run_playbook))
+            .route("/watchdog", web::get().to(deadman::list))
+            .route("/watchdog/{name}/ping", web::post().to(deadman::ping))
+            .route("/policy/explain", web::get().to(policy::explain))
+            .route("/policy/explain", web::post().to(policy::explain))
+            .route("/provenance", web::get().to(provenance::get_provenance))
+            .route("/content", web::get().to(content::get_content))
+            .route("/system/history", web::get().to(host_history::get_history))
+            .route("/net/dns-lookup", web::get().to(dns::dns_lookup));
+        #[cfg(feature = "sync")]
+        let app = app
+            .route("/sync/plan", web::post().to(sync::sync_plan))
+            .route("/sync/files", web::put().to(sync::sync_file))
+            .route("/sync/links", web::post().to(sync::sync_links))
+            .route("/sync/delete", web::post().to(sync::sync_delete));
+        #[cfg(feature = "desktop")]
+        let app = app
+            .app_data(recordings.clone())
+            .route("/screen/ocr", web::post().to(ocr::screen_ocr))
+            .route("/screen/wait-for-image", web::post().to(image_match::wait_for_image))
+            .route("/screen/recordings", web::post().to(screen::start_recording))
+            .route("/screen/recordings/{job_id}", web::get().to(screen::get_recording))
+            .route("/screen/recordings/{job_id}/stop", web::post().to(screen::stop_recording))
+            .route("/screen/recordings/{job_id}/frames/{frame}", web::get().to(screen::get_frame));
+        #[cfg(feature = "browser")]
+        let app = app.route("/browser/run", web::post().to(browser::run_browser));
+        #[cfg(feature = "documents")]
+        let app = app.route("/documents/run", web::post().to(documents::run_document));
+        #[cfg(feature = "fetch")]
+        let app = app.route("/fetch", web::post().to(fetch::fetch));
+        #[cfg(feature = "shell")]
+        let app = app.route("/shell", web::get().to(shell::open_shell));
+        app
+    })
+    .workers(workers)
+    .on_connect(|connection, data| {
+        disconnect::on_connect(connection, data);
+        tls::on_connect(connection, data);
     })
-    .bind("0.0.0.0:6565")?
-    .run()
-    .await
+    // Shutdown commands must run before the server stops accepting requests.
+    .disable_signals();
+    for (listener, _) in listeners {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &tls {
+            server = server.listen_rustls_0_23(listener, tls.server.clone())?;
+            continue;
+        }
+        server = server.listen(listener)?;
+    }
+    let server = server.run();
+    
+    let handle = server.handle();
+    watchdog::notify("READY=1");
+    let config = lifecycle_config.clone();
+    actix_rt::spawn(async move {
+        watchdog::run(self_check_addr, loopback, &config).await;
+    });
+    let (bus, jobs, config) = (lifecycle_bus.clone(), lifecycle_jobs.clone(), lifecycle_config.clone());
+    actix_rt::spawn(async move {
+        lifecycle::run_stage(lifecycle::Stage::Startup, &bus, &jobs, &config).await;
+    });
+    actix_rt::spawn(async move {
+        lifecycle::shutdown_signal().await;
+        lifecycle_bus.publish(events::AGENT_STOPPING, None, serde_json::json!({}));
+        watchdog::notify("STOPPING=1");
+        lifecycle::run_stage(lifecycle::Stage::Shutdown, &lifecycle_bus, &lifecycle_jobs, &lifecycle_config).await;
+        handle.stop(true).await;
+    });
+    server.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[actix_rt::test]
+    async fn async_jobs_of_capped_keys_are_killed() {
+        let mut config = AppConfig::default();
+        let key = api_keys::ApiKey { max_timeout: Some(1), ..Default::default() };
+        config.api_keys.insert("capped".to_string(), key);
+        let request = serde_json::json!({ "command": "sleep 30" });
+        let req = queued::restore(&request, Some("capped".to_string()), None, None, Vec::new(), &config).unwrap();
+        let job = AsyncJob::new("capped-job".to_string(), &req, request, &config).unwrap();
+        let bus = web::Data::new(EventBus::new());
+        let jobs = web::Data::new(JobRegistry::with_history(None));
+        let config = web::Data::new(config);
+        start_async_job(job, &bus, &jobs, &config).await.unwrap();
+
+        let started = Instant::now();
+        let job = loop {
+            let job = jobs.get("capped-job").unwrap();
+            if job.status.is_done() || started.elapsed() > Duration::from_secs(10) {
+                break job;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert!(job.status == jobs::JobStatus::Failed);
+        assert_eq!(job.error.as_deref(), Some("Timed out after 1s"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
 }
@@ -0,0 +1,151 @@
+//! `GET /metrics` in the Prometheus text exposition format.
+//!
+//! Job resource usage is aggregated per request `tag` (`tag=""` for untagged
+//! jobs) rather than per job, to keep label cardinality bounded. Job outcomes,
+//! durations and output sizes are across all jobs; requests are counted per
+//! method, route pattern and status (see [`crate::latency`]).
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use std::fmt::Write;
+
+use crate::jobs::JobRegistry;
+use crate::latency;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// A metric and its samples.
+pub struct Family {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub help: &'static str,
+    pub samples: Vec<Sample>,
+}
+
+pub struct Sample {
+    /// Appended to the family name, e.g. `_bucket` for a histogram.
+    pub suffix: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+impl Sample {
+    pub fn new(suffix: &'static str, labels: Vec<(&'static str, String)>, value: f64) -> Self {
+        Sample { suffix, labels, value }
+    }
+}
+
+/// Observations counted in buckets by upper bound.
+#[derive(Clone)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Per bucket, not cumulative.
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.counts[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// The `_bucket`, `_sum` and `_count` samples, with `labels`.
+    pub fn samples(&self, labels: Vec<(&'static str, String)>) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let mut labels = labels.clone();
+            labels.push(("le", bound.to_string()));
+            samples.push(Sample::new("_bucket", labels, cumulative as f64));
+        }
+        let mut labels_inf = labels.clone();
+        labels_inf.push(("le", "+Inf".to_string()));
+        samples.push(Sample::new("_bucket", labels_inf, self.count as f64));
+        samples.push(Sample::new("_sum", labels.clone(), self.sum));
+        samples.push(Sample::new("_count", labels, self.count as f64));
+        samples
+    }
+}
+
+/// The agent's metrics now.
+pub fn collect(jobs: &JobRegistry) -> Vec<Family> {
+    let totals = jobs.usage_totals();
+    let per_tag = |value: &dyn Fn(&crate::jobs::UsageTotals) -> f64| {
+        totals
+            .iter()
+            .map(|(tag, totals)| Sample::new("", vec![("tag", tag.clone())], value(totals)))
+            .collect()
+    };
+    let mut families = vec![
+        Family {
+            name: "machine_agent_job_usage_measured_total",
+            kind: "counter",
+            help: "Jobs whose resource usage was measured.",
+            samples: per_tag(&|totals| totals.jobs as f64),
+        },
+        Family {
+            name: "machine_agent_job_cpu_seconds_total",
+            kind: "counter",
+            help: "User plus system CPU time of job process trees.",
+            samples: per_tag(&|totals| totals.cpu_seconds),
+        },
+        Family {
+            name: "machine_agent_job_peak_memory_bytes_sum",
+            kind: "counter",
+            help: "Sum of the peak resident memory of jobs; divide by the measured count for the mean.",
+            samples: per_tag(&|totals| totals.peak_memory_bytes_sum as f64),
+        },
+        Family {
+            name: "machine_agent_job_peak_memory_bytes_max",
+            kind: "gauge",
+            help: "Largest peak resident memory of any job.",
+            samples: per_tag(&|totals| totals.peak_memory_bytes_max as f64),
+        },
+    ];
+    families.extend(jobs.stats_families());
+    families.extend(latency::families());
+    families
+}
+
+/// Escape a label value: backslash, double quote and newline.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// `families` in the text exposition format.
+pub fn render(families: &[Family]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind);
+        for sample in &family.samples {
+            let labels: Vec<String> = sample.labels.iter().map(|(name, value)| format!("{}=\"{}\"", name, label(value))).collect();
+            if labels.is_empty() {
+                let _ = writeln!(out, "{}{} {}", family.name, sample.suffix, sample.value);
+            } else {
+                let _ = writeln!(out, "{}{}{{{}}} {}", family.name, sample.suffix, labels.join(","), sample.value);
+            }
+        }
+    }
+    out
+}
+
+/// GET /metrics - agent metrics for Prometheus.
+pub async fn get_metrics(jobs: web::Data<JobRegistry>) -> ActixResult<HttpResponse> {
+    let out = render(&collect(&jobs));
+    Ok(HttpResponse::Ok().content_type(CONTENT_TYPE).body(out))
+}
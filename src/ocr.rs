@@ -0,0 +1,149 @@
+//! OCR over desktop screenshots, for GUI verification steps such as
+//! "confirm the dialog says Installation complete".
+//!
+//! Recognition runs the `tesseract` CLI with TSV output, which gives word-level
+//! bounding boxes without linking libtesseract/leptonica into the agent.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use tokio::process::Command as TokioCommand;
+
+use crate::log_error_with_traceback;
+use crate::screen::{self, Region};
+
+#[derive(Deserialize)]
+pub struct OcrRequest {
+    /// Only return words lying entirely inside this screen region.
+    region: Option<Region>,
+    #[serde(default = "default_lang")]
+    lang: String,
+    /// Words below this tesseract confidence (0-100) are dropped.
+    #[serde(default)]
+    min_confidence: f64,
+}
+
+fn default_lang() -> String {
+    "eng".to_string()
+}
+
+#[derive(Serialize, Clone)]
+pub struct OcrWord {
+    pub text: String,
+    pub confidence: f64,
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+    #[serde(skip)]
+    line_key: (u32, u32, u32),
+}
+
+#[derive(Serialize)]
+struct OcrResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    words: Option<Vec<OcrWord>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Run tesseract on an image and return the recognized words.
+pub async fn recognize(image: &Path, lang: &str) -> io::Result<Vec<OcrWord>> {
+    let output = TokioCommand::new("tesseract")
+        .arg(image)
+        .arg("stdout")
+        .args(["-l", lang, "tsv"])
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "tesseract failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(parse_tsv(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse tesseract TSV output, keeping word-level (level 5) rows only.
+fn parse_tsv(tsv: &str) -> Vec<OcrWord> {
+    tsv.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.splitn(12, '\t').collect();
+            if cols.len() < 12 || cols[0] != "5" {
+                return None;
+            }
+            let text = cols[11].trim();
+            if text.is_empty() {
+                return None;
+            }
+            let num = |i: usize| cols[i].parse::<i64>().ok();
+            let key = |i: usize| cols[i].parse::<u32>().unwrap_or(0);
+            Some(OcrWord {
+                text: text.to_string(),
+                confidence: cols[10].parse().unwrap_or(-1.0),
+                x: num(6)?,
+                y: num(7)?,
+                width: num(8)?,
+                height: num(9)?,
+                line_key: (key(2), key(3), key(4)),
+            })
+        })
+        .collect()
+}
+
+/// Join words back into text, one output line per tesseract line.
+pub fn words_to_text(words: &[OcrWord]) -> String {
+    let mut text = String::new();
+    let mut current_line = None;
+    for word in words {
+        if current_line.is_some() {
+            text.push(if current_line == Some(word.line_key) { ' ' } else { '\n' });
+        }
+        text.push_str(&word.text);
+        current_line = Some(word.line_key);
+    }
+    text
+}
+
+/// POST /screen/ocr - capture the screen and return recognized text with boxes.
+pub async fn screen_ocr(req: web::Json<OcrRequest>) -> ActixResult<HttpResponse> {
+    let image = std::env::temp_dir().join(format!("machine_agent_ocr_{}.png", uuid::Uuid::new_v4()));
+
+    let result = async {
+        screen::capture(&image).await?;
+        recognize(&image, &req.lang).await
+    }
+    .await;
+    let _ = tokio::fs::remove_file(&image).await;
+
+    match result {
+        Ok(words) => {
+            let words: Vec<OcrWord> = words
+                .into_iter()
+                .filter(|w| w.confidence >= req.min_confidence)
+                .filter(|w| req.region.is_none_or(|r| r.contains(w.x, w.y, w.width, w.height)))
+                .collect();
+            Ok(HttpResponse::Ok().json(OcrResponse {
+                success: true,
+                text: Some(words_to_text(&words)),
+                words: Some(words),
+                error: None,
+            }))
+        }
+        Err(e) => {
+            let error_msg = format!("OCR failed: {}", e);
+            log_error_with_traceback("/screen/ocr", &error_msg, &format!("{:?}", e), None);
+            Ok(HttpResponse::InternalServerError().json(OcrResponse {
+                success: false,
+                text: None,
+                words: None,
+                error: Some(error_msg),
+            }))
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Uploading what finished jobs leave behind to object storage, so evidence
+//! is kept centrally and local disks stay small.
+//!
+//! Three backends implement [`Store`], each behind its own feature: S3 and
+//! S3-compatible stores (`s3`, see [`crate::s3`]), Azure Blob Storage
+//! (`azure`, see [`crate::azure_blob`]) and Google Cloud Storage (`gcs`, see
+//! [`crate::gcs`]). `AGENT_OFFLOAD_BACKEND` picks one; when unset, the backend
+//! whose bucket or container variable is set is used.
+//!
+//! A job's log file, its artifacts and the `outputs` its request declared are
+//! uploaded under `<AGENT_OFFLOAD_PREFIX><hostname>/<job_id>/` before the job
+//! completes, and the object URLs are recorded on the job as `objects`. With
+//! `AGENT_OFFLOAD_DELETE_LOCAL=true` the log file and artifacts (never the
+//! declared outputs) are deleted once uploaded. Uploads go through the
+//! outbound proxy, allowlist and resolvers; failures are logged and the job
+//! completes regardless.
+
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::sync::Arc;
+
+use crate::bandwidth::Bandwidth;
+use crate::config::AppConfig;
+use crate::jobs::JobRegistry;
+use crate::outbound::Outbound;
+use crate::{artifacts, events, log_error, provenance};
+
+/// An object store files are offloaded to.
+#[cfg_attr(not(feature = "offload"), allow(dead_code))]
+pub trait Store: Send + Sync {
+    /// Upload the first `length` bytes of `path` as `key`, streaming them
+    /// within `bandwidth`; returns the object's URL.
+    fn put<'a>(&'a self, outbound: &'a Outbound, bandwidth: &'a Bandwidth, key: &'a str, path: &'a Path, length: u64) -> BoxFuture<'a, Result<String, String>>;
+
+    /// The service's base URL, for `machine_agent doctor`.
+    fn endpoint(&self) -> &str;
+}
+
+#[derive(Clone)]
+#[cfg_attr(not(feature = "offload"), allow(dead_code))]
+pub struct Offload {
+    pub store: Arc<dyn Store>,
+    /// Key prefix, empty or ending in `/`.
+    pub prefix: String,
+    /// Delete the log file and artifacts once uploaded.
+    pub delete_local: bool,
+}
+
+/// A file a job left behind, uploaded.
+#[derive(Clone, Serialize)]
+pub struct StoredObject {
+    /// Local path it was uploaded from.
+    pub file: String,
+    pub url: String,
+}
+
+/// The first of `names` that is set and not empty.
+pub fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .map(|value| value.trim().to_string())
+        .find(|value| !value.is_empty())
+}
+
+pub fn env_flag(name: &str) -> Option<bool> {
+    std::env::var(name).ok().map(|value| matches!(value.trim(), "1" | "true" | "yes"))
+}
+
+/// Backends with the variable that selects each when `AGENT_OFFLOAD_BACKEND`
+/// is unset.
+const BACKENDS: [(&str, &str); 3] = [("s3", "AGENT_S3_BUCKET"), ("azure", "AGENT_AZURE_CONTAINER"), ("gcs", "AGENT_GCS_BUCKET")];
+
+/// The offload configuration, `None` when no backend is configured.
+pub fn from_env() -> Result<Option<Offload>, String> {
+    let backend = match env_var(&["AGENT_OFFLOAD_BACKEND"]) {
+        Some(backend) => backend.to_lowercase(),
+        None => {
+            let configured: Vec<&str> = BACKENDS
+                .iter()
+                .filter(|(_, variable)| env_var(&[variable]).is_some())
+                .map(|(backend, _)| *backend)
+                .collect();
+            match configured[..] {
+                [] => return Ok(None),
+                [backend] => backend.to_string(),
+                _ => {
+                    return Err(format!(
+                        "Offload is configured for {}; set AGENT_OFFLOAD_BACKEND to pick one",
+                        configured.join(" and ")
+                    ))
+                }
+            }
+        }
+    };
+    let store: Result<Arc<dyn Store>, String> = match backend.as_str() {
+        #[cfg(feature = "s3")]
+        "s3" => crate::s3::S3::from_env().map(|store| Arc::new(store) as _),
+        #[cfg(feature = "azure")]
+        "azure" => crate::azure_blob::AzureBlob::from_env().map(|store| Arc::new(store) as _),
+        #[cfg(feature = "gcs")]
+        "gcs" => crate::gcs::Gcs::from_env().map(|store| Arc::new(store) as _),
+        other => Err(match other {
+            "s3" | "azure" | "gcs" => format!("The {} offload backend needs the agent built with the {} feature", other, other),
+            _ => format!("Unknown AGENT_OFFLOAD_BACKEND {:?}: expected s3, azure or gcs", other),
+        }),
+    };
+    let store = store?;
+    let mut prefix = env_var(&["AGENT_OFFLOAD_PREFIX", "AGENT_S3_PREFIX"])
+        .unwrap_or_default()
+        .trim_matches('/')
+        .to_string();
+    if !prefix.is_empty() {
+        prefix.push('/');
+    }
+    Ok(Some(Offload {
+        store,
+        prefix,
+        delete_local: env_flag("AGENT_OFFLOAD_DELETE_LOCAL").unwrap_or(false),
+    }))
+}
+
+/// `path` as key segments: `/var/out/report.pdf` becomes
+/// `var/out/report.pdf`, `C:\out\a.txt` becomes `C/out/a.txt`.
+fn key_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Prefix(prefix) => Some(match prefix.kind() {
+                Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                    format!("{}/{}", server.to_string_lossy(), share.to_string_lossy())
+                }
+                _ => prefix.as_os_str().to_string_lossy().replace([':', '\\', '?', '.'], ""),
+            }),
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The files to upload: (local path, key below the job's prefix, whether the
+/// agent owns the file and may delete it).
+fn job_files(job_id: &str, log_file: Option<&str>, outputs: &[String]) -> Vec<(PathBuf, String, bool)> {
+    let mut files = Vec::new();
+    if let Some(log_file) = log_file.map(PathBuf::from) {
+        if let Some(name) = log_file.file_name() {
+            let key = name.to_string_lossy().into_owned();
+            files.push((log_file, key, true));
+        }
+    }
+    if let Ok(entries) = std::fs::read_dir(artifacts::artifacts_dir().join(job_id)) {
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|path| path.is_file()) {
+            let key = format!("artifacts/{}", path.file_name().unwrap_or_default().to_string_lossy());
+            files.push((path, key, true));
+        }
+    }
+    for output in outputs {
+        let path = provenance::resolve(output);
+        if path.is_file() {
+            let key = format!("outputs/{}", key_path(&path));
+            files.push((path, key, false));
+        }
+    }
+    files
+}
+
+/// Upload a finished job's files, when offload is configured, and record
+/// the objects on the job.
+pub async fn offload_job(job_id: &str, outputs: &[String], jobs: &JobRegistry, config: &AppConfig) {
+    let Some(offload) = &config.offload else {
+        return;
+    };
+    let Some(job) = jobs.get(job_id) else {
+        return;
+    };
+    let files = job_files(job_id, job.log_file.as_deref(), outputs);
+    if files.is_empty() {
+        return;
+    }
+
+    let base = format!("{}{}/{}/", offload.prefix, events::hostname(), job_id);
+    let mut objects = Vec::new();
+    for (path, key, owned) in files {
+        let uploaded = match std::fs::metadata(&path) {
+            Ok(metadata) => {
+                offload
+                    .store
+                    .put(&config.outbound, &config.bandwidth, &format!("{}{}", base, key), &path, metadata.len())
+                    .await
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        match uploaded {
+            Ok(url) => {
+                objects.push(StoredObject {
+                    file: path.display().to_string(),
+                    url,
+                });
+                if owned && offload.delete_local {
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        log_error("offload", &format!("Failed to delete {}: {}", path.display(), e), Some(&job.command));
+                    }
+                }
+            }
+            Err(e) => log_error(
+                "offload",
+                &format!("Failed to upload {}: {}", path.display(), e),
+                Some(&job.command),
+            ),
+        }
+    }
+    if offload.delete_local {
+        // Empty once every artifact is uploaded; otherwise kept.
+        let _ = std::fs::remove_dir(artifacts::artifacts_dir().join(job_id));
+    }
+    jobs.update(job_id, |job| job.objects = objects);
+}
+
+/// Percent-encode everything but unreserved characters (and `/` with
+/// `keep_slash`), as request signing expects.
+#[cfg(feature = "offload")]
+pub fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(any(feature = "s3", feature = "azure"))]
+pub fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    use hmac::{Hmac, Mac};
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// A failed response as an error message, with the start of its body.
+#[cfg(feature = "offload")]
+pub async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, String> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(format!("{} {}", status, body.chars().take(300).collect::<String>()))
+}
+
+/// Send `request` with the first `length` bytes of `path` streamed as its
+/// body, within `bandwidth`.
+#[cfg(feature = "offload")]
+pub async fn send_file(request: reqwest::RequestBuilder, bandwidth: &Bandwidth, path: &Path, length: u64) -> Result<(), String> {
+    use tokio::io::AsyncReadExt;
+
+    let file = tokio::fs::File::open(path).await.map_err(|e| e.to_string())?;
+    let response = request
+        .header(reqwest::header::CONTENT_LENGTH, length)
+        .body(reqwest::Body::wrap_stream(bandwidth.throttle(tokio_util::io::ReaderStream::new(file.take(length)))))
+        .send()
+        .await
+        .map_err(|e| crate::outbound::describe(&e))?;
+    check_status(response).await.map(|_| ())
+}
+
+/// An OAuth access token, fetched again shortly before it expires.
+#[cfg(any(feature = "azure", feature = "gcs"))]
+#[derive(Default)]
+pub struct TokenCache(tokio::sync::Mutex<Option<(String, std::time::Instant)>>);
+
+#[cfg(any(feature = "azure", feature = "gcs"))]
+impl TokenCache {
+    /// The cached token, or a new one from `fetch`, which returns the token
+    /// and its lifetime in seconds.
+    pub async fn get<F, Fut>(&self, fetch: F) -> Result<String, String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<(String, u64), String>>,
+    {
+        let mut cached = self.0.lock().await;
+        if let Some((token, expires)) = cached.as_ref() {
+            if *expires > std::time::Instant::now() {
+                return Ok(token.clone());
+            }
+        }
+        let (token, lifetime) = fetch().await?;
+        // A minute early, so a token doesn't expire mid-upload.
+        let expires = std::time::Instant::now() + std::time::Duration::from_secs(lifetime.saturating_sub(60));
+        *cached = Some((token.clone(), expires));
+        Ok(token)
+    }
+}
+
+/// `access_token` and `expires_in` from a token endpoint's JSON response;
+/// some send `expires_in` as a string.
+#[cfg(any(feature = "azure", feature = "gcs"))]
+pub async fn parse_token(response: reqwest::Response) -> Result<(String, u64), String> {
+    let body: serde_json::Value = check_status(response)
+        .await?
+        .json()
+        .await
+        .map_err(|e| format!("Invalid token response: {}", e))?;
+    let token = body["access_token"]
+        .as_str()
+        .ok_or("Token response without access_token")?
+        .to_string();
+    let lifetime = match &body["expires_in"] {
+        serde_json::Value::Number(number) => number.as_u64(),
+        serde_json::Value::String(text) => text.parse().ok(),
+        _ => None,
+    };
+    Ok((token, lifetime.unwrap_or(300)))
+}
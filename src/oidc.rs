@@ -0,0 +1,270 @@
+//! The `oidc` backend of [`crate::auth`] (`oidc` feature): callers present
+//! a JWT from the site's OpenID Connect provider in `Authorization: Bearer`,
+//! and the agent checks its signature against the provider's published keys
+//! and its issuer, audience and lifetime.
+//!
+//! `AGENT_OIDC_ISSUER` is the provider (e.g.
+//! `https://login.example.com/realms/ops`) and `AGENT_OIDC_AUDIENCE` the
+//! audience tokens must be issued for. The keys are found through the
+//! issuer's discovery document unless `AGENT_OIDC_JWKS_URL` names them, and
+//! are fetched again hourly, or when a token is signed with one the agent
+//! hasn't seen. The caller's name is the token's `sub` claim, or the claim
+//! named by `AGENT_OIDC_NAME_CLAIM` (e.g. `email` or `azp`).
+
+use actix_web::HttpRequest;
+use base64::Engine;
+use futures_util::future::LocalBoxFuture;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+use crate::auth::Backend;
+use crate::offload::env_var;
+use crate::outbound::Outbound;
+
+/// How long a key set is used before it is fetched again.
+const KEYS_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Least time between fetches for tokens signed with an unknown key.
+const MIN_REFETCH: Duration = Duration::from_secs(60);
+
+/// How long fetching the discovery document or the keys may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clock difference tolerated on `exp` and `nbf`.
+const LEEWAY_SECS: i64 = 60;
+
+/// A key from the provider's JWK set.
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    /// Keys for encryption are of no use here.
+    #[serde(rename = "use", default)]
+    key_use: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+struct KeySet {
+    keys: Vec<Jwk>,
+    fetched: Instant,
+}
+
+pub struct Oidc {
+    issuer: String,
+    audience: String,
+    /// `AGENT_OIDC_JWKS_URL`; discovered from the issuer when unset.
+    jwks_url: Option<String>,
+    name_claim: String,
+    outbound: Outbound,
+    /// Held while fetching, so a burst of requests fetches once.
+    keys: tokio::sync::Mutex<Option<KeySet>>,
+}
+
+impl Oidc {
+    pub fn from_env(outbound: &Outbound) -> Result<Self, String> {
+        let issuer = env_var(&["AGENT_OIDC_ISSUER"]).ok_or("The oidc auth backend needs AGENT_OIDC_ISSUER")?;
+        let audience = env_var(&["AGENT_OIDC_AUDIENCE"]).ok_or("The oidc auth backend needs AGENT_OIDC_AUDIENCE")?;
+        let jwks_url = env_var(&["AGENT_OIDC_JWKS_URL"]);
+        outbound.check(jwks_url.as_deref().unwrap_or(&issuer)).map_err(|e| format!("The oidc auth backend: {}", e))?;
+        Ok(Oidc {
+            issuer,
+            audience,
+            jwks_url,
+            name_claim: env_var(&["AGENT_OIDC_NAME_CLAIM"]).unwrap_or_else(|| "sub".to_string()),
+            outbound: outbound.clone(),
+            keys: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    async fn get_json(&self, client: &reqwest::Client, url: &str) -> Result<Value, String> {
+        self.outbound.check(url)?;
+        let response = client
+            .get(url)
+            .timeout(FETCH_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| format!("Request to {} failed: {}", url, crate::outbound::describe(&e)))?;
+        if !response.status().is_success() {
+            return Err(format!("{} returned {}", url, response.status()));
+        }
+        response.json().await.map_err(|e| format!("Unexpected response from {}: {}", url, e))
+    }
+
+    async fn fetch_keys(&self) -> Result<Vec<Jwk>, String> {
+        let client = self.outbound.client()?.build().map_err(|e| e.to_string())?;
+        let jwks_url = match &self.jwks_url {
+            Some(jwks_url) => jwks_url.clone(),
+            None => {
+                let discovery = format!("{}/.well-known/openid-configuration", self.issuer.trim_end_matches('/'));
+                let document = self.get_json(&client, &discovery).await?;
+                document
+                    .get("jwks_uri")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| format!("{} has no jwks_uri", discovery))?
+                    .to_string()
+            }
+        };
+        let set: JwkSet = serde_json::from_value(self.get_json(&client, &jwks_url).await?)
+            .map_err(|e| format!("Invalid key set from {}: {}", jwks_url, e))?;
+        Ok(set.keys.into_iter().filter(|key| key.key_use.as_deref().is_none_or(|key_use| key_use == "sig")).collect())
+    }
+
+    /// Check `message` was signed with `alg` by the provider's key `kid`.
+    async fn verify(&self, kid: Option<&str>, alg: &str, message: &[u8], signature: &[u8]) -> Result<(), String> {
+        let mut keys = self.keys.lock().await;
+        let stale = keys.as_ref().is_none_or(|set| set.fetched.elapsed() > KEYS_MAX_AGE);
+        let unknown = keys.as_ref().is_some_and(|set| {
+            set.fetched.elapsed() > MIN_REFETCH && kid.is_some_and(|kid| !set.keys.iter().any(|key| key.kid.as_deref() == Some(kid)))
+        });
+        if stale || unknown {
+            match self.fetch_keys().await {
+                Ok(fetched) => {
+                    *keys = Some(KeySet {
+                        keys: fetched,
+                        fetched: Instant::now(),
+                    })
+                }
+                // Carry on with the keys there are, if any.
+                Err(e) if keys.is_some() => crate::log_error("oidc", &format!("Failed to refresh the provider's keys: {}", e), None),
+                Err(e) => return Err(format!("Cannot check OIDC tokens: {}", e)),
+            }
+        }
+        let candidates = keys.as_ref().map(|set| set.keys.as_slice()).unwrap_or_default();
+        let mut candidates = candidates.iter().filter(|key| kid.is_none() || key.kid.as_deref() == kid).peekable();
+        if candidates.peek().is_none() {
+            return Err(format!("Invalid OIDC token: signed with unknown key {:?}", kid.unwrap_or_default()));
+        }
+        if candidates.any(|key| verify_with(key, alg, message, signature).is_ok()) {
+            return Ok(());
+        }
+        Err("Invalid OIDC token: bad signature".to_string())
+    }
+
+    async fn check(&self, token: &str) -> Result<String, String> {
+        let invalid = |reason: &str| format!("Invalid OIDC token: {}", reason);
+        let decode = |part: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(part.trim_end_matches('='));
+        let Some((signed, signature)) = token.rsplit_once('.') else {
+            return Err(invalid("expected a JWT"));
+        };
+        let Some((header, payload)) = signed.split_once('.') else {
+            return Err(invalid("expected a JWT"));
+        };
+        let header: Value = decode(header)
+            .ok()
+            .and_then(|header| serde_json::from_slice(&header).ok())
+            .ok_or_else(|| invalid("malformed header"))?;
+        let claims: Value = decode(payload)
+            .ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or_else(|| invalid("malformed claims"))?;
+        let signature = decode(signature).map_err(|_| invalid("malformed signature"))?;
+        let alg = header.get("alg").and_then(Value::as_str).unwrap_or_default();
+        let kid = header.get("kid").and_then(Value::as_str);
+        self.verify(kid, alg, signed.as_bytes(), &signature).await?;
+
+        if claims.get("iss").and_then(Value::as_str).map(|iss| iss.trim_end_matches('/')) != Some(self.issuer.trim_end_matches('/')) {
+            return Err(invalid("issued by another provider"));
+        }
+        let audience_matches = match claims.get("aud") {
+            Some(Value::String(aud)) => *aud == self.audience,
+            Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(&self.audience)),
+            _ => false,
+        };
+        if !audience_matches {
+            return Err(invalid("issued for another audience"));
+        }
+        let now = chrono::Utc::now().timestamp();
+        match claims.get("exp").and_then(Value::as_i64) {
+            Some(exp) if exp + LEEWAY_SECS > now => {}
+            Some(_) => return Err(invalid("expired")),
+            None => return Err(invalid("no exp claim")),
+        }
+        if claims.get("nbf").and_then(Value::as_i64).is_some_and(|nbf| nbf - LEEWAY_SECS > now) {
+            return Err(invalid("not valid yet"));
+        }
+        claims
+            .get(&self.name_claim)
+            .and_then(Value::as_str)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| invalid(&format!("no {} claim", self.name_claim)))
+    }
+}
+
+fn rsa_parameters(alg: &str) -> Option<&'static signature::RsaParameters> {
+    match alg {
+        "RS256" => Some(&signature::RSA_PKCS1_2048_8192_SHA256),
+        "RS384" => Some(&signature::RSA_PKCS1_2048_8192_SHA384),
+        "RS512" => Some(&signature::RSA_PKCS1_2048_8192_SHA512),
+        "PS256" => Some(&signature::RSA_PSS_2048_8192_SHA256),
+        "PS384" => Some(&signature::RSA_PSS_2048_8192_SHA384),
+        "PS512" => Some(&signature::RSA_PSS_2048_8192_SHA512),
+        _ => None,
+    }
+}
+
+/// Check `signature` with one key of the set.
+fn verify_with(key: &Jwk, alg: &str, message: &[u8], signature: &[u8]) -> Result<(), String> {
+    let field = |value: &Option<String>| {
+        let value = value.as_deref().ok_or("incomplete key")?;
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(value.trim_end_matches('=')).map_err(|e| e.to_string())
+    };
+    let verified = match (alg, key.kty.as_str(), key.crv.as_deref()) {
+        (_, "RSA", _) if rsa_parameters(alg).is_some() => {
+            let components = RsaPublicKeyComponents { n: field(&key.n)?, e: field(&key.e)? };
+            components.verify(rsa_parameters(alg).unwrap_or(&signature::RSA_PKCS1_2048_8192_SHA256), message, signature)
+        }
+        ("ES256", "EC", Some("P-256")) | ("ES384", "EC", Some("P-384")) => {
+            let algorithm = if alg == "ES256" { &signature::ECDSA_P256_SHA256_FIXED } else { &signature::ECDSA_P384_SHA384_FIXED };
+            let point: Vec<u8> = [vec![0x04], field(&key.x)?, field(&key.y)?].concat();
+            UnparsedPublicKey::new(algorithm, point).verify(message, signature)
+        }
+        ("EdDSA", "OKP", Some("Ed25519")) => UnparsedPublicKey::new(&signature::ED25519, field(&key.x)?).verify(message, signature),
+        // `none` and shared-secret algorithms included.
+        _ => return Err(format!("unsupported algorithm {:?} for a {} key", alg, key.kty)),
+    };
+    verified.map_err(|_| "bad signature".to_string())
+}
+
+impl Backend for Oidc {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn credential(&self) -> &'static str {
+        "OIDC token"
+    }
+
+    fn identify<'a>(&'a self, req: &'a HttpRequest) -> LocalBoxFuture<'a, Result<Option<String>, String>> {
+        Box::pin(async move {
+            let token = req
+                .headers()
+                .get("Authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(str::trim);
+            match token {
+                // Not a JWT: an API key, perhaps, for another backend.
+                Some(token) if token.matches('.').count() == 2 => self.check(token).await.map(Some),
+                _ => Ok(None),
+            }
+        })
+    }
+}
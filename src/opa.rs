@@ -0,0 +1,143 @@
+//! Delegating execution decisions to Open Policy Agent, so a central
+//! policy-as-code repository governs what the agent may run.
+//!
+//! Either an OPA server is queried over its REST API (`AGENT_OPA_URL`, needs
+//! the `opa` feature) or a local `opa` binary evaluates a policy file or
+//! bundle directory (`AGENT_OPA_POLICY`, queried with `AGENT_OPA_QUERY`).
+//! Either way the input is the full request context built by [`input`], and
+//! the decision is a boolean or an object like
+//! `{"allow": false, "reason": "deploys are frozen"}`. An undefined result
+//! or an unreachable OPA denies.
+
+use actix_web::HttpRequest;
+use serde_json::{json, Map, Value};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command as TokioCommand;
+
+use crate::clock;
+use crate::events;
+use crate::outbound::Outbound;
+use crate::tls;
+
+/// How long a decision may take before the request is denied.
+const DECISION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request headers never passed to OPA.
+const SECRET_HEADERS: [&str; 3] = ["authorization", "x-api-key", "cookie"];
+
+#[derive(Clone)]
+pub enum Opa {
+    /// OPA REST API data document, e.g. http://127.0.0.1:8181/v1/data/agent/allow.
+    Server { url: String, outbound: Box<Outbound> },
+    /// Policy file or bundle directory evaluated with `opa eval`.
+    Local { policy: PathBuf, query: String },
+}
+
+pub struct Decision {
+    pub allow: bool,
+    pub reason: Option<String>,
+}
+
+/// The input document: who is asking, through which endpoint, for what.
+pub fn input(endpoint: &str, http_req: &HttpRequest, api_key: Option<&str>, request: &Value) -> Value {
+    let headers: Map<String, Value> = http_req
+        .headers()
+        .iter()
+        .filter(|(name, _)| !SECRET_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), Value::from(value.to_str().ok()?))))
+        .collect();
+    json!({
+        "endpoint": endpoint,
+        "request": request,
+        "api_key": api_key,
+        "client": http_req.peer_addr().map(|addr| addr.ip().to_string()),
+        "client_certificate": tls::client_subject(http_req),
+        "headers": headers,
+        "agent": {
+            "hostname": events::hostname(),
+            "os": std::env::consts::OS,
+        },
+        "time": clock::now(),
+    })
+}
+
+impl Opa {
+    pub async fn decide(&self, input: &Value) -> Result<Decision, String> {
+        let result = match tokio::time::timeout(DECISION_TIMEOUT, self.query(input)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(format!("No decision within {}s", DECISION_TIMEOUT.as_secs())),
+        };
+        match result {
+            Some(Value::Bool(allow)) => Ok(Decision { allow, reason: None }),
+            Some(Value::Object(fields)) => Ok(Decision {
+                allow: fields.get("allow").and_then(Value::as_bool).unwrap_or(false),
+                reason: fields.get("reason").and_then(Value::as_str).map(str::to_string),
+            }),
+            Some(other) => Err(format!("Expected a boolean or an object with allow, got {}", other)),
+            None => Ok(Decision {
+                allow: false,
+                reason: Some("The policy is undefined for this request".to_string()),
+            }),
+        }
+    }
+
+    /// The query result; `None` when undefined.
+    async fn query(&self, input: &Value) -> Result<Option<Value>, String> {
+        match self {
+            Opa::Server { url, outbound } => query_server(url, outbound, input).await,
+            Opa::Local { policy, query } => {
+                let mut child = TokioCommand::new("opa")
+                    .args(["eval", "--format", "json", "--stdin-input", "--data"])
+                    .arg(policy)
+                    .arg(query)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(|e| format!("Failed to run opa: {}", e))?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin
+                        .write_all(input.to_string().as_bytes())
+                        .await
+                        .map_err(|e| format!("Failed to write opa input: {}", e))?;
+                }
+                let output = child.wait_with_output().await.map_err(|e| format!("opa failed: {}", e))?;
+                if !output.status.success() {
+                    return Err(format!("opa eval failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+                }
+                let body: Value = serde_json::from_slice(&output.stdout)
+                    .map_err(|e| format!("Unexpected opa output: {}", e))?;
+                // {"result": [{"expressions": [{"value": ...}]}]}, or {} when undefined.
+                Ok(body
+                    .pointer("/result/0/expressions/0/value")
+                    .cloned())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "opa")]
+async fn query_server(url: &str, outbound: &Outbound, input: &Value) -> Result<Option<Value>, String> {
+    outbound.check(url)?;
+    let client = outbound.client()?.build().map_err(|e| e.to_string())?;
+    let response = client
+        .post(url)
+        .json(&json!({ "input": input }))
+        .send()
+        .await
+        .map_err(|e| format!("OPA request failed: {}", crate::outbound::describe(&e)))?;
+    if !response.status().is_success() {
+        return Err(format!("OPA returned {}", response.status()));
+    }
+    let body: Value = response.json().await.map_err(|e| format!("Unexpected OPA response: {}", e))?;
+    Ok(body.get("result").cloned())
+}
+
+#[cfg(not(feature = "opa"))]
+async fn query_server(_url: &str, _outbound: &Outbound, _input: &Value) -> Result<Option<Value>, String> {
+    Err("AGENT_OPA_URL needs the agent built with the opa feature".to_string())
+}
@@ -0,0 +1,247 @@
+//! Connections the agent itself opens (OPA server, WebDriver, object storage),
+//! as opposed to the commands it runs.
+//!
+//! They go through the proxies in `AGENT_HTTP_PROXY`, `AGENT_HTTPS_PROXY` and
+//! `AGENT_ALL_PROXY`, falling back to the conventional `HTTP_PROXY`,
+//! `HTTPS_PROXY` and `ALL_PROXY`. Proxy URLs may be `http://`, `https://`,
+//! `socks5://` or `socks5h://` (resolving names through the proxy), with
+//! credentials in the userinfo. Hosts matching `AGENT_NO_PROXY` (or `NO_PROXY`)
+//! are connected to directly: comma-separated hostnames, which also match
+//! their subdomains, IP addresses and CIDR ranges, or `*` for everything.
+//!
+//! With `AGENT_OUTBOUND_ALLOW` set, only the destinations it lists may be
+//! contacted, including redirect targets, so a caller can't point the agent
+//! at arbitrary hosts (e.g. through `webdriver_url`). Entries are hostnames
+//! (`*.corp` or `.corp` for a domain and its subdomains), IP addresses or CIDR
+//! ranges, each optionally with a `:port` (IPv6 in brackets). Hostnames are
+//! matched as written in the URL, not by what they resolve to; CIDR ranges
+//! match IP literal URLs. The proxies themselves are always reachable.
+//!
+//! Names are resolved through `AGENT_DNS_SERVERS` when set (see [`crate::dns`]).
+
+use std::net::IpAddr;
+
+use crate::dns::Dns;
+
+/// What the agent's own connections go through and may reach.
+#[derive(Clone, Default)]
+pub struct Outbound {
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub proxy: Proxy,
+    pub allow: Allowlist,
+    pub dns: Dns,
+}
+
+/// The proxies outbound connections go through.
+#[derive(Clone, Default)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub struct Proxy {
+    /// For `http://` URLs.
+    pub http: Option<String>,
+    /// For `https://` URLs.
+    pub https: Option<String>,
+    /// For any URL without a scheme-specific proxy.
+    pub all: Option<String>,
+    /// Hosts never proxied.
+    pub no_proxy: Option<String>,
+}
+
+/// `AGENT_<NAME>`, else `<NAME>`, else `<name>`; empty counts as unset.
+fn env_var(name: &str) -> Option<String> {
+    [format!("AGENT_{}", name), name.to_string(), name.to_lowercase()]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .map(|value| value.trim().to_string())
+        .find(|value| !value.is_empty())
+}
+
+impl Proxy {
+    pub fn from_env() -> Self {
+        Proxy {
+            http: env_var("HTTP_PROXY"),
+            https: env_var("HTTPS_PROXY"),
+            all: env_var("ALL_PROXY"),
+            no_proxy: env_var("NO_PROXY"),
+        }
+    }
+}
+
+/// Redirects followed before giving up, as reqwest does by default.
+#[cfg(feature = "http")]
+const MAX_REDIRECTS: usize = 10;
+
+#[cfg(feature = "http")]
+impl Outbound {
+    /// A client builder that resolves through the configured DNS servers,
+    /// connects through the configured proxies and only follows redirects to
+    /// allowed destinations. Callers check the URLs they request with
+    /// [`Outbound::check`].
+    pub fn client(&self) -> Result<reqwest::ClientBuilder, String> {
+        let builder = client(&self.proxy, &self.allow)?;
+        Ok(self.dns.apply(builder))
+    }
+
+    /// Whether `url` may be contacted; the error says why not.
+    pub fn check(&self, url: &str) -> Result<(), String> {
+        self.allow.check(url)
+    }
+}
+
+#[cfg(feature = "http")]
+fn client(proxy: &Proxy, allow: &Allowlist) -> Result<reqwest::ClientBuilder, String> {
+    let no_proxy = proxy.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string);
+    let allow = allow.clone();
+    let redirect = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match allow.check_url(attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(e) => attempt.error(format!("Redirect refused: {}", e)),
+        }
+    });
+    // Only what's configured here applies, not whatever reqwest would pick up.
+    let mut builder = reqwest::Client::builder().no_proxy().redirect(redirect);
+    for (scheme, url) in [("http", &proxy.http), ("https", &proxy.https), ("all", &proxy.all)] {
+        let Some(url) = url else { continue };
+        let proxy = match scheme {
+            "http" => reqwest::Proxy::http(url),
+            "https" => reqwest::Proxy::https(url),
+            _ => reqwest::Proxy::all(url),
+        }
+        .map_err(|e| format!("Invalid proxy {}: {}", url, e))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+    }
+    Ok(builder)
+}
+
+/// A destination in `AGENT_OUTBOUND_ALLOW`.
+#[derive(Clone)]
+enum Destination {
+    /// A hostname; with `subdomains`, also any name below it.
+    Host { name: String, subdomains: bool, port: Option<u16> },
+    /// An IP address or CIDR range, matched against IP literal URLs only.
+    Network { addr: IpAddr, prefix: u8, port: Option<u16> },
+}
+
+fn parse_network(spec: &str, port: Option<u16>) -> Option<Destination> {
+    let (addr, prefix) = match spec.split_once('/') {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (spec.parse::<IpAddr>().ok()?, None),
+    };
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    (prefix <= max).then_some(Destination::Network { addr, prefix, port })
+}
+
+fn parse_destination(entry: &str) -> Result<Destination, String> {
+    let invalid = || format!("Invalid AGENT_OUTBOUND_ALLOW entry {:?}", entry);
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+    // `[v6]:port`, bare IPv6 without a port, or `host:port`.
+    let (host, port) = if let Some(rest) = entry.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        match rest.strip_prefix(':') {
+            Some(port) => (host, Some(parse_port(port)?)),
+            None if rest.is_empty() => (host, None),
+            None => return Err(invalid()),
+        }
+    } else if entry.matches(':').count() > 1 {
+        (entry, None)
+    } else {
+        match entry.split_once(':') {
+            Some((host, port)) => (host, Some(parse_port(port)?)),
+            None => (entry, None),
+        }
+    };
+    if host.contains(':') || host.contains('/') || host.parse::<IpAddr>().is_ok() {
+        return parse_network(host, port).ok_or_else(invalid);
+    }
+    let host = host.to_lowercase();
+    let (name, subdomains) = match host.strip_prefix("*.").or_else(|| host.strip_prefix('.')) {
+        Some(domain) => (domain.to_string(), true),
+        None => (host.clone(), false),
+    };
+    if name.is_empty() || name.contains('*') {
+        return Err(invalid());
+    }
+    Ok(Destination::Host { name, subdomains, port })
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    let (ip, network, bits) = match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => (u32::from(ip) as u128, u32::from(network) as u128, 32),
+        (IpAddr::V6(ip), IpAddr::V6(network)) => (u128::from(ip), u128::from(network), 128),
+        _ => return false,
+    };
+    let shift = bits - prefix as u32;
+    shift >= bits || ip >> shift == network >> shift
+}
+
+/// A request error with its causes, which say e.g. that a redirect was refused.
+#[cfg(feature = "http")]
+pub fn describe(error: &reqwest::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+/// `AGENT_OUTBOUND_ALLOW`: the destinations the agent itself may contact.
+/// Unrestricted when unset.
+#[derive(Clone, Default)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub struct Allowlist(Option<Vec<Destination>>);
+
+impl Allowlist {
+    pub fn from_env() -> Result<Self, String> {
+        let Some(spec) = std::env::var("AGENT_OUTBOUND_ALLOW").ok().filter(|spec| !spec.trim().is_empty()) else {
+            return Ok(Allowlist(None));
+        };
+        let destinations = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(parse_destination)
+            .collect::<Result<_, _>>()?;
+        Ok(Allowlist(Some(destinations)))
+    }
+
+    /// Whether `host` (a name as written, or an IP address) may be contacted
+    /// on `port`.
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        let Some(destinations) = &self.0 else {
+            return true;
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_lowercase();
+        let ip = host.parse::<IpAddr>().ok();
+        destinations.iter().any(|destination| match destination {
+            Destination::Host { name, subdomains, port: allowed } => {
+                allowed.is_none_or(|allowed| allowed == port)
+                    && (host == *name || (*subdomains && host.strip_suffix(name.as_str()).is_some_and(|sub| sub.ends_with('.'))))
+            }
+            Destination::Network { addr, prefix, port: allowed } => {
+                allowed.is_none_or(|allowed| allowed == port) && ip.is_some_and(|ip| in_network(ip, *addr, *prefix))
+            }
+        })
+    }
+
+    #[cfg(feature = "http")]
+    fn check(&self, url: &str) -> Result<(), String> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+        self.check_url(&parsed)
+    }
+
+    #[cfg(feature = "http")]
+    fn check_url(&self, url: &reqwest::Url) -> Result<(), String> {
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or_default();
+        if self.allows(host, port) {
+            Ok(())
+        } else {
+            Err(format!("{}:{} is not in AGENT_OUTBOUND_ALLOW", host, port))
+        }
+    }
+}
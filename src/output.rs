@@ -0,0 +1,157 @@
+//! Reading a running job's stdout/stderr pipes and acting on what it prints:
+//! `::progress::` lines (stdout), expect rules (both streams), the output
+//! kept for `/jobs/{id}/output` and the per-job log file (both streams, as
+//! raw bytes or with timestamps), and the asciicast recording.
+
+use actix_web::web;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+use crate::asciicast;
+use crate::events::{self, EventBus};
+use crate::expect::Expecter;
+use crate::jobs::JobRegistry;
+use crate::progress;
+use crate::timestamps::Stamper;
+
+/// How long to keep reading after the process exited; background
+/// grandchildren can hold the pipes open indefinitely.
+pub const DRAIN_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Shared state of all pumps of one job.
+pub struct JobOutput {
+    pub job_id: String,
+    pub jobs: web::Data<JobRegistry>,
+    pub bus: web::Data<EventBus>,
+    pub expect: Option<Mutex<Expecter>>,
+    pub log: Option<tokio::sync::Mutex<tokio::fs::File>>,
+    pub cast: Option<tokio::sync::Mutex<asciicast::Recorder>>,
+    /// When the command started, if log lines are stamped.
+    pub timestamps: Option<Instant>,
+}
+
+/// Decode as much of `pending` as is valid UTF-8, keeping an incomplete
+/// trailing sequence for the next read.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let valid = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..valid]).to_string();
+    pending.drain(..valid);
+    text
+}
+
+impl JobOutput {
+    fn on_line(&self, line: &str) {
+        let Some(progress) = progress::parse_line(line) else {
+            return;
+        };
+        self.bus.publish(
+            events::JOB_PROGRESS,
+            Some(&self.job_id),
+            serde_json::json!({ "percent": progress.percent, "message": progress.message }),
+        );
+        self.jobs.update(&self.job_id, |job| job.progress = Some(progress));
+    }
+
+    async fn on_text(&self, text: &str) {
+        let responses = match &self.expect {
+            Some(expect) => expect.lock().unwrap().feed(text),
+            None => return,
+        };
+        if responses.is_empty() {
+            return;
+        }
+        let Some(stdin) = self.jobs.stdin(&self.job_id) else {
+            return;
+        };
+        let mut guard = stdin.lock().await;
+        if let Some(pipe) = guard.as_mut() {
+            for response in responses {
+                if pipe.write_all(response.as_bytes()).await.is_err() || pipe.flush().await.is_err() {
+                    *guard = None;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Read one of a job's pipes until EOF.
+pub async fn pump<R>(mut reader: R, stream: Stream, output: Arc<JobOutput>)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 4096];
+    let mut pending = Vec::new();
+    let mut line = String::new();
+    let mut stamper = output.timestamps.map(Stamper::new);
+    // Stamped bytes not yet decoded, when lines are stamped.
+    let mut stamped_pending = Vec::new();
+    loop {
+        let n = match reader.read(&mut chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let stamped = stamper.as_mut().map(|stamper| stamper.stamp(&chunk[..n]));
+        if let Some(log) = &output.log {
+            let _ = log.lock().await.write_all(stamped.as_deref().unwrap_or(&chunk[..n])).await;
+        }
+        if let Some(stamped) = stamped {
+            stamped_pending.extend_from_slice(&stamped);
+            let stamped = take_utf8(&mut stamped_pending);
+            if !stamped.is_empty() {
+                output.jobs.append_output(&output.job_id, stream, &stamped);
+            }
+        }
+        pending.extend_from_slice(&chunk[..n]);
+        let text = take_utf8(&mut pending);
+        if text.is_empty() {
+            continue;
+        }
+
+        if stamper.is_none() {
+            output.jobs.append_output(&output.job_id, stream, &text);
+        }
+        if let Some(cast) = &output.cast {
+            let _ = cast.lock().await.output(&text).await;
+        }
+        if stream == Stream::Stdout {
+            for piece in text.split_inclusive('\n') {
+                line.push_str(piece);
+                if line.ends_with('\n') {
+                    output.on_line(&line);
+                    line.clear();
+                }
+            }
+        }
+        output.on_text(&text).await;
+    }
+    if !line.is_empty() {
+        output.on_line(&line);
+    }
+    if let Some(log) = &output.log {
+        let _ = log.lock().await.flush().await;
+    }
+    if let Some(cast) = &output.cast {
+        let _ = cast.lock().await.flush().await;
+    }
+}
+
+/// Wait for a job's pumps to reach EOF after its process exited; returns
+/// false if some pipe was still open when the grace period ran out.
+pub async fn drain(pumps: Vec<JoinHandle<()>>) -> bool {
+    tokio::time::timeout(DRAIN_GRACE, futures_util::future::join_all(pumps))
+        .await
+        .is_ok()
+}
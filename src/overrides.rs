@@ -0,0 +1,191 @@
+//! Settings from a `config.toml` file and from command-line flags, on top of
+//! the `AGENT_*` environment variables.
+//!
+//! Both are applied as the environment variables [`AppConfig::from_env`]
+//! reads, before anything else starts, so every setting works the same way
+//! wherever it comes from. A flag wins over the environment, which wins over
+//! the file.
+//!
+//! The file is `--config <file>`, `AGENT_CONFIG_FILE`, or `config.toml` next
+//! to the executable when it exists. Its keys are the variable names without
+//! `AGENT_`, in lower case, and a key that names no setting is an error;
+//! lists are joined with commas:
+//!
+//! ```toml
+//! bind = ["0.0.0.0:6565", "[::]:6565"]
+//! workers = 4
+//! api_keys_file = "/etc/machine_agent/keys.json"
+//! tls_self_signed = true
+//! ```
+//!
+//! [`AppConfig::from_env`]: crate::config::AppConfig::from_env
+
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::get_exe_dir;
+
+/// The command line: settings, each overriding the `AGENT_*` variable in
+/// parentheses, and a subcommand.
+#[derive(Parser)]
+#[command(name = "machine_agent", about = "Runs commands on this host for remote callers over HTTP")]
+pub struct Cli {
+    /// Settings file (AGENT_CONFIG_FILE; default config.toml next to the executable)
+    #[arg(long, value_name = "file")]
+    config: Option<PathBuf>,
+    /// Listen addresses (AGENT_BIND)
+    #[arg(long, value_name = "address,...")]
+    bind: Option<String>,
+    /// Port for every listen address
+    #[arg(long, value_name = "port")]
+    port: Option<u16>,
+    /// Request handler threads (AGENT_WORKERS)
+    #[arg(long, value_name = "count")]
+    workers: Option<usize>,
+    /// Log file (AGENT_ERROR_LOG)
+    #[arg(long, value_name = "file")]
+    error_log: Option<String>,
+    /// error, warn, info, debug or trace (AGENT_LOG_LEVEL)
+    #[arg(long, value_name = "level")]
+    log_level: Option<String>,
+    /// Per-job output logs (AGENT_JOB_LOG_DIR)
+    #[arg(long, value_name = "dir")]
+    job_log_dir: Option<String>,
+    /// Job history database (AGENT_HISTORY_DB)
+    #[arg(long, value_name = "file|off")]
+    history_db: Option<String>,
+    /// Timeout of requests without one (AGENT_DEFAULT_TIMEOUT_SECS)
+    #[arg(long, value_name = "secs")]
+    default_timeout: Option<u64>,
+    /// Output kept per job (AGENT_JOB_OUTPUT_MAX_BYTES)
+    #[arg(long, value_name = "bytes")]
+    job_output_max_bytes: Option<usize>,
+    /// API keys (AGENT_API_KEYS_FILE)
+    #[arg(long, value_name = "file")]
+    api_keys_file: Option<String>,
+    /// Allowed and denied commands (AGENT_COMMAND_RULES_FILE)
+    #[arg(long, value_name = "file")]
+    command_rules_file: Option<String>,
+    /// HTTPS certificate (AGENT_TLS_CERT)
+    #[arg(long, value_name = "file")]
+    tls_cert: Option<String>,
+    /// HTTPS key (AGENT_TLS_KEY)
+    #[arg(long, value_name = "file")]
+    tls_key: Option<String>,
+    /// Generate a self-signed certificate (AGENT_TLS_SELF_SIGNED)
+    #[arg(long)]
+    tls_self_signed: bool,
+    /// Require client certificates from this CA (AGENT_TLS_CLIENT_CA)
+    #[arg(long, value_name = "file")]
+    tls_client_ca: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// What to do instead of serving, with the same settings.
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check the settings and the host, and exit
+    Doctor {
+        /// Print the checks as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Open or close the listen ports in the host firewall, or show them
+    Firewall {
+        #[arg(value_parser = ["add", "remove", "status"])]
+        action: String,
+    },
+}
+
+/// What the command line asked for besides settings.
+pub struct Overrides {
+    pub command: Option<Command>,
+    /// The settings file that was applied.
+    pub config_file: Option<PathBuf>,
+}
+
+fn set(variable: &str, value: &str) {
+    // Only called from `apply`, at the top of `main` before other threads
+    // read the environment.
+    std::env::set_var(variable, value);
+}
+
+/// Apply the flags of `cli` and then the settings file.
+pub fn apply(cli: Cli) -> Result<Overrides, String> {
+    let flags = [
+        ("AGENT_BIND", cli.bind),
+        ("AGENT_WORKERS", cli.workers.map(|workers| workers.to_string())),
+        ("AGENT_ERROR_LOG", cli.error_log),
+        ("AGENT_LOG_LEVEL", cli.log_level),
+        ("AGENT_JOB_LOG_DIR", cli.job_log_dir),
+        ("AGENT_HISTORY_DB", cli.history_db),
+        ("AGENT_DEFAULT_TIMEOUT_SECS", cli.default_timeout.map(|secs| secs.to_string())),
+        ("AGENT_JOB_OUTPUT_MAX_BYTES", cli.job_output_max_bytes.map(|bytes| bytes.to_string())),
+        ("AGENT_API_KEYS_FILE", cli.api_keys_file),
+        ("AGENT_COMMAND_RULES_FILE", cli.command_rules_file),
+        ("AGENT_TLS_CERT", cli.tls_cert),
+        ("AGENT_TLS_KEY", cli.tls_key),
+        ("AGENT_TLS_SELF_SIGNED", cli.tls_self_signed.then(|| "true".to_string())),
+        ("AGENT_TLS_CLIENT_CA", cli.tls_client_ca),
+    ];
+    for (variable, value) in flags {
+        if let Some(value) = value {
+            set(variable, &value);
+        }
+    }
+
+    let config_file = cli.config.or_else(|| std::env::var_os("AGENT_CONFIG_FILE").filter(|path| !path.is_empty()).map(PathBuf::from));
+    let config_file = match config_file {
+        Some(path) => Some(path),
+        None => Some(get_exe_dir().join("config.toml")).filter(|path| path.is_file()),
+    };
+    if let Some(path) = &config_file {
+        for (variable, value) in load(path)? {
+            if std::env::var_os(&variable).is_none() {
+                set(&variable, &value);
+            }
+        }
+    }
+
+    if let Some(port) = cli.port {
+        let bind: Vec<String> = config::bind_from_env()
+            .iter()
+            .map(|address| match address.rsplit_once(':') {
+                Some((host, _)) => format!("{}:{}", host, port),
+                None => format!("{}:{}", address, port),
+            })
+            .collect();
+        set("AGENT_BIND", &bind.join(","));
+    }
+    Ok(Overrides { command: cli.command, config_file })
+}
+
+/// The variables a settings file sets; a key that isn't one of
+/// [`config::VARIABLES`] is an error.
+pub fn load(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let table: toml::Table = contents.parse().map_err(|e| format!("Invalid settings file {}: {}", path.display(), e))?;
+    let scalar = |key: &str, value: &toml::Value| match value {
+        toml::Value::String(text) => Ok(text.clone()),
+        toml::Value::Integer(number) => Ok(number.to_string()),
+        toml::Value::Float(number) => Ok(number.to_string()),
+        toml::Value::Boolean(flag) => Ok(flag.to_string()),
+        _ => Err(format!("{} in {} must be a string, number, boolean or list of them", key, path.display())),
+    };
+    table
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::Array(items) => items.iter().map(|item| scalar(key, item)).collect::<Result<Vec<_>, _>>()?.join(","),
+                value => scalar(key, value)?,
+            };
+            let variable = format!("AGENT_{}", key.to_uppercase());
+            if !config::VARIABLES.contains(&variable.as_str()) {
+                return Err(format!("Unknown setting {:?} in {}", key, path.display()));
+            }
+            Ok((variable, value))
+        })
+        .collect()
+}
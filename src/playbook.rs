@@ -0,0 +1,543 @@
+//! Playbooks: an ordered list of steps run as a single job, including steps
+//! that reboot the machine.
+//!
+//! Before a `reboot` step the remaining steps are written to
+//! `playbooks/<job_id>.json` next to the executable. When the agent starts
+//! again (it has to be installed to start with the system) [`resume_pending`]
+//! picks the file up and carries on with the same job id.
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command as TokioCommand;
+
+use crate::api_keys::RunAs;
+use crate::auth::{self, Caller};
+use crate::clock;
+use crate::concurrency;
+use crate::config::AppConfig;
+use crate::disconnect;
+use crate::events::{self, EventBus};
+use crate::jobs::{Job, JobRegistry};
+use crate::output::Stream;
+use crate::policy;
+use crate::progress::Progress;
+use crate::supervisor;
+use crate::tls;
+use crate::{get_exe_dir, log_error};
+
+/// Output kept per step in the job record and the state file.
+const MAX_STEP_OUTPUT: usize = 4 * 1024;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Step {
+    Run {
+        command: String,
+        /// Carry on with the next step if this one exits non-zero.
+        #[serde(default)]
+        continue_on_error: bool,
+    },
+    Reboot {
+        /// Seconds between answering/recording and restarting the machine.
+        #[serde(default = "default_reboot_delay")]
+        delay_secs: u64,
+    },
+}
+
+fn default_reboot_delay() -> u64 {
+    5
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StepResult {
+    pub index: usize,
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Everything needed to continue a playbook after a reboot.
+#[derive(Serialize, Deserialize)]
+struct PlaybookState {
+    job_id: String,
+    name: String,
+    steps: Vec<Step>,
+    next_step: usize,
+    results: Vec<StepResult>,
+    started_at: String,
+    /// Set right before rebooting; a state file without it belongs to a run
+    /// the agent was killed in the middle of.
+    rebooting: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+    /// Subject of the client certificate the playbook was started with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_certificate: Option<String>,
+    /// User the steps run as, forced by the API key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    run_as: Option<String>,
+    /// The API key's `max_timeout`, after which a step's process tree is
+    /// killed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_timeout: Option<u64>,
+}
+
+fn state_dir() -> PathBuf {
+    get_exe_dir().join("playbooks")
+}
+
+fn state_path(job_id: &str) -> PathBuf {
+    state_dir().join(format!("{}.json", job_id))
+}
+
+impl PlaybookState {
+    fn save(&self) -> io::Result<()> {
+        std::fs::create_dir_all(state_dir())?;
+        // Write-then-rename so a crash never leaves a truncated state file.
+        let path = state_path(&self.job_id);
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(temp, path)
+    }
+
+    fn remove(&self) {
+        let _ = std::fs::remove_file(state_path(&self.job_id));
+    }
+
+    fn record(&self) -> Job {
+        let mut job = Job::new(&self.job_id, &self.name, None);
+        job.started_at = self.started_at.clone();
+        job.steps = Some(self.results.clone());
+        job.api_key = self.api_key.clone();
+        job.client_certificate = self.client_certificate.clone();
+        job.run_as = self.run_as.clone();
+        job
+    }
+}
+
+fn tail(text: &[u8]) -> String {
+    let text = String::from_utf8_lossy(text);
+    let mut start = text.len().saturating_sub(MAX_STEP_OUTPUT);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    text[start..].to_string()
+}
+
+async fn run_command(command: &str, run_as: Option<&str>, timeout: Option<u64>) -> io::Result<std::process::Output> {
+    let mut cmd = if cfg!(target_os = "windows") {
+        let mut cmd = TokioCommand::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = TokioCommand::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    if let Some(user) = run_as {
+        RunAs::resolve(user)?.apply(&mut cmd);
+    }
+    let Some(timeout) = timeout.filter(|&secs| secs > 0) else {
+        return cmd.output().await;
+    };
+    // So the whole tree can be killed.
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let pid = child.id();
+    match tokio::time::timeout(Duration::from_secs(timeout), child.wait_with_output()).await {
+        Ok(output) => output,
+        Err(_) => {
+            if let Some(pid) = pid {
+                if let Err(e) = disconnect::kill_tree(pid) {
+                    log_error("/playbooks/run", &format!("Failed to kill timed out step: {}", e), Some(command));
+                }
+            }
+            Err(io::Error::new(io::ErrorKind::TimedOut, format!("Timed out after {}s", timeout)))
+        }
+    }
+}
+
+async fn reboot() -> io::Result<()> {
+    let status = if cfg!(target_os = "windows") {
+        TokioCommand::new("shutdown").args(["/r", "/t", "0"]).status().await?
+    } else {
+        TokioCommand::new("shutdown").args(["-r", "now"]).status().await?
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("shutdown exited with {}", status)))
+    }
+}
+
+/// Run the playbook from `state.next_step` until it ends or reboots.
+async fn run(mut state: PlaybookState, bus: EventBus, jobs: web::Data<JobRegistry>) {
+    let total = state.steps.len();
+    while state.next_step < total {
+        let index = state.next_step;
+        let step = state.steps[index].clone();
+        let progress = Progress {
+            percent: Some((index * 100 / total) as f64),
+            message: Some(format!("Step {} of {}", index + 1, total)),
+            updated_at: clock::now(),
+        };
+        bus.publish(
+            events::JOB_PROGRESS,
+            Some(&state.job_id),
+            serde_json::json!({ "percent": progress.percent, "message": progress.message }),
+        );
+        jobs.update(&state.job_id, |job| job.progress = Some(progress));
+        let started_at = clock::now();
+
+        match step {
+            Step::Run {
+                command,
+                continue_on_error,
+            } => {
+                let output = run_command(&command, state.run_as.as_deref(), state.max_timeout).await;
+                let mut result = StepResult {
+                    index,
+                    action: "run".to_string(),
+                    command: Some(command.clone()),
+                    started_at,
+                    finished_at: Some(clock::now()),
+                    return_code: None,
+                    stdout: None,
+                    stderr: None,
+                    error: None,
+                };
+                let failed = match output {
+                    Ok(output) => {
+                        jobs.append_output(&state.job_id, Stream::Stdout, &String::from_utf8_lossy(&output.stdout));
+                        jobs.append_output(&state.job_id, Stream::Stderr, &String::from_utf8_lossy(&output.stderr));
+                        result.return_code = output.status.code();
+                        result.stdout = Some(tail(&output.stdout));
+                        result.stderr = Some(tail(&output.stderr));
+                        !output.status.success()
+                    }
+                    Err(e) => {
+                        result.error = Some(e.to_string());
+                        true
+                    }
+                };
+                let return_code = result.return_code;
+                let error = result.error.clone();
+                state.results.push(result);
+                let steps = state.results.clone();
+                jobs.update(&state.job_id, |job| job.steps = Some(steps));
+                state.next_step += 1;
+
+                if failed && !continue_on_error {
+                    state.remove();
+                    match error {
+                        Some(error) => {
+                            jobs.fail(&state.job_id, &error);
+                            bus.publish(events::JOB_FAILED, Some(&state.job_id), serde_json::json!({
+                                "command": state.name,
+                                "step": index,
+                                "error": error,
+                            }));
+                        }
+                        None => {
+                            jobs.finish(&state.job_id, return_code);
+                            bus.publish(events::JOB_FINISHED, Some(&state.job_id), serde_json::json!({
+                                "command": state.name,
+                                "step": index,
+                                "return_code": return_code,
+                            }));
+                        }
+                    }
+                    return;
+                }
+            }
+            Step::Reboot { delay_secs } => {
+                state.results.push(StepResult {
+                    index,
+                    action: "reboot".to_string(),
+                    command: None,
+                    started_at,
+                    finished_at: None,
+                    return_code: None,
+                    stdout: None,
+                    stderr: None,
+                    error: None,
+                });
+                state.next_step += 1;
+                state.rebooting = true;
+                let steps = state.results.clone();
+                jobs.update(&state.job_id, |job| job.steps = Some(steps));
+
+                let rebooted = match state.save() {
+                    Ok(()) => {
+                        bus.publish(events::JOB_PROGRESS, Some(&state.job_id), serde_json::json!({
+                            "message": "Rebooting",
+                        }));
+                        tokio::time::sleep(Duration::from_secs(delay_secs)).await;
+                        reboot().await
+                    }
+                    Err(e) => Err(e),
+                };
+                match rebooted {
+                    // The machine is going down; the job continues after startup.
+                    Ok(()) => return,
+                    Err(e) => {
+                        let error_msg = format!("Failed to reboot: {}", e);
+                        log_error("/playbooks/run", &error_msg, Some(&state.name));
+                        state.remove();
+                        jobs.fail(&state.job_id, &error_msg);
+                        bus.publish(events::JOB_FAILED, Some(&state.job_id), serde_json::json!({
+                            "command": state.name,
+                            "step": index,
+                            "error": error_msg,
+                        }));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    state.remove();
+    let return_code = state.results.iter().rev().find_map(|result| result.return_code);
+    jobs.finish(&state.job_id, return_code);
+    bus.publish(events::JOB_FINISHED, Some(&state.job_id), serde_json::json!({
+        "command": state.name,
+        "return_code": return_code,
+        "steps": total,
+    }));
+}
+
+/// Continue playbooks that rebooted the machine; called once at startup.
+pub fn resume_pending(bus: &EventBus, jobs: &web::Data<JobRegistry>, config: &AppConfig) {
+    let Ok(entries) = std::fs::read_dir(state_dir()) else {
+        return;
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let state = std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<PlaybookState>(&bytes).ok());
+        let Some(mut state) = state else {
+            log_error("startup", &format!("Unreadable playbook state {}", path.display()), None);
+            continue;
+        };
+
+        if let Some(result) = state.results.last_mut().filter(|result| result.action == "reboot") {
+            result.finished_at = Some(clock::now());
+        }
+        jobs.insert(state.record());
+
+        if !state.rebooting || config.read_only {
+            let error_msg = if state.rebooting {
+                "Not resumed after the reboot: the agent is read-only (AGENT_READ_ONLY)"
+            } else {
+                "Agent restarted while the playbook was running"
+            };
+            log_error("startup", error_msg, Some(&state.name));
+            state.remove();
+            jobs.interrupt(&state.job_id, error_msg);
+            bus.publish(events::JOB_FAILED, Some(&state.job_id), serde_json::json!({
+                "command": state.name,
+                "error": error_msg,
+                "interrupted": true,
+            }));
+            continue;
+        }
+
+        println!("Resuming playbook {} after reboot", state.job_id);
+        state.rebooting = false;
+        if let Err(e) = state.save() {
+            log_error("startup", &format!("Failed to update playbook state: {}", e), Some(&state.name));
+        }
+        bus.publish(events::JOB_STARTED, Some(&state.job_id), serde_json::json!({
+            "command": state.name,
+            "resumed_after_reboot": true,
+        }));
+        let job_id = state.job_id.clone();
+        let (concurrency, bus, jobs) = (config.concurrency.clone(), bus.clone(), jobs.clone());
+        supervisor::spawn_job_task(job_id.clone(), jobs.clone(), bus.clone(), async move {
+            let _slot = concurrency.wait_for(&job_id).await;
+            run(state, bus, jobs).await;
+        });
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PlaybookRequest {
+    /// Shown as the job's command; defaults to the first step's command.
+    pub name: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Serialize)]
+struct PlaybookResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Authorize and start a playbook for `endpoint`; returns the job id. `request`
+/// is what OPA is shown as the request.
+pub async fn start(
+    endpoint: &str,
+    http_req: &HttpRequest,
+    playbook: PlaybookRequest,
+    request: &serde_json::Value,
+    bus: &EventBus,
+    jobs: &web::Data<JobRegistry>,
+    config: &AppConfig,
+) -> Result<String, (StatusCode, String)> {
+    let caller = match auth::authenticate(config, http_req).await {
+        Ok(caller) => caller,
+        Err(error_msg) => {
+            log_error(endpoint, &error_msg, None);
+            return Err((StatusCode::UNAUTHORIZED, error_msg));
+        }
+    };
+    if let Some(Caller { name: key_name, key, .. }) = &caller {
+        let banned = playbook.steps.iter().find_map(|step| match step {
+            Step::Run { command, .. } => key.banned_shell(command, &[]),
+            Step::Reboot { .. } => None,
+        });
+        if let Some(shell) = banned {
+            let error_msg = format!("{} is not allowed for API key {:?}", shell, key_name);
+            log_error(endpoint, &error_msg, None);
+            return Err((StatusCode::FORBIDDEN, error_msg));
+        }
+        if let Some(user) = &key.run_as {
+            if let Err(e) = RunAs::resolve(user) {
+                let error_msg = format!("Cannot run as {}: {}", user, e);
+                log_error(endpoint, &error_msg, None);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, error_msg));
+            }
+        }
+    }
+
+    let refused = playbook.steps.iter().find_map(|step| match step {
+        Step::Run { command, .. } => config.command_rules.check(command).err(),
+        Step::Reboot { .. } => None,
+    });
+    if let Some(error_msg) = refused {
+        log_error(endpoint, &error_msg, None);
+        return Err((StatusCode::FORBIDDEN, error_msg));
+    }
+
+    if let Some(opa) = &config.opa {
+        let api_key = caller.as_ref().map(|caller| caller.name.as_str());
+        if let Err((status, error_msg)) = policy::consult_opa(opa, endpoint, http_req, api_key, request).await {
+            log_error(endpoint, &error_msg, None);
+            return Err((status, error_msg));
+        }
+    }
+    #[cfg(feature = "authz")]
+    if let Some(authz) = &config.authz {
+        let commands = playbook
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Run { command, .. } => Some(command.as_str()),
+                Step::Reboot { .. } => None,
+            })
+            .collect();
+        let api_key = caller.as_ref().map(|caller| caller.name.as_str());
+        let run_as = caller.as_ref().and_then(|caller| caller.key.run_as.as_deref());
+        let summary = crate::authz::Summary::new(endpoint, http_req, api_key, commands, run_as);
+        if let Err((status, error_msg)) = policy::consult_authz(authz, &summary).await {
+            log_error(endpoint, &error_msg, None);
+            return Err((status, error_msg));
+        }
+    }
+
+    let slot = match config.concurrency.acquire().await {
+        Ok(slot) => slot,
+        Err(error_msg) => {
+            log_error(endpoint, &error_msg, None);
+            return Err((StatusCode::TOO_MANY_REQUESTS, error_msg));
+        }
+    };
+
+    let name = playbook.name.unwrap_or_else(|| match &playbook.steps[0] {
+        Step::Run { command, .. } => format!("playbook: {}", command),
+        Step::Reboot { .. } => "playbook".to_string(),
+    });
+    let state = PlaybookState {
+        job_id: uuid::Uuid::new_v4().to_string(),
+        name,
+        steps: playbook.steps,
+        next_step: 0,
+        results: Vec::new(),
+        started_at: clock::now(),
+        rebooting: false,
+        api_key: caller.as_ref().map(|caller| caller.name.clone()),
+        client_certificate: tls::client_subject(http_req),
+        max_timeout: caller.as_ref().and_then(|caller| caller.key.max_timeout),
+        run_as: caller.and_then(|caller| caller.key.run_as.clone()),
+    };
+    // Saved up front so an agent crash mid-playbook is reported on restart.
+    if let Err(e) = state.save() {
+        let error_msg = format!("Failed to save playbook state: {}", e);
+        log_error(endpoint, &error_msg, Some(&state.name));
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, error_msg));
+    }
+
+    let job_id = state.job_id.clone();
+    jobs.insert(state.record());
+    bus.publish(events::JOB_STARTED, Some(&job_id), serde_json::json!({
+        "command": state.name,
+        "steps": state.steps.len(),
+    }));
+    let run = run(state, bus.clone(), jobs.clone());
+    supervisor::spawn_job_task(job_id.clone(), jobs.clone(), bus.clone(), async move {
+        run.await;
+        drop(slot);
+    });
+    Ok(job_id)
+}
+
+/// POST /playbooks/run - run steps in order as one job, surviving reboots.
+pub async fn run_playbook(
+    http_req: HttpRequest,
+    req: web::Json<PlaybookRequest>,
+    bus: web::Data<EventBus>,
+    jobs: web::Data<JobRegistry>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let req = req.into_inner();
+    let request = serde_json::to_value(&req).unwrap_or_default();
+    match start("/playbooks/run", &http_req, req, &request, &bus, &jobs, &config).await {
+        Ok(job_id) => Ok(HttpResponse::Ok().json(PlaybookResponse {
+            success: true,
+            job_id: Some(job_id),
+            status: Some("running".to_string()),
+            error: None,
+        })),
+        Err((status, error_msg)) => Ok(concurrency::response(status).json(PlaybookResponse {
+            success: false,
+            job_id: None,
+            status: None,
+            error: Some(error_msg),
+        })),
+    }
+}
@@ -0,0 +1,490 @@
+//! Request policy: the rules an execute request passes through before it
+//! runs, evaluated the same way for real requests and for `/policy/explain`.
+//!
+//! [`evaluate`] covers the API key rules (authentication, defaults,
+//! overrides, `max_timeout`, `banned_shells`, `run_as`), the operator's
+//! command rules, OPA and the authorization service, when configured;
+//! [`explain`] adds the
+//! per-request checks the handlers make afterwards (async-only fields, hooks,
+//! execution windows, free slots, guards and host load) without running
+//! anything.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api_keys::RunAs;
+use crate::auth::Caller;
+use crate::config::AppConfig;
+use crate::encoding;
+use crate::jobs::JobRegistry;
+use crate::opa;
+use crate::{auth, guards, pressure, shells, time_window, tls, ExecuteRequest};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Allow,
+    /// The request was changed (defaults, overrides, caps, user).
+    Modify,
+    Deny,
+    /// The job would be recorded as skipped without running.
+    Skip,
+    /// The job would wait as queued before starting.
+    Queue,
+}
+
+/// One rule's verdict on a request.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub matched: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effect: Option<Effect>,
+    pub reason: String,
+}
+
+impl RuleMatch {
+    fn matched(rule: &str, effect: Effect, reason: String) -> Self {
+        RuleMatch {
+            rule: rule.to_string(),
+            matched: true,
+            effect: Some(effect),
+            reason,
+        }
+    }
+
+    fn passed(rule: &str, reason: String) -> Self {
+        RuleMatch {
+            rule: rule.to_string(),
+            matched: false,
+            effect: None,
+            reason,
+        }
+    }
+}
+
+/// Why a request was refused before it became a job.
+pub struct Denial {
+    pub status: StatusCode,
+    pub error: String,
+    pub command: String,
+}
+
+pub struct Evaluation {
+    pub request: Result<ExecuteRequest, Denial>,
+    /// The request body after the key's defaults and overrides.
+    pub shaped: Value,
+    pub rules: Vec<RuleMatch>,
+}
+
+/// Authenticate an execute request and apply its API key's rules. Matched
+/// rules are kept on the request (and so on its job) for auditing.
+pub async fn evaluate(endpoint: &str, http_req: &HttpRequest, mut body: Value, config: &AppConfig) -> Evaluation {
+    let command = body.get("command").and_then(|command| command.as_str()).unwrap_or_default().to_string();
+    let mut rules = Vec::new();
+    let deny = |status: StatusCode, error: String| Denial {
+        status,
+        error,
+        command: command.clone(),
+    };
+
+    let caller = match auth::authenticate(config, http_req).await {
+        Ok(None) => {
+            rules.push(RuleMatch::passed("api_key", "No API keys configured".to_string()));
+            None
+        }
+        Ok(Some(caller)) => {
+            let reason = match caller.backend {
+                "key" => format!("Authenticated as {:?}", caller.name),
+                backend => format!("Authenticated as {:?} by {}", caller.name, backend),
+            };
+            rules.push(RuleMatch::matched("api_key", Effect::Allow, reason));
+            Some(caller)
+        }
+        Err(error_msg) => {
+            rules.push(RuleMatch::matched("api_key", Effect::Deny, error_msg.clone()));
+            let request = Err(deny(StatusCode::UNAUTHORIZED, error_msg));
+            return Evaluation { request, shaped: body, rules };
+        }
+    };
+
+    if let Some(Caller { key, .. }) = &caller {
+        let (filled, replaced) = key.shape(&mut body);
+        if !key.defaults.is_empty() {
+            rules.push(if filled.is_empty() {
+                RuleMatch::passed("defaults", "The request sets every defaulted field".to_string())
+            } else {
+                RuleMatch::matched("defaults", Effect::Modify, format!("Filled in {}", filled.join(", ")))
+            });
+        }
+        if !replaced.is_empty() {
+            rules.push(RuleMatch::matched("overrides", Effect::Modify, format!("Replaced {}", replaced.join(", "))));
+        }
+    }
+
+    let mut req: ExecuteRequest = match serde_json::from_value(body.clone()) {
+        Ok(req) => req,
+        Err(e) => {
+            let error_msg = format!("Invalid request: {}", e);
+            rules.push(RuleMatch::matched("request", Effect::Deny, error_msg.clone()));
+            let request = Err(deny(StatusCode::BAD_REQUEST, error_msg));
+            return Evaluation { request, shaped: body, rules };
+        }
+    };
+    if let Err(error_msg) = encoding::decode_request(&mut req)
+        .and_then(|()| req.prepare_process())
+        .and_then(|()| req.confinement().check())
+        .and_then(|()| req.shell.map_or(Ok(()), |shell| shells::check(shell, &config.allowed_shells))) {
+        rules.push(RuleMatch::matched("request", Effect::Deny, error_msg.clone()));
+        return Evaluation { request: Err(deny(StatusCode::BAD_REQUEST, error_msg)), shaped: body, rules };
+    }
+    if req.command_os.is_some() {
+        // So OPA and the explanation see the command.
+        body["command"] = Value::String(req.command.clone());
+    }
+    req.client_certificate = tls::client_subject(http_req);
+    if let Some(subject) = &req.client_certificate {
+        rules.push(RuleMatch::matched("client_certificate", Effect::Allow, format!("Presented {:?}", subject)));
+    }
+    if let Err((status, error_msg)) = apply_key(&mut req, caller, &mut rules) {
+        return Evaluation { request: Err(deny(status, error_msg)), shaped: body, rules };
+    }
+    if !config.command_rules.is_empty() {
+        match config.command_rules.check(&req.command) {
+            Ok(Some(name)) => rules.push(RuleMatch::matched("command_rules", Effect::Allow, format!("Allowed by rule {:?}", name))),
+            Ok(None) => rules.push(RuleMatch::passed("command_rules", "The command matches no denied rule".to_string())),
+            Err(error_msg) => {
+                rules.push(RuleMatch::matched("command_rules", Effect::Deny, error_msg.clone()));
+                return Evaluation { request: Err(deny(StatusCode::FORBIDDEN, error_msg)), shaped: body, rules };
+            }
+        }
+    }
+
+    if let Some(opa) = &config.opa {
+        match consult_opa(opa, endpoint, http_req, req.api_key.as_deref(), &body).await {
+            Ok(rule) => rules.push(rule),
+            Err((status, error_msg)) => {
+                rules.push(RuleMatch::matched("opa", Effect::Deny, error_msg.clone()));
+                return Evaluation { request: Err(deny(status, error_msg)), shaped: body, rules };
+            }
+        }
+    }
+
+    #[cfg(feature = "authz")]
+    if let Some(authz) = &config.authz {
+        let run_as = req.run_as.as_ref().map(|run_as| run_as.user.as_str());
+        let summary = crate::authz::Summary::new(endpoint, http_req, req.api_key.as_deref(), vec![&req.command], run_as);
+        match consult_authz(authz, &summary).await {
+            Ok(rule) => rules.push(rule),
+            Err((status, error_msg)) => {
+                rules.push(RuleMatch::matched("authz", Effect::Deny, error_msg.clone()));
+                return Evaluation { request: Err(deny(status, error_msg)), shaped: body, rules };
+            }
+        }
+    }
+
+    req.policy = rules.iter().filter(|rule| rule.matched).cloned().collect();
+    Evaluation { request: Ok(req), shaped: body, rules }
+}
+
+/// The API key's caps, shell bans and user switch.
+fn apply_key(
+    req: &mut ExecuteRequest,
+    caller: Option<Caller>,
+    rules: &mut Vec<RuleMatch>,
+) -> Result<(), (StatusCode, String)> {
+    let Some(Caller { name, key, .. }) = caller else {
+        return Ok(());
+    };
+    req.api_key = Some(name.clone());
+
+    if let Some(max_timeout) = key.max_timeout {
+        req.max_timeout = Some(max_timeout);
+        // 0 is no timeout at all.
+        rules.push(if req.timeout == 0 || req.timeout > max_timeout {
+            let reason = match req.timeout {
+                0 => format!("timeout capped from none to {}s", max_timeout),
+                timeout => format!("timeout capped from {}s to {}s", timeout, max_timeout),
+            };
+            req.timeout = max_timeout;
+            RuleMatch::matched("max_timeout", Effect::Modify, reason)
+        } else {
+            RuleMatch::passed("max_timeout", format!("timeout {}s is within {}s", req.timeout, max_timeout))
+        });
+    }
+
+    if !key.banned_shells.is_empty() {
+        let runs_in = match (req.shell, req.interpreter) {
+            (Some(shell), _) => vec![shell.name()],
+            (None, Some(interpreter)) => vec![interpreter.name(), interpreter.program()],
+            (None, None) => Vec::new(),
+        };
+        if let Some(shell) = key.banned_shell(&req.command, &runs_in) {
+            let error_msg = format!("{} is not allowed for API key {:?}", shell, name);
+            rules.push(RuleMatch::matched("banned_shells", Effect::Deny, error_msg.clone()));
+            return Err((StatusCode::FORBIDDEN, error_msg));
+        }
+        let reason = format!("The command uses none of {}", key.banned_shells.join(", "));
+        rules.push(RuleMatch::passed("banned_shells", reason));
+    }
+
+    if let Some(user) = &key.run_as {
+        let resolved = if req.interactive_session {
+            Err((
+                StatusCode::FORBIDDEN,
+                format!("interactive_session is not allowed for API key {:?} (run_as)", name),
+            ))
+        } else {
+            RunAs::resolve(user)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Cannot run as {}: {}", user, e)))
+        };
+        match resolved {
+            Ok(run_as) => {
+                rules.push(RuleMatch::matched("run_as", Effect::Modify, format!("Runs as {}", user)));
+                req.run_as = Some(run_as);
+            }
+            Err((status, error_msg)) => {
+                rules.push(RuleMatch::matched("run_as", Effect::Deny, error_msg.clone()));
+                return Err((status, error_msg));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ask OPA about a request; `Err` carries the status and message to deny with.
+pub async fn consult_opa(
+    opa: &opa::Opa,
+    endpoint: &str,
+    http_req: &HttpRequest,
+    api_key: Option<&str>,
+    request: &Value,
+) -> Result<RuleMatch, (StatusCode, String)> {
+    let input = opa::input(endpoint, http_req, api_key, request);
+    match opa.decide(&input).await {
+        Ok(decision) if decision.allow => {
+            let reason = decision.reason.unwrap_or_else(|| "Allowed by OPA".to_string());
+            Ok(RuleMatch::matched("opa", Effect::Allow, reason))
+        }
+        Ok(decision) => {
+            let reason = decision.reason.unwrap_or_else(|| "no reason given".to_string());
+            Err((StatusCode::FORBIDDEN, format!("Denied by OPA: {}", reason)))
+        }
+        Err(e) => Err((StatusCode::SERVICE_UNAVAILABLE, format!("OPA unavailable: {}", e))),
+    }
+}
+
+/// Ask the authorization service about a request; `Err` carries the status
+/// and message to deny with.
+#[cfg(feature = "authz")]
+pub async fn consult_authz(authz: &crate::authz::Authz, summary: &crate::authz::Summary<'_>) -> Result<RuleMatch, (StatusCode, String)> {
+    match authz.decide(summary).await {
+        Ok(decision) if decision.allow => {
+            let reason = decision.reason.unwrap_or_else(|| "Allowed by the authorization service".to_string());
+            let reason = if decision.cached { format!("{} (cached)", reason) } else { reason };
+            Ok(RuleMatch::matched("authz", Effect::Allow, reason))
+        }
+        Ok(decision) => {
+            let reason = decision.reason.unwrap_or_else(|| "no reason given".to_string());
+            Err((StatusCode::FORBIDDEN, format!("Denied by the authorization service: {}", reason)))
+        }
+        Err(e) => Err((StatusCode::SERVICE_UNAVAILABLE, format!("Authorization service unavailable: {}", e))),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExplainRequest {
+    /// `/execute` (default) or `/execute-async`.
+    #[serde(default = "default_endpoint")]
+    endpoint: String,
+    /// The request body to evaluate.
+    request: Value,
+}
+
+fn default_endpoint() -> String {
+    "/execute".to_string()
+}
+
+#[derive(Serialize)]
+struct ExplainResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endpoint: Option<String>,
+    /// allow, deny, skip or queue.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decision: Option<Effect>,
+    /// HTTP status the request would be refused with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    rules: Vec<RuleMatch>,
+    /// The request body after the API key's defaults and overrides.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// GET /policy/explain - evaluate a hypothetical request, with the caller's
+/// API key, against every rule and report the outcome without running it.
+pub async fn explain(
+    http_req: HttpRequest,
+    body: web::Json<ExplainRequest>,
+    jobs: web::Data<JobRegistry>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let ExplainRequest { endpoint, request } = body.into_inner();
+    let is_async = match endpoint.as_str() {
+        "/execute" => false,
+        "/execute-async" => true,
+        _ => {
+            return Ok(HttpResponse::BadRequest().json(ExplainResponse {
+                success: false,
+                endpoint: None,
+                decision: None,
+                status: None,
+                reason: None,
+                rules: Vec::new(),
+                request: None,
+                error: Some(format!("Unknown endpoint {:?}; use /execute or /execute-async", endpoint)),
+            }));
+        }
+    };
+
+    let Evaluation { request, shaped, mut rules } = evaluate(&endpoint, &http_req, request, &config).await;
+    let denied = match request {
+        Ok(req) => request_rules(&req, is_async, &jobs, &config, &mut rules).await,
+        Err(denial) => Some((denial.status, denial.error)),
+    };
+
+    let (decision, status, reason) = match denied {
+        Some((status, reason)) => (Effect::Deny, Some(status.as_u16()), Some(reason)),
+        None => match rules
+            .iter()
+            .find(|rule| matches!(rule.effect, Some(Effect::Skip | Effect::Queue)))
+        {
+            Some(rule) => (rule.effect.unwrap_or(Effect::Allow), None, Some(rule.reason.clone())),
+            None => (Effect::Allow, None, None),
+        },
+    };
+    Ok(HttpResponse::Ok().json(ExplainResponse {
+        success: true,
+        endpoint: Some(endpoint),
+        decision: Some(decision),
+        status,
+        reason,
+        rules,
+        request: Some(shaped),
+        error: None,
+    }))
+}
+
+fn deny(rules: &mut Vec<RuleMatch>, rule: &str, status: StatusCode, error_msg: String) -> Option<(StatusCode, String)> {
+    rules.push(RuleMatch::matched(rule, Effect::Deny, error_msg.clone()));
+    Some((status, error_msg))
+}
+
+/// The handlers' own checks, in their order. Returns the denial, if any.
+async fn request_rules(
+    req: &ExecuteRequest,
+    is_async: bool,
+    jobs: &JobRegistry,
+    config: &AppConfig,
+    rules: &mut Vec<RuleMatch>,
+) -> Option<(StatusCode, String)> {
+    if req.command.trim().is_empty() {
+        return deny(rules, "request", StatusCode::BAD_REQUEST, "Command must be a non-empty string".to_string());
+    }
+    if !is_async {
+        let async_only = [
+            (!req.expect.is_empty(), "expect"),
+            (req.record_cast, "record_cast"),
+            (req.splay_secs.is_some_and(|secs| secs > 0), "splay_secs"),
+            (req.on_window_miss == time_window::OnWindowMiss::Queue, "on_window_miss=queue"),
+            (req.on_missed != time_window::OnMissed::default(), "on_missed"),
+            (!req.on_success.is_empty(), "on_success"),
+            (!req.on_failure.is_empty(), "on_failure"),
+        ];
+        if let Some((_, field)) = async_only.iter().find(|(set, _)| *set) {
+            let error_msg = format!("{} is only supported by /execute-async", field);
+            return deny(rules, "async_only", StatusCode::BAD_REQUEST, error_msg);
+        }
+    }
+    if is_async {
+        if let Some(field) = req.sync_only_field() {
+            let error_msg = format!("{} is only supported by /execute", field);
+            return deny(rules, "sync_only", StatusCode::BAD_REQUEST, error_msg);
+        }
+    }
+
+    if let Some(name) = &req.hooks {
+        match crate::resolve_hooks(req, config) {
+            Ok(_) => rules.push(RuleMatch::matched("hooks", Effect::Allow, format!("Runs hook set {:?}", name))),
+            Err(error_msg) => return deny(rules, "hooks", StatusCode::BAD_REQUEST, error_msg),
+        }
+    }
+
+    if !req.allowed_windows.is_empty() {
+        let schedule = match time_window::Schedule::new(&req.allowed_windows) {
+            Ok(schedule) => schedule,
+            Err(error_msg) => return deny(rules, "allowed_windows", StatusCode::BAD_REQUEST, error_msg),
+        };
+        if schedule.allows(chrono::Local::now()) {
+            rules.push(RuleMatch::matched("allowed_windows", Effect::Allow, "Inside an allowed window".to_string()));
+        } else if is_async && req.on_window_miss == time_window::OnWindowMiss::Queue {
+            let reason = crate::window_miss_error(&schedule);
+            rules.push(RuleMatch::matched("allowed_windows", Effect::Queue, reason));
+        } else {
+            return deny(rules, "allowed_windows", StatusCode::CONFLICT, crate::window_miss_error(&schedule));
+        }
+    }
+
+    // Jobs the agent queues wait for a slot when they are due.
+    let window_queued = rules.iter().any(|rule| rule.rule == "allowed_windows" && rule.effect == Some(Effect::Queue));
+    let splayed = is_async && req.splay_secs.is_some_and(|secs| secs > 0);
+    let limiter = &config.concurrency;
+    if limiter.enabled() && !window_queued && !splayed {
+        let in_use = format!("{} of {} slots in use", limiter.running(), limiter.max_concurrent);
+        if limiter.running() < limiter.max_concurrent {
+            rules.push(RuleMatch::passed("concurrency", in_use));
+        } else if limiter.waiting() < limiter.max_queue_depth {
+            let reason = format!("{}; the request would wait up to {}s for one", in_use, limiter.queue_timeout.as_secs());
+            rules.push(RuleMatch::matched("concurrency", Effect::Queue, reason));
+        } else {
+            let error_msg = format!("Too many commands running (at most {}, with {} more waiting)", limiter.max_concurrent, limiter.waiting());
+            return deny(rules, "concurrency", StatusCode::TOO_MANY_REQUESTS, error_msg);
+        }
+    }
+
+    // Guards and host load reflect the host right now; a queued job checks
+    // them again when it starts.
+    if !req.guards.is_empty() {
+        match guards::evaluate(&req.guards, jobs).await {
+            Some(reason) => {
+                rules.push(RuleMatch::matched("guards", Effect::Skip, reason));
+                return None;
+            }
+            None => rules.push(RuleMatch::passed("guards", format!("All {} guards hold", req.guards.len()))),
+        }
+    }
+
+    if let Some(check) = pressure::check(config).await {
+        let Some(reason) = check.reason else {
+            rules.push(RuleMatch::passed("host_pressure", "The host is within its thresholds".to_string()));
+            return None;
+        };
+        if is_async && check.decision == pressure::Decision::Deferred {
+            rules.push(RuleMatch::matched("host_pressure", Effect::Queue, reason));
+        } else if window_queued {
+            // A queued job that finds the host under pressure is skipped.
+            rules.push(RuleMatch::matched("host_pressure", Effect::Skip, reason));
+        } else {
+            let error_msg = format!("Host under pressure: {}", reason);
+            return deny(rules, "host_pressure", StatusCode::SERVICE_UNAVAILABLE, error_msg);
+        }
+    }
+    None
+}
@@ -0,0 +1,96 @@
+//! Host load guardrails: agent-wide thresholds checked right before a job is
+//! spawned, so new work isn't piled onto a host that is already struggling.
+
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::clock;
+use crate::config::AppConfig;
+use crate::host;
+
+/// What to do with a job while the host is over a threshold.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum OnHostPressure {
+    #[default]
+    Reject,
+    /// `/execute-async` only: hold the job as `queued` and retry.
+    Defer,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allowed,
+    Deferred,
+    Rejected,
+}
+
+/// The host state a start decision was based on; recorded on the job.
+/// Metrics that could not be read are left out and don't block.
+#[derive(Serialize, Clone)]
+pub struct HostCheck {
+    pub decision: Decision,
+    pub checked_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_load: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_memory_mb: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_disk_mb: Option<u64>,
+    /// First threshold the host was over.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Measure the host against the configured thresholds; `None` when no
+/// threshold is configured. The decision is `Allowed`, or `on_host_pressure`
+/// when a threshold is exceeded.
+pub async fn check(config: &AppConfig) -> Option<HostCheck> {
+    if config.max_cpu_load.is_none() && config.min_free_memory_mb.is_none() && config.min_free_disk_mb.is_none() {
+        return None;
+    }
+    const MB: u64 = 1024 * 1024;
+    let cpu_load = match config.max_cpu_load {
+        Some(_) => host::cpu_load().await.ok(),
+        None => None,
+    };
+    let free_memory_mb = match config.min_free_memory_mb {
+        Some(_) => host::free_memory_bytes().await.ok().map(|bytes| bytes / MB),
+        None => None,
+    };
+    let free_disk_mb = match config.min_free_disk_mb {
+        Some(_) => {
+            // Commands run in the agent's working directory.
+            let cwd = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+            host::free_disk_bytes(&cwd).await.ok().map(|bytes| bytes / MB)
+        }
+        None => None,
+    };
+
+    let reason = match (cpu_load, config.max_cpu_load) {
+        (Some(load), Some(max)) if load > max => Some(format!("CPU load {:.2} is above {:.2}", load, max)),
+        _ => None,
+    }
+    .or_else(|| match (free_memory_mb, config.min_free_memory_mb) {
+        (Some(free), Some(min)) if free < min => Some(format!("Only {} MB of memory free (need {} MB)", free, min)),
+        _ => None,
+    })
+    .or_else(|| match (free_disk_mb, config.min_free_disk_mb) {
+        (Some(free), Some(min)) if free < min => Some(format!("Only {} MB of disk free (need {} MB)", free, min)),
+        _ => None,
+    });
+
+    let decision = match (&reason, config.on_host_pressure) {
+        (None, _) => Decision::Allowed,
+        (Some(_), OnHostPressure::Reject) => Decision::Rejected,
+        (Some(_), OnHostPressure::Defer) => Decision::Deferred,
+    };
+    Some(HostCheck {
+        decision,
+        checked_at: clock::now(),
+        cpu_load,
+        free_memory_mb,
+        free_disk_mb,
+        reason,
+    })
+}
@@ -0,0 +1,44 @@
+//! Structured progress reporting from scripts.
+//!
+//! A command reports progress by printing lines of the form
+//!
+//! ```text
+//! ::progress::{"percent": 42, "message": "Copying files"}
+//! ::progress::75
+//! ```
+//!
+//! on stdout. The agent parses them into the job's `progress` and emits a
+//! `com.machineagent.job.progress` event for each update.
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+
+pub const PREFIX: &str = "::progress::";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Progress {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_deserializing)]
+    pub updated_at: String,
+}
+
+/// Parse a progress line; returns `None` for ordinary output and malformed payloads.
+pub fn parse_line(line: &str) -> Option<Progress> {
+    let payload = line.trim_end_matches(['\r', '\n']).strip_prefix(PREFIX)?.trim();
+    let mut progress = match serde_json::from_str::<serde_json::Value>(payload).ok()? {
+        serde_json::Value::Number(n) => Progress {
+            percent: n.as_f64(),
+            message: None,
+            updated_at: String::new(),
+        },
+        value @ serde_json::Value::Object(_) => serde_json::from_value(value).ok()?,
+        _ => return None,
+    };
+    progress.percent = progress.percent.map(|p| p.clamp(0.0, 100.0));
+    progress.updated_at = clock::now();
+    Some(progress)
+}
@@ -0,0 +1,306 @@
+//! Provenance stamps on files written by jobs, so "what created this file on
+//! the server" can be answered later from the file itself.
+//!
+//! The stamp is a small JSON record stored in an extended attribute
+//! (`user.machine_agent.provenance`, Linux, macOS and FreeBSD) or an NTFS alternate data
+//! stream (`<file>:machine_agent.provenance`, Windows). It survives renames
+//! within the filesystem; copies to filesystems without xattrs/ADS drop it.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::artifacts;
+use crate::clock;
+use crate::events;
+use crate::jobs::{Job, JobRegistry};
+use crate::log_error;
+
+#[cfg(unix)]
+const ATTRIBUTE: &str = "user.machine_agent.provenance";
+#[cfg(windows)]
+const STREAM: &str = "machine_agent.provenance";
+
+/// Largest stamp read back; ours are a few hundred bytes.
+#[cfg(unix)]
+const MAX_STAMP: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub struct Provenance {
+    pub job_id: String,
+    pub command: String,
+    /// Name of the API key the job was submitted with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Subject of the client certificate the job was submitted with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_as: Option<String>,
+    pub hostname: String,
+    pub stamped_at: String,
+}
+
+impl Provenance {
+    pub fn for_job(job: &Job) -> Self {
+        Provenance {
+            job_id: job.id.clone(),
+            command: job.command.clone(),
+            api_key: job.api_key.clone(),
+            client_certificate: job.client_certificate.clone(),
+            run_as: job.run_as.clone(),
+            hostname: events::hostname(),
+            stamped_at: clock::now(),
+        }
+    }
+}
+
+/// Attach `provenance` to the file at `path`, replacing an older stamp.
+pub fn stamp(path: &Path, provenance: &Provenance) -> io::Result<()> {
+    let value = serde_json::to_vec(provenance).map_err(io::Error::other)?;
+    write_stamp(path, &value)
+}
+
+/// The stamp on the file at `path`; `None` when it has none.
+pub fn read(path: &Path) -> io::Result<Option<Provenance>> {
+    if !path.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "No such file"));
+    }
+    match read_stamp(path)? {
+        Some(value) => serde_json::from_slice(&value)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Unreadable provenance: {}", e))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(unix)]
+fn c_string(value: &std::ffi::OsStr) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(value.as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contains NUL"))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn write_stamp(path: &Path, value: &[u8]) -> io::Result<()> {
+    let path = c_string(path.as_os_str())?;
+    let name = c_string(ATTRIBUTE.as_ref())?;
+    let ptr = value.as_ptr() as *const libc::c_void;
+    // SAFETY: both strings are NUL-terminated and `ptr` is valid for `value.len()` bytes.
+    #[cfg(target_os = "linux")]
+    let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), ptr, value.len(), 0) };
+    // SAFETY: as above.
+    #[cfg(target_os = "macos")]
+    let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), ptr, value.len(), 0, 0) };
+    if result != 0 {
+        return Err(xattr_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn read_stamp(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let path = c_string(path.as_os_str())?;
+    let name = c_string(ATTRIBUTE.as_ref())?;
+    let mut value = vec![0u8; MAX_STAMP];
+    let ptr = value.as_mut_ptr() as *mut libc::c_void;
+    // SAFETY: both strings are NUL-terminated and `ptr` is valid for `value.len()` bytes.
+    #[cfg(target_os = "linux")]
+    let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), ptr, value.len()) };
+    // SAFETY: as above.
+    #[cfg(target_os = "macos")]
+    let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), ptr, value.len(), 0, 0) };
+    if len < 0 {
+        let e = io::Error::last_os_error();
+        #[cfg(target_os = "linux")]
+        let missing = libc::ENODATA;
+        #[cfg(target_os = "macos")]
+        let missing = libc::ENOATTR;
+        if e.raw_os_error() == Some(missing) {
+            return Ok(None);
+        }
+        return Err(xattr_error_from(e));
+    }
+    value.truncate(len as usize);
+    Ok(Some(value))
+}
+
+/// FreeBSD keeps the `user.` namespace apart from the name.
+#[cfg(target_os = "freebsd")]
+fn write_stamp(path: &Path, value: &[u8]) -> io::Result<()> {
+    let path = c_string(path.as_os_str())?;
+    let name = c_string(ATTRIBUTE.trim_start_matches("user.").as_ref())?;
+    let ptr = value.as_ptr() as *const libc::c_void;
+    // SAFETY: both strings are NUL-terminated and `ptr` is valid for `value.len()` bytes.
+    let written = unsafe { libc::extattr_set_file(path.as_ptr(), libc::EXTATTR_NAMESPACE_USER, name.as_ptr(), ptr, value.len()) };
+    if written < 0 {
+        return Err(xattr_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "freebsd")]
+fn read_stamp(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let path = c_string(path.as_os_str())?;
+    let name = c_string(ATTRIBUTE.trim_start_matches("user.").as_ref())?;
+    let mut value = vec![0u8; MAX_STAMP];
+    let ptr = value.as_mut_ptr() as *mut libc::c_void;
+    // SAFETY: both strings are NUL-terminated and `ptr` is valid for `value.len()` bytes.
+    let len = unsafe { libc::extattr_get_file(path.as_ptr(), libc::EXTATTR_NAMESPACE_USER, name.as_ptr(), ptr, value.len()) };
+    if len < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ENOATTR) {
+            return Ok(None);
+        }
+        return Err(xattr_error_from(e));
+    }
+    value.truncate(len as usize);
+    Ok(Some(value))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+fn xattr_error() -> io::Error {
+    xattr_error_from(io::Error::last_os_error())
+}
+
+/// Report filesystems without user xattrs as unsupported.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+fn xattr_error_from(e: io::Error) -> io::Error {
+    if e.raw_os_error() == Some(libc::ENOTSUP) {
+        return io::Error::new(io::ErrorKind::Unsupported, "The filesystem does not support extended attributes");
+    }
+    e
+}
+
+#[cfg(windows)]
+fn stream_path(path: &Path) -> PathBuf {
+    let mut stream = path.as_os_str().to_owned();
+    stream.push(":");
+    stream.push(STREAM);
+    PathBuf::from(stream)
+}
+
+#[cfg(windows)]
+fn write_stamp(path: &Path, value: &[u8]) -> io::Result<()> {
+    // Writing the stream leaves the file's own contents and mtime alone.
+    std::fs::write(stream_path(path), value).map_err(|e| {
+        // ERROR_INVALID_NAME: a FAT or network volume without stream support.
+        if e.raw_os_error() == Some(123) {
+            return io::Error::new(io::ErrorKind::Unsupported, "The filesystem does not support alternate data streams");
+        }
+        e
+    })
+}
+
+#[cfg(windows)]
+fn read_stamp(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match std::fs::read(stream_path(path)) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd", windows)))]
+fn write_stamp(_path: &Path, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "File provenance is not supported on this platform"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd", windows)))]
+fn read_stamp(_path: &Path) -> io::Result<Option<Vec<u8>>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "File provenance is not supported on this platform"))
+}
+
+/// Resolve a path the way the job's command saw it.
+pub fn resolve(path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    if path.is_absolute() {
+        return path;
+    }
+    std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")).join(path)
+}
+
+/// Stamp everything a finished job wrote that the agent knows about: its log
+/// file, its artifacts and the `outputs` its request declared. Stamped paths
+/// are recorded on the job.
+pub async fn stamp_job(job_id: &str, outputs: &[String], jobs: &JobRegistry) {
+    let Some(job) = jobs.get(job_id) else {
+        return;
+    };
+    let mut files: Vec<PathBuf> = outputs.iter().map(|output| resolve(output)).collect();
+    files.extend(job.log_file.as_ref().map(PathBuf::from));
+    if let Ok(entries) = std::fs::read_dir(artifacts::artifacts_dir().join(job_id)) {
+        files.extend(entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|path| path.is_file()));
+    }
+    if files.is_empty() {
+        return;
+    }
+
+    let provenance = Provenance::for_job(&job);
+    let command = job.command.clone();
+    let stamped = web::block(move || {
+        let mut stamped = Vec::new();
+        for file in files {
+            match stamp(&file, &provenance) {
+                Ok(()) => stamped.push(file.display().to_string()),
+                // Declared outputs the command didn't create, and filesystems
+                // without xattrs/ADS, are expected; anything else is logged.
+                Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::Unsupported) => {}
+                Err(e) => log_error(
+                    "provenance",
+                    &format!("Failed to stamp {}: {}", file.display(), e),
+                    Some(&command),
+                ),
+            }
+        }
+        stamped
+    })
+    .await
+    .unwrap_or_default();
+    jobs.update(job_id, |job| job.stamped_files = stamped);
+}
+
+#[derive(Deserialize)]
+pub struct ProvenanceQuery {
+    path: String,
+}
+
+#[derive(Serialize)]
+struct ProvenanceResponse {
+    success: bool,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// GET /provenance?path=... - which job wrote a file.
+pub async fn get_provenance(query: web::Query<ProvenanceQuery>) -> ActixResult<HttpResponse> {
+    let path = resolve(&query.path);
+    let display = path.display().to_string();
+    let result = web::block(move || read(&path)).await.map_err(io::Error::other).and_then(|r| r);
+    let (mut response, error) = match result {
+        Ok(Some(provenance)) => {
+            return Ok(HttpResponse::Ok().json(ProvenanceResponse {
+                success: true,
+                path: display,
+                provenance: Some(provenance),
+                error: None,
+            }))
+        }
+        Ok(None) => (HttpResponse::NotFound(), "No provenance recorded for this file".to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (HttpResponse::NotFound(), e.to_string()),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => (HttpResponse::NotImplemented(), e.to_string()),
+        Err(e) => {
+            log_error("/provenance", &format!("Failed to read provenance of {}: {}", display, e), None);
+            (HttpResponse::InternalServerError(), e.to_string())
+        }
+    };
+    Ok(response.json(ProvenanceResponse {
+        success: false,
+        path: display,
+        provenance: None,
+        error: Some(error),
+    }))
+}
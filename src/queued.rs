@@ -0,0 +1,226 @@
+//! Queued `/execute-async` jobs surviving an agent restart.
+//!
+//! While a job waits (for its execution window, its splay or the host to
+//! calm down) its request is kept in `queued/<job_id>.json` next to the
+//! executable. When the agent starts again [`resume_pending`] queues the jobs
+//! that are not due yet as before; a job that came due while the agent was
+//! down is handled as its `on_missed` says: `skip` records it as skipped,
+//! `run_once` starts it as soon as its windows allow. A queued job has a
+//! single start time, so there is never more than one run to catch up on.
+
+use actix_web::web;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io;
+use std::path::PathBuf;
+
+use crate::api_keys::RunAs;
+use crate::chains::Chain;
+use crate::config::AppConfig;
+use crate::events::{self, EventBus};
+use crate::jobs::{Job, JobRegistry};
+use crate::policy::RuleMatch;
+use crate::time_window::{OnMissed, Schedule};
+use crate::{clock, encoding, get_exe_dir, log_error, shells, supervisor, AsyncJob, ExecuteRequest};
+
+/// Everything needed to queue a job again after a restart.
+#[derive(Serialize, Deserialize)]
+struct QueuedState {
+    job_id: String,
+    command: String,
+    /// The request after the key's defaults and overrides.
+    request: Value,
+    /// When the job was to start; `None` for a job deferred by host pressure,
+    /// which was due right away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_certificate: Option<String>,
+    /// User the job runs as, forced by the API key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    run_as: Option<String>,
+    #[serde(default)]
+    policy: Vec<RuleMatch>,
+    #[serde(default, skip_serializing_if = "Chain::is_empty")]
+    chain: Chain,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    follows: Option<String>,
+}
+
+fn state_dir() -> PathBuf {
+    get_exe_dir().join("queued")
+}
+
+fn state_path(job_id: &str) -> PathBuf {
+    state_dir().join(format!("{}.json", job_id))
+}
+
+/// Keep a job that was just queued, to start at `due`.
+pub fn save(job: &AsyncJob, due: Option<&str>) -> io::Result<()> {
+    let state = QueuedState {
+        job_id: job.job_id.clone(),
+        command: job.command.clone(),
+        request: job.request.clone(),
+        due: due.map(str::to_string),
+        api_key: job.api_key.clone(),
+        client_certificate: job.client_certificate.clone(),
+        run_as: job.run_as.as_ref().map(|run_as| run_as.user.clone()),
+        policy: job.policy.clone(),
+        chain: job.chain.clone(),
+        follows: job.follows.clone(),
+    };
+    std::fs::create_dir_all(state_dir())?;
+    // Write-then-rename so a crash never leaves a truncated state file.
+    let path = state_path(&job.job_id);
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_vec_pretty(&state)?)?;
+    std::fs::rename(temp, path)
+}
+
+/// Forget a job that is no longer queued.
+pub fn remove(job_id: &str) {
+    let _ = std::fs::remove_file(state_path(job_id));
+}
+
+/// Rebuild a request that was evaluated before, with what its evaluation
+/// added; the request's own fields are checked again.
+pub fn restore(
+    request: &Value,
+    api_key: Option<String>,
+    client_certificate: Option<String>,
+    run_as: Option<&str>,
+    policy: Vec<RuleMatch>,
+    config: &AppConfig,
+) -> Result<ExecuteRequest, String> {
+    let mut req: ExecuteRequest = serde_json::from_value(request.clone()).map_err(|e| format!("Invalid request: {}", e))?;
+    encoding::decode_request(&mut req)?;
+    req.prepare_process()?;
+    if let Some(shell) = req.shell {
+        shells::check(shell, &config.allowed_shells)?;
+    }
+    req.max_timeout = api_key.as_ref().and_then(|name| config.api_keys.get(name)).and_then(|key| key.max_timeout);
+    req.api_key = api_key;
+    req.client_certificate = client_certificate;
+    req.policy = policy;
+    req.run_as = match run_as {
+        Some(user) => Some(RunAs::resolve(user).map_err(|e| format!("Cannot run as {}: {}", user, e))?),
+        None => None,
+    };
+    Ok(req)
+}
+
+/// Queue `job` for `req`, which the agent itself submits, to start when its
+/// windows, splay, guards and the host allow; returns its job ID.
+pub fn submit(
+    job: AsyncJob,
+    req: ExecuteRequest,
+    bus: &web::Data<EventBus>,
+    jobs: &web::Data<JobRegistry>,
+    config: &web::Data<AppConfig>,
+) -> Result<String, String> {
+    let schedule = Schedule::new(&req.allowed_windows)?;
+    let now = Local::now();
+    let opens = if schedule.allows(now) { Some(now) } else { schedule.next_open(now) };
+    let queued_until = opens
+        .map(|opens| opens + chrono::Duration::from_std(job.splay.unwrap_or_default()).unwrap_or_default())
+        .filter(|&starts| starts > now)
+        .map(|starts| clock::format(&starts));
+    let job_id = job.job_id.clone();
+    crate::queue_job(&job, queued_until, &req.guards, bus, jobs);
+    supervisor::spawn_job_task(
+        job_id.clone(),
+        jobs.clone(),
+        bus.get_ref().clone(),
+        crate::run_queued(schedule, job, req.guards, bus.clone(), jobs.clone(), config.clone()),
+    );
+    Ok(job_id)
+}
+
+/// Queue `state`'s job again, or skip it if it was missed and should be.
+fn resume(
+    state: QueuedState,
+    bus: &web::Data<EventBus>,
+    jobs: &web::Data<JobRegistry>,
+    config: &web::Data<AppConfig>,
+) -> Result<(), String> {
+    let req = restore(&state.request, state.api_key, state.client_certificate, state.run_as.as_deref(), state.policy, config)?;
+    let schedule = Schedule::new(&req.allowed_windows)?;
+    let mut job = AsyncJob::new(state.job_id, &req, state.request, config)?;
+    job.chain = state.chain;
+    job.follows = state.follows;
+
+    let now = Local::now();
+    let due = state
+        .due
+        .as_deref()
+        .and_then(|due| DateTime::parse_from_rfc3339(due).ok())
+        .map(|due| due.with_timezone(&Local));
+    let queued_until = match due.filter(|&due| due > now) {
+        Some(due) => {
+            // What is left of the splay once the window opens.
+            let opens = if schedule.allows(now) { Some(now) } else { schedule.next_open(now) };
+            job.splay = opens.and_then(|opens| (due - opens).to_std().ok()).filter(|splay| !splay.is_zero());
+            Some(clock::format(&due))
+        }
+        None if req.on_missed == OnMissed::Skip => {
+            let reason = match &state.due {
+                Some(due) => format!("Missed: the agent was down when the job was due at {}", due),
+                None => "Missed: the agent was down when the job was due".to_string(),
+            };
+            remove(&job.job_id);
+            job.splay = None;
+            crate::skip_job(job.into_record(None), &reason, bus, jobs);
+            return Ok(());
+        }
+        None => {
+            job.splay = None;
+            schedule.next_open(now).filter(|_| !schedule.allows(now)).map(|opens| clock::format(&opens))
+        }
+    };
+
+    tracing::info!(job_id = %job.job_id, command = %job.command, "Resuming queued job after restart");
+    let job_id = job.job_id.clone();
+    crate::queue_job(&job, queued_until, &req.guards, bus, jobs);
+    supervisor::spawn_job_task(
+        job_id,
+        jobs.clone(),
+        bus.get_ref().clone(),
+        crate::run_queued(schedule, job, req.guards, bus.clone(), jobs.clone(), config.clone()),
+    );
+    Ok(())
+}
+
+/// Queue again the jobs that were queued when the agent stopped; called
+/// once at startup, before reconciling the job history.
+pub fn resume_pending(bus: &web::Data<EventBus>, jobs: &web::Data<JobRegistry>, config: &web::Data<AppConfig>) {
+    let Ok(entries) = std::fs::read_dir(state_dir()) else {
+        return;
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let state = std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<QueuedState>(&bytes).ok());
+        let Some(state) = state else {
+            log_error("startup", &format!("Unreadable queued job {}", path.display()), None);
+            continue;
+        };
+        let (job_id, command) = (state.job_id.clone(), state.command.clone());
+        if let Err(e) = resume(state, bus, jobs, config) {
+            let error_msg = format!("Failed to resume queued job: {}", e);
+            log_error("startup", &error_msg, Some(&command));
+            remove(&job_id);
+            jobs.insert(Job::new(&job_id, &command, None));
+            jobs.fail(&job_id, &error_msg);
+            bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                "command": command,
+                "error": error_msg,
+            }));
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Read-only observer mode, for auditors and for quarantined hosts under
+//! investigation: health, metrics, job history and file reads keep working,
+//! and nothing that runs commands or changes the host does.
+//!
+//! `AGENT_READ_ONLY=true` turns the whole agent read-only; callers whose API
+//! keys entry has `"role": "observer"` are read-only whatever it says. Either
+//! way such requests get `403 Forbidden`. A request changes the host unless
+//! it is a `GET` (`/shell` aside) or one of the few `POST`s that only look
+//! ([`READS`]), so an endpoint added later is refused until listed. On a
+//! read-only agent, queued jobs are skipped when due and playbooks
+//! interrupted by a reboot are not resumed.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+
+use crate::api_keys::Role;
+use crate::auth;
+use crate::config::AppConfig;
+use crate::log_error;
+
+/// Why a queued job that comes due on a read-only agent is skipped.
+pub const SKIPPED: &str = "The agent is read-only (AGENT_READ_ONLY)";
+
+/// Routes taking a `POST` that change nothing.
+const READS: &[&str] = &[
+    "/policy/explain",
+    "/jobs/{id}/stream-token",
+    "/sync/plan",
+    "/screen/ocr",
+    "/screen/wait-for-image",
+];
+
+/// Whether a request for `route` changes the host or runs anything.
+pub fn mutates(method: &Method, route: &str) -> bool {
+    match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => route == "/shell",
+        _ => !READS.contains(&route),
+    }
+}
+
+/// Why `req`, which changes the host, is refused, if it is.
+async fn refusal(config: &AppConfig, req: &ServiceRequest, route: &str) -> Option<String> {
+    if config.read_only {
+        return Some(format!("The agent is read-only (AGENT_READ_ONLY): {} {} is not allowed", req.method(), route));
+    }
+    if !config.api_keys.values().any(|key| key.role == Role::Observer) {
+        return None;
+    }
+    // Callers that don't authenticate never get here (`auth::guard`).
+    match auth::authenticate(config, req.request()).await {
+        Ok(Some(caller)) if caller.key.role == Role::Observer => {
+            Some(format!("{:?} is an observer: {} {} is not allowed", caller.name, req.method(), route))
+        }
+        _ => None,
+    }
+}
+
+/// Middleware refusing requests that change the host, when the agent or the
+/// caller is read-only.
+pub async fn guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let route = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+    let error_msg = match req.app_data::<web::Data<AppConfig>>() {
+        Some(config) if mutates(req.method(), &route) => refusal(config, &req, &route).await,
+        _ => None,
+    };
+    let Some(error_msg) = error_msg else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+    log_error(&route, &error_msg, None);
+    let response = HttpResponse::Forbidden().json(serde_json::json!({
+        "success": false,
+        "error": error_msg,
+    }));
+    Ok(req.into_response(response).map_into_right_body())
+}
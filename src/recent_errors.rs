@@ -0,0 +1,56 @@
+//! The agent's last internal errors, kept in memory for `/health/details` so
+//! triage doesn't need access to `app_error.log`.
+//!
+//! Every error logged is recorded here too, without the command or
+//! traceback, and with the message cut short.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// How many errors are kept.
+const KEPT: usize = 50;
+
+/// Longest message kept, in characters.
+const MAX_MESSAGE: usize = 300;
+
+#[derive(Serialize, Clone)]
+pub struct RecentError {
+    pub time: String,
+    /// Where it happened: the endpoint, or e.g. `startup` or `watchdog`.
+    pub code: String,
+    pub message: String,
+}
+
+struct Recent {
+    errors: VecDeque<RecentError>,
+    total: u64,
+}
+
+static RECENT: Mutex<Recent> = Mutex::new(Recent {
+    errors: VecDeque::new(),
+    total: 0,
+});
+
+pub fn record(time: &str, code: &str, message: &str) {
+    let mut truncated: String = message.chars().take(MAX_MESSAGE).collect();
+    if truncated.len() < message.len() {
+        truncated.push('…');
+    }
+    let mut recent = RECENT.lock().unwrap();
+    if recent.errors.len() == KEPT {
+        recent.errors.pop_front();
+    }
+    recent.errors.push_back(RecentError {
+        time: time.to_string(),
+        code: code.to_string(),
+        message: truncated,
+    });
+    recent.total += 1;
+}
+
+/// Errors since the agent started, and the last ones, newest first.
+pub fn snapshot() -> (u64, Vec<RecentError>) {
+    let recent = RECENT.lock().unwrap();
+    (recent.total, recent.errors.iter().rev().cloned().collect())
+}
@@ -0,0 +1,102 @@
+//! Cached results of read-only check commands, so fleet-wide inventory sweeps
+//! every few minutes don't run the same expensive command each time.
+//!
+//! An `/execute` request with `cache_ttl` (seconds) is answered from the
+//! cache when the same command ran with the same environment less than
+//! `cache_ttl` seconds ago, and its result is cached otherwise. The key is a
+//! SHA-256 of the command, the shell or interpreter running it, the request's environment variables, the user it
+//! runs as, the working directory, its stdin and the agent's environment
+//! variables (unless the request clears them). Only commands that ran to
+//! completion are cached, whatever their exit code.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::content;
+
+/// Entries kept at most; the oldest are dropped first.
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Clone)]
+pub struct CachedResult {
+    /// The job that produced the result.
+    pub job_id: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub return_code: Option<i32>,
+    pub artifacts: Option<Vec<String>>,
+}
+
+struct Entry {
+    result: CachedResult,
+    stored: Instant,
+}
+
+#[derive(Default)]
+pub struct ResultCache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// The cache key of `command` run by the shell or interpreter `runs_in` with the extra `env` (or only those, with
+/// `clear_env`) and `stdin` as `run_as` from `cwd`.
+pub fn key(command: &OsStr, runs_in: &str, env: &[(String, OsString)], clear_env: bool, cwd: &Path, stdin: Option<&str>, run_as: Option<&str>) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |value: &[u8]| {
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    };
+    field(command.as_encoded_bytes());
+    field(runs_in.as_bytes());
+    field(&(env.len() as u64).to_le_bytes());
+    for (name, value) in env {
+        field(name.as_bytes());
+        field(value.as_encoded_bytes());
+    }
+    field(run_as.unwrap_or_default().as_bytes());
+    field(cwd.as_os_str().as_encoded_bytes());
+    // Distinguishes no stdin from an empty one.
+    field(&[stdin.is_some() as u8]);
+    field(stdin.unwrap_or_default().as_bytes());
+    if clear_env {
+        return content::hex(&hasher.finalize());
+    }
+    let mut vars: Vec<(String, String)> = std::env::vars_os()
+        .map(|(name, value)| (name.to_string_lossy().into_owned(), value.to_string_lossy().into_owned()))
+        .collect();
+    vars.sort();
+    for (name, value) in &vars {
+        field(name.as_bytes());
+        field(value.as_bytes());
+    }
+    content::hex(&hasher.finalize())
+}
+
+impl ResultCache {
+    /// The result stored under `key` less than `ttl` ago.
+    pub fn get(&self, key: &str, ttl: Duration) -> Option<CachedResult> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        (entry.stored.elapsed() < ttl).then(|| entry.result.clone())
+    }
+
+    pub fn insert(&self, key: String, result: CachedResult) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(
+            key,
+            Entry {
+                result,
+                stored: Instant::now(),
+            },
+        );
+        while entries.len() > MAX_ENTRIES {
+            let oldest = entries.iter().min_by_key(|(_, entry)| entry.stored).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! S3 and S3-compatible object storage (AWS S3, MinIO, Ceph, R2, ...) for
+//! [`crate::offload`], with requests signed by AWS Signature Version 4.
+
+use futures_util::future::BoxFuture;
+use std::path::Path;
+
+use crate::bandwidth::Bandwidth;
+use crate::offload::{self, env_flag, env_var, hmac, uri_encode, Store};
+use crate::outbound::Outbound;
+
+pub struct S3 {
+    /// `scheme://host[:port]`, without a trailing slash.
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    /// `<endpoint>/<bucket>/<key>` rather than `<bucket>.<host>/<key>`.
+    path_style: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl S3 {
+    pub fn from_env() -> Result<Self, String> {
+        let missing = |name: &str| format!("The s3 offload backend needs {}", name);
+        let bucket = env_var(&["AGENT_S3_BUCKET"]).ok_or_else(|| missing("AGENT_S3_BUCKET"))?;
+        let region = env_var(&["AGENT_S3_REGION", "AWS_REGION", "AWS_DEFAULT_REGION"]).unwrap_or_else(|| "us-east-1".to_string());
+        let custom_endpoint = env_var(&["AGENT_S3_ENDPOINT"]);
+        Ok(S3 {
+            path_style: env_flag("AGENT_S3_PATH_STYLE").unwrap_or(custom_endpoint.is_some()),
+            endpoint: custom_endpoint
+                .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region))
+                .trim_end_matches('/')
+                .to_string(),
+            bucket,
+            region,
+            access_key_id: env_var(&["AGENT_S3_ACCESS_KEY_ID", "AWS_ACCESS_KEY_ID"]).ok_or_else(|| missing("AGENT_S3_ACCESS_KEY_ID"))?,
+            secret_access_key: env_var(&["AGENT_S3_SECRET_ACCESS_KEY", "AWS_SECRET_ACCESS_KEY"])
+                .ok_or_else(|| missing("AGENT_S3_SECRET_ACCESS_KEY"))?,
+            session_token: env_var(&["AGENT_S3_SESSION_TOKEN", "AWS_SESSION_TOKEN"]),
+        })
+    }
+
+    /// URL of an object and its path as signed.
+    fn object_url(&self, key: &str) -> Result<(reqwest::Url, String), String> {
+        let key = uri_encode(key, true);
+        let endpoint = reqwest::Url::parse(&self.endpoint).map_err(|e| format!("Invalid AGENT_S3_ENDPOINT: {}", e))?;
+        let (url, path) = if self.path_style {
+            let path = format!("/{}/{}", uri_encode(&self.bucket, false), key);
+            (format!("{}{}", self.endpoint, path), path)
+        } else {
+            let host = endpoint.host_str().unwrap_or_default();
+            let port = endpoint.port().map(|port| format!(":{}", port)).unwrap_or_default();
+            let path = format!("/{}", key);
+            (format!("{}://{}.{}{}{}", endpoint.scheme(), self.bucket, host, port, path), path)
+        };
+        let url = reqwest::Url::parse(&url).map_err(|e| format!("Invalid object URL {}: {}", url, e))?;
+        Ok((url, path))
+    }
+
+    /// SigV4 headers for an unsigned-payload PUT of `url`.
+    fn sign_put(&self, url: &reqwest::Url, path: &str) -> Vec<(&'static str, String)> {
+        use sha2::{Digest, Sha256};
+
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", "UNSIGNED-PAYLOAD".to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_request = format!("PUT\n{}\n\n{}\n{}\nUNSIGNED-PAYLOAD", path, canonical_headers, signed_headers);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = hmac(format!("AWS4{}", self.secret_access_key).as_bytes(), &date);
+        let key = hmac(&key, &self.region);
+        let key = hmac(&key, "s3");
+        let key = hmac(&key, "aws4_request");
+        let signature = hex(&hmac(&key, &string_to_sign));
+
+        headers.retain(|(name, _)| *name != "host");
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id, scope, signed_headers, signature
+            ),
+        ));
+        headers
+    }
+}
+
+impl Store for S3 {
+    fn put<'a>(&'a self, outbound: &'a Outbound, bandwidth: &'a Bandwidth, key: &'a str, path: &'a Path, length: u64) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let (url, signed_path) = self.object_url(key)?;
+            outbound.check(url.as_str())?;
+            let client = outbound.client()?.build().map_err(|e| e.to_string())?;
+            let mut request = client.put(url.clone());
+            for (name, value) in self.sign_put(&url, &signed_path) {
+                request = request.header(name, value);
+            }
+            offload::send_file(request, bandwidth, path, length).await?;
+            Ok(url.to_string())
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+}
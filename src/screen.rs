@@ -0,0 +1,332 @@
+//! Desktop screenshots and frame-sequence recordings of automation runs.
+//!
+//! Capture shells out to whatever the platform provides (PowerShell/.NET on
+//! Windows, `screencapture` on macOS, `grim`/ImageMagick/`gnome-screenshot`/
+//! `scrot` on Linux), so no native graphics libraries are linked in. Note that
+//! a Windows service in session 0 captures a blank desktop.
+
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::process::Command as TokioCommand;
+use tokio::sync::watch;
+
+use crate::clock;
+use crate::config::AppConfig;
+use crate::{get_exe_dir, log_error};
+
+const MIN_INTERVAL_MS: u64 = 100;
+
+/// A rectangle in screen pixel coordinates.
+#[derive(Deserialize, Clone, Copy)]
+pub struct Region {
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+}
+
+impl Region {
+    /// Whether the given box lies entirely inside this region.
+    pub fn contains(&self, x: i64, y: i64, width: i64, height: i64) -> bool {
+        x >= self.x
+            && y >= self.y
+            && x + width <= self.x + self.width
+            && y + height <= self.y + self.height
+    }
+}
+
+/// Capture the whole (virtual) desktop to a PNG file at `path`.
+pub async fn capture(path: &Path) -> io::Result<()> {
+    let path_str = path.to_string_lossy().to_string();
+    let candidates: Vec<(&str, Vec<String>)> = if cfg!(target_os = "windows") {
+        let script = format!(
+            "Add-Type -AssemblyName System.Windows.Forms,System.Drawing; \
+             $b=[System.Windows.Forms.SystemInformation]::VirtualScreen; \
+             $bmp=New-Object System.Drawing.Bitmap $b.Width,$b.Height; \
+             $g=[System.Drawing.Graphics]::FromImage($bmp); \
+             $g.CopyFromScreen($b.Left,$b.Top,0,0,$bmp.Size); \
+             $bmp.Save('{}',[System.Drawing.Imaging.ImageFormat]::Png)",
+            path_str.replace('\'', "''")
+        );
+        vec![("powershell", vec!["-NoProfile".into(), "-NonInteractive".into(), "-Command".into(), script])]
+    } else if cfg!(target_os = "macos") {
+        vec![("screencapture", vec!["-x".into(), path_str])]
+    } else {
+        vec![
+            ("grim", vec![path_str.clone()]),
+            ("import", vec!["-window".into(), "root".into(), path_str.clone()]),
+            ("gnome-screenshot", vec!["-f".into(), path_str.clone()]),
+            ("scrot", vec!["-o".into(), path_str]),
+        ]
+    };
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No screen capture tool available");
+    for (program, args) in candidates {
+        match TokioCommand::new(program).args(&args).output().await {
+            Ok(output) if output.status.success() && path.exists() => return Ok(()),
+            Ok(output) => {
+                last_error = io::Error::other(format!(
+                    "{} failed: {}",
+                    program,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn recordings_dir() -> PathBuf {
+    get_exe_dir().join("recordings")
+}
+
+struct Recording {
+    directory: PathBuf,
+    interval_ms: u64,
+    started_at: String,
+    stopped_at: Option<String>,
+    frames: Arc<AtomicU32>,
+    last_error: Arc<Mutex<Option<String>>>,
+    stop: Option<watch::Sender<bool>>,
+}
+
+#[derive(Default)]
+pub struct RecordingRegistry {
+    recordings: Mutex<HashMap<String, Recording>>,
+}
+
+#[derive(Deserialize)]
+pub struct StartRecordingRequest {
+    job_id: String,
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+    #[serde(default = "default_max_frames")]
+    max_frames: u32,
+}
+
+fn default_interval_ms() -> u64 {
+    1000
+}
+
+fn default_max_frames() -> u32 {
+    600
+}
+
+#[derive(Serialize)]
+struct RecordingResponse {
+    success: bool,
+    job_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    directory: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stopped_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl RecordingResponse {
+    fn error(job_id: &str, error: &str) -> Self {
+        RecordingResponse {
+            success: false,
+            job_id: job_id.to_string(),
+            status: None,
+            directory: None,
+            interval_ms: None,
+            frame_count: None,
+            frames: None,
+            started_at: None,
+            stopped_at: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    fn from_recording(job_id: &str, recording: &Recording, frames: Option<Vec<String>>) -> Self {
+        // The capture task drops its receiver when it stops on its own (max_frames).
+        let active = recording.stop.as_ref().is_some_and(|stop| !stop.is_closed());
+        let status = if active { "recording" } else { "stopped" };
+        RecordingResponse {
+            success: true,
+            job_id: job_id.to_string(),
+            status: Some(status.to_string()),
+            directory: Some(recording.directory.to_string_lossy().to_string()),
+            interval_ms: Some(recording.interval_ms),
+            frame_count: Some(recording.frames.load(Ordering::Relaxed)),
+            frames,
+            started_at: Some(recording.started_at.clone()),
+            stopped_at: recording.stopped_at.clone(),
+            error: recording.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+fn valid_job_id(job_id: &str) -> bool {
+    !job_id.is_empty()
+        && job_id.len() <= 128
+        && job_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// POST /screen/recordings - start capturing frames for a job.
+pub async fn start_recording(
+    req: web::Json<StartRecordingRequest>,
+    registry: web::Data<RecordingRegistry>,
+) -> ActixResult<HttpResponse> {
+    let job_id = req.job_id.trim();
+    if !valid_job_id(job_id) {
+        let error_msg = "job_id must be 1-128 characters of [A-Za-z0-9_-]";
+        log_error("/screen/recordings", error_msg, None);
+        return Ok(HttpResponse::BadRequest().json(RecordingResponse::error(job_id, error_msg)));
+    }
+
+    let mut recordings = registry.recordings.lock().unwrap();
+    if recordings.get(job_id).is_some_and(|r| r.stop.as_ref().is_some_and(|stop| !stop.is_closed())) {
+        return Ok(HttpResponse::Conflict().json(RecordingResponse::error(
+            job_id,
+            "A recording is already running for this job",
+        )));
+    }
+
+    let directory = recordings_dir().join(job_id);
+    if let Err(e) = std::fs::create_dir_all(&directory) {
+        let error_msg = format!("Failed to create recording directory: {}", e);
+        log_error("/screen/recordings", &error_msg, None);
+        return Ok(HttpResponse::InternalServerError().json(RecordingResponse::error(job_id, &error_msg)));
+    }
+
+    let interval_ms = req.interval_ms.max(MIN_INTERVAL_MS);
+    let max_frames = req.max_frames;
+    let frames = Arc::new(AtomicU32::new(0));
+    let last_error = Arc::new(Mutex::new(None));
+    let (stop_tx, mut stop_rx) = watch::channel(false);
+
+    let task_directory = directory.clone();
+    let task_frames = frames.clone();
+    let task_error = last_error.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_millis(interval_ms));
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = stop_rx.changed() => break,
+            }
+            let index = task_frames.load(Ordering::Relaxed);
+            if index >= max_frames {
+                break;
+            }
+            let frame_path = task_directory.join(format!("frame_{:05}.png", index + 1));
+            match capture(&frame_path).await {
+                Ok(()) => {
+                    task_frames.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => *task_error.lock().unwrap() = Some(e.to_string()),
+            }
+        }
+    });
+
+    let recording = Recording {
+        directory,
+        interval_ms,
+        started_at: clock::now(),
+        stopped_at: None,
+        frames,
+        last_error,
+        stop: Some(stop_tx),
+    };
+    let response = RecordingResponse::from_recording(job_id, &recording, None);
+    recordings.insert(job_id.to_string(), recording);
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// POST /screen/recordings/{job_id}/stop - stop capturing frames.
+pub async fn stop_recording(
+    path: web::Path<String>,
+    registry: web::Data<RecordingRegistry>,
+) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    let mut recordings = registry.recordings.lock().unwrap();
+    let Some(recording) = recordings.get_mut(&job_id) else {
+        return Ok(HttpResponse::NotFound().json(RecordingResponse::error(&job_id, "Recording not found")));
+    };
+
+    if let Some(stop) = recording.stop.take() {
+        let _ = stop.send(true);
+        recording.stopped_at = Some(clock::now());
+    }
+
+    Ok(HttpResponse::Ok().json(RecordingResponse::from_recording(&job_id, recording, None)))
+}
+
+/// GET /screen/recordings/{job_id} - recording status and captured frame files.
+pub async fn get_recording(
+    path: web::Path<String>,
+    registry: web::Data<RecordingRegistry>,
+) -> ActixResult<HttpResponse> {
+    let job_id = path.into_inner();
+    let recordings = registry.recordings.lock().unwrap();
+    let Some(recording) = recordings.get(&job_id) else {
+        return Ok(HttpResponse::NotFound().json(RecordingResponse::error(&job_id, "Recording not found")));
+    };
+
+    let mut frames: Vec<String> = std::fs::read_dir(&recording.directory)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .filter(|name| name.starts_with("frame_"))
+                .collect()
+        })
+        .unwrap_or_default();
+    frames.sort();
+
+    Ok(HttpResponse::Ok().json(RecordingResponse::from_recording(&job_id, recording, Some(frames))))
+}
+
+/// GET /screen/recordings/{job_id}/frames/{frame} - download a single frame.
+pub async fn get_frame(
+    path: web::Path<(String, String)>,
+    registry: web::Data<RecordingRegistry>,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let (job_id, frame) = path.into_inner();
+    let directory = match registry.recordings.lock().unwrap().get(&job_id) {
+        Some(recording) => recording.directory.clone(),
+        None => {
+            return Ok(HttpResponse::NotFound().json(RecordingResponse::error(&job_id, "Recording not found")));
+        }
+    };
+
+    let valid_name = frame.starts_with("frame_")
+        && frame.ends_with(".png")
+        && !frame.contains(['/', '\\'])
+        && !frame.contains("..");
+    if !valid_name {
+        return Ok(HttpResponse::BadRequest().json(RecordingResponse::error(&job_id, "Invalid frame name")));
+    }
+
+    match tokio::fs::read(directory.join(&frame)).await {
+        Ok(bytes) => {
+            let mut response = HttpResponse::Ok();
+            response.content_type("image/png");
+            Ok(config.bandwidth.respond(response, bytes))
+        }
+        Err(_) => Ok(HttpResponse::NotFound().json(RecordingResponse::error(&job_id, "Frame not found"))),
+    }
+}
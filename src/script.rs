@@ -0,0 +1,133 @@
+//! `POST /execute-script` - `/execute` for a multi-line script, which is
+//! written to a temporary file and run by its `interpreter` instead of being
+//! escaped into a single `command` string.
+//!
+//! The file is created fresh (never through an existing path) in the system
+//! temporary directory, readable only by the user the script runs as, and
+//! removed once the script is done. Policy rules, logs and the job record see
+//! the script as the job's `command`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use tokio::process::Command as TokioCommand;
+
+use crate::api_keys::RunAs;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpreter {
+    Bash,
+    /// Windows PowerShell, or PowerShell 7 (`pwsh`) elsewhere.
+    Powershell,
+    Python,
+    Node,
+}
+
+impl Interpreter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Interpreter::Bash => "bash",
+            Interpreter::Powershell => "powershell",
+            Interpreter::Python => "python",
+            Interpreter::Node => "node",
+        }
+    }
+
+    /// The program running scripts on this platform.
+    pub fn program(self) -> &'static str {
+        self.invocation().0
+    }
+
+    /// The program and the arguments before the script's path.
+    fn invocation(self) -> (&'static str, &'static [&'static str]) {
+        // Profiles, prompts and the execution policy would all get in the
+        // way of an unattended script.
+        const POWERSHELL: &[&str] = &["-NoProfile", "-NonInteractive", "-ExecutionPolicy", "Bypass", "-File"];
+        match self {
+            Interpreter::Bash => ("bash", &[]),
+            Interpreter::Powershell if cfg!(target_os = "windows") => ("powershell", POWERSHELL),
+            Interpreter::Powershell => ("pwsh", POWERSHELL),
+            Interpreter::Python if cfg!(target_os = "windows") => ("python", &[]),
+            Interpreter::Python => ("python3", &[]),
+            Interpreter::Node => ("node", &[]),
+        }
+    }
+
+    /// PowerShell only runs files ending in `.ps1`.
+    fn extension(self) -> &'static str {
+        match self {
+            Interpreter::Bash => "sh",
+            Interpreter::Powershell => "ps1",
+            Interpreter::Python => "py",
+            Interpreter::Node => "js",
+        }
+    }
+}
+
+/// Turn an `/execute-script` body into an `/execute` one: its `script`
+/// becomes the `command`.
+pub fn into_command(body: &mut Value) -> Result<(), String> {
+    let Some(fields) = body.as_object_mut() else {
+        return Err("Invalid request: expected a JSON object".to_string());
+    };
+    for field in ["command", "command_base64", "shell"] {
+        if fields.contains_key(field) {
+            return Err(format!("{} is not supported by /execute-script; send the script as script", field));
+        }
+    }
+    if !fields.contains_key("interpreter") {
+        return Err("interpreter is required: bash, powershell, python or node".to_string());
+    }
+    match fields.remove("script") {
+        Some(Value::String(script)) => {
+            fields.insert("command".to_string(), Value::String(script));
+            Ok(())
+        }
+        Some(_) => Err("script must be a string".to_string()),
+        None => Err("script is required".to_string()),
+    }
+}
+
+/// A script written out for its interpreter; the file is removed when this
+/// is dropped.
+pub struct ScriptFile {
+    path: PathBuf,
+    interpreter: Interpreter,
+}
+
+impl ScriptFile {
+    /// Write `script` to a new file only `run_as` (or the agent's user) can
+    /// read.
+    pub fn write(interpreter: Interpreter, script: &OsStr, run_as: Option<&RunAs>) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("machine_agent_script_{}.{}", uuid::Uuid::new_v4(), interpreter.extension()));
+        let mut options = std::fs::OpenOptions::new();
+        // Fails rather than follow anything already at the path.
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path)?;
+        let script_file = ScriptFile { path, interpreter };
+        file.write_all(script.as_encoded_bytes())?;
+        if let Some(run_as) = run_as {
+            run_as.chown(&script_file.path)?;
+        }
+        Ok(script_file)
+    }
+
+    /// A process running the script.
+    pub fn command(&self) -> TokioCommand {
+        let (program, args) = self.interpreter.invocation();
+        let mut cmd = TokioCommand::new(program);
+        cmd.args(args).arg(&self.path);
+        cmd
+    }
+}
+
+impl Drop for ScriptFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
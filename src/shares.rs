@@ -0,0 +1,119 @@
+//! Windows file shares for the file APIs (`/sync/*` and `/fetch`), so
+//! deployment targets on file servers can be written with the share's own
+//! credentials.
+//!
+//! `dest` may be a UNC path (`\\fs01\deploy\app`) or a `\\?\` long path; the
+//! standard library already switches paths longer than `MAX_PATH` to the
+//! `\\?\` form. `AGENT_SHARE_CREDENTIALS` names a JSON file of credentials per
+//! share:
+//!
+//! ```json
+//! {"\\\\fs01\\deploy": {"username": "CORP\\svc-deploy", "password": "..."}}
+//! ```
+//!
+//! Before a path on a listed share is used, the agent connects to the share
+//! with them, like `net use \\fs01\deploy` without a drive letter; the
+//! connection lasts until the agent exits. Shares that aren't listed are
+//! reached as the agent's own account. UNC paths only exist on Windows.
+
+use actix_web::web;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, Prefix};
+use std::sync::{Arc, Mutex};
+
+#[derive(Deserialize, Clone)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Clone, Default)]
+pub struct Shares {
+    /// By `\\server\share`, lowercase.
+    credentials: HashMap<String, Credentials>,
+    connected: Arc<Mutex<HashSet<String>>>,
+}
+
+fn normalize(share: &str) -> String {
+    share.replace('/', "\\").trim_end_matches('\\').to_lowercase()
+}
+
+pub fn load(path: &Path) -> Result<Shares, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let credentials: HashMap<String, Credentials> =
+        serde_json::from_slice(&contents).map_err(|e| format!("Invalid share credentials file {}: {}", path.display(), e))?;
+    Ok(Shares {
+        credentials: credentials.into_iter().map(|(share, credentials)| (normalize(&share), credentials)).collect(),
+        connected: Arc::default(),
+    })
+}
+
+/// The `\\server\share` a UNC path is on, lowercase.
+fn share_of(path: &Path) -> Option<String> {
+    let Some(Component::Prefix(prefix)) = path.components().next() else {
+        return None;
+    };
+    match prefix.kind() {
+        Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+            Some(normalize(&format!(r"\\{}\{}", server.to_string_lossy(), share.to_string_lossy())))
+        }
+        _ => None,
+    }
+}
+
+impl Shares {
+    /// Connect to the share `path` is on, if it has credentials.
+    pub async fn connect(&self, path: &Path) -> Result<(), String> {
+        let Some(share) = share_of(path) else {
+            return Ok(());
+        };
+        let Some(credentials) = self.credentials.get(&share).cloned() else {
+            return Ok(());
+        };
+        if self.connected.lock().unwrap().contains(&share) {
+            return Ok(());
+        }
+        let result = {
+            let share = share.clone();
+            web::block(move || add_connection(&share, &credentials))
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)))
+        };
+        match result {
+            Ok(()) => {
+                self.connected.lock().unwrap().insert(share);
+                Ok(())
+            }
+            Err(e) => Err(format!("Failed to connect to {}: {}", share, e)),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn add_connection(share: &str, credentials: &Credentials) -> io::Result<()> {
+    use windows_sys::Win32::NetworkManagement::WNet::{WNetAddConnection2W, CONNECT_TEMPORARY, NETRESOURCEW, RESOURCETYPE_DISK};
+
+    let wide = |value: &str| value.encode_utf16().chain(std::iter::once(0)).collect::<Vec<u16>>();
+    let mut remote = wide(share);
+    let username = wide(&credentials.username);
+    let password = wide(&credentials.password);
+    // SAFETY: NETRESOURCEW is plain data; unused fields stay null.
+    let mut resource: NETRESOURCEW = unsafe { std::mem::zeroed() };
+    resource.dwType = RESOURCETYPE_DISK;
+    resource.lpRemoteName = remote.as_mut_ptr();
+    // SAFETY: the strings are NUL-terminated and outlive the call.
+    let result = unsafe { WNetAddConnection2W(&resource, password.as_ptr(), username.as_ptr(), CONNECT_TEMPORARY) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(result as i32))
+    }
+}
+
+#[cfg(not(windows))]
+fn add_connection(_share: &str, _credentials: &Credentials) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "File shares are only supported on Windows"))
+}
@@ -0,0 +1,470 @@
+//! `GET /shell` - an interactive shell in a pseudo-terminal over a
+//! WebSocket, for work that doesn't fit one-off commands.
+//!
+//! The shell runs in a PTY (ConPTY on Windows) as a job like any other,
+//! listed under `/jobs`, with events, and cancellable with `DELETE
+//! /jobs/{id}`. Binary frames from the client are keystrokes; text frames
+//! are JSON control messages:
+//!
+//! ```json
+//! {"type": "input", "data": "ls -l\r"}
+//! {"type": "resize", "cols": 120, "rows": 40}
+//! ```
+//!
+//! Terminal output comes back as binary frames, after a `started` text frame
+//! with the job ID and before an `exit` one with the return code or why the
+//! session ended. A session is closed after `AGENT_SHELL_IDLE_TIMEOUT_SECS`
+//! without input and after `AGENT_SHELL_MAX_SECS` in all, and recorded as
+//! `session.cast` when the request or `AGENT_SHELL_RECORD` asks for it.
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result as ActixResult};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, Session};
+use portable_pty::{ChildKiller, CommandBuilder, MasterPty, PtySize};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use crate::asciicast::{self, Recorder};
+use crate::config::{env_parse, AppConfig};
+use crate::events::{self, EventBus};
+use crate::jobs::{Job, JobRegistry};
+use crate::output::Stream;
+use crate::{artifacts, auth, cloud, log_error, policy, timekeeping, tls};
+
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 900;
+const DEFAULT_COLS: u16 = 80;
+const DEFAULT_ROWS: u16 = 24;
+/// Keeps proxies from closing a quiet connection.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Output still read after the shell exits, from processes it left behind.
+const DRAIN: Duration = Duration::from_millis(200);
+const READ_BUFFER: usize = 8 * 1024;
+
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub idle_timeout: Option<Duration>,
+    pub max_duration: Option<Duration>,
+    /// Record every session, whatever the request says.
+    pub record: bool,
+}
+
+pub fn from_env() -> Settings {
+    let secs = |name| env_parse::<u64>(name).filter(|&secs| secs > 0).map(Duration::from_secs);
+    Settings {
+        idle_timeout: match env_parse::<u64>("AGENT_SHELL_IDLE_TIMEOUT_SECS") {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS)),
+        },
+        max_duration: secs("AGENT_SHELL_MAX_SECS"),
+        record: matches!(std::env::var("AGENT_SHELL_RECORD").as_deref(), Ok("1" | "true" | "yes")),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ShellQuery {
+    /// Program to run; the user's shell (`cmd.exe` on Windows) when absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shell: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cols: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rows: Option<u16>,
+    #[serde(default)]
+    record: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+}
+
+/// Text frames from the client.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Control {
+    Input { data: String },
+    Resize { cols: u16, rows: u16 },
+}
+
+/// Text frames to the client.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Notice<'a> {
+    Started {
+        job_id: &'a str,
+        shell: &'a str,
+    },
+    Exit {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        return_code: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<&'a str>,
+    },
+    Error {
+        error: &'a str,
+    },
+}
+
+impl Notice<'_> {
+    async fn send(&self, session: &mut Session) -> Result<(), actix_ws::Closed> {
+        session.text(serde_json::to_string(self).unwrap_or_default()).await
+    }
+}
+
+#[derive(Serialize)]
+struct ShellResponse {
+    success: bool,
+    error: String,
+}
+
+fn failure(status: StatusCode, error_msg: String) -> HttpResponse {
+    HttpResponse::build(status).json(ShellResponse {
+        success: false,
+        error: error_msg,
+    })
+}
+
+fn default_shell() -> String {
+    if cfg!(windows) {
+        return std::env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string());
+    }
+    std::env::var("SHELL").ok().filter(|shell| !shell.is_empty()).unwrap_or_else(|| "/bin/sh".to_string())
+}
+
+/// A shell running in a pseudo-terminal, and the threads moving its bytes.
+struct Pty {
+    master: Box<dyn MasterPty + Send>,
+    killer: Box<dyn ChildKiller + Send + Sync>,
+    pid: Option<u32>,
+    input: mpsc::Sender<Vec<u8>>,
+    output: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    exit: oneshot::Receiver<Option<i32>>,
+}
+
+fn spawn(shell: &str, cwd: PathBuf, size: PtySize, env: Vec<(&'static str, String)>) -> Result<Pty, String> {
+    let pair = portable_pty::native_pty_system()
+        .openpty(size)
+        .map_err(|e| format!("Failed to open a pseudo-terminal: {}", e))?;
+    let mut cmd = CommandBuilder::new(shell);
+    cmd.cwd(cwd);
+    if cfg!(unix) && std::env::var_os("TERM").is_none() {
+        cmd.env("TERM", "xterm-256color");
+    }
+    for (name, value) in env {
+        cmd.env(name, value);
+    }
+    let mut child = pair
+        .slave
+        .spawn_command(cmd)
+        .map_err(|e| format!("Failed to start {}: {}", shell, e))?;
+    // The shell holds the terminal's other end now; without this the output
+    // never ends.
+    drop(pair.slave);
+    let mut reader = pair.master.try_clone_reader().map_err(|e| e.to_string())?;
+    let mut writer = pair.master.take_writer().map_err(|e| e.to_string())?;
+
+    let (output_tx, output) = tokio::sync::mpsc::channel(64);
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; READ_BUFFER];
+        // Fails with EIO on Linux once the shell and its children are gone.
+        while let Ok(read @ 1..) = reader.read(&mut buffer) {
+            if output_tx.blocking_send(buffer[..read].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    let (input, input_rx) = mpsc::channel::<Vec<u8>>();
+    std::thread::spawn(move || {
+        for bytes in input_rx {
+            if writer.write_all(&bytes).and_then(|()| writer.flush()).is_err() {
+                break;
+            }
+        }
+    });
+    let killer = child.clone_killer();
+    let pid = child.process_id();
+    let (exit_tx, exit) = oneshot::channel();
+    std::thread::spawn(move || {
+        let code = child.wait().ok().map(|status| status.exit_code() as i32);
+        let _ = exit_tx.send(code);
+    });
+    Ok(Pty {
+        master: pair.master,
+        killer,
+        pid,
+        input,
+        output,
+        exit,
+    })
+}
+
+/// Text of the next chunk of output, holding back a character split across
+/// chunks.
+fn decode(pending: &mut Vec<u8>, bytes: &[u8]) -> String {
+    pending.extend_from_slice(bytes);
+    let incomplete = match std::str::from_utf8(pending) {
+        Err(e) if e.error_len().is_none() => pending.len() - e.valid_up_to(),
+        _ => 0,
+    };
+    let rest = pending.split_off(pending.len() - incomplete);
+    let text = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    text
+}
+
+/// Why a session ended.
+enum End {
+    /// The shell exited, with this code when it has one.
+    Exited(Option<i32>),
+    Disconnected,
+    Idle(Duration),
+    TooLong(Duration),
+    Cancelled,
+}
+
+impl End {
+    fn error(&self) -> Option<String> {
+        match self {
+            End::Exited(_) => None,
+            End::Disconnected => Some("Client disconnected".to_string()),
+            End::Idle(timeout) => Some(format!("No input for {}s", timeout.as_secs())),
+            End::TooLong(limit) => Some(format!("Session ran longer than {}s", limit.as_secs())),
+            End::Cancelled => Some(crate::CANCELLED.to_string()),
+        }
+    }
+}
+
+struct Context {
+    job_id: String,
+    shell: String,
+    jobs: web::Data<JobRegistry>,
+    bus: web::Data<EventBus>,
+    config: web::Data<AppConfig>,
+}
+
+/// Relay between the client and the shell until either ends.
+async fn relay(
+    mut session: Session,
+    mut messages: AggregatedMessageStream,
+    mut pty: Pty,
+    mut cast: Option<Recorder>,
+    context: Context,
+) {
+    let Context { job_id, shell, jobs, bus, config } = context;
+    let settings = &config.shell;
+    let cancel = jobs.attach_cancel(&job_id);
+    let started = Instant::now();
+    let far = started + Duration::from_secs(60 * 60 * 24 * 365);
+    let mut last_input = started;
+    let mut exited = None;
+    let mut drain_until = far;
+    let mut pending = Vec::new();
+    let mut ping = tokio::time::interval_at(started + PING_INTERVAL, PING_INTERVAL);
+
+    let _ = Notice::Started { job_id: &job_id, shell: &shell }.send(&mut session).await;
+    let end = loop {
+        let idle_deadline = settings.idle_timeout.map_or(far, |timeout| last_input + timeout);
+        let max_deadline = settings.max_duration.map_or(far, |limit| started + limit);
+        tokio::select! {
+            message = messages.recv() => match message {
+                Some(Ok(AggregatedMessage::Binary(bytes))) => {
+                    last_input = Instant::now();
+                    let _ = pty.input.send(bytes.to_vec());
+                }
+                Some(Ok(AggregatedMessage::Text(text))) => match serde_json::from_str::<Control>(&text) {
+                    Ok(Control::Input { data }) => {
+                        last_input = Instant::now();
+                        let _ = pty.input.send(data.into_bytes());
+                    }
+                    Ok(Control::Resize { cols, rows }) => {
+                        let size = PtySize { rows, cols, ..Default::default() };
+                        if let Err(e) = pty.master.resize(size) {
+                            let error_msg = format!("Failed to resize the terminal: {}", e);
+                            let _ = Notice::Error { error: &error_msg }.send(&mut session).await;
+                        } else if let Some(recorder) = &mut cast {
+                            let _ = recorder.resize(cols, rows).await;
+                        }
+                    }
+                    Err(e) => {
+                        let error_msg = format!("Invalid control message: {}", e);
+                        let _ = Notice::Error { error: &error_msg }.send(&mut session).await;
+                    }
+                },
+                Some(Ok(AggregatedMessage::Ping(bytes))) => {
+                    let _ = session.pong(&bytes).await;
+                }
+                Some(Ok(AggregatedMessage::Pong(_))) => {}
+                Some(Ok(AggregatedMessage::Close(_)) | Err(_)) | None => break End::Disconnected,
+            },
+            output = pty.output.recv() => match output {
+                Some(bytes) => {
+                    let text = decode(&mut pending, &bytes);
+                    jobs.append_output(&job_id, Stream::Stdout, &text);
+                    if let Some(recorder) = &mut cast {
+                        if let Err(e) = recorder.output(&text).await {
+                            log_error("/shell", &format!("Failed to write asciicast: {}", e), Some(&shell));
+                            cast = None;
+                        }
+                    }
+                    if session.binary(bytes).await.is_err() {
+                        break End::Disconnected;
+                    }
+                }
+                // The exit status can come just after the last output.
+                None => match exited {
+                    Some(code) => break End::Exited(code),
+                    None => break End::Exited(tokio::time::timeout(DRAIN, &mut pty.exit).await.ok().and_then(|code| code.ok().flatten())),
+                },
+            },
+            code = &mut pty.exit, if exited.is_none() => {
+                exited = Some(code.ok().flatten());
+                drain_until = Instant::now() + DRAIN;
+            }
+            () = tokio::time::sleep_until(drain_until) => break End::Exited(exited.flatten()),
+            () = tokio::time::sleep_until(idle_deadline) => break End::Idle(settings.idle_timeout.unwrap_or_default()),
+            () = tokio::time::sleep_until(max_deadline) => break End::TooLong(settings.max_duration.unwrap_or_default()),
+            () = cancel.notified() => break End::Cancelled,
+            _ = ping.tick() => {
+                let _ = session.ping(b"").await;
+            }
+        }
+    };
+
+    let return_code = match end {
+        End::Exited(code) => code,
+        _ => {
+            let _ = pty.killer.kill();
+            None
+        }
+    };
+    // Hangs up on anything the shell left running.
+    drop(pty);
+    if let Some(mut recorder) = cast {
+        if let Err(e) = recorder.flush().await {
+            log_error("/shell", &format!("Failed to write asciicast: {}", e), Some(&shell));
+        }
+    }
+    let error = end.error();
+    let _ = Notice::Exit { return_code, error: error.as_deref() }.send(&mut session).await;
+    let _ = session.close(None).await;
+    crate::compress_job_output(&job_id, &jobs, &config).await;
+    match error {
+        None => {
+            jobs.finish(&job_id, return_code);
+            bus.publish(events::JOB_FINISHED, Some(&job_id), serde_json::json!({
+                "command": shell,
+                "return_code": return_code,
+                "shell": true,
+            }));
+        }
+        Some(error) => {
+            jobs.fail(&job_id, &error);
+            bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+                "command": shell,
+                "error": error,
+                "shell": true,
+            }));
+        }
+    }
+}
+
+/// GET /shell - open an interactive shell over a WebSocket.
+pub async fn open_shell(
+    http_req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<ShellQuery>,
+    config: web::Data<AppConfig>,
+    jobs: web::Data<JobRegistry>,
+    bus: web::Data<EventBus>,
+) -> ActixResult<HttpResponse> {
+    let query = query.into_inner();
+    let caller = match auth::authenticate(&config, &http_req).await {
+        Ok(caller) => caller,
+        Err(error_msg) => return Ok(failure(StatusCode::UNAUTHORIZED, error_msg)),
+    };
+    // What is typed into a shell can't be checked against command rules or
+    // a key's restrictions.
+    if !config.command_rules.is_empty() {
+        let error_msg = "Shell sessions are not available while AGENT_COMMAND_RULES_FILE is set".to_string();
+        return Ok(failure(StatusCode::FORBIDDEN, error_msg));
+    }
+    if let Some(caller) = &caller {
+        if caller.key.run_as.is_some() || !caller.key.banned_shells.is_empty() {
+            let error_msg = format!("Shell sessions are not allowed for {:?} (run_as, banned_shells)", caller.name);
+            return Ok(failure(StatusCode::FORBIDDEN, error_msg));
+        }
+    }
+    let key_name = caller.as_ref().map(|caller| caller.name.as_str());
+    if let Some(opa) = &config.opa {
+        let request = serde_json::to_value(&query).unwrap_or_default();
+        if let Err((status, error_msg)) = policy::consult_opa(opa, "/shell", &http_req, key_name, &request).await {
+            return Ok(failure(status, error_msg));
+        }
+    }
+
+    let (response, mut session, messages) = actix_ws::handle(&http_req, body)?;
+    let shell = query.shell.clone().filter(|shell| !shell.trim().is_empty()).unwrap_or_else(default_shell);
+    let cwd = query
+        .cwd
+        .clone()
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" }).map(PathBuf::from))
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_else(|| PathBuf::from("."));
+    let (cols, rows) = (query.cols.unwrap_or(DEFAULT_COLS), query.rows.unwrap_or(DEFAULT_ROWS));
+    let size = PtySize { rows, cols, ..Default::default() };
+    let job_id = uuid::Uuid::new_v4().to_string();
+    let mut env = vec![("AGENT_JOB_ID", job_id.clone())];
+    env.extend(cloud::env());
+    env.extend(timekeeping::env());
+
+    let cast = if query.record || config.shell.record {
+        let recorder = match artifacts::job_dir(&job_id) {
+            Ok(dir) => Recorder::create_sized(&dir.join(asciicast::FILE_NAME), &shell, cols, rows).await,
+            Err(e) => Err(e),
+        };
+        Some(recorder.map_err(|e| format!("Failed to create asciicast: {}", e)))
+    } else {
+        None
+    };
+    let started = match cast.transpose() {
+        Ok(cast) => spawn(&shell, cwd, size, env).map(|pty| (pty, cast)),
+        // An unrecorded session when one was asked for is refused.
+        Err(error_msg) => Err(error_msg),
+    };
+    let (pty, cast) = match started {
+        Ok(started) => started,
+        Err(error_msg) => {
+            log_error("/shell", &error_msg, Some(&shell));
+            actix_web::rt::spawn(async move {
+                let _ = Notice::Exit { return_code: None, error: Some(&error_msg) }.send(&mut session).await;
+                let _ = session.close(None).await;
+            });
+            return Ok(response);
+        }
+    };
+
+    let mut job = Job::new(&job_id, &shell, pty.pid);
+    job.tag = query.tag.clone();
+    job.api_key = key_name.map(str::to_string);
+    job.client_certificate = tls::client_subject(&http_req);
+    jobs.insert(job);
+    bus.publish(events::JOB_STARTED, Some(&job_id), serde_json::json!({
+        "command": shell,
+        "shell": true,
+    }));
+
+    let context = Context {
+        job_id,
+        shell,
+        jobs,
+        bus,
+        config,
+    };
+    actix_web::rt::spawn(relay(session, messages.aggregate_continuations(), pty, cast, context));
+    Ok(response)
+}
@@ -0,0 +1,99 @@
+//! The interpreters a request's `shell` field can pick instead of the
+//! platform's own (`cmd /C` on Windows, `sh -c` elsewhere).
+//!
+//! `AGENT_ALLOWED_SHELLS` narrows them down, e.g. `powershell,pwsh` on a
+//! Windows host; a request that leaves `shell` out always gets the platform's
+//! shell.
+
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::fmt;
+use tokio::process::Command as TokioCommand;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Sh,
+    Bash,
+    Zsh,
+    Cmd,
+    /// Windows PowerShell.
+    Powershell,
+    /// PowerShell 7 and later.
+    Pwsh,
+}
+
+impl Shell {
+    pub const ALL: [Shell; 6] = [Shell::Sh, Shell::Bash, Shell::Zsh, Shell::Cmd, Shell::Powershell, Shell::Pwsh];
+
+    /// The shell commands run in when a request doesn't pick one.
+    pub fn platform() -> Self {
+        if cfg!(target_os = "windows") {
+            Shell::Cmd
+        } else {
+            Shell::Sh
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Shell::Sh => "sh",
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Cmd => "cmd",
+            Shell::Powershell => "powershell",
+            Shell::Pwsh => "pwsh",
+        }
+    }
+
+    /// The program and the arguments before the command.
+    pub fn invocation(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            Shell::Sh | Shell::Bash | Shell::Zsh => (self.name(), &["-c"]),
+            Shell::Cmd => ("cmd", &["/C"]),
+            // No profile scripts, and no prompts a job could hang on.
+            Shell::Powershell | Shell::Pwsh => (self.name(), &["-NoProfile", "-NonInteractive", "-Command"]),
+        }
+    }
+
+    /// A process running `command` in this shell.
+    pub fn command(self, command: &OsStr) -> TokioCommand {
+        let (program, args) = self.invocation();
+        let mut cmd = TokioCommand::new(program);
+        cmd.args(args).arg(command);
+        cmd
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// `AGENT_ALLOWED_SHELLS`: every shell when unset.
+pub fn from_env() -> Result<Vec<Shell>, String> {
+    let Ok(names) = std::env::var("AGENT_ALLOWED_SHELLS") else {
+        return Ok(Shell::ALL.to_vec());
+    };
+    let mut shells = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        let shell = Shell::ALL.into_iter().find(|shell| shell.name().eq_ignore_ascii_case(name)).ok_or_else(|| {
+            let known: Vec<&str> = Shell::ALL.iter().map(|shell| shell.name()).collect();
+            format!("Unknown shell {:?} in AGENT_ALLOWED_SHELLS; expected one of {}", name, known.join(", "))
+        })?;
+        shells.push(shell);
+    }
+    Ok(shells)
+}
+
+/// Whether a request may pick `shell`.
+pub fn check(shell: Shell, allowed: &[Shell]) -> Result<(), String> {
+    if shell == Shell::Cmd && !cfg!(target_os = "windows") {
+        return Err("shell cmd is only available on Windows".to_string());
+    }
+    if !allowed.contains(&shell) {
+        return Err(format!("shell {} is not allowed on this agent (AGENT_ALLOWED_SHELLS)", shell));
+    }
+    Ok(())
+}
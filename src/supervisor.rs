@@ -0,0 +1,56 @@
+//! Keeping panics visible: a panic anywhere is written to the error log, and
+//! a panic in a task that owns a job marks the job interrupted instead of
+//! leaving it `running` forever.
+//!
+//! Jobs a previous run of the agent left unfinished are reconciled at startup
+//! by [`JobRegistry::reconcile_history`].
+
+use actix_web::web;
+use std::future::Future;
+
+use crate::events::{self, EventBus};
+use crate::jobs::{JobRegistry, JobStatus};
+use crate::{log_error, log_error_with_traceback};
+
+/// Log every panic with a backtrace, then report it as before.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = std::thread::current();
+        let error_msg = format!("Panic in thread {}: {}", thread.name().unwrap_or("<unnamed>"), info);
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        log_error_with_traceback("panic", &error_msg, &backtrace.to_string(), None);
+        default_hook(info);
+    }));
+}
+
+/// Spawn a task that drives a job to completion; if it panics, the job is
+/// marked interrupted and a failure event is published.
+pub fn spawn_job_task<F>(job_id: String, jobs: web::Data<JobRegistry>, bus: EventBus, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handle = tokio::spawn(task);
+    tokio::spawn(async move {
+        let Err(e) = handle.await else {
+            return;
+        };
+        if !e.is_panic() {
+            return;
+        }
+        let Some(job) = jobs.get(&job_id) else {
+            return;
+        };
+        if !matches!(job.status, JobStatus::Running | JobStatus::Queued) {
+            return;
+        }
+        let error_msg = "The agent task running the job panicked (see app_error.log)";
+        log_error("supervisor", error_msg, Some(&job.command));
+        jobs.interrupt(&job_id, error_msg);
+        bus.publish(events::JOB_FAILED, Some(&job_id), serde_json::json!({
+            "command": job.command,
+            "error": error_msg,
+            "interrupted": true,
+        }));
+    });
+}
@@ -0,0 +1,802 @@
+//! rsync-like delta sync of directory trees, so deploying a mostly unchanged
+//! application only transfers what changed.
+//!
+//! The controller sends a manifest of the tree (path, size, SHA-256 and
+//! optionally mode, modification time and owner of each file, or the target
+//! of each symlink) to `POST /sync/plan`. The agent compares it
+//! with `dest` and answers with the files to transfer, each with the hashes
+//! of the fixed-size blocks it already has. The controller then sends each of
+//! those files with `PUT /sync/files`, with only the blocks whose hash
+//! differs in the body; the agent rebuilds the file from them and its own
+//! copy, checks the whole file's hash and moves it into place. Symlinks are
+//! created with `POST /sync/links`, and files the manifest doesn't list are
+//! removed with `POST /sync/delete`.
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result as ActixResult};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::api_keys::RunAs;
+use crate::config::AppConfig;
+use crate::{auth, content, log_error, policy};
+
+/// Size of the blocks files are compared and sent in.
+pub const BLOCK_SIZE: u64 = 128 * 1024;
+
+/// `dest` must be absolute; on Windows that includes UNC (`\\server\share\dir`)
+/// and `\\?\` paths.
+pub fn dest_dir(dest: &str) -> Result<PathBuf, String> {
+    let dest = PathBuf::from(dest);
+    if !dest.is_absolute() {
+        return Err(format!("dest must be an absolute path, not {}", dest.display()));
+    }
+    Ok(dest)
+}
+
+/// A manifest path, relative and without `..`, below `dest`.
+fn target(dest: &Path, path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+    let valid = !path.is_empty() && relative.components().all(|component| matches!(component, Component::Normal(_)));
+    if !valid {
+        return Err(format!("Invalid path {:?}: expected a relative path without ..", path));
+    }
+    // Joined a component at a time: `/` doesn't separate anything in a
+    // `\\?\` path.
+    Ok(relative.components().fold(dest.to_path_buf(), |target, component| target.join(component)))
+}
+
+/// Octal permission bits, e.g. `755`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("Invalid mode {:?}: expected octal permissions such as 755", mode))
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn owner_of(metadata: &std::fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner_of(_metadata: &std::fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+fn mtime_of(metadata: &std::fs::Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    Some(match modified.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs_f64().ceil() as i64),
+    })
+}
+
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+        Ok(())
+    }
+}
+
+/// What a synced file should have besides its content. Mode and owner are
+/// Unix only and left alone elsewhere.
+#[derive(Default)]
+struct Attributes {
+    mode: Option<u32>,
+    /// Unix seconds.
+    mtime: Option<i64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl Attributes {
+    fn parse(mode: Option<&str>, mtime: Option<i64>, uid: Option<u32>, gid: Option<u32>) -> Result<Self, String> {
+        Ok(Attributes {
+            mode: mode.map(parse_mode).transpose()?,
+            mtime,
+            uid,
+            gid,
+        })
+    }
+
+    fn sets_owner(&self) -> bool {
+        self.uid.is_some() || self.gid.is_some()
+    }
+
+    /// Whether the file with `metadata` lacks any of them.
+    fn differ(&self, metadata: &std::fs::Metadata) -> bool {
+        let mode_differs = self.mode.is_some() && mode_of(metadata).is_some_and(|current| Some(current) != self.mode);
+        let mtime_differs = self.mtime.is_some() && mtime_of(metadata) != self.mtime;
+        let owner_differs = owner_of(metadata).is_some_and(|(uid, gid)| {
+            self.uid.is_some_and(|wanted| wanted != uid) || self.gid.is_some_and(|wanted| wanted != gid)
+        });
+        mode_differs || mtime_differs || owner_differs
+    }
+
+    /// Owner first: changing it clears the setuid and setgid bits.
+    fn apply(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        if self.sets_owner() {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+        }
+        if let Some(mode) = self.mode {
+            set_mode(path, mode)?;
+        }
+        if let Some(mtime) = self.mtime {
+            let time = if mtime >= 0 {
+                UNIX_EPOCH + Duration::from_secs(mtime as u64)
+            } else {
+                UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs())
+            };
+            set_modified(path, time)?;
+        }
+        Ok(())
+    }
+}
+
+fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    std::fs::File::options().write(true).open(path)?.set_modified(time)
+}
+
+/// A symlink target for the link at manifest path `path`: relative, and
+/// staying below `dest` from the link's directory, so nothing written
+/// through the link can land outside `dest`. That holds as long as the
+/// link's directory isn't reached through another link; see `below_link`.
+fn link_target(path: &str, target: &str) -> Result<PathBuf, String> {
+    let invalid = || format!("Invalid symlink target {:?} for {}: expected a relative path that stays below dest", target, path);
+    let mut depth = Path::new(path).components().count().saturating_sub(1);
+    let mut relative = PathBuf::new();
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => depth = depth.checked_sub(1).ok_or_else(invalid)?,
+            Component::CurDir => continue,
+            Component::RootDir | Component::Prefix(_) => return Err(invalid()),
+        }
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        return Err(invalid());
+    }
+    Ok(relative)
+}
+
+/// Whether a directory between `dest` and `path` is a symlink.
+fn below_link(dest: &Path, path: &Path) -> bool {
+    path.ancestors()
+        .skip(1)
+        .take_while(|dir| *dir != dest && dir.starts_with(dest))
+        .any(|dir| std::fs::symlink_metadata(dir).is_ok_and(|metadata| metadata.file_type().is_symlink()))
+}
+
+/// The SHA-256 of the file at `path` and of each of its blocks.
+fn file_hashes(path: &Path) -> io::Result<(String, Vec<String>)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut whole = Sha256::new();
+    let mut blocks = Vec::new();
+    let mut block = vec![0; BLOCK_SIZE as usize];
+    loop {
+        let mut filled = 0;
+        while filled < block.len() {
+            match file.read(&mut block[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        whole.update(&block[..filled]);
+        blocks.push(content::hex(&Sha256::digest(&block[..filled])));
+        if filled < block.len() {
+            break;
+        }
+    }
+    Ok((content::hex(&whole.finalize()), blocks))
+}
+
+/// Files and symlinks below `dir`, as `/`-separated paths relative to
+/// `base`; symlinks are not followed.
+fn walk(dir: &Path, base: &Path, files: &mut Vec<String>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), base, files)?;
+        } else if file_type.is_file() || file_type.is_symlink() {
+            let path = entry.path();
+            let relative = path.strip_prefix(base).unwrap_or(&path);
+            let segments: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+            files.push(segments.join("/"));
+        }
+    }
+    Ok(())
+}
+
+/// Check the caller's API key and OPA; the user files are handed to.
+pub async fn authorize(endpoint: &str, http_req: &HttpRequest, request: &Value, config: &AppConfig) -> Result<Option<RunAs>, (StatusCode, String)> {
+    let caller = auth::authenticate(config, http_req).await.map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
+    let run_as = match caller.as_ref().and_then(|caller| caller.key.run_as.as_ref()) {
+        Some(user) => Some(RunAs::resolve(user).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Cannot run as {}: {}", user, e)))?),
+        None => None,
+    };
+    if let Some(opa) = &config.opa {
+        policy::consult_opa(opa, endpoint, http_req, caller.as_ref().map(|caller| caller.name.as_str()), request).await?;
+    }
+    Ok(run_as)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ManifestEntry {
+    /// Relative to `dest`, `/`-separated.
+    path: String,
+    /// Size and SHA-256 are left out for symlinks.
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    sha256: String,
+    /// Octal permissions, e.g. `755` (Unix).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    /// Modification time, Unix seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    /// Numeric owner and group (Unix).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    /// The entry is a symlink to this target, relative to its directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symlink: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PlanRequest {
+    dest: String,
+    files: Vec<ManifestEntry>,
+    /// Also list files under `dest` the manifest doesn't have.
+    #[serde(default)]
+    delete: bool,
+}
+
+#[derive(Serialize)]
+struct Transfer {
+    path: String,
+    /// Hashes of the blocks the agent has; empty when it has no such file.
+    blocks: Vec<String>,
+    /// The content store has the file's content: send no blocks.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stored: bool,
+}
+
+#[derive(Serialize, Default)]
+struct PlanResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_size: Option<u64>,
+    /// Files to send with `PUT /sync/files`.
+    transfer: Vec<Transfer>,
+    /// Symlinks to create with `POST /sync/links`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    links: Vec<String>,
+    /// Files to remove with `POST /sync/delete`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    delete: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unchanged: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn plan(dest: &Path, req: &PlanRequest, content_store: bool) -> Result<PlanResponse, String> {
+    let mut transfer = Vec::new();
+    let mut links = Vec::new();
+    let mut unchanged = 0;
+    for entry in &req.files {
+        let path = target(dest, &entry.path)?;
+        if let Some(symlink) = &entry.symlink {
+            let link = link_target(&entry.path, symlink)?;
+            if std::fs::read_link(&path).is_ok_and(|current| current == link) {
+                unchanged += 1;
+            } else {
+                links.push(entry.path.clone());
+            }
+            continue;
+        }
+        let attributes = Attributes::parse(entry.mode.as_deref(), entry.mtime, entry.uid, entry.gid)?;
+        let stored = content_store && content::find(&entry.sha256, entry.size).is_some();
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                transfer.push(Transfer {
+                    path: entry.path.clone(),
+                    blocks: Vec::new(),
+                    stored,
+                });
+                continue;
+            }
+        };
+        let (sha256, blocks) = file_hashes(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if metadata.len() == entry.size && sha256.eq_ignore_ascii_case(&entry.sha256) && !attributes.differ(&metadata) {
+            unchanged += 1;
+        } else {
+            transfer.push(Transfer {
+                path: entry.path.clone(),
+                blocks,
+                stored,
+            });
+        }
+    }
+
+    let mut delete = Vec::new();
+    if req.delete && dest.is_dir() {
+        let listed: HashSet<&str> = req.files.iter().map(|entry| entry.path.as_str()).collect();
+        let mut present = Vec::new();
+        walk(dest, dest, &mut present).map_err(|e| format!("Failed to list {}: {}", dest.display(), e))?;
+        delete = present.into_iter().filter(|path| !listed.contains(path.as_str())).collect();
+        delete.sort();
+    }
+    Ok(PlanResponse {
+        success: true,
+        block_size: Some(BLOCK_SIZE),
+        transfer,
+        links,
+        delete,
+        unchanged: Some(unchanged),
+        error: None,
+    })
+}
+
+fn plan_failure(status: StatusCode, error_msg: String) -> HttpResponse {
+    HttpResponse::build(status).json(PlanResponse {
+        error: Some(error_msg),
+        ..Default::default()
+    })
+}
+
+/// POST /sync/plan - compare a manifest with `dest` and report what to send.
+pub async fn sync_plan(http_req: HttpRequest, req: web::Json<PlanRequest>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let req = req.into_inner();
+    let request = serde_json::json!({"dest": req.dest, "files": req.files.len(), "delete": req.delete});
+    if let Err((status, error_msg)) = authorize("/sync/plan", &http_req, &request, &config).await {
+        log_error("/sync/plan", &error_msg, None);
+        return Ok(plan_failure(status, error_msg));
+    }
+    let dest = match dest_dir(&req.dest) {
+        Ok(dest) => dest,
+        Err(e) => return Ok(plan_failure(StatusCode::BAD_REQUEST, e)),
+    };
+    if let Err(error_msg) = config.shares.connect(&dest).await {
+        log_error("/sync/plan", &error_msg, None);
+        return Ok(plan_failure(StatusCode::BAD_GATEWAY, error_msg));
+    }
+    let content_store = config.content_store;
+    match web::block(move || plan(&dest, &req, content_store)).await {
+        Ok(Ok(response)) => Ok(HttpResponse::Ok().json(response)),
+        Ok(Err(e)) => Ok(plan_failure(StatusCode::BAD_REQUEST, e)),
+        Err(e) => Ok(plan_failure(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct FileQuery {
+    dest: String,
+    path: String,
+    size: u64,
+    sha256: String,
+    /// Comma-separated indexes of the blocks in the body, in order; the whole
+    /// file when left out, none when empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blocks: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+}
+
+#[derive(Serialize, Default)]
+struct FileResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    /// Bytes received in the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received: Option<u64>,
+    /// Bytes copied from the agent's existing file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reused: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn file_failure(status: StatusCode, error_msg: String) -> HttpResponse {
+    HttpResponse::build(status).json(FileResponse {
+        error: Some(error_msg),
+        ..Default::default()
+    })
+}
+
+/// The blocks the body carries.
+fn sent_blocks(blocks: Option<&str>, count: u64) -> Result<BTreeSet<u64>, String> {
+    let Some(blocks) = blocks else {
+        return Ok((0..count).collect());
+    };
+    let mut sent = BTreeSet::new();
+    for index in blocks.split(',').map(str::trim).filter(|index| !index.is_empty()) {
+        match index.parse::<u64>() {
+            Ok(index) if index < count => {
+                sent.insert(index);
+            }
+            _ => return Err(format!("Invalid block {:?}: the file has {} blocks", index, count)),
+        }
+    }
+    Ok(sent)
+}
+
+/// Build `temp` from the body's blocks and `source`'s others; returns the
+/// bytes received and reused.
+async fn assemble<S>(temp: &Path, source: &Path, query: &FileQuery, sent: &BTreeSet<u64>, body: &mut S) -> Result<(u64, u64), String>
+where
+    S: futures_util::Stream<Item = Result<web::Bytes, actix_web::error::PayloadError>> + Unpin,
+{
+    let mut output = tokio::fs::File::create(temp).await.map_err(|e| e.to_string())?;
+    let mut source = tokio::fs::File::open(source).await.ok();
+    let mut hasher = Sha256::new();
+    let mut pending = web::BytesMut::new();
+    let (mut received, mut reused) = (0, 0);
+    let count = query.size.div_ceil(BLOCK_SIZE);
+    for index in 0..count {
+        let length = BLOCK_SIZE.min(query.size - index * BLOCK_SIZE) as usize;
+        let block = if sent.contains(&index) {
+            while pending.len() < length {
+                match body.next().await {
+                    Some(chunk) => pending.extend_from_slice(&chunk.map_err(|e| e.to_string())?),
+                    None => return Err(format!("The body ended before block {}", index)),
+                }
+            }
+            received += length as u64;
+            pending.split_to(length).freeze()
+        } else {
+            let Some(source) = source.as_mut() else {
+                return Err(format!("Block {} was not sent and there is no existing file", index));
+            };
+            let mut block = vec![0; length];
+            source.seek(io::SeekFrom::Start(index * BLOCK_SIZE)).await.map_err(|e| e.to_string())?;
+            source
+                .read_exact(&mut block)
+                .await
+                .map_err(|_| format!("Block {} was not sent and the existing file doesn't have it", index))?;
+            reused += length as u64;
+            web::Bytes::from(block)
+        };
+        hasher.update(&block);
+        output.write_all(&block).await.map_err(|e| e.to_string())?;
+    }
+    if !pending.is_empty() || body.next().await.is_some() {
+        return Err("The body is longer than the blocks it should carry".to_string());
+    }
+    output.sync_all().await.map_err(|e| e.to_string())?;
+    let sha256 = content::hex(&hasher.finalize());
+    if !sha256.eq_ignore_ascii_case(&query.sha256) {
+        return Err(format!("Checksum mismatch: expected {}, got {}", query.sha256, sha256));
+    }
+    Ok((received, reused))
+}
+
+/// PUT /sync/files - write one file from the blocks in the body and the
+/// agent's existing copy.
+pub async fn sync_file(
+    http_req: HttpRequest,
+    query: web::Query<FileQuery>,
+    payload: web::Payload,
+    config: web::Data<AppConfig>,
+) -> ActixResult<HttpResponse> {
+    let query = query.into_inner();
+    let request = serde_json::to_value(&query).unwrap_or_default();
+    let run_as = match authorize("/sync/files", &http_req, &request, &config).await {
+        Ok(run_as) => run_as,
+        Err((status, error_msg)) => {
+            log_error("/sync/files", &error_msg, None);
+            return Ok(file_failure(status, error_msg));
+        }
+    };
+    let checked = dest_dir(&query.dest).and_then(|dest| {
+        let path = target(&dest, &query.path)?;
+        let attributes = Attributes::parse(query.mode.as_deref(), query.mtime, query.uid, query.gid)?;
+        let sent = sent_blocks(query.blocks.as_deref(), query.size.div_ceil(BLOCK_SIZE))?;
+        Ok((path, attributes, sent))
+    });
+    let (path, attributes, sent) = match checked {
+        Ok(checked) => checked,
+        Err(e) => return Ok(file_failure(StatusCode::BAD_REQUEST, e)),
+    };
+    if run_as.is_some() && attributes.sets_owner() {
+        let error_msg = "uid and gid can't be set with an API key that has run_as".to_string();
+        return Ok(file_failure(StatusCode::FORBIDDEN, error_msg));
+    }
+    if let Err(error_msg) = config.shares.connect(&path).await {
+        log_error("/sync/files", &error_msg, None);
+        return Ok(file_failure(StatusCode::BAD_GATEWAY, error_msg));
+    }
+    if let Some(parent) = path.parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            let error_msg = format!("Failed to create {}: {}", parent.display(), e);
+            log_error("/sync/files", &error_msg, None);
+            return Ok(file_failure(StatusCode::INTERNAL_SERVER_ERROR, error_msg));
+        }
+    }
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".sync-tmp");
+    let temp = PathBuf::from(temp);
+    // The stored blob has every block right; the existing file only those
+    // the controller didn't send.
+    let stored = config.content_store.then(|| content::find(&query.sha256, query.size)).flatten();
+    let source = stored.as_deref().unwrap_or(&path);
+    let mut body = std::pin::pin!(config.bandwidth.throttle(payload));
+    let assembled = assemble(&temp, source, &query, &sent, &mut body).await;
+    if assembled.is_err() && stored.is_some() {
+        content::evict(&query.sha256);
+    }
+    let assembled = assembled.and_then(|counts| {
+        attributes.apply(&temp).map_err(|e| format!("Failed to set attributes: {}", e))?;
+        if let Some(run_as) = &run_as {
+            run_as.chown(&temp).map_err(|e| format!("Failed to hand the file to {}: {}", run_as.user, e))?;
+        }
+        std::fs::rename(&temp, &path).map_err(|e| format!("Failed to replace {}: {}", path.display(), e))?;
+        Ok(counts)
+    });
+    if assembled.is_ok() && config.content_store {
+        if let Err(e) = content::add(&path, &query.sha256) {
+            log_error("/sync/files", &format!("Failed to add {} to the content store: {}", path.display(), e), None);
+        }
+    }
+    match assembled {
+        Ok((received, reused)) => Ok(HttpResponse::Ok().json(FileResponse {
+            success: true,
+            path: Some(query.path),
+            received: Some(received),
+            reused: Some(reused),
+            error: None,
+        })),
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            let error_msg = format!("{}: {}", path.display(), e);
+            log_error("/sync/files", &error_msg, None);
+            Ok(file_failure(StatusCode::BAD_REQUEST, error_msg))
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Link {
+    /// Relative to `dest`, `/`-separated.
+    path: String,
+    target: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct LinksRequest {
+    dest: String,
+    links: Vec<Link>,
+}
+
+#[derive(Serialize, Default)]
+struct LinksResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Windows has file and directory symlinks; which one depends on what the
+/// target is now.
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    if link.parent().map(|dir| dir.join(target)).is_some_and(|resolved| resolved.is_dir()) {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// Create each link, replacing whatever file or link is at its path.
+fn create_links(dest: &Path, links: Vec<(PathBuf, PathBuf)>, run_as: Option<&RunAs>) -> Result<usize, String> {
+    let mut created = 0;
+    let mut errors = Vec::new();
+    for (path, target) in links {
+        if below_link(dest, &path) {
+            errors.push(format!("{}: a directory on the way is a symlink", path.display()));
+            continue;
+        }
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".sync-tmp");
+        let temp = PathBuf::from(temp);
+        let _ = std::fs::remove_file(&temp);
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| symlink(&target, &temp))
+            .and_then(|()| run_as.map_or(Ok(()), |run_as| run_as.chown_link(&temp)))
+            .and_then(|()| std::fs::rename(&temp, &path));
+        match result {
+            Ok(()) => created += 1,
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp);
+                errors.push(format!("{}: {}", path.display(), e));
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(created)
+    } else {
+        Err(format!("Failed to create {}", errors.join(", ")))
+    }
+}
+
+/// POST /sync/links - create the symlinks of a directory sync.
+pub async fn sync_links(http_req: HttpRequest, req: web::Json<LinksRequest>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let req = req.into_inner();
+    let request = serde_json::to_value(&req).unwrap_or_default();
+    let failure = |status: StatusCode, error_msg: String| {
+        HttpResponse::build(status).json(LinksResponse {
+            error: Some(error_msg),
+            ..Default::default()
+        })
+    };
+    let run_as = match authorize("/sync/links", &http_req, &request, &config).await {
+        Ok(run_as) => run_as,
+        Err((status, error_msg)) => {
+            log_error("/sync/links", &error_msg, None);
+            return Ok(failure(status, error_msg));
+        }
+    };
+    let checked = dest_dir(&req.dest).and_then(|dest| {
+        let links = req
+            .links
+            .iter()
+            .map(|link| Ok((target(&dest, &link.path)?, link_target(&link.path, &link.target)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok((dest, links))
+    });
+    let (dest, links) = match checked {
+        Ok(checked) => checked,
+        Err(e) => return Ok(failure(StatusCode::BAD_REQUEST, e)),
+    };
+    if let Err(error_msg) = config.shares.connect(&dest).await {
+        log_error("/sync/links", &error_msg, None);
+        return Ok(failure(StatusCode::BAD_GATEWAY, error_msg));
+    }
+    match web::block(move || create_links(&dest, links, run_as.as_ref())).await {
+        Ok(Ok(created)) => Ok(HttpResponse::Ok().json(LinksResponse {
+            success: true,
+            created: Some(created),
+            error: None,
+        })),
+        Ok(Err(e)) => {
+            log_error("/sync/links", &e, None);
+            Ok(failure(StatusCode::INTERNAL_SERVER_ERROR, e))
+        }
+        Err(e) => Ok(failure(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DeleteRequest {
+    dest: String,
+    /// Relative to `dest`.
+    paths: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+struct DeleteResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Remove the files and the directories that are left empty, up to `dest`.
+fn delete(dest: &Path, targets: Vec<PathBuf>) -> Result<usize, String> {
+    let mut deleted = 0;
+    let mut errors = Vec::new();
+    for path in targets {
+        match std::fs::remove_file(&path) {
+            Ok(()) => deleted += 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                errors.push(format!("{}: {}", path.display(), e));
+                continue;
+            }
+        }
+        let mut dir = path.parent();
+        while let Some(parent) = dir.filter(|parent| *parent != dest && parent.starts_with(dest)) {
+            if std::fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+    }
+    if errors.is_empty() {
+        Ok(deleted)
+    } else {
+        Err(format!("Failed to delete {}", errors.join(", ")))
+    }
+}
+
+/// POST /sync/delete - remove files the manifest no longer has.
+pub async fn sync_delete(http_req: HttpRequest, req: web::Json<DeleteRequest>, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let req = req.into_inner();
+    let request = serde_json::to_value(&req).unwrap_or_default();
+    let failure = |status: StatusCode, error_msg: String| {
+        HttpResponse::build(status).json(DeleteResponse {
+            error: Some(error_msg),
+            ..Default::default()
+        })
+    };
+    if let Err((status, error_msg)) = authorize("/sync/delete", &http_req, &request, &config).await {
+        log_error("/sync/delete", &error_msg, None);
+        return Ok(failure(status, error_msg));
+    }
+    let checked = dest_dir(&req.dest).and_then(|dest| {
+        let targets = req.paths.iter().map(|path| target(&dest, path)).collect::<Result<Vec<_>, _>>()?;
+        Ok((dest, targets))
+    });
+    let (dest, targets) = match checked {
+        Ok(checked) => checked,
+        Err(e) => return Ok(failure(StatusCode::BAD_REQUEST, e)),
+    };
+    if let Err(error_msg) = config.shares.connect(&dest).await {
+        log_error("/sync/delete", &error_msg, None);
+        return Ok(failure(StatusCode::BAD_GATEWAY, error_msg));
+    }
+    match web::block(move || delete(&dest, targets)).await {
+        Ok(Ok(deleted)) => Ok(HttpResponse::Ok().json(DeleteResponse {
+            success: true,
+            deleted: Some(deleted),
+            error: None,
+        })),
+        Ok(Err(e)) => {
+            log_error("/sync/delete", &e, None);
+            Ok(failure(StatusCode::INTERNAL_SERVER_ERROR, e))
+        }
+        Err(e) => Ok(failure(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
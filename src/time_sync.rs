@@ -0,0 +1,269 @@
+//! Checking, and fixing, the host's time synchronization, since a drifted
+//! clock is behind many certificate and Kerberos failures.
+//!
+//! `GET /admin/time-sync` asks the host's time service whether it is
+//! synchronized, to what and (chrony only) how far off the clock is;
+//! `POST /admin/time-sync` makes it resynchronize now:
+//!
+//! - Windows Time: `w32tm /query /status`, and `w32tm /resync /force` after
+//!   starting the service if it is stopped;
+//! - chrony, when running: `chronyc tracking`, and `chronyc makestep`;
+//! - systemd-timesyncd, when active: `timedatectl`, and restarting the
+//!   service, which synchronizes as it starts.
+//!
+//! Resynchronizing needs root, or Administrator on Windows.
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Result as ActixResult};
+use serde::Serialize;
+use std::process::Command;
+
+use crate::config::AppConfig;
+use crate::{auth, capabilities, log_error, policy, timekeeping};
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    WindowsTime,
+    Chrony,
+    Timesyncd,
+}
+
+/// What the time service says about the clock.
+#[derive(Serialize, Default)]
+pub struct SyncState {
+    /// `None` when no supported time service is running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<Backend>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    synchronized: Option<bool>,
+    /// The server the clock follows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stratum: Option<u8>,
+    /// Host clock minus the source's; positive when the host is ahead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset_ms: Option<f64>,
+}
+
+/// Run a time service tool; its output, or why it failed.
+fn run(program: &str, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if output.status.success() {
+        return Ok(stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let message = if stderr.trim().is_empty() { stdout.trim() } else { stderr.trim() };
+    Err(format!("{} {} failed ({}): {}", program, args.join(" "), output.status, message))
+}
+
+/// `Name : value` lines.
+fn fields(output: &str) -> impl Iterator<Item = (&str, &str)> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+}
+
+/// The running time service, if any.
+pub fn detect() -> Option<Backend> {
+    if cfg!(windows) {
+        return Some(Backend::WindowsTime);
+    }
+    // Fails with "Cannot talk to daemon" when chronyd isn't running.
+    if capabilities::on_path("chronyc") && run("chronyc", &["tracking"]).is_ok() {
+        return Some(Backend::Chrony);
+    }
+    if capabilities::on_path("systemctl")
+        && run("systemctl", &["is-active", "systemd-timesyncd"]).is_ok_and(|state| state.trim() == "active")
+    {
+        return Some(Backend::Timesyncd);
+    }
+    None
+}
+
+fn windows_status() -> Result<SyncState, String> {
+    let status = run("w32tm", &["/query", "/status"])?;
+    let mut state = SyncState {
+        backend: Some(Backend::WindowsTime),
+        ..Default::default()
+    };
+    for (name, value) in fields(&status) {
+        match name {
+            // "3(not synchronized)" until the first sync.
+            "Leap Indicator" => state.synchronized = Some(!value.starts_with('3')),
+            "Stratum" => state.stratum = value.split_whitespace().next().and_then(|stratum| stratum.parse().ok()),
+            // e.g. "time.windows.com,0x9", or "Local CMOS Clock" when
+            // nothing is configured.
+            "Source" => {
+                let source = value.split(',').next().unwrap_or(value);
+                if source.contains("CMOS Clock") || source.contains("Free-running") {
+                    state.synchronized = Some(false);
+                } else {
+                    state.source = Some(source.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(state)
+}
+
+fn chrony_status() -> Result<SyncState, String> {
+    let tracking = run("chronyc", &["tracking"])?;
+    let mut state = SyncState {
+        backend: Some(Backend::Chrony),
+        ..Default::default()
+    };
+    for (name, value) in fields(&tracking) {
+        match name {
+            // "A9FEA97B (169.254.169.123)"; "00000000 ()" without a source.
+            "Reference ID" => {
+                state.source = value
+                    .split_once('(')
+                    .map(|(_, source)| source.trim_end_matches(')').to_string())
+                    .filter(|source| !source.is_empty());
+            }
+            "Stratum" => state.stratum = value.parse().ok(),
+            // "0.000011361 seconds fast of NTP time"
+            "System time" => {
+                let mut words = value.split_whitespace();
+                let seconds = words.next().and_then(|seconds| seconds.parse::<f64>().ok());
+                let sign = if words.nth(1) == Some("slow") { -1.0 } else { 1.0 };
+                state.offset_ms = seconds.map(|seconds| (sign * seconds * 1_000_000.0).round() / 1000.0);
+            }
+            "Leap status" => state.synchronized = Some(value != "Not synchronised"),
+            _ => {}
+        }
+    }
+    Ok(state)
+}
+
+fn timesyncd_status() -> Result<SyncState, String> {
+    let synchronized = run("timedatectl", &["show", "--property=NTPSynchronized", "--value"])?;
+    // Only systemd 239 and later have show-timesync.
+    let source = run("timedatectl", &["show-timesync", "--property=ServerName", "--value"])
+        .ok()
+        .map(|server| server.trim().to_string())
+        .filter(|server| !server.is_empty());
+    Ok(SyncState {
+        backend: Some(Backend::Timesyncd),
+        synchronized: Some(synchronized.trim() == "yes"),
+        source,
+        ..Default::default()
+    })
+}
+
+fn status(backend: Backend) -> Result<SyncState, String> {
+    match backend {
+        Backend::WindowsTime => windows_status(),
+        Backend::Chrony => chrony_status(),
+        Backend::Timesyncd => timesyncd_status(),
+    }
+}
+
+fn resync(backend: Backend) -> Result<(), String> {
+    match backend {
+        Backend::WindowsTime => {
+            // A stopped service is a common cause of drift; resync fails
+            // with "The service has not been started" until it runs.
+            if let Err(e) = run("net", &["start", "w32time"]) {
+                if !e.contains("already been started") {
+                    return Err(e);
+                }
+            }
+            run("w32tm", &["/resync", "/force"]).map(|_| ())
+        }
+        Backend::Chrony => run("chronyc", &["makestep"]).map(|_| ()),
+        Backend::Timesyncd => run("systemctl", &["restart", "systemd-timesyncd"]).map(|_| ()),
+    }
+}
+
+/// The time service's state, after resynchronizing when `fix` is set; with
+/// `fix`, also the state before.
+pub fn apply(fix: bool) -> Result<(SyncState, Option<SyncState>), (StatusCode, String)> {
+    let failed = |e| (StatusCode::INTERNAL_SERVER_ERROR, e);
+    let Some(backend) = detect() else {
+        if fix {
+            let error_msg = "No running time service (Windows Time, chrony or systemd-timesyncd) to resynchronize";
+            return Err((StatusCode::CONFLICT, error_msg.to_string()));
+        }
+        return Ok((SyncState::default(), None));
+    };
+    if !fix {
+        return Ok((status(backend).map_err(failed)?, None));
+    }
+    let before = status(backend).ok();
+    resync(backend).map_err(failed)?;
+    Ok((status(backend).map_err(failed)?, before))
+}
+
+#[derive(Serialize, Default)]
+struct TimeSyncResponse {
+    success: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    state: Option<SyncState>,
+    /// The state before resynchronizing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before: Option<SyncState>,
+    /// The agent's own view of the clock, as in `/health`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<timekeeping::ClockStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn failure(status: StatusCode, error_msg: String) -> HttpResponse {
+    HttpResponse::build(status).json(TimeSyncResponse {
+        error: Some(error_msg),
+        ..Default::default()
+    })
+}
+
+async fn respond(config: web::Data<AppConfig>, fix: bool) -> ActixResult<HttpResponse> {
+    match web::block(move || apply(fix)).await? {
+        Ok((state, before)) => {
+            if fix {
+                // So /health shows the offset after the fix.
+                timekeeping::check(&config).await;
+            }
+            Ok(HttpResponse::Ok().json(TimeSyncResponse {
+                success: true,
+                state: Some(state),
+                before,
+                clock: timekeeping::status(),
+                error: None,
+            }))
+        }
+        Err((status, error_msg)) => {
+            log_error("/admin/time-sync", &error_msg, None);
+            Ok(failure(status, error_msg))
+        }
+    }
+}
+
+/// GET /admin/time-sync - whether the host's time service keeps the clock
+/// synchronized.
+pub async fn get_time_sync(config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    respond(config, false).await
+}
+
+/// POST /admin/time-sync - make the time service resynchronize now.
+pub async fn resync_time(http_req: HttpRequest, config: web::Data<AppConfig>) -> ActixResult<HttpResponse> {
+    let caller = match auth::authenticate(&config, &http_req).await {
+        Ok(caller) => caller,
+        Err(error_msg) => return Ok(failure(StatusCode::UNAUTHORIZED, error_msg)),
+    };
+    if let Some(opa) = &config.opa {
+        let request = serde_json::json!({ "method": "POST" });
+        let key_name = caller.as_ref().map(|caller| caller.name.as_str());
+        if let Err((status, error_msg)) = policy::consult_opa(opa, "/admin/time-sync", &http_req, key_name, &request).await {
+            return Ok(failure(status, error_msg));
+        }
+    }
+    respond(config, true).await
+}
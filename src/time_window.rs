@@ -0,0 +1,142 @@
+//! Host-local time windows a job is allowed to start in, e.g. only
+//! 22:00-06:00 on weekdays. Windows are closed during blackout periods.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, Weekday};
+use serde::Deserialize;
+
+use crate::blackout;
+
+/// Back-to-back blackout periods skipped looking for the next window.
+const MAX_BLACKOUT_HOPS: usize = 64;
+
+#[derive(Deserialize, Clone)]
+pub struct TimeWindow {
+    /// "HH:MM" (or "HH:MM:SS"), host-local.
+    pub start: String,
+    /// End of the window; an end at or before `start` wraps past midnight.
+    pub end: String,
+    /// Days the window opens on ("mon", "tuesday", ...); every day when empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+}
+
+/// What to do with a request that arrives outside its windows.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnWindowMiss {
+    #[default]
+    Reject,
+    /// Hold the job as `queued` and start it when the next window opens.
+    Queue,
+}
+
+/// What to do with a queued job that came due while the agent was down.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnMissed {
+    /// Record it as skipped.
+    #[default]
+    Skip,
+    /// Start it once the agent is back (still only inside its windows).
+    RunOnce,
+}
+
+struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    days: Vec<Weekday>,
+}
+
+impl Window {
+    fn opens_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn contains(&self, now: DateTime<Local>) -> bool {
+        let time = now.time();
+        let today = now.weekday();
+        if self.start < self.end {
+            self.opens_on(today) && time >= self.start && time < self.end
+        } else {
+            (self.opens_on(today) && time >= self.start) || (self.opens_on(today.pred()) && time < self.end)
+        }
+    }
+}
+
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M:%S"))
+        .map_err(|_| format!("Invalid window time {:?}; expected HH:MM", value))
+}
+
+impl Schedule {
+    pub fn new(windows: &[TimeWindow]) -> Result<Self, String> {
+        let windows = windows
+            .iter()
+            .map(|window| {
+                let days = window
+                    .days
+                    .iter()
+                    .map(|day| day.parse::<Weekday>().map_err(|_| format!("Invalid window day {:?}", day)))
+                    .collect::<Result<_, _>>()?;
+                Ok(Window {
+                    start: parse_time(&window.start)?,
+                    end: parse_time(&window.end)?,
+                    days,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Schedule { windows })
+    }
+
+    /// No windows means no restriction, blackouts included.
+    pub fn allows(&self, now: DateTime<Local>) -> bool {
+        self.windows.is_empty() || (self.open(now) && blackout::covering(now).is_none())
+    }
+
+    /// Whether a window is open, blackouts aside.
+    fn open(&self, now: DateTime<Local>) -> bool {
+        self.windows.iter().any(|window| window.contains(now))
+    }
+
+    /// When the next window opens after `now` (`now` itself if one is open),
+    /// outside blackout periods.
+    pub fn next_open(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        if self.windows.is_empty() {
+            return Some(now);
+        }
+        let mut at = now;
+        for _ in 0..MAX_BLACKOUT_HOPS {
+            let opens = if self.open(at) { at } else { self.next_window(at)? };
+            match blackout::covering(opens) {
+                Some(period) => at = period.end,
+                None => return Some(opens),
+            }
+        }
+        None
+    }
+
+    /// When the next window opens after `now`, blackouts aside.
+    fn next_window(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        (0..=7)
+            .flat_map(|offset| {
+                let date = now.date_naive() + Duration::days(offset);
+                self.windows
+                    .iter()
+                    .filter(move |window| window.opens_on(date.weekday()))
+                    .filter_map(move |window| date.and_time(window.start).and_local_timezone(Local).earliest())
+            })
+            .filter(|opens| *opens > now)
+            .min()
+    }
+}
+
+/// A random delay of up to `max_secs` whole seconds.
+pub fn splay(max_secs: u64) -> std::time::Duration {
+    let random = uuid::Uuid::new_v4().as_u128();
+    std::time::Duration::from_secs((random % (u128::from(max_secs) + 1)) as u64)
+}
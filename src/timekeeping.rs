@@ -0,0 +1,249 @@
+//! Agent uptime, host boot time and how far the host clock may be off, so a
+//! scheduled job that ran at the wrong time can be traced to clock drift.
+//!
+//! On Linux the kernel says whether NTP keeps the clock synchronized and how
+//! large it estimates the error to be. With `AGENT_NTP_SERVER` set, the agent
+//! also asks that server (SNTP) at startup and every 10 minutes and reports
+//! the measured offset; an offset of more than a second is logged.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+use crate::config::AppConfig;
+use crate::{clock, log_error};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+const NTP_TIMEOUT: Duration = Duration::from_secs(3);
+const NTP_PORT: u16 = 123;
+/// Seconds from the NTP epoch (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+/// Offsets beyond this are logged.
+const SKEW_LOGGED_MS: f64 = 1000.0;
+
+static STARTED: OnceLock<(Instant, DateTime<Utc>)> = OnceLock::new();
+static LAST_CHECK: Mutex<Option<Result<Measurement, String>>> = Mutex::new(None);
+
+/// Note the agent's start; called first thing in `main`.
+pub fn init() {
+    let _ = STARTED.set((Instant::now(), Utc::now()));
+}
+
+fn started() -> (Instant, DateTime<Utc>) {
+    *STARTED.get_or_init(|| (Instant::now(), Utc::now()))
+}
+
+/// When the agent started, for `/health`.
+pub fn started_at() -> String {
+    clock::format(&started().1)
+}
+
+pub fn uptime_secs() -> u64 {
+    started().0.elapsed().as_secs()
+}
+
+/// When the host booted, if the platform says.
+pub fn boot_time() -> Option<String> {
+    boot_instant().map(|boot| clock::format(&boot))
+}
+
+#[cfg(target_os = "linux")]
+fn boot_instant() -> Option<DateTime<Utc>> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let btime = stat.lines().find_map(|line| line.strip_prefix("btime "))?;
+    DateTime::from_timestamp(btime.trim().parse().ok()?, 0)
+}
+
+#[cfg(windows)]
+fn boot_instant() -> Option<DateTime<Utc>> {
+    // SAFETY: no arguments; milliseconds since boot.
+    let since_boot = unsafe { windows_sys::Win32::System::SystemInformation::GetTickCount64() };
+    Some(Utc::now() - chrono::Duration::milliseconds(since_boot as i64))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn boot_instant() -> Option<DateTime<Utc>> {
+    None
+}
+
+/// One SNTP exchange with `AGENT_NTP_SERVER`.
+#[derive(Serialize, Clone)]
+pub struct Measurement {
+    pub server: String,
+    /// Host clock minus the server's; positive when the host is ahead.
+    pub offset_ms: f64,
+    pub round_trip_ms: f64,
+    pub checked_at: String,
+}
+
+/// What is known about the host clock's accuracy.
+#[derive(Serialize)]
+pub struct ClockStatus {
+    /// Whether the kernel considers NTP to keep the clock synchronized.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub synchronized: Option<bool>,
+    /// The kernel's estimate of the clock's error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_error_ms: Option<f64>,
+    /// The last check against `AGENT_NTP_SERVER`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ntp: Option<Measurement>,
+    /// Why the last check against `AGENT_NTP_SERVER` failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ntp_error: Option<String>,
+}
+
+/// The clock's status; `None` when nothing is known about it.
+pub fn status() -> Option<ClockStatus> {
+    let (synchronized, estimated_error_ms) = kernel();
+    let (ntp, ntp_error) = match LAST_CHECK.lock().unwrap().clone() {
+        Some(Ok(measurement)) => (Some(measurement), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+    if synchronized.is_none() && ntp.is_none() && ntp_error.is_none() {
+        return None;
+    }
+    Some(ClockStatus {
+        synchronized,
+        estimated_error_ms,
+        ntp,
+        ntp_error,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn kernel() -> (Option<bool>, Option<f64>) {
+    // SAFETY: with `modes` zeroed, adjtimex only reads the kernel's state
+    // into `timex`.
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    if unsafe { libc::adjtimex(&mut timex) } == -1 {
+        return (None, None);
+    }
+    let synchronized = timex.status & libc::STA_UNSYNC == 0;
+    // Meaningless while unsynchronized.
+    (Some(synchronized), synchronized.then_some(timex.esterror as f64 / 1000.0))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel() -> (Option<bool>, Option<f64>) {
+    (None, None)
+}
+
+/// Split `server` into host and port, 123 unless given.
+fn host_port(server: &str) -> Result<(&str, u16), String> {
+    match server.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            let port = port.parse().map_err(|_| format!("Invalid AGENT_NTP_SERVER {:?}", server))?;
+            Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+        }
+        _ => Ok((server.trim_start_matches('[').trim_end_matches(']'), NTP_PORT)),
+    }
+}
+
+fn ntp_seconds(bytes: &[u8]) -> f64 {
+    let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64;
+    let fraction = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as f64;
+    seconds + fraction / 4_294_967_296.0 - NTP_UNIX_OFFSET
+}
+
+fn unix_seconds(time: DateTime<Utc>) -> f64 {
+    time.timestamp() as f64 + time.timestamp_subsec_nanos() as f64 / 1e9
+}
+
+/// Measure the host clock against `server`.
+async fn measure(server: &str, config: &AppConfig) -> Result<Measurement, String> {
+    let (host, port) = host_port(server)?;
+    if !config.outbound.allow.allows(host, port) {
+        return Err(format!("{} is not in AGENT_OUTBOUND_ALLOW", server));
+    }
+    let ip = config.outbound.dns.resolve(host).await?;
+    let bind = if ip.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).await.map_err(|e| format!("Failed to open a UDP socket: {}", e))?;
+    socket
+        .connect((ip, port))
+        .await
+        .map_err(|e| format!("Failed to reach {}: {}", server, e))?;
+
+    // Version 3, client mode; the server echoes our transmit time back as the
+    // originate time, which is how its answer is matched to this request.
+    let mut request = [0u8; 48];
+    request[0] = 0x1b;
+    let sent = Utc::now();
+    let transmit = unix_seconds(sent) + NTP_UNIX_OFFSET;
+    request[40..44].copy_from_slice(&(transmit as u32).to_be_bytes());
+    request[44..48].copy_from_slice(&((transmit.fract() * 4_294_967_296.0) as u32).to_be_bytes());
+    let mut response = [0u8; 48];
+    let exchange = async {
+        socket.send(&request).await?;
+        socket.recv(&mut response).await
+    };
+    let received = match tokio::time::timeout(NTP_TIMEOUT, exchange).await {
+        Ok(Ok(48..)) => Utc::now(),
+        Ok(Ok(_)) => return Err(format!("Short answer from {}", server)),
+        Ok(Err(e)) => return Err(format!("Failed to query {}: {}", server, e)),
+        Err(_) => return Err(format!("No answer from {} within {}s", server, NTP_TIMEOUT.as_secs())),
+    };
+    if response[0] & 0x07 != 4 || response[1] == 0 || response[24..32] != request[40..48] {
+        return Err(format!("{} sent an unusable answer", server));
+    }
+
+    let (t1, t4) = (unix_seconds(sent), unix_seconds(received));
+    let (t2, t3) = (ntp_seconds(&response[32..40]), ntp_seconds(&response[40..48]));
+    let tenths = |seconds: f64| (seconds * 10_000.0).round() / 10.0;
+    Ok(Measurement {
+        server: server.to_string(),
+        offset_ms: tenths(-((t2 - t1) + (t3 - t4)) / 2.0),
+        round_trip_ms: tenths((t4 - t1) - (t3 - t2)),
+        checked_at: clock::format(&received),
+    })
+}
+
+/// Measure once and keep the result for `/health`.
+pub async fn check(config: &AppConfig) {
+    let Some(server) = &config.ntp_server else {
+        return;
+    };
+    let result = measure(server, config).await;
+    match &result {
+        Ok(measurement) if measurement.offset_ms.abs() > SKEW_LOGGED_MS => {
+            let direction = if measurement.offset_ms > 0.0 { "ahead of" } else { "behind" };
+            let error_msg = format!("The host clock is {:.0}ms {} {}", measurement.offset_ms.abs(), direction, server);
+            log_error("clock", &error_msg, None);
+        }
+        Ok(_) => {}
+        Err(e) => log_error("clock", e, None),
+    }
+    *LAST_CHECK.lock().unwrap() = Some(result);
+}
+
+/// Re-check the clock every [`CHECK_INTERVAL`]; runs forever.
+pub async fn run(config: actix_web::web::Data<AppConfig>) {
+    if config.ntp_server.is_none() {
+        return;
+    }
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        check(&config).await;
+    }
+}
+
+/// Variables describing the host clock for lifecycle commands, such as one
+/// registering the agent with an inventory.
+pub fn env() -> Vec<(&'static str, String)> {
+    let mut vars = Vec::new();
+    if let Some(boot_time) = boot_time() {
+        vars.push(("AGENT_BOOT_TIME", boot_time));
+    }
+    if let Some(status) = status() {
+        if let Some(synchronized) = status.synchronized {
+            vars.push(("AGENT_CLOCK_SYNCHRONIZED", synchronized.to_string()));
+        }
+        if let Some(ntp) = status.ntp {
+            vars.push(("AGENT_CLOCK_OFFSET_MS", format!("{:.1}", ntp.offset_ms)));
+        }
+    }
+    vars
+}
@@ -0,0 +1,54 @@
+//! Timestamps on captured output lines, so post-mortems can line up what a
+//! script printed with external events.
+//!
+//! With `timestamps`, each line of a job's captured output (the `/execute`
+//! response and the job log) starts with the host time and the seconds since
+//! the command started, from a monotonic clock that host clock changes don't
+//! affect: `[2026-10-15T09:30:00.123+02:00 +12.345s] `.
+
+use chrono::SecondsFormat;
+use std::time::Instant;
+
+use crate::clock;
+
+/// Stamps the lines of one output stream, across however many reads they
+/// arrive in.
+pub struct Stamper {
+    started: Instant,
+    line_start: bool,
+}
+
+impl Stamper {
+    /// `started` is when the command started, shared by its streams.
+    pub fn new(started: Instant) -> Self {
+        Stamper {
+            started,
+            line_start: true,
+        }
+    }
+
+    /// `bytes` with every line that starts in it stamped.
+    pub fn stamp(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let prefix = format!(
+            "[{} +{:.3}s] ",
+            clock::format_opts(&chrono::Utc::now(), SecondsFormat::Millis),
+            self.started.elapsed().as_secs_f64()
+        );
+        let mut stamped = Vec::with_capacity(bytes.len() + prefix.len());
+        for &byte in bytes {
+            if self.line_start {
+                stamped.extend_from_slice(prefix.as_bytes());
+            }
+            stamped.push(byte);
+            self.line_start = byte == b'\n';
+        }
+        stamped
+    }
+}
+
+/// `line` without its stamp, if it has one.
+pub fn strip(line: &str) -> &str {
+    line.strip_prefix('[')
+        .and_then(|rest| rest.split_once("s] "))
+        .map_or(line, |(_, line)| line)
+}
@@ -0,0 +1,424 @@
+//! HTTPS for the API (`tls` feature), served with rustls.
+//!
+//! `AGENT_TLS_CERT` and `AGENT_TLS_KEY` (or `--tls-cert` and `--tls-key`)
+//! name PEM files: the certificate chain, leaf first, and its private key
+//! (PKCS#8, PKCS#1 or SEC1). For lab setups, `AGENT_TLS_SELF_SIGNED=true`
+//! (`--tls-self-signed`) generates a self-signed certificate for the host's
+//! name and addresses on first start, into those paths or `tls/` next to the
+//! executable, and reuses it afterwards. Every listener then serves HTTPS
+//! only, HTTP/2 and HTTP/1.1.
+//!
+//! `AGENT_TLS_CLIENT_CA` (`--tls-client-ca`) makes client certificates
+//! mandatory: the handshake fails unless the client presents one signed by a
+//! CA in that PEM bundle. The certificate's subject is kept with the
+//! connection and recorded on jobs, in their policy trail and in OPA's input.
+
+use actix_web::dev::Extensions;
+use actix_web::HttpRequest;
+use std::any::Any;
+use std::io;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::get_exe_dir;
+use crate::listen::ListenerInfo;
+
+/// Where the certificate comes from.
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub self_signed: bool,
+    /// CA bundle client certificates must be signed by.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        let path = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+        Settings {
+            cert: path("AGENT_TLS_CERT"),
+            key: path("AGENT_TLS_KEY"),
+            self_signed: matches!(std::env::var("AGENT_TLS_SELF_SIGNED").as_deref(), Ok("1" | "true" | "yes")),
+            client_ca: path("AGENT_TLS_CLIENT_CA"),
+        }
+    }
+
+    /// The certificate and key files, `None` when serving plain HTTP.
+    pub fn files(&self) -> Result<Option<(PathBuf, PathBuf)>, String> {
+        if self.client_ca.is_some() && self.cert.is_none() && self.key.is_none() && !self.self_signed {
+            return Err("AGENT_TLS_CLIENT_CA needs HTTPS: set AGENT_TLS_CERT and AGENT_TLS_KEY or AGENT_TLS_SELF_SIGNED".to_string());
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Ok(Some((cert.clone(), key.clone()))),
+            (None, None) if self.self_signed => {
+                let dir = get_exe_dir().join("tls");
+                Ok(Some((dir.join("cert.pem"), dir.join("key.pem"))))
+            }
+            (None, None) => Ok(None),
+            _ => Err("Set both AGENT_TLS_CERT and AGENT_TLS_KEY (--tls-cert and --tls-key)".to_string()),
+        }
+    }
+
+    /// Whether a self-signed certificate would be generated on start.
+    #[cfg(feature = "tls")]
+    pub fn generates(&self) -> Result<bool, String> {
+        Ok(self.self_signed && self.files()?.is_some_and(|(cert, key)| !cert.exists() && !key.exists()))
+    }
+}
+
+/// The HTTPS setup of a started agent.
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct Tls {
+    #[cfg(feature = "tls")]
+    pub server: rustls::ServerConfig,
+    pub loopback: Loopback,
+    pub cert: PathBuf,
+    /// Whether the certificate was generated on this start.
+    pub generated: bool,
+}
+
+/// How the agent connects to its own listeners, for the watchdog.
+#[derive(Clone, Default)]
+pub struct Loopback {
+    /// Trusts exactly the agent's own certificate.
+    #[cfg(feature = "tls")]
+    connector: Option<tokio_rustls::TlsConnector>,
+}
+
+impl Loopback {
+    /// Send `request` to `addr` and read the response until the connection
+    /// closes.
+    pub async fn request(&self, addr: &str, request: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(addr).await?;
+        #[cfg(feature = "tls")]
+        if let Some(connector) = &self.connector {
+            let name = rustls::pki_types::ServerName::try_from("localhost").map_err(io::Error::other)?;
+            let mut stream = connector.connect(name, stream).await?;
+            return exchange(&mut stream, request).await;
+        }
+        exchange(&mut stream, request).await
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, request: &[u8]) -> io::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response).await {
+        // The server may close without a TLS close_notify.
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => Ok(response),
+        Err(e) => Err(e),
+        Ok(_) => Ok(response),
+    }
+}
+
+/// The verified client certificate of a connection.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+struct ClientCertificate {
+    /// e.g. `CN=deploy-controller, O=Example Corp`.
+    subject: String,
+    /// e.g. `deploy-controller`.
+    common_name: Option<String>,
+}
+
+/// `HttpServer::on_connect` hook keeping the client certificate's subject
+/// with each HTTPS connection that presented one.
+#[cfg(feature = "tls")]
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<actix_tls::accept::rustls_0_23::TlsStream<actix_web::rt::net::TcpStream>>() else {
+        return;
+    };
+    let Some(certificate) = stream.get_ref().1.peer_certificates().and_then(|chain| chain.first()) else {
+        return;
+    };
+    if let Ok((_, parsed)) = x509_parser::parse_x509_certificate(certificate.as_ref()) {
+        data.insert(ClientCertificate {
+            subject: parsed.subject().to_string(),
+            common_name: parsed.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()).map(str::to_string),
+        });
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn on_connect(_connection: &dyn Any, _data: &mut Extensions) {}
+
+/// Subject of the client certificate `req` came with.
+pub fn client_subject(req: &HttpRequest) -> Option<String> {
+    req.conn_data::<ClientCertificate>().map(|certificate| certificate.subject.clone())
+}
+
+/// Common name of the client certificate `req` came with.
+pub fn client_common_name(req: &HttpRequest) -> Option<String> {
+    req.conn_data::<ClientCertificate>().and_then(|certificate| certificate.common_name.clone())
+}
+
+#[cfg(feature = "tls")]
+mod rustls_config {
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+    use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// The agent's certificate chain and key.
+    pub struct Identity {
+        pub chain: Vec<CertificateDer<'static>>,
+        pub key: PrivateKeyDer<'static>,
+    }
+
+    pub fn provider() -> Arc<CryptoProvider> {
+        Arc::new(rustls::crypto::ring::default_provider())
+    }
+
+    fn certificates(path: &Path, what: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+        let certificates = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read {} {}: {}", what, path.display(), e))?;
+        if certificates.is_empty() {
+            return Err(format!("No certificate in {}", path.display()));
+        }
+        Ok(certificates)
+    }
+
+    pub fn identity(cert: &Path, key: &Path) -> Result<Identity, String> {
+        Ok(Identity {
+            chain: certificates(cert, "TLS certificate")?,
+            key: PrivateKeyDer::from_pem_file(key).map_err(|e| format!("Failed to read TLS key {}: {}", key.display(), e))?,
+        })
+    }
+
+    /// The server configuration for `identity`, requiring client
+    /// certificates signed by `client_ca` if given.
+    pub fn server(identity: &Identity, client_ca: Option<&Path>) -> Result<rustls::ServerConfig, String> {
+        let builder = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match client_ca {
+            Some(client_ca) => builder.with_client_cert_verifier(Arc::new(SignedOrOwn {
+                signed: client_verifier(client_ca)?,
+                own: identity.chain[0].clone(),
+            })),
+            None => builder.with_no_client_auth(),
+        };
+        builder
+            .with_single_cert(identity.chain.clone(), identity.key.clone_key())
+            .map_err(|e| format!("Invalid TLS certificate or key: {}", e))
+    }
+
+    /// Accepts client certificates signed by a CA in `client_ca`.
+    pub fn client_verifier(client_ca: &Path) -> Result<Arc<dyn ClientCertVerifier>, String> {
+        let mut roots = RootCertStore::empty();
+        for certificate in certificates(client_ca, "client CA bundle")? {
+            roots
+                .add(certificate)
+                .map_err(|e| format!("Invalid CA certificate in {}: {}", client_ca.display(), e))?;
+        }
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+            .build()
+            .map_err(|e| format!("Invalid client CA bundle {}: {}", client_ca.display(), e))
+    }
+
+    /// Client certificates signed by the client CA, and the agent's own for
+    /// its watchdog; presenting either proves holding its key.
+    #[derive(Debug)]
+    struct SignedOrOwn {
+        signed: Arc<dyn ClientCertVerifier>,
+        own: CertificateDer<'static>,
+    }
+
+    impl ClientCertVerifier for SignedOrOwn {
+        fn root_hint_subjects(&self) -> &[DistinguishedName] {
+            self.signed.root_hint_subjects()
+        }
+
+        fn verify_client_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            now: UnixTime,
+        ) -> Result<ClientCertVerified, rustls::Error> {
+            if end_entity.as_ref() == self.own.as_ref() {
+                return Ok(ClientCertVerified::assertion());
+            }
+            self.signed.verify_client_cert(end_entity, intermediates, now)
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.signed.verify_tls12_signature(message, cert, dss)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.signed.verify_tls13_signature(message, cert, dss)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.signed.supported_verify_schemes()
+        }
+    }
+
+    /// Accepts only the agent's own certificate, whatever name it is for.
+    #[derive(Debug)]
+    struct Pinned {
+        certificate: CertificateDer<'static>,
+        algorithms: WebPkiSupportedAlgorithms,
+    }
+
+    impl ServerCertVerifier for Pinned {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            if end_entity.as_ref() == self.certificate.as_ref() {
+                Ok(ServerCertVerified::assertion())
+            } else {
+                Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+            }
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.algorithms.supported_schemes()
+        }
+    }
+
+    /// A connector for the agent's own listeners, presenting its certificate
+    /// when clients need one.
+    pub fn loopback(identity: Identity, client_auth: bool) -> Result<tokio_rustls::TlsConnector, String> {
+        let provider = provider();
+        let algorithms = provider.signature_verification_algorithms;
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(Pinned {
+                certificate: identity.chain[0].clone(),
+                algorithms,
+            }));
+        let client = if client_auth {
+            builder.with_client_auth_cert(identity.chain, identity.key).map_err(|e| e.to_string())?
+        } else {
+            builder.with_no_client_auth()
+        };
+        Ok(tokio_rustls::TlsConnector::from(Arc::new(client)))
+    }
+}
+
+/// Check the certificate and key without starting anything.
+#[cfg(feature = "tls")]
+pub fn check(cert: &std::path::Path, key: &std::path::Path) -> Result<(), String> {
+    rustls_config::server(&rustls_config::identity(cert, key)?, None).map(|_| ())
+}
+
+/// Check the client CA bundle without starting anything.
+#[cfg(feature = "tls")]
+pub fn check_client_ca(client_ca: &std::path::Path) -> Result<(), String> {
+    rustls_config::client_verifier(client_ca).map(|_| ())
+}
+
+/// Names a self-signed certificate is for: the host name, loopback and the
+/// specific addresses listened on.
+#[cfg(feature = "tls")]
+fn self_signed_names(listeners: &[ListenerInfo]) -> Vec<String> {
+    let mut names = vec![crate::events::hostname(), "localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    for info in listeners {
+        let ip = info.address.ip();
+        if !ip.is_unspecified() {
+            names.push(ip.to_string());
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    names.retain(|name| name != "unknown" && seen.insert(name.clone()));
+    names
+}
+
+#[cfg(feature = "tls")]
+fn generate(cert: &std::path::Path, key: &std::path::Path, listeners: &[ListenerInfo]) -> Result<(), String> {
+    let generated = rcgen::generate_simple_self_signed(self_signed_names(listeners))
+        .map_err(|e| format!("Failed to generate a self-signed certificate: {}", e))?;
+    for path in [cert, key] {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let write = |options: &std::fs::OpenOptions, path: &std::path::Path, pem: String| {
+        use std::io::Write;
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(pem.as_bytes()))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    };
+    write(&options, key, generated.signing_key.serialize_pem())?;
+    write(std::fs::OpenOptions::new().write(true).create_new(true), cert, generated.cert.pem())
+}
+
+/// Load the certificate, generating a self-signed one first if asked to;
+/// `None` when serving plain HTTP.
+#[cfg(feature = "tls")]
+pub fn load(settings: &Settings, listeners: &[ListenerInfo]) -> Result<Option<Tls>, String> {
+    let Some((cert, key)) = settings.files()? else {
+        return Ok(None);
+    };
+    let generated = settings.generates()?;
+    if generated {
+        generate(&cert, &key, listeners)?;
+    }
+    let identity = rustls_config::identity(&cert, &key)?;
+    let server = rustls_config::server(&identity, settings.client_ca.as_deref())?;
+    Ok(Some(Tls {
+        server,
+        loopback: Loopback {
+            connector: Some(rustls_config::loopback(identity, settings.client_ca.is_some())?),
+        },
+        cert,
+        generated,
+    }))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn load(settings: &Settings, _listeners: &[ListenerInfo]) -> Result<Option<Tls>, String> {
+    match settings.files()? {
+        Some(_) => Err("HTTPS needs the agent built with the tls feature".to_string()),
+        None => Ok(None),
+    }
+}
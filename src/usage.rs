@@ -0,0 +1,179 @@
+//! CPU time and peak memory of a job's process tree, for capacity planning.
+//!
+//! Linux 5.3 or later only: the command's shell is waited for on its pidfd,
+//! which the async runtime polls like a socket so no thread blocks on a
+//! running job. Once it has exited, its resource usage (including every
+//! descendant it waited for) is read with `waitid(WNOWAIT)`, which leaves the
+//! exit status for the normal reaping. Elsewhere no usage is reported.
+
+use serde::Serialize;
+use std::io;
+use std::process::{ExitStatus, Output};
+use std::time::Instant;
+
+use crate::line_events;
+use crate::output;
+use crate::timestamps::Stamper;
+
+#[derive(Serialize, Clone, Copy)]
+pub struct Usage {
+    /// User plus system time.
+    pub cpu_seconds: f64,
+    /// Largest resident set of any process in the tree.
+    pub peak_memory_bytes: u64,
+}
+
+/// Wait until `pid` has exited, without reaping it.
+#[cfg(target_os = "linux")]
+async fn exited(pid: u32) -> io::Result<()> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::Interest;
+
+    // SAFETY: plain syscall; the descriptor it returns is ours to close.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a descriptor nothing else owns.
+    let pidfd = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+    // A pidfd turns readable when its process exits.
+    AsyncFd::with_interest(pidfd, Interest::READABLE)?.readable().await?.retain_ready();
+    Ok(())
+}
+
+/// The usage of `pid`, which has exited and not been reaped.
+#[cfg(target_os = "linux")]
+fn exited_usage(pid: u32) -> Option<Usage> {
+    // SAFETY: both structs are plain data the kernel fills in; the raw
+    // syscall is used because the libc wrapper has no rusage argument.
+    unsafe {
+        let mut info: libc::siginfo_t = std::mem::zeroed();
+        let mut rusage: libc::rusage = std::mem::zeroed();
+        let result = libc::syscall(
+            libc::SYS_waitid,
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info as *mut libc::siginfo_t,
+            libc::WEXITED | libc::WNOWAIT | libc::WNOHANG,
+            &mut rusage as *mut libc::rusage,
+        );
+        // WNOHANG leaves `info` zeroed while the process still runs.
+        if result != 0 || info.si_pid() == 0 {
+            return None;
+        }
+        let seconds = |time: libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1_000_000.0;
+        Some(Usage {
+            cpu_seconds: seconds(rusage.ru_utime) + seconds(rusage.ru_stime),
+            // ru_maxrss is in kilobytes.
+            peak_memory_bytes: rusage.ru_maxrss as u64 * 1024,
+        })
+    }
+}
+
+/// `child.wait()`, also returning the job's usage where it can be measured.
+pub async fn wait(child: &mut tokio::process::Child) -> io::Result<(ExitStatus, Option<Usage>)> {
+    #[cfg(target_os = "linux")]
+    let usage = match child.id() {
+        Some(pid) => match exited(pid).await {
+            Ok(()) => exited_usage(pid),
+            // No pidfds before Linux 5.3.
+            Err(_) => None,
+        },
+        None => None,
+    };
+    #[cfg(not(target_os = "linux"))]
+    let usage = None;
+    Ok((child.wait().await?, usage))
+}
+
+/// Read `pipe` into `bytes` until EOF, passing each line to `lines` and
+/// stamping it with `timestamps`.
+async fn read_pipe(
+    pipe: Option<impl tokio::io::AsyncRead + Unpin>,
+    bytes: &mut Vec<u8>,
+    stream: line_events::Stream,
+    lines: Option<line_events::Sender>,
+    timestamps: Option<Instant>,
+) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    if lines.is_none() && timestamps.is_none() {
+        let _ = pipe.read_to_end(bytes).await;
+        return;
+    }
+    let mut stamper = timestamps.map(Stamper::new);
+    let mut pipe = BufReader::new(pipe);
+    let mut line = Vec::new();
+    while pipe.read_until(b'\n', &mut line).await.is_ok_and(|read| read > 0) {
+        if let Some(lines) = &lines {
+            line_events::send(lines, stream, &line);
+        }
+        match &mut stamper {
+            Some(stamper) => bytes.extend(stamper.stamp(&line)),
+            None => bytes.extend_from_slice(&line),
+        }
+        line.clear();
+    }
+}
+
+/// `cmd.output()`, also returning the job's usage where it can be measured;
+/// `spawned` gets the pid once the command is running, `stdin` is written to
+/// the command (else its stdin is empty), `lines` gets each line of output as
+/// it is printed, and with `timestamps` (when the command started) the
+/// output lines are stamped. Output still unread [`output::DRAIN_GRACE`]
+/// after the command exited (a background process holding the pipes) is
+/// left out.
+pub async fn output(
+    cmd: &mut tokio::process::Command,
+    spawned: impl FnOnce(u32),
+    stdin: Option<Vec<u8>>,
+    lines: Option<line_events::Sender>,
+    timestamps: Option<Instant>,
+) -> io::Result<(Output, Option<Usage>)> {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+
+    let input = if stdin.is_some() { Stdio::piped() } else { Stdio::null() };
+    let mut child = cmd.stdin(input).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    if let Some(pid) = child.id() {
+        spawned(pid);
+    }
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let (stdout_pipe, stderr_pipe, stdin_pipe) = (child.stdout.take(), child.stderr.take(), child.stdin.take());
+    let (exited_tx, exited_rx) = tokio::sync::oneshot::channel::<()>();
+    let exited = async {
+        let write = async move {
+            if let (Some(mut pipe), Some(stdin)) = (stdin_pipe, stdin) {
+                // A command that doesn't read its stdin closes the pipe early.
+                let _ = pipe.write_all(&stdin).await;
+            }
+        };
+        let (waited, ()) = tokio::join!(wait(&mut child), write);
+        let _ = exited_tx.send(());
+        waited
+    };
+    // Both pipes are drained while waiting so the command can't block on a full one.
+    let drained = async {
+        let reads = async {
+            tokio::join!(
+                read_pipe(stdout_pipe, &mut stdout, line_events::Stream::Stdout, lines.clone(), timestamps),
+                read_pipe(stderr_pipe, &mut stderr, line_events::Stream::Stderr, lines.clone(), timestamps),
+            )
+        };
+        let grace = async {
+            let _ = exited_rx.await;
+            tokio::time::sleep(output::DRAIN_GRACE).await;
+        };
+        tokio::select! {
+            _ = reads => {}
+            () = grace => {}
+        }
+    };
+    let (waited, ()) = tokio::join!(exited, drained);
+    let (status, usage) = waited?;
+    Ok((Output { status, stdout, stderr }, usage))
+}
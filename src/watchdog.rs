@@ -0,0 +1,120 @@
+//! Liveness signals for an external supervisor, so a wedged agent gets
+//! restarted instead of sitting there accepting connections and doing nothing.
+//!
+//! Every `AGENT_HEARTBEAT_INTERVAL_SECS` the agent requests its own `/health`
+//! over loopback. Only when that answers does it send a heartbeat:
+//!
+//! - systemd (`Type=notify`, `WatchdogSec=`): `READY=1` once listening,
+//!   `WATCHDOG=1` per heartbeat and `STOPPING=1` on shutdown, through
+//!   `NOTIFY_SOCKET`;
+//! - `AGENT_HEARTBEAT_FILE`: the file is rewritten with the current time, for
+//!   cron or monitoring checks on its age.
+//!
+//! With `AGENT_WATCHDOG_MAX_MISSES`, the agent exits after that many failed
+//! self-checks in a row, so a service manager without a watchdog (the Windows
+//! SCM with "restart the service" recovery, or `Restart=on-failure`) starts a
+//! fresh one.
+
+use std::path::Path;
+use std::time::Duration;
+
+use crate::clock;
+use crate::config::AppConfig;
+use crate::log_error;
+use crate::tls::Loopback;
+
+/// How long the self-check may take before it counts as missed.
+const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send a state change to systemd; a no-op when not started by it.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return;
+    };
+    let bytes = path.as_encoded_bytes();
+    let result = match bytes.strip_prefix(b"@") {
+        // Abstract socket namespace.
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    };
+    if let Err(e) = result {
+        log_error("watchdog", &format!("Failed to notify systemd: {}", e), None);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
+
+/// The systemd watchdog timeout, when one is armed for this process.
+fn systemd_watchdog() -> Option<Duration> {
+    if let Some(pid) = std::env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+/// Whether the server answers `/health` on `addr`.
+async fn self_check(addr: &str, loopback: &Loopback) -> bool {
+    let check = loopback.request(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    match tokio::time::timeout(SELF_CHECK_TIMEOUT, check).await {
+        Ok(Ok(response)) => response.starts_with(b"HTTP/1.1 200"),
+        _ => false,
+    }
+}
+
+fn touch(path: &Path) {
+    if let Err(e) = std::fs::write(path, format!("{}\n", clock::now())) {
+        log_error("watchdog", &format!("Failed to write heartbeat file {}: {}", path.display(), e), None);
+    }
+}
+
+/// Send heartbeats while the server at `addr` is healthy; runs forever.
+pub async fn run(addr: String, loopback: Loopback, config: &AppConfig) {
+    let systemd = systemd_watchdog();
+    if systemd.is_none() && config.heartbeat_file.is_none() && config.watchdog_max_misses.is_none() {
+        return;
+    }
+    // systemd recommends pinging at half the timeout.
+    let interval = match systemd {
+        Some(timeout) => config.heartbeat_interval.min(timeout / 2),
+        None => config.heartbeat_interval,
+    };
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut misses = 0;
+    loop {
+        ticker.tick().await;
+        if !self_check(&addr, &loopback).await {
+            misses += 1;
+            log_error("watchdog", &format!("Self-check of /health failed ({} in a row)", misses), None);
+            if config.watchdog_max_misses.is_some_and(|max| misses >= max) {
+                let error_msg = format!("Exiting after {} failed self-checks so the service manager restarts the agent", misses);
+                eprintln!("{}", error_msg);
+                log_error("watchdog", &error_msg, None);
+                std::process::exit(1);
+            }
+            continue;
+        }
+        misses = 0;
+        if systemd.is_some() {
+            notify("WATCHDOG=1");
+        }
+        if let Some(path) = &config.heartbeat_file {
+            touch(path);
+        }
+    }
+}